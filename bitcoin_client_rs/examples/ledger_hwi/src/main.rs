@@ -4,7 +4,7 @@ use std::sync::Arc;
 
 use bitcoin::{
     consensus::encode::deserialize,
-    hashes::hex::{FromHex, ToHex},
+    hashes::hex::ToHex,
     util::{bip32, psbt::Psbt},
 };
 
@@ -15,7 +15,7 @@ use regex::Regex;
 use ledger_bitcoin_client::{
     async_client::{BitcoinClient, Transport},
     psbt::PartialSignature,
-    wallet::{Version, WalletPolicy, WalletPubKey},
+    wallet::{Version, WalletHmac, WalletPolicy, WalletPubKey},
 };
 
 mod transport;
@@ -132,7 +132,7 @@ async fn get_extended_pubkey<T: Transport>(
     derivation_path: &str,
     display: bool,
 ) -> Result<(), Box<dyn Error>> {
-    let path = bip32::DerivationPath::from_str(&derivation_path).map_err(|e| format!("{}", e))?;
+    let path = bip32::DerivationPath::from_str(derivation_path).map_err(|e| format!("{}", e))?;
     let xpk = client
         .get_extended_pubkey(&path, display)
         .await
@@ -152,7 +152,7 @@ async fn register_wallet<T: Transport>(
         .register_wallet(&wallet)
         .await
         .map_err(|e| format!("{:#?}", e))?;
-    println!("{}", hmac.to_hex());
+    println!("{}", hmac);
     Ok(())
 }
 
@@ -163,16 +163,13 @@ async fn sign<T: Transport>(
     policy: &str,
     hmac: Option<&str>,
 ) -> Result<(), Box<dyn Error>> {
-    let psbt: Psbt = deserialize(&base64::decode(&psbt)?).map_err(|e| format!("{:#?}", e))?;
+    let psbt: Psbt = deserialize(&base64::decode(psbt)?).map_err(|e| format!("{:#?}", e))?;
     let (descriptor_template, keys) = extract_keys_and_template(policy)?;
     let wallet = WalletPolicy::new(name.to_string(), Version::V2, descriptor_template, keys);
-    let hmac = if let Some(s) = hmac {
-        let mut h = [b'\0'; 32];
-        h.copy_from_slice(&Vec::from_hex(&s).map_err(|e| format!("{:#?}", e))?);
-        Some(h)
-    } else {
-        None
-    };
+    let hmac = hmac
+        .map(WalletHmac::from_str)
+        .transpose()
+        .map_err(|e| format!("{:#?}", e))?;
 
     let res = client
         .sign_psbt(&psbt, &wallet, hmac.as_ref())
@@ -221,7 +218,7 @@ async fn sign_message<T: Transport>(
     message: &str,
     derivation_path: &str,
 ) -> Result<(), Box<dyn Error>> {
-    let path = bip32::DerivationPath::from_str(&derivation_path).map_err(|e| format!("{}", e))?;
+    let path = bip32::DerivationPath::from_str(derivation_path).map_err(|e| format!("{}", e))?;
     let (header, ecdsa_sig) = client
         .sign_message(message.as_bytes(), &path)
         .await
@@ -1,4 +1,3 @@
-use std::convert::TryFrom;
 use std::error::Error;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::sync::Arc;
@@ -40,7 +39,7 @@ impl Transport for TransportHID {
             })
             .map(|answer| {
                 (
-                    StatusWord::try_from(answer.retcode()).unwrap_or(StatusWord::Unknown),
+                    StatusWord::from_u16(answer.retcode()),
                     answer.data().to_vec(),
                 )
             })
@@ -73,7 +72,7 @@ impl Transport for TransportTcp {
         let mut req = vec![0u8; command_bytes.len() + 4];
         req[..4].copy_from_slice(&(command_bytes.len() as u32).to_be_bytes());
         req[4..].copy_from_slice(&command_bytes);
-        stream.write(&req).await?;
+        stream.write_all(&req).await?;
 
         let mut buff = [0u8; 4];
         let len = match stream.read(&mut buff).await? {
@@ -85,7 +84,7 @@ impl Transport for TransportTcp {
         stream.read_exact(&mut resp).await?;
         let answer = APDUAnswer::from_answer(resp).map_err(|_| "Invalid Answer")?;
         Ok((
-            StatusWord::try_from(answer.retcode()).unwrap_or(StatusWord::Unknown),
+            StatusWord::from_u16(answer.retcode()),
             answer.data().to_vec(),
         ))
     }
@@ -0,0 +1,12 @@
+#![no_main]
+
+use ledger_bitcoin_client::{psbt::SignPsbtYield, response};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    response::parse_master_fingerprint_response(data);
+    response::parse_extended_pubkey_response(data);
+    response::parse_address_response(data);
+    response::parse_sign_message_response(data);
+    SignPsbtYield::from_slice(data);
+});
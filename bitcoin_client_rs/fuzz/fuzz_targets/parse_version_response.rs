@@ -0,0 +1,8 @@
+#![no_main]
+
+use ledger_bitcoin_client::response::parse_version_response;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    parse_version_response(data);
+});
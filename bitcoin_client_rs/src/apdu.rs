@@ -1,5 +1,6 @@
 use core::convert::TryFrom;
 use core::fmt::Debug;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 // p2 encodes the protocol version implemented
 pub const CURRENT_PROTOCOL_VERSION: u8 = 1;
@@ -55,57 +56,173 @@ impl TryFrom<u8> for ClientCommandCode {
     }
 }
 
+/// Status word ending the responses of the device, see `src/boilerplate/sw.h` in the app.
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
-#[repr(u16)]
 pub enum StatusWord {
     /// Rejected by user
-    Deny = 0x6985,
+    Deny,
+    /// Security status not satisfied, the device is locked
+    SecurityStatusNotSatisfied,
     /// Incorrect Data
-    IncorrectData = 0x6A80,
+    IncorrectData,
     /// Not Supported
-    NotSupported = 0x6A82,
+    NotSupported,
     /// Wrong P1P2
-    WrongP1P2 = 0x6A86,
+    WrongP1P2,
     /// Wrong DataLength
-    WrongDataLength = 0x6A87,
+    WrongDataLength,
     /// Ins not supported
-    InsNotSupported = 0x6D00,
+    InsNotSupported,
     /// Cla not supported
-    ClaNotSupported = 0x6E00,
+    ClaNotSupported,
+    /// Wrong response length
+    WrongResponseLength,
     /// Bad state
-    BadState = 0xB007,
+    BadState,
     /// Signature fail
-    SignatureFail = 0xB008,
+    SignatureFail,
     /// Success
-    OK = 0x9000,
+    OK,
     /// The command is interrupted, and requires the client's response
-    InterruptedExecution = 0xE000,
-    /// Unknown
-    Unknown,
+    InterruptedExecution,
+    /// Status word unknown to the client
+    Unknown(u16),
+}
+
+impl StatusWord {
+    /// Returns the status word of the value, `Unknown` for the values not known to the client.
+    pub fn from_u16(value: u16) -> Self {
+        match value {
+            0x6985 => StatusWord::Deny,
+            0x6982 => StatusWord::SecurityStatusNotSatisfied,
+            0x6A80 => StatusWord::IncorrectData,
+            0x6A82 => StatusWord::NotSupported,
+            0x6A86 => StatusWord::WrongP1P2,
+            0x6A87 => StatusWord::WrongDataLength,
+            0x6D00 => StatusWord::InsNotSupported,
+            0x6E00 => StatusWord::ClaNotSupported,
+            0xB000 => StatusWord::WrongResponseLength,
+            0xB007 => StatusWord::BadState,
+            0xB008 => StatusWord::SignatureFail,
+            0x9000 => StatusWord::OK,
+            0xE000 => StatusWord::InterruptedExecution,
+            value => StatusWord::Unknown(value),
+        }
+    }
+
+    /// Returns the value of the status word.
+    pub fn to_u16(self) -> u16 {
+        match self {
+            StatusWord::Deny => 0x6985,
+            StatusWord::SecurityStatusNotSatisfied => 0x6982,
+            StatusWord::IncorrectData => 0x6A80,
+            StatusWord::NotSupported => 0x6A82,
+            StatusWord::WrongP1P2 => 0x6A86,
+            StatusWord::WrongDataLength => 0x6A87,
+            StatusWord::InsNotSupported => 0x6D00,
+            StatusWord::ClaNotSupported => 0x6E00,
+            StatusWord::WrongResponseLength => 0xB000,
+            StatusWord::BadState => 0xB007,
+            StatusWord::SignatureFail => 0xB008,
+            StatusWord::OK => 0x9000,
+            StatusWord::InterruptedExecution => 0xE000,
+            StatusWord::Unknown(value) => value,
+        }
+    }
 }
 
 impl TryFrom<u16> for StatusWord {
     type Error = ();
 
+    /// Fails for the values not known to the client, see `from_u16` to keep them.
     fn try_from(value: u16) -> Result<Self, Self::Error> {
-        match value {
-            0x6985 => Ok(StatusWord::Deny),
-            0x6A80 => Ok(StatusWord::IncorrectData),
-            0x6A82 => Ok(StatusWord::NotSupported),
-            0x6A86 => Ok(StatusWord::WrongP1P2),
-            0x6A87 => Ok(StatusWord::WrongDataLength),
-            0x6D00 => Ok(StatusWord::InsNotSupported),
-            0x6E00 => Ok(StatusWord::ClaNotSupported),
-            0xB007 => Ok(StatusWord::BadState),
-            0xB008 => Ok(StatusWord::SignatureFail),
-            0x9000 => Ok(StatusWord::OK),
-            0xE000 => Ok(StatusWord::InterruptedExecution),
-            _ => Err(()),
+        match StatusWord::from_u16(value) {
+            StatusWord::Unknown(_) => Err(()),
+            sw => Ok(sw),
         }
     }
 }
 
-#[derive(Clone)]
+impl From<StatusWord> for u16 {
+    fn from(sw: StatusWord) -> Self {
+        sw.to_u16()
+    }
+}
+
+/// Splits the raw response of the device into its status word and its data.
+/// Returns None if the response is shorter than a status word.
+/// Status words unknown to the client are returned as `StatusWord::Unknown` with their value.
+pub fn decode_response(response: &[u8]) -> Option<(StatusWord, Vec<u8>)> {
+    if response.len() < 2 {
+        return None;
+    }
+    let (data, sw) = response.split_at(response.len() - 2);
+    let sw = StatusWord::from_u16(u16::from_be_bytes([sw[0], sw[1]]));
+    Some((sw, data.to_vec()))
+}
+
+/// Error of a transport exchanging raw APDU bytes, see `client::ByteTransport`.
+#[derive(Debug)]
+pub enum ByteTransportError<E: Debug> {
+    Transport(E),
+    /// The response is shorter than a status word.
+    InvalidResponse(Vec<u8>),
+}
+
+/// Direction of the bytes passed to an `ApduLogger`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// Command sent to the device.
+    Command,
+    /// Response of the device, the data followed by the status word.
+    Response,
+}
+
+/// Callback receiving the bytes of every APDU exchanged with the device.
+pub type ApduLogger = Box<dyn Fn(Direction, &[u8]) + Send + Sync>;
+
+/// Counters of the exchanges of a client with the device, see `BitcoinClient::stats`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Commands sent to the device, including the responses to its client commands.
+    pub exchanges: u64,
+    /// Client commands of the device answered by the interpreter, each one costing an
+    /// exchange with `continue_interrupted`.
+    pub client_commands: u64,
+    /// Bytes of the commands sent to the device.
+    pub bytes_sent: u64,
+    /// Bytes of the responses of the device, status words included.
+    pub bytes_received: u64,
+}
+
+/// State of the device inferred by the client, see `BitcoinClient::poll_state`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DeviceState {
+    /// No command that may prompt the user is in flight.
+    Idle,
+    /// A command that may prompt the user is in flight, from its first exchange to its
+    /// response: the device displays the data to confirm, or is still processing it.
+    AwaitingUserConfirmation,
+}
+
+// Counts a command that may prompt the user while it is in flight, also when the future
+// of an async request is dropped before it completes.
+pub(crate) struct PendingPrompt<'a>(&'a AtomicUsize);
+
+impl<'a> PendingPrompt<'a> {
+    pub(crate) fn new(pending: &'a AtomicUsize) -> Self {
+        pending.fetch_add(1, Ordering::SeqCst);
+        Self(pending)
+    }
+}
+
+impl Drop for PendingPrompt<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct APDUCommand {
     pub cla: u8,
     pub ins: u8,
@@ -115,11 +232,28 @@ pub struct APDUCommand {
 }
 
 impl APDUCommand {
+    /// Returns the bytes of the command: CLA, INS, P1, P2, the length of the data (Lc) on
+    /// one byte and the data, which must not exceed 255 bytes.
     pub fn encode(&self) -> Vec<u8> {
         let mut vec = vec![self.cla, self.ins, self.p1, self.p2, self.data.len() as u8];
         vec.extend(self.data.iter());
         vec
     }
+
+    /// Parses the bytes of a command encoded with `encode`.
+    /// Returns None if the length of the data differs from Lc.
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        match bytes {
+            [cla, ins, p1, p2, lc, data @ ..] if *lc as usize == data.len() => Some(Self {
+                cla: *cla,
+                ins: *ins,
+                p1: *p1,
+                p2: *p2,
+                data: data.to_vec(),
+            }),
+            _ => None,
+        }
+    }
 }
 
 impl core::default::Default for APDUCommand {
@@ -128,8 +262,78 @@ impl core::default::Default for APDUCommand {
             cla: Cla::Default as u8,
             ins: 0x00,
             p1: 0x00,
-            p2: CURRENT_PROTOCOL_VERSION as u8,
+            p2: CURRENT_PROTOCOL_VERSION,
             data: Vec::new(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apdu_command_roundtrip() {
+        for len in [0, 1, 254, 255] {
+            let command = APDUCommand {
+                cla: Cla::Bitcoin as u8,
+                ins: BitcoinCommandCode::SignPSBT as u8,
+                p1: 0x00,
+                p2: CURRENT_PROTOCOL_VERSION,
+                data: vec![0xab; len],
+            };
+            let bytes = command.encode();
+            assert_eq!(bytes.len(), 5 + len);
+            assert_eq!(bytes[4] as usize, len);
+            assert_eq!(APDUCommand::decode(&bytes), Some(command));
+        }
+
+        assert_eq!(APDUCommand::decode(&[0xe1, 0x04, 0x00, 0x01]), None);
+        assert_eq!(
+            APDUCommand::decode(&[0xe1, 0x04, 0x00, 0x01, 0x02, 0xab]),
+            None
+        );
+        assert_eq!(
+            APDUCommand::decode(&[0xe1, 0x04, 0x00, 0x01, 0x00, 0xab]),
+            None
+        );
+    }
+
+    #[test]
+    fn test_status_word_roundtrip() {
+        let known = [
+            (StatusWord::Deny, 0x6985),
+            (StatusWord::SecurityStatusNotSatisfied, 0x6982),
+            (StatusWord::IncorrectData, 0x6A80),
+            (StatusWord::NotSupported, 0x6A82),
+            (StatusWord::WrongP1P2, 0x6A86),
+            (StatusWord::WrongDataLength, 0x6A87),
+            (StatusWord::InsNotSupported, 0x6D00),
+            (StatusWord::ClaNotSupported, 0x6E00),
+            (StatusWord::WrongResponseLength, 0xB000),
+            (StatusWord::BadState, 0xB007),
+            (StatusWord::SignatureFail, 0xB008),
+            (StatusWord::OK, 0x9000),
+            (StatusWord::InterruptedExecution, 0xE000),
+        ];
+        for (sw, value) in known {
+            assert_eq!(StatusWord::from_u16(value), sw);
+            assert_eq!(sw.to_u16(), value);
+            assert_eq!(StatusWord::try_from(value), Ok(sw));
+            let mut response = vec![0x01];
+            response.extend_from_slice(&value.to_be_bytes());
+            assert_eq!(decode_response(&response), Some((sw, vec![0x01])));
+        }
+        for value in 0..=u16::MAX {
+            let sw = StatusWord::from_u16(value);
+            assert_eq!(u16::from(sw), value);
+            match known.iter().find(|(_, v)| *v == value) {
+                Some((known, _)) => assert_eq!(sw, *known),
+                None => {
+                    assert_eq!(sw, StatusWord::Unknown(value));
+                    assert_eq!(StatusWord::try_from(value), Err(()));
+                }
+            }
+        }
+    }
+}
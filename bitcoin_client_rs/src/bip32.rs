@@ -0,0 +1,473 @@
+//! Helpers building the BIP32 derivation paths used by the Qtum application, and the
+//! addresses of the Qtum networks.
+use core::convert::TryFrom;
+
+use bitcoin::{
+    bech32::{self, FromBase32, ToBase32},
+    hashes::Hash,
+    secp256k1::{Secp256k1, Verification},
+    util::{
+        address::{Payload, WitnessVersion},
+        base58,
+        bip32::{ChildNumber, DerivationPath, Error},
+    },
+    PubkeyHash, PublicKey, Script, ScriptHash,
+};
+
+use crate::wallet::AddressType;
+
+/// Coin type of the Qtum mainnet, registered in SLIP-0044.
+pub const QTUM_COIN_TYPE: u32 = 2301;
+/// Coin type shared by all the test networks.
+pub const TESTNET_COIN_TYPE: u32 = 1;
+
+/// Prefixes of the addresses of a Qtum network.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct AddressParams {
+    /// Version byte of the base58 P2PKH addresses.
+    pub pubkey_prefix: u8,
+    /// Version byte of the base58 P2SH addresses.
+    pub script_prefix: u8,
+    /// Human readable part of the bech32 segwit addresses.
+    pub hrp: &'static str,
+}
+
+/// Address prefixes of the Qtum mainnet.
+pub const QTUM_MAINNET_PARAMS: AddressParams = AddressParams {
+    pubkey_prefix: 58,
+    script_prefix: 50,
+    hrp: "qc",
+};
+/// Address prefixes of the Qtum testnet.
+pub const QTUM_TESTNET_PARAMS: AddressParams = AddressParams {
+    pubkey_prefix: 120,
+    script_prefix: 110,
+    hrp: "tq",
+};
+/// Address prefixes of a Qtum regtest node with the default chain parameters: the base58
+/// prefixes of the testnet, and `qcrt` for the segwit addresses.
+pub const QTUM_REGTEST_PARAMS: AddressParams = AddressParams {
+    pubkey_prefix: 120,
+    script_prefix: 110,
+    hrp: "qcrt",
+};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum QtumNetwork {
+    Mainnet,
+    Testnet,
+    /// A local regtest network, with the address prefixes of its chain parameters,
+    /// usually `QTUM_REGTEST_PARAMS`.
+    Regtest(AddressParams),
+}
+
+/// Error decoding a Qtum address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QtumAddressError {
+    /// The string is neither a base58 nor a bech32 address of a known type.
+    Invalid,
+    /// The address is valid but has the prefixes of another network.
+    NetworkMismatch,
+}
+
+impl QtumNetwork {
+    /// Returns a regtest network with the default chain parameters of Qtum.
+    pub fn regtest() -> Self {
+        QtumNetwork::Regtest(QTUM_REGTEST_PARAMS)
+    }
+
+    /// Returns the BIP44 coin type of the network.
+    pub fn coin_type(&self) -> u32 {
+        match self {
+            QtumNetwork::Mainnet => QTUM_COIN_TYPE,
+            QtumNetwork::Testnet | QtumNetwork::Regtest(_) => TESTNET_COIN_TYPE,
+        }
+    }
+
+    /// Returns the address prefixes of the network.
+    pub fn params(&self) -> AddressParams {
+        match self {
+            QtumNetwork::Mainnet => QTUM_MAINNET_PARAMS,
+            QtumNetwork::Testnet => QTUM_TESTNET_PARAMS,
+            QtumNetwork::Regtest(params) => *params,
+        }
+    }
+
+    /// Returns the Bitcoin network whose `bitcoin::Address` values stand for the addresses
+    /// of the network.
+    pub fn bitcoin_network(&self) -> bitcoin::Network {
+        match self {
+            QtumNetwork::Mainnet => bitcoin::Network::Bitcoin,
+            QtumNetwork::Testnet => bitcoin::Network::Testnet,
+            QtumNetwork::Regtest(_) => bitcoin::Network::Regtest,
+        }
+    }
+
+    /// Returns the address of the script_pubkey on the network, encoded with the prefixes
+    /// of the Qtum chain parameters: `Q` and `M` on mainnet, `q` and `m` on testnet and
+    /// regtest for the legacy addresses, `qc`, `tq` and `qcrt` for the segwit ones.
+    /// Returns None for the scripts without an address, like `OP_RETURN` or contract outputs.
+    pub fn address(&self, script_pubkey: &Script) -> Option<String> {
+        let params = self.params();
+        match Payload::from_script(script_pubkey).ok()? {
+            Payload::PubkeyHash(hash) => {
+                let mut data = vec![params.pubkey_prefix];
+                data.extend_from_slice(&hash[..]);
+                Some(base58::check_encode_slice(&data))
+            }
+            Payload::ScriptHash(hash) => {
+                let mut data = vec![params.script_prefix];
+                data.extend_from_slice(&hash[..]);
+                Some(base58::check_encode_slice(&data))
+            }
+            Payload::WitnessProgram { version, program } => {
+                let mut data = vec![version.into()];
+                data.extend(program.to_base32());
+                let variant = match version {
+                    WitnessVersion::V0 => bech32::Variant::Bech32,
+                    _ => bech32::Variant::Bech32m,
+                };
+                bech32::encode(params.hrp, data, variant).ok()
+            }
+        }
+    }
+
+    /// Decodes an address of the network into its script_pubkey, the reverse of `address`.
+    /// The legacy addresses of the testnet and of regtest share their prefixes: they cannot
+    /// be told apart.
+    pub fn script_pubkey(&self, address: &str) -> Result<Script, QtumAddressError> {
+        let params = self.params();
+        if let Ok(data) = base58::from_check(address) {
+            if data.len() != 21 {
+                return Err(QtumAddressError::Invalid);
+            }
+            let payload = if data[0] == params.pubkey_prefix {
+                Payload::PubkeyHash(PubkeyHash::from_slice(&data[1..]).expect("20 bytes"))
+            } else if data[0] == params.script_prefix {
+                Payload::ScriptHash(ScriptHash::from_slice(&data[1..]).expect("20 bytes"))
+            } else {
+                return Err(QtumAddressError::NetworkMismatch);
+            };
+            return Ok(payload.script_pubkey());
+        }
+
+        let (hrp, data, variant) =
+            bech32::decode(address).map_err(|_| QtumAddressError::Invalid)?;
+        let (version, program) = data.split_first().ok_or(QtumAddressError::Invalid)?;
+        let version = WitnessVersion::try_from(*version).map_err(|_| QtumAddressError::Invalid)?;
+        let program = Vec::<u8>::from_base32(program).map_err(|_| QtumAddressError::Invalid)?;
+        let expected_variant = match version {
+            WitnessVersion::V0 => bech32::Variant::Bech32,
+            _ => bech32::Variant::Bech32m,
+        };
+        let valid_length = match version {
+            WitnessVersion::V0 => program.len() == 20 || program.len() == 32,
+            _ => (2..=40).contains(&program.len()),
+        };
+        if variant != expected_variant || !valid_length {
+            return Err(QtumAddressError::Invalid);
+        }
+        if hrp != params.hrp {
+            return Err(QtumAddressError::NetworkMismatch);
+        }
+        Ok(Payload::WitnessProgram { version, program }.script_pubkey())
+    }
+}
+
+/// Variant of the Qtum application running on the device, told by the name returned by
+/// `get_version`: the mainnet app derives mainnet keys and addresses only, the test app
+/// testnet and regtest ones only.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AppVariant {
+    /// The "Qtum" app.
+    Mainnet,
+    /// The "Qtum Test" app.
+    Test,
+    /// An app with another name, like an app derived from the Qtum app.
+    Other,
+}
+
+impl AppVariant {
+    /// Returns the variant of the app with the given name.
+    pub fn from_app_name(name: &str) -> Self {
+        match name {
+            "Qtum" => AppVariant::Mainnet,
+            "Qtum Test" => AppVariant::Test,
+            _ => AppVariant::Other,
+        }
+    }
+
+    /// Returns true if the app serves the network. An app of another name is assumed to.
+    pub fn supports(&self, network: &QtumNetwork) -> bool {
+        match self {
+            AppVariant::Mainnet => *network == QtumNetwork::Mainnet,
+            AppVariant::Test => *network != QtumNetwork::Mainnet,
+            AppVariant::Other => true,
+        }
+    }
+}
+
+/// A scheme deriving the addresses of an account from its keys.
+/// The standard script types of `AddressType` are the schemes supported by the application.
+pub trait AddressScheme {
+    /// Returns the BIP44 purpose of the accounts using the scheme.
+    fn purpose(&self) -> u32;
+
+    /// Returns the script_pubkey of the address of the given key,
+    /// None if the key cannot be used with the scheme.
+    fn script_pubkey<C: Verification>(
+        &self,
+        secp: &Secp256k1<C>,
+        key: &PublicKey,
+    ) -> Option<Script>;
+}
+
+impl AddressScheme for AddressType {
+    fn purpose(&self) -> u32 {
+        match self {
+            AddressType::Legacy => 44,
+            AddressType::NestedSegwit => 49,
+            AddressType::NativeSegwit => 84,
+            AddressType::Taproot => 86,
+        }
+    }
+
+    fn script_pubkey<C: Verification>(
+        &self,
+        secp: &Secp256k1<C>,
+        key: &PublicKey,
+    ) -> Option<Script> {
+        match self {
+            AddressType::Legacy => Some(Script::new_p2pkh(&key.pubkey_hash())),
+            AddressType::NestedSegwit => Some(Script::new_p2sh(
+                &Script::new_v0_p2wpkh(&key.wpubkey_hash()?).script_hash(),
+            )),
+            AddressType::NativeSegwit => Some(Script::new_v0_p2wpkh(&key.wpubkey_hash()?)),
+            AddressType::Taproot => {
+                if !key.compressed {
+                    return None;
+                }
+                Some(Script::new_v1_p2tr(secp, key.inner.into(), None))
+            }
+        }
+    }
+}
+
+/// Returns the BIP44 purpose of the address scheme.
+pub fn purpose(kind: impl AddressScheme) -> u32 {
+    kind.purpose()
+}
+
+/// Builds the path m/purpose'/coin_type'/account' of a standard account.
+/// Returns an error if the account index is already hardened.
+pub fn qtum_account_xpub_path(
+    account: u32,
+    kind: impl AddressScheme,
+    network: QtumNetwork,
+) -> Result<DerivationPath, Error> {
+    Ok(DerivationPath::from(vec![
+        ChildNumber::from_hardened_idx(kind.purpose())?,
+        ChildNumber::from_hardened_idx(network.coin_type())?,
+        ChildNumber::from_hardened_idx(account)?,
+    ]))
+}
+
+/// Builds the path m/purpose'/coin_type'/account'/change of a standard account,
+/// the purpose being chosen according to the address scheme.
+/// Returns an error if the account index is already hardened.
+pub fn qtum_account_path(
+    account: u32,
+    change: bool,
+    kind: impl AddressScheme,
+    network: QtumNetwork,
+) -> Result<DerivationPath, Error> {
+    let path = qtum_account_xpub_path(account, kind, network)?;
+    Ok(path.child(ChildNumber::Normal {
+        index: change as u32,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::str::FromStr;
+
+    #[test]
+    fn test_qtum_address() {
+        use bitcoin::hashes::hex::FromHex;
+        let script = |hex: &str| Script::from(Vec::from_hex(hex).unwrap());
+        let hash = "331e3bb02f5f3a6d7e2b0c3d7d2b1c2a3d4e5f60";
+
+        let p2pkh = script(&format!("76a914{}88ac", hash));
+        assert_eq!(
+            QtumNetwork::Mainnet.address(&p2pkh).unwrap(),
+            "QRGGnieNVYe7GwJiXWoPZBTS2Z4xU2AXWn"
+        );
+        assert_eq!(
+            QtumNetwork::Testnet.address(&p2pkh).unwrap(),
+            "qNDfqT8EWjNRyox63XT9cxLD3q3SVmZ9qH"
+        );
+        let p2sh = script(&format!("a914{}87", hash));
+        assert_eq!(
+            QtumNetwork::Mainnet.address(&p2sh).unwrap(),
+            "MCZSurG4p6w6jUC2LA8qgBH8zX1QpPJRww"
+        );
+        let p2wpkh = script(&format!("0014{}", hash));
+        assert_eq!(
+            QtumNetwork::Mainnet.address(&p2wpkh).unwrap(),
+            "qc1qxv0rhvp0tuax6l3tps7h62cu9g75uhmq29y85g"
+        );
+        assert_eq!(
+            QtumNetwork::Testnet.address(&p2wpkh).unwrap(),
+            "tq1qxv0rhvp0tuax6l3tps7h62cu9g75uhmqgn9e9w"
+        );
+        let p2tr = script("5120000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f");
+        assert_eq!(
+            QtumNetwork::Testnet.address(&p2tr).unwrap(),
+            "tq1pqqqsyqcyq5rqwzqfpg9scrgwpugpzysnzs23v9ccrydpk8qarc0sv5635y"
+        );
+        assert_eq!(
+            QtumNetwork::Mainnet.address(&script("6a0568656c6c6f")),
+            None
+        );
+    }
+
+    #[test]
+    fn test_qtum_script_pubkey() {
+        use bitcoin::hashes::hex::FromHex;
+        let script = |hex: &str| Script::from(Vec::from_hex(hex).unwrap());
+        let hash = "331e3bb02f5f3a6d7e2b0c3d7d2b1c2a3d4e5f60";
+        let scripts = [
+            script(&format!("76a914{}88ac", hash)),
+            script(&format!("a914{}87", hash)),
+            script(&format!("0014{}", hash)),
+            script("5120000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f"),
+        ];
+
+        let regtest = QtumNetwork::regtest();
+        for network in [QtumNetwork::Mainnet, QtumNetwork::Testnet, regtest] {
+            for script_pubkey in &scripts {
+                let address = network.address(script_pubkey).unwrap();
+                assert_eq!(network.script_pubkey(&address).unwrap(), *script_pubkey);
+            }
+        }
+        assert_eq!(
+            regtest.address(&scripts[2]).unwrap(),
+            "qcrt1qxv0rhvp0tuax6l3tps7h62cu9g75uhmqqkwav9"
+        );
+
+        // A custom regtest with other prefixes.
+        let custom = QtumNetwork::Regtest(AddressParams {
+            pubkey_prefix: 0x6f,
+            script_prefix: 0xc4,
+            hrp: "qtrt",
+        });
+        let address = custom.address(&scripts[0]).unwrap();
+        assert!(address.starts_with('m') || address.starts_with('n'));
+        assert_eq!(custom.script_pubkey(&address).unwrap(), scripts[0]);
+        assert!(custom.address(&scripts[2]).unwrap().starts_with("qtrt1"));
+
+        // Addresses of the mainnet given to regtest, and the other way around.
+        for script_pubkey in &scripts {
+            let address = QtumNetwork::Mainnet.address(script_pubkey).unwrap();
+            assert_eq!(
+                regtest.script_pubkey(&address),
+                Err(QtumAddressError::NetworkMismatch)
+            );
+            let address = regtest.address(script_pubkey).unwrap();
+            assert_eq!(
+                QtumNetwork::Mainnet.script_pubkey(&address),
+                Err(QtumAddressError::NetworkMismatch)
+            );
+        }
+
+        assert_eq!(
+            regtest.script_pubkey("qcrt1qxv0rhvp0tuax6l3tps7h62cu9g75uhmqqkwav8"),
+            Err(QtumAddressError::Invalid)
+        );
+        assert_eq!(
+            regtest.script_pubkey("not an address"),
+            Err(QtumAddressError::Invalid)
+        );
+    }
+
+    #[test]
+    fn test_qtum_account_path() {
+        assert_eq!(
+            qtum_account_path(0, false, AddressType::Legacy, QtumNetwork::Mainnet).unwrap(),
+            DerivationPath::from_str("m/44'/2301'/0'/0").unwrap()
+        );
+        assert_eq!(
+            qtum_account_path(3, true, AddressType::NestedSegwit, QtumNetwork::Mainnet).unwrap(),
+            DerivationPath::from_str("m/49'/2301'/3'/1").unwrap()
+        );
+        assert_eq!(
+            qtum_account_path(1, false, AddressType::NativeSegwit, QtumNetwork::Testnet).unwrap(),
+            DerivationPath::from_str("m/84'/1'/1'/0").unwrap()
+        );
+        assert_eq!(
+            qtum_account_xpub_path(0, AddressType::Taproot, QtumNetwork::Testnet).unwrap(),
+            DerivationPath::from_str("m/86'/1'/0'").unwrap()
+        );
+        assert!(
+            qtum_account_path(0x80000000, false, AddressType::Legacy, QtumNetwork::Mainnet)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_address_scheme_script_pubkey() {
+        let secp = Secp256k1::verification_only();
+        let key = PublicKey::from_str(
+            "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+        )
+        .unwrap();
+
+        let script = AddressType::Legacy.script_pubkey(&secp, &key).unwrap();
+        assert!(script.is_p2pkh());
+        let script = AddressType::NestedSegwit
+            .script_pubkey(&secp, &key)
+            .unwrap();
+        assert!(script.is_p2sh());
+        let script = AddressType::NativeSegwit
+            .script_pubkey(&secp, &key)
+            .unwrap();
+        assert!(script.is_v0_p2wpkh());
+        let script = AddressType::Taproot.script_pubkey(&secp, &key).unwrap();
+        assert_eq!(
+            script,
+            bitcoin::Address::p2tr(&secp, key.inner.into(), None, bitcoin::Network::Testnet)
+                .script_pubkey()
+        );
+
+        let uncompressed = PublicKey::new_uncompressed(key.inner);
+        assert!(AddressType::Legacy
+            .script_pubkey(&secp, &uncompressed)
+            .is_some());
+        assert!(AddressType::NativeSegwit
+            .script_pubkey(&secp, &uncompressed)
+            .is_none());
+        assert!(AddressType::Taproot
+            .script_pubkey(&secp, &uncompressed)
+            .is_none());
+        assert_eq!(purpose(AddressType::Taproot), 86);
+    }
+
+    #[test]
+    fn test_app_variant() {
+        let mainnet = AppVariant::from_app_name("Qtum");
+        let test = AppVariant::from_app_name("Qtum Test");
+        assert_eq!(mainnet, AppVariant::Mainnet);
+        assert_eq!(test, AppVariant::Test);
+        assert_eq!(AppVariant::from_app_name("Bitcoin"), AppVariant::Other);
+        assert_eq!(AppVariant::from_app_name("qtum"), AppVariant::Other);
+
+        assert!(mainnet.supports(&QtumNetwork::Mainnet));
+        assert!(!mainnet.supports(&QtumNetwork::Testnet));
+        assert!(!mainnet.supports(&QtumNetwork::regtest()));
+        assert!(!test.supports(&QtumNetwork::Mainnet));
+        assert!(test.supports(&QtumNetwork::Testnet));
+        assert!(test.supports(&QtumNetwork::regtest()));
+        assert!(AppVariant::Other.supports(&QtumNetwork::Mainnet));
+    }
+}
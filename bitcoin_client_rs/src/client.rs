@@ -1,8 +1,11 @@
 use core::fmt::Debug;
+use core::ops::ControlFlow;
 use core::str::FromStr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::time::Duration;
+use std::time::Instant;
 
 use bitcoin::{
-    consensus::encode::{deserialize_partial, VarInt},
     secp256k1::ecdsa,
     util::{
         bip32::{DerivationPath, ExtendedPubKey, Fingerprint},
@@ -14,22 +17,246 @@ use bitcoin::{
 use miniscript::{Descriptor, DescriptorPublicKey};
 
 use crate::{
-    apdu::{APDUCommand, StatusWord},
+    apdu::{
+        decode_response, APDUCommand, ApduLogger, ByteTransportError, Cla, DeviceState, Direction,
+        PendingPrompt, Stats, StatusWord,
+    },
+    bip32::{AppVariant, QtumAddressError, QtumNetwork},
     command,
     error::BitcoinClientError,
-    interpreter::{get_merkleized_map_commitment, ClientCommandInterpreter},
+    interpreter::ClientCommandInterpreter,
     psbt::*,
-    wallet::WalletPolicy,
+    response::{
+        parse_address_response, parse_extended_pubkey_response, parse_master_fingerprint_response,
+        parse_sign_message_response, parse_version_response,
+    },
+    wallet::{
+        AddressCache, Capabilities, SigningLimits, Version, WalletHmac, WalletPolicy, WalletPubKey,
+    },
 };
 
 /// BitcoinClient calls and interprets commands with the Ledger Device.
 pub struct BitcoinClient<T: Transport> {
     transport: T,
+    apdu_logger: Option<ApduLogger>,
+    cla: u8,
+    address_cache: Option<AddressCache>,
+    network: Option<QtumNetwork>,
+    extra_preimages: Vec<Vec<u8>>,
+    keepalive: usize,
+    io_timeout: Option<Duration>,
+    interactive_timeout: Option<Duration>,
+    master_fingerprint: std::sync::Mutex<Option<Fingerprint>>,
+    app_version: std::sync::Mutex<Option<String>>,
+    stats: std::sync::Mutex<Stats>,
+    pending_prompts: AtomicUsize,
 }
 
 impl<T: Transport> BitcoinClient<T> {
     pub fn new(transport: T) -> Self {
-        Self { transport }
+        Self {
+            transport,
+            apdu_logger: None,
+            cla: Cla::Bitcoin as u8,
+            address_cache: None,
+            network: None,
+            extra_preimages: Vec::new(),
+            keepalive: 0,
+            io_timeout: None,
+            interactive_timeout: None,
+            master_fingerprint: std::sync::Mutex::new(None),
+            app_version: std::sync::Mutex::new(None),
+            stats: std::sync::Mutex::new(Stats::default()),
+            pending_prompts: AtomicUsize::new(0),
+        }
+    }
+
+    /// Sets the CLA byte of the application commands, for apps derived from the
+    /// Bitcoin application that use another one. The framework commands are unchanged.
+    pub fn with_cla(mut self, cla: u8) -> Self {
+        self.cla = cla;
+        self
+    }
+
+    /// Keeps in memory the addresses returned by `get_wallet_address` without display,
+    /// so that the device is only asked once for each wallet, change and address index.
+    /// Displayed addresses are always requested from the device, for the user to confirm them.
+    pub fn with_address_cache(mut self) -> Self {
+        self.address_cache = Some(AddressCache::default());
+        self
+    }
+
+    /// Decodes the addresses returned by `get_wallet_address` with the prefixes of the Qtum
+    /// network, for an app returning Qtum addresses, for instance on a local regtest with
+    /// `QtumNetwork::regtest()`. The returned `bitcoin::Address` has the network given by
+    /// `QtumNetwork::bitcoin_network`, and an address of another network fails with
+    /// `BitcoinClientError::NetworkMismatch`. By default the addresses are decoded in the
+    /// Bitcoin format. `check_app_network` checks that the app on the device serves it.
+    pub fn with_network(mut self, network: QtumNetwork) -> Self {
+        self.network = Some(network);
+        self
+    }
+
+    /// Serves the given preimages to the device, in addition to the wallet policy and its
+    /// keys, when it requests them by their SHA256 in `register_wallet`, `get_wallet_address`
+    /// and the signing of a PSBT.
+    /// The policies supported by the app up to version 2.1 request no other preimages: the
+    /// preimages of the `sha256`, `hash256`, `ripemd160` and `hash160` fragments of a
+    /// miniscript policy are not needed to sign, they are added to the witness when the
+    /// input is finalized. The extra preimages are meant for the apps asking for more data
+    /// committed in a policy.
+    pub fn with_extra_preimages(mut self, preimages: Vec<Vec<u8>>) -> Self {
+        self.extra_preimages = preimages;
+        self
+    }
+
+    /// Sets the master fingerprint of the device, known from a previous connection, for the
+    /// helpers needing it, like `get_keyexpression`, not to request it from the device.
+    /// Without it, the fingerprint is requested once and kept; `clear_master_fingerprint`
+    /// forgets it when another device may be connected. `get_master_fingerprint` and
+    /// `check_wallet_device` always ask the device.
+    pub fn with_master_fingerprint(self, fingerprint: Fingerprint) -> Self {
+        *self
+            .master_fingerprint
+            .lock()
+            .expect("fingerprint cache not poisoned") = Some(fingerprint);
+        self
+    }
+
+    /// Forgets the master fingerprint set `with_master_fingerprint` or kept from the device,
+    /// for instance after the device was replaced behind the transport.
+    pub fn clear_master_fingerprint(&self) {
+        *self
+            .master_fingerprint
+            .lock()
+            .expect("fingerprint cache not poisoned") = None;
+    }
+
+    /// Tolerates up to `retries` timeouts of the transport for each command, while the user
+    /// takes time to confirm a prompt: after a timeout recognized by
+    /// `Transport::read_after_timeout`, the response is read again without resending the
+    /// command. Other transport errors still fail the command.
+    pub fn with_keepalive(mut self, retries: usize) -> Self {
+        self.keepalive = retries;
+        self
+    }
+
+    /// Fails the exchanges with the device that take longer than `io` with
+    /// `BitcoinClientError::Timeout { interactive: false }`, and the exchanges of the commands
+    /// that may prompt the user longer than `interactive` with `Timeout { interactive: true }`.
+    /// The commands prompting the user are those using the client command interpreter, like
+    /// `register_wallet`, `sign_psbt` or `sign_message`, and the displayed extended pubkeys.
+    /// The response is read again after each timeout of the transport recognized by
+    /// `Transport::read_after_timeout` until the timeout of the exchange elapses, whatever
+    /// the number of retries set `with_keepalive`.
+    /// The transport receives the timeout of each exchange with
+    /// `Transport::exchange_with_timeout`, to stop waiting for the device once it elapses.
+    /// The timeouts are measured with `std::time::Instant`, not available on `wasm32`.
+    pub fn with_timeouts(mut self, io: Duration, interactive: Duration) -> Self {
+        self.io_timeout = Some(io);
+        self.interactive_timeout = Some(interactive);
+        self
+    }
+
+    /// Returns the counters of the exchanges with the device since the client was created or
+    /// since the last `reset_stats`. Reset before an operation, they tell whether its time
+    /// goes to round trips with the device, like the client commands answered while signing
+    /// a PSBT with many inputs, or to the transfer of the bytes.
+    pub fn stats(&self) -> Stats {
+        *self.stats.lock().expect("stats not poisoned")
+    }
+
+    /// Returns whether the device is awaiting the confirmation of the user, for a wallet to
+    /// show a "check your device" message. The application gives no way to read the
+    /// state of its screens: it is inferred from the commands in flight, the device being
+    /// `AwaitingUserConfirmation` from the first exchange of a command that may prompt the
+    /// user, see `command::prompts_user`, to its response, and `Idle` otherwise.
+    /// It does not wait for the device and can be called while another thread or task
+    /// runs the command.
+    pub fn poll_state(&self) -> DeviceState {
+        if self.pending_prompts.load(Ordering::SeqCst) > 0 {
+            DeviceState::AwaitingUserConfirmation
+        } else {
+            DeviceState::Idle
+        }
+    }
+
+    /// Resets the counters returned by `stats`.
+    pub fn reset_stats(&self) {
+        *self.stats.lock().expect("stats not poisoned") = Stats::default();
+    }
+
+    /// Sets a callback called with the bytes of every command sent to the device and of
+    /// every response received, including the exchanges answering the client commands.
+    /// With the `tracing` feature, the exchanges are also emitted as `tracing` events.
+    pub fn set_apdu_logger(&mut self, logger: ApduLogger) {
+        self.apdu_logger = Some(logger);
+    }
+
+    fn exchange(
+        &self,
+        req: &APDUCommand,
+        interactive: bool,
+    ) -> Result<(StatusWord, Vec<u8>), BitcoinClientError<T::Error>> {
+        let mut req = req.clone();
+        if req.cla == Cla::Bitcoin as u8 {
+            req.cla = self.cla;
+        }
+        let timeout = if interactive {
+            self.interactive_timeout
+        } else {
+            self.io_timeout
+        };
+        let start = timeout.map(|timeout| (Instant::now(), timeout));
+        let mut res = match timeout {
+            Some(timeout) => self.transport.exchange_with_timeout(&req, timeout),
+            None => self.transport.exchange(&req),
+        };
+        let mut retries = 0;
+        while let Err(e) = &res {
+            match start {
+                Some((start, timeout)) if start.elapsed() >= timeout => {
+                    return Err(BitcoinClientError::Timeout { interactive });
+                }
+                Some(_) => {}
+                None if retries == self.keepalive => break,
+                None => {}
+            }
+            retries += 1;
+            match self.transport.read_after_timeout(e) {
+                Some(retry) => res = retry,
+                None => break,
+            }
+        }
+        let res = res.map_err(BitcoinClientError::Transport)?;
+        {
+            let mut stats = self.stats.lock().expect("stats not poisoned");
+            stats.exchanges += 1;
+            stats.bytes_sent += req.encode().len() as u64;
+            stats.bytes_received += res.1.len() as u64 + 2;
+        }
+
+        if self.apdu_logger.is_some() || cfg!(feature = "tracing") {
+            let command = req.encode();
+            let mut response = res.1.clone();
+            response.extend_from_slice(&res.0.to_u16().to_be_bytes());
+            self.log_apdu(Direction::Command, &command);
+            self.log_apdu(Direction::Response, &response);
+        }
+
+        Ok(res)
+    }
+
+    fn log_apdu(&self, direction: Direction, apdu: &[u8]) {
+        if let Some(logger) = &self.apdu_logger {
+            logger(direction, apdu);
+        }
+
+        #[cfg(feature = "tracing")]
+        {
+            use bitcoin::hashes::hex::ToHex;
+            tracing::debug!(?direction, apdu = %apdu.to_hex(), "apdu");
+        }
     }
 
     fn make_request(
@@ -37,18 +264,40 @@ impl<T: Transport> BitcoinClient<T> {
         req: &APDUCommand,
         interpreter: Option<&mut ClientCommandInterpreter>,
     ) -> Result<Vec<u8>, BitcoinClientError<T::Error>> {
-        let (mut sw, mut data) = self
-            .transport
-            .exchange(req)
-            .map_err(BitcoinClientError::Transport)?;
+        self.make_request_with_yield(req, interpreter, |_| Ok(()))
+    }
+
+    // Like make_request, calling `on_yield` with each value yielded by the device before
+    // responding to it. The request is abandoned if `on_yield` returns an error.
+    fn make_request_with_yield<F>(
+        &self,
+        req: &APDUCommand,
+        interpreter: Option<&mut ClientCommandInterpreter>,
+        mut on_yield: F,
+    ) -> Result<Vec<u8>, BitcoinClientError<T::Error>>
+    where
+        F: FnMut(&[u8]) -> Result<(), BitcoinClientError<T::Error>>,
+    {
+        let prompts_user = command::prompts_user(req);
+        let _prompt = if prompts_user {
+            Some(PendingPrompt::new(&self.pending_prompts))
+        } else {
+            None
+        };
+        let interactive = interpreter.is_some() || prompts_user;
+        let (mut sw, mut data) = self.exchange(req, interactive)?;
 
         if let Some(interpreter) = interpreter {
             while sw == StatusWord::InterruptedExecution {
                 let response = interpreter.execute(data)?;
-                let res = self
-                    .transport
-                    .exchange(&command::continue_interrupted(response))
-                    .map_err(BitcoinClientError::Transport)?;
+                self.stats
+                    .lock()
+                    .expect("stats not poisoned")
+                    .client_commands += 1;
+                for result in interpreter.take_yielded() {
+                    on_yield(&result)?;
+                }
+                let res = self.exchange(&command::continue_interrupted(response), interactive)?;
                 sw = res.0;
                 data = res.1;
             }
@@ -81,57 +330,140 @@ impl<T: Transport> BitcoinClient<T> {
             BitcoinClientError::ClientError("Failed to parse descriptor".to_string())
         })?;
 
-        if descriptor
+        let script_pubkey = descriptor
             .at_derivation_index(address_index)
-            .script_pubkey()
-            != expected_address.script_pubkey()
-        {
+            .script_pubkey();
+        if script_pubkey != expected_address.script_pubkey() {
+            // The descriptor only produces one script type, a different type returned by
+            // the device is reported as such rather than as a generic invalid address.
+            let derived = bitcoin::Address::from_script(&script_pubkey, expected_address.network)
+                .ok()
+                .and_then(|address| address.address_type());
+            if derived != expected_address.address_type() {
+                return Err(BitcoinClientError::AddressTypeMismatch {
+                    expected: derived,
+                    device: expected_address.address_type(),
+                });
+            }
             return Err(BitcoinClientError::InvalidResponse("Invalid address. Please update your Bitcoin app. If the problem persists, report a bug at https://github.com/LedgerHQ/app-bitcoin-new".to_string()));
         }
 
         Ok(())
     }
 
+    /// Returns an interpreter for a command of the wallet policy, knowing the serialization
+    /// of the policy, its keys and the preimages set `with_extra_preimages`.
+    fn wallet_interpreter(&self, wallet: &WalletPolicy) -> ClientCommandInterpreter {
+        let mut intpr = ClientCommandInterpreter::new();
+        for preimage in &self.extra_preimages {
+            intpr.add_known_preimage(preimage.clone());
+        }
+        intpr.add_known_preimage(wallet.serialize());
+        let keys: Vec<String> = wallet.keys.iter().map(|k| k.to_string()).collect();
+        intpr.add_known_list(&keys);
+        // the serialization of version 2 policies only commits to the hash of the template,
+        // these policies being supported by the apps of version 2.1.0 and later
+        if wallet.version == Version::V2 {
+            intpr.add_known_preimage(wallet.descriptor_template.as_bytes().to_vec());
+        }
+        intpr
+    }
+
+    /// Decodes an address returned by the device, see `with_network`.
+    fn parse_address(
+        &self,
+        address: &str,
+    ) -> Result<bitcoin::Address, BitcoinClientError<T::Error>> {
+        let network = match self.network {
+            Some(network) => network,
+            None => return Ok(bitcoin::Address::from_str(address)?),
+        };
+        let script_pubkey = network.script_pubkey(address).map_err(|e| match e {
+            QtumAddressError::NetworkMismatch => BitcoinClientError::NetworkMismatch {
+                address: address.to_string(),
+            },
+            QtumAddressError::Invalid => {
+                BitcoinClientError::Parse(format!("invalid Qtum address {}", address))
+            }
+        })?;
+        Ok(bitcoin::Address::from_script(
+            &script_pubkey,
+            network.bitcoin_network(),
+        )?)
+    }
+
     /// Returns the currently running app's name, version and state flags
     pub fn get_version(&self) -> Result<(String, String, Vec<u8>), BitcoinClientError<T::Error>> {
         let cmd = command::get_version();
         let data = self.make_request(&cmd, None)?;
-        if data.is_empty() || data[0] != 0x01 {
-            return Err(BitcoinClientError::UnexpectedResult {
-                command: cmd.ins,
-                data,
-            });
-        }
+        parse_version_response(&data).ok_or(BitcoinClientError::UnexpectedResult {
+            command: cmd.ins,
+            data,
+        })
+    }
 
-        let (name, i): (String, usize) =
-            deserialize_partial(&data[1..]).map_err(|_| BitcoinClientError::UnexpectedResult {
-                command: cmd.ins,
-                data: data.clone(),
-            })?;
+    /// Returns the variant of the running app, told by the name returned by `get_version`.
+    pub fn app_variant(&self) -> Result<AppVariant, BitcoinClientError<T::Error>> {
+        let (name, _, _) = self.get_version()?;
+        Ok(AppVariant::from_app_name(&name))
+    }
 
-        let (version, j): (String, usize) = deserialize_partial(&data[i + 1..]).map_err(|_| {
-            BitcoinClientError::UnexpectedResult {
-                command: cmd.ins,
-                data: data.clone(),
+    /// Checks that the running app serves the network set `with_network`, failing with
+    /// `BitcoinClientError::AppNetworkMismatch` otherwise, for instance when the test app is
+    /// open while the client expects mainnet addresses. Returns the variant of the app, also
+    /// when no network is set.
+    pub fn check_app_network(&self) -> Result<AppVariant, BitcoinClientError<T::Error>> {
+        let app = self.app_variant()?;
+        match self.network {
+            Some(network) if !app.supports(&network) => {
+                #[cfg(feature = "tracing")]
+                tracing::warn!(?app, ?network, "the app does not serve the network");
+                Err(BitcoinClientError::AppNetworkMismatch { app, network })
             }
-        })?;
+            _ => Ok(app),
+        }
+    }
 
-        let (flags, _): (Vec<u8>, usize) =
-            deserialize_partial(&data[i + j + 1..]).map_err(|_| {
+    /// Retrieve the master fingerprint.
+    /// The fingerprint is kept for the helpers needing it, like `get_keyexpression`, see
+    /// `with_master_fingerprint`.
+    pub fn get_master_fingerprint(&self) -> Result<Fingerprint, BitcoinClientError<T::Error>> {
+        let cmd = command::get_master_fingerprint();
+        self.make_request(&cmd, None).and_then(|data| {
+            let fingerprint = parse_master_fingerprint_response(&data).ok_or_else(|| {
                 BitcoinClientError::UnexpectedResult {
                     command: cmd.ins,
                     data: data.clone(),
                 }
             })?;
-
-        Ok((name, version, flags))
+            *self
+                .master_fingerprint
+                .lock()
+                .expect("fingerprint cache not poisoned") = Some(fingerprint);
+            Ok(fingerprint)
+        })
     }
 
-    /// Retrieve the master fingerprint.
-    pub fn get_master_fingerprint(&self) -> Result<Fingerprint, BitcoinClientError<T::Error>> {
-        let cmd = command::get_master_fingerprint();
-        self.make_request(&cmd, None)
-            .map(|data| Fingerprint::from(data.as_slice()))
+    /// Retrieves the master fingerprint and the extended pubkey derived with the given path,
+    /// displaying the extended pubkey on screen for the user to confirm that the device is
+    /// the expected one.
+    /// A wallet can pin both on the first connection (trust on first use) and compare the
+    /// master fingerprint on the next connections, as the displayed key is only confirmed once.
+    /// With a path of depth 1, the parent fingerprint of the key must be the master fingerprint.
+    pub fn confirm_device_identity(
+        &self,
+        path: &DerivationPath,
+    ) -> Result<(Fingerprint, ExtendedPubKey), BitcoinClientError<T::Error>> {
+        let fingerprint = self.get_master_fingerprint()?;
+        let xpub = self.get_extended_pubkey(path, true)?;
+        if xpub.depth as usize != path.len()
+            || (path.len() == 1 && xpub.parent_fingerprint != fingerprint)
+        {
+            return Err(BitcoinClientError::InvalidResponse(
+                "Extended pubkey does not match the master fingerprint".to_string(),
+            ));
+        }
+        Ok((fingerprint, xpub))
     }
 
     /// Retrieve the bip32 extended pubkey derived with the given path
@@ -142,46 +474,142 @@ impl<T: Transport> BitcoinClient<T> {
         display: bool,
     ) -> Result<ExtendedPubKey, BitcoinClientError<T::Error>> {
         let cmd = command::get_extended_pubkey(path, display);
-        self.make_request(&cmd, None).and_then(|data| {
-            ExtendedPubKey::from_str(&String::from_utf8_lossy(&data)).map_err(|_| {
-                BitcoinClientError::UnexpectedResult {
-                    command: cmd.ins,
-                    data,
-                }
-            })
-        })
+        let data = self.make_request(&cmd, None)?;
+        // the unexpected bytes are kept for the diagnosis
+        let xpub = parse_extended_pubkey_response(&data).ok_or_else(|| {
+            BitcoinClientError::UnexpectedResult {
+                command: cmd.ins,
+                data: data.clone(),
+            }
+        })?;
+        Ok(ExtendedPubKey::from_str(xpub)?)
+    }
+
+    /// Retrieves the extended pubkey derived with the given path without displaying it, like
+    /// `get_extended_pubkey` with `display` false.
+    pub fn get_extended_pubkey_silent(
+        &self,
+        path: &DerivationPath,
+    ) -> Result<ExtendedPubKey, BitcoinClientError<T::Error>> {
+        self.get_extended_pubkey(path, false)
+    }
+
+    /// Retrieves the extended pubkey derived with the given path, displayed on the device for
+    /// the user to confirm it, like `get_extended_pubkey` with `display` true.
+    pub fn confirm_extended_pubkey(
+        &self,
+        path: &DerivationPath,
+    ) -> Result<ExtendedPubKey, BitcoinClientError<T::Error>> {
+        self.get_extended_pubkey(path, true)
+    }
+
+    /// Retrieves the extended pubkey derived with the given path, optionally displayed on
+    /// screen, and returns it with its origin as the key expression `[fingerprint/path]xpub`
+    /// of the keys of a `WalletPolicy`.
+    /// The master fingerprint is only requested from the device if it is not known yet, see
+    /// `with_master_fingerprint`.
+    pub fn get_keyexpression(
+        &self,
+        path: &DerivationPath,
+        display: bool,
+    ) -> Result<String, BitcoinClientError<T::Error>> {
+        let cached = *self
+            .master_fingerprint
+            .lock()
+            .expect("fingerprint cache not poisoned");
+        let fingerprint = match cached {
+            Some(fingerprint) => fingerprint,
+            None => self.get_master_fingerprint()?,
+        };
+        let xpub = self.get_extended_pubkey(path, display)?;
+        Ok(WalletPubKey::from(((fingerprint, path.clone()), xpub)).to_string())
+    }
+
+    /// Returns the features of the wallet policies supported by the app, derived from the
+    /// version returned by `get_version`. The version is requested once and kept, until the
+    /// `ReconnectingClient` connects again.
+    pub fn capabilities(&self) -> Result<Capabilities, BitcoinClientError<T::Error>> {
+        Ok(Capabilities::of_app_version(&self.app_version()?))
+    }
+
+    /// Returns the limits of the transactions signed by the app, derived from its version
+    /// like `capabilities`. The signing methods return `TooManyInputs` or `TooManyOutputs`
+    /// for a larger transaction before sending it.
+    pub fn signing_limits(&self) -> Result<SigningLimits, BitcoinClientError<T::Error>> {
+        Ok(SigningLimits::of_app_version(&self.app_version()?))
+    }
+
+    /// Returns the version of the app, requested with `get_version` once and kept.
+    fn app_version(&self) -> Result<String, BitcoinClientError<T::Error>> {
+        let cached = self
+            .app_version
+            .lock()
+            .expect("app version cache not poisoned")
+            .clone();
+        if let Some(app_version) = cached {
+            return Ok(app_version);
+        }
+        let (_, app_version, _) = self.get_version()?;
+        *self
+            .app_version
+            .lock()
+            .expect("app version cache not poisoned") = Some(app_version.clone());
+        Ok(app_version)
     }
 
     /// Registers the given wallet policy, returns the wallet ID and HMAC.
+    /// Version 2 policies require an app of version 2.1.0 or later: the version of the app is
+    /// checked first, returning `UnsupportedAppVersion` for an older app, and
+    /// `UnsupportedFeature` for a policy using taproot scripts that the app does not support.
+    /// A policy failing `WalletPolicy::validate`, like a name longer than the device accepts,
+    /// returns `InvalidPolicy` without being sent.
     #[allow(clippy::type_complexity)]
     pub fn register_wallet(
         &self,
         wallet: &WalletPolicy,
-    ) -> Result<([u8; 32], [u8; 32]), BitcoinClientError<T::Error>> {
-        let cmd = command::register_wallet(wallet);
-        let mut intpr = ClientCommandInterpreter::new();
-        intpr.add_known_preimage(wallet.serialize());
-        let keys: Vec<String> = wallet.keys.iter().map(|k| k.to_string()).collect();
-        intpr.add_known_list(&keys);
-        // necessary for version 1 of the protocol (introduced in version 2.1.0)
-        intpr.add_known_preimage(wallet.descriptor_template.as_bytes().to_vec());
-        let (id, hmac) = self.make_request(&cmd, Some(&mut intpr)).and_then(|data| {
-            if data.len() < 64 {
-                Err(BitcoinClientError::UnexpectedResult {
-                    command: cmd.ins,
-                    data,
-                })
-            } else {
-                let mut id = [0x00; 32];
-                id.copy_from_slice(&data[0..32]);
-                let mut hmac = [0x00; 32];
-                hmac.copy_from_slice(&data[32..64]);
-                Ok((id, hmac))
+    ) -> Result<([u8; 32], WalletHmac), BitcoinClientError<T::Error>> {
+        wallet.validate()?;
+        if wallet.version == Version::V2 {
+            let capabilities = self.capabilities()?;
+            match capabilities.missing_for(wallet) {
+                Some(_) if !capabilities.wallet_policy_v2 => {
+                    return Err(BitcoinClientError::UnsupportedAppVersion)
+                }
+                Some(feature) => return Err(BitcoinClientError::UnsupportedFeature(feature)),
+                None => {}
             }
-        })?;
+        }
+        let cmd = command::register_wallet(wallet);
+        let mut intpr = self.wallet_interpreter(wallet);
+        let (id, hmac) = self
+            .make_request(&cmd, Some(&mut intpr))
+            .map_err(|e| e.for_wallet(wallet))
+            .and_then(|data| {
+                if data.len() != 64 {
+                    Err(BitcoinClientError::UnexpectedResult {
+                        command: cmd.ins,
+                        data,
+                    })
+                } else {
+                    let mut id = [0x00; 32];
+                    id.copy_from_slice(&data[0..32]);
+                    let mut hmac = [0x00; 32];
+                    hmac.copy_from_slice(&data[32..64]);
+                    Ok((id, WalletHmac(hmac)))
+                }
+            })?;
+
+        if let Some(cache) = &self.address_cache {
+            cache.invalidate(id);
+        }
 
         #[cfg(feature = "paranoid_client")]
         {
+            if id != wallet.id() {
+                return Err(BitcoinClientError::InvalidResponse(
+                    "Wallet id returned by the device does not match the wallet policy".to_string(),
+                ));
+            }
             let device_addr = self.get_wallet_address(wallet, Some(&hmac), false, 0, false)?;
             self.check_address(wallet, false, 0, &device_addr)?;
         }
@@ -189,31 +617,133 @@ impl<T: Transport> BitcoinClient<T> {
         Ok((id, hmac))
     }
 
+    /// Returns an hmac with which the device accepts the wallet policy: `cached_hmac` if the
+    /// device derives an address of the wallet with it, without displaying it, see
+    /// `is_hmac_valid`, or else the hmac of a new registration of the wallet, which the user
+    /// must approve. The address cache is not used to check `cached_hmac`.
+    /// Standard wallets do not need it, they are used without hmac.
+    pub fn ensure_registered(
+        &self,
+        wallet: &WalletPolicy,
+        cached_hmac: Option<WalletHmac>,
+    ) -> Result<WalletHmac, BitcoinClientError<T::Error>> {
+        if let Some(hmac) = cached_hmac {
+            if self.is_hmac_valid(wallet, &hmac)? {
+                return Ok(hmac);
+            }
+        }
+        let (_, hmac) = self.register_wallet(wallet)?;
+        Ok(hmac)
+    }
+
+    /// Returns whether the hmac is the one of the registration of the wallet policy on this
+    /// device, deriving its first receive address without displaying it: false when the
+    /// device rejects the hmac, for instance after a reset or with another seed, rather than
+    /// an error. Validates a stored registration without prompting the user.
+    /// The device is always asked, the addresses kept `with_address_cache` not depending on
+    /// the hmac.
+    pub fn is_hmac_valid(
+        &self,
+        wallet: &WalletPolicy,
+        hmac: &WalletHmac,
+    ) -> Result<bool, BitcoinClientError<T::Error>> {
+        match self.request_wallet_address(wallet, Some(hmac), false, 0, false) {
+            Ok(_) => Ok(true),
+            // the hmac is not the one of the registration of the wallet on this device
+            Err(BitcoinClientError::Device {
+                status: StatusWord::SignatureFail,
+                ..
+            }) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Registers the wallet, then retrieves its first `receive_count` receive addresses and
+    /// `change_count` change addresses with the hmac of the registration, without displaying
+    /// them, for the user to compare them with the addresses shown by the other cosigners.
+    /// Returns the hmac with the receive and change addresses.
+    #[allow(clippy::type_complexity)]
+    pub fn register_and_preview(
+        &self,
+        wallet: &WalletPolicy,
+        receive_count: u32,
+        change_count: u32,
+    ) -> Result<
+        (WalletHmac, Vec<bitcoin::Address>, Vec<bitcoin::Address>),
+        BitcoinClientError<T::Error>,
+    > {
+        let (_, hmac) = self.register_wallet(wallet)?;
+        let mut receive = Vec::with_capacity(receive_count as usize);
+        for index in 0..receive_count {
+            receive.push(self.get_wallet_address(wallet, Some(&hmac), false, index, false)?);
+        }
+        let mut change = Vec::with_capacity(change_count as usize);
+        for index in 0..change_count {
+            change.push(self.get_wallet_address(wallet, Some(&hmac), true, index, false)?);
+        }
+        Ok((hmac, receive, change))
+    }
+
     /// For a given wallet that was already registered on the device (or a standard wallet that does not need registration),
     /// returns the address for a certain `change`/`address_index` combination.
+    /// A policy whose keys have no wildcard, like `wpkh(@0/0/5)`, has a single address, checked
+    /// whatever the `change` and `address_index`. Note that the Ledger app only registers
+    /// policies whose keys are ranged, ending with `/**` or `/<M;N>/*`.
+    /// `change` selects the branch of the keys: `/0/*` or `/1/*` for `/**`, `/M/*` or `/N/*`
+    /// for the multipath expression `/<M;N>/*`.
+    /// With `display`, the device shows the address with its change and index for the user
+    /// to confirm it, and answers `StatusWord::Deny` if they reject it.
     pub fn get_wallet_address(
         &self,
         wallet: &WalletPolicy,
-        wallet_hmac: Option<&[u8; 32]>,
+        wallet_hmac: Option<&WalletHmac>,
         change: bool,
         address_index: u32,
         display: bool,
     ) -> Result<bitcoin::Address, BitcoinClientError<T::Error>> {
-        let mut intpr = ClientCommandInterpreter::new();
-        intpr.add_known_preimage(wallet.serialize());
-        let keys: Vec<String> = wallet.keys.iter().map(|k| k.to_string()).collect();
-        intpr.add_known_list(&keys);
-        // necessary for version 1 of the protocol (introduced in version 2.1.0)
-        intpr.add_known_preimage(wallet.descriptor_template.as_bytes().to_vec());
-        let cmd = command::get_wallet_address(wallet, wallet_hmac, change, address_index, display);
-        let address = self.make_request(&cmd, Some(&mut intpr)).and_then(|data| {
-            bitcoin::Address::from_str(&String::from_utf8_lossy(&data)).map_err(|_| {
-                BitcoinClientError::UnexpectedResult {
-                    command: cmd.ins,
-                    data,
-                }
-            })
-        })?;
+        if wallet_hmac.is_none() && !wallet.is_standard() {
+            return Err(BitcoinClientError::HmacRequired);
+        }
+        let cache = self.address_cache.as_ref().filter(|_| !display);
+        if let Some(address) = cache.and_then(|c| c.get(wallet.id(), change, address_index)) {
+            return Ok(address);
+        }
+        let address =
+            self.request_wallet_address(wallet, wallet_hmac, change, address_index, display)?;
+        if let Some(cache) = cache {
+            cache.insert(wallet.id(), change, address_index, address.clone());
+        }
+
+        Ok(address)
+    }
+
+    /// Requests the address of the wallet from the device, without the address cache.
+    fn request_wallet_address(
+        &self,
+        wallet: &WalletPolicy,
+        wallet_hmac: Option<&WalletHmac>,
+        change: bool,
+        address_index: u32,
+        display: bool,
+    ) -> Result<bitcoin::Address, BitcoinClientError<T::Error>> {
+        let mut intpr = self.wallet_interpreter(wallet);
+        let cmd = command::get_wallet_address(
+            wallet,
+            wallet_hmac.map(|hmac| &hmac.0),
+            change,
+            address_index,
+            display,
+        );
+        let data = self
+            .make_request(&cmd, Some(&mut intpr))
+            .map_err(|e| e.for_wallet(wallet))?;
+        // the unexpected bytes are kept for the diagnosis
+        let address =
+            parse_address_response(&data).ok_or_else(|| BitcoinClientError::UnexpectedResult {
+                command: cmd.ins,
+                data: data.clone(),
+            })?;
+        let address = self.parse_address(address)?;
 
         #[cfg(feature = "paranoid_client")]
         {
@@ -223,108 +753,370 @@ impl<T: Transport> BitcoinClient<T> {
         Ok(address)
     }
 
+    /// Returns the address of the wallet without displaying it, to scan the addresses of the
+    /// wallet, like `get_wallet_address` with `display` false.
+    pub fn get_wallet_address_silent(
+        &self,
+        wallet: &WalletPolicy,
+        wallet_hmac: Option<&WalletHmac>,
+        change: bool,
+        address_index: u32,
+    ) -> Result<bitcoin::Address, BitcoinClientError<T::Error>> {
+        self.get_wallet_address(wallet, wallet_hmac, change, address_index, false)
+    }
+
+    /// Returns the address of the wallet, displayed on the device for the user to confirm it,
+    /// like `get_wallet_address` with `display` true.
+    pub fn confirm_wallet_address(
+        &self,
+        wallet: &WalletPolicy,
+        wallet_hmac: Option<&WalletHmac>,
+        change: bool,
+        address_index: u32,
+    ) -> Result<bitcoin::Address, BitcoinClientError<T::Error>> {
+        self.get_wallet_address(wallet, wallet_hmac, change, address_index, true)
+    }
+
+    /// Displays the addresses of the wallet at the given indexes one after the other, for the
+    /// user to confirm each of them on the device, for instance to verify the addresses of a
+    /// multisig wallet with the other cosigners.
+    /// A rejected address does not stop the walk: returns each index with its address if the
+    /// user confirmed it, or `None` if they rejected it. Other errors abort.
+    #[allow(clippy::type_complexity)]
+    pub fn display_addresses(
+        &self,
+        wallet: &WalletPolicy,
+        wallet_hmac: Option<&WalletHmac>,
+        change: bool,
+        indexes: &[u32],
+    ) -> Result<Vec<(u32, Option<bitcoin::Address>)>, BitcoinClientError<T::Error>> {
+        let mut addresses = Vec::with_capacity(indexes.len());
+        for &index in indexes {
+            match self.get_wallet_address(wallet, wallet_hmac, change, index, true) {
+                Ok(address) => addresses.push((index, Some(address))),
+                Err(BitcoinClientError::Device {
+                    status: StatusWord::Deny,
+                    ..
+                }) => addresses.push((index, None)),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(addresses)
+    }
+
+    /// Checks that the connected device owns one of the keys of the wallet, comparing its
+    /// master fingerprint with the fingerprints of the key origins.
+    /// Calling it before `sign_psbt` catches a registration hmac used with another device
+    /// before the device rejects it in the middle of the signing.
+    pub fn check_wallet_device(
+        &self,
+        wallet: &WalletPolicy,
+    ) -> Result<(), BitcoinClientError<T::Error>> {
+        let fingerprint = self.get_master_fingerprint()?;
+        if wallet.device_key_index(fingerprint).is_some() {
+            Ok(())
+        } else {
+            Err(BitcoinClientError::WrongDeviceForWallet { fingerprint })
+        }
+    }
+
     /// Signs a PSBT using a registered wallet (or a standard wallet that does not need registration).
     /// Signature requires explicit approval from the user.
+    /// PSBTs created by software only aware of PSBT v0 must first go through `psbt::normalize`.
+    /// The application cannot sign a raw signature hash: the device computes the hash of each
+    /// input from the PSBT, whose transaction is shown to the user.
+    /// The ECDSA signatures of the device have a low R, see `PartialSignature::is_low_r` for
+    /// wallets relying on it to predict the size of the transaction.
+    /// Taproot inputs spent through a script leaf need `tap_key_origins` listing the leaf
+    /// hashes of each key and `tap_scripts`; their signatures carry the hash of the leaf.
     #[allow(clippy::type_complexity)]
     pub fn sign_psbt(
         &self,
         psbt: &Psbt,
         wallet: &WalletPolicy,
-        wallet_hmac: Option<&[u8; 32]>,
+        wallet_hmac: Option<&WalletHmac>,
     ) -> Result<Vec<(usize, PartialSignature)>, BitcoinClientError<T::Error>> {
-        let mut intpr = ClientCommandInterpreter::new();
-        intpr.add_known_preimage(wallet.serialize());
-        let keys: Vec<String> = wallet.keys.iter().map(|k| k.to_string()).collect();
-        intpr.add_known_list(&keys);
-        // necessary for version 1 of the protocol (introduced in version 2.1.0)
-        intpr.add_known_preimage(wallet.descriptor_template.as_bytes().to_vec());
+        let mut signatures = Vec::new();
+        self.sign_psbt_stream(psbt, wallet, wallet_hmac, |input_index, signature| {
+            signatures.push((input_index, signature));
+            ControlFlow::Continue(())
+        })?;
+        Ok(signatures)
+    }
 
-        let global_map: Vec<(Vec<u8>, Vec<u8>)> = get_v2_global_pairs(psbt)
+    /// Signs a PSBT like `sign_psbt`, asking the device to sign only the inputs at the given
+    /// indexes, for example the inputs of the user in a collaborative transaction.
+    /// The whole transaction is still sent and shown to the user, and the signature hashes
+    /// commit to all its inputs and outputs. Only the signatures of the selected inputs
+    /// are returned.
+    #[allow(clippy::type_complexity)]
+    pub fn sign_psbt_inputs(
+        &self,
+        psbt: &Psbt,
+        wallet: &WalletPolicy,
+        wallet_hmac: Option<&WalletHmac>,
+        inputs: &[usize],
+    ) -> Result<Vec<(usize, PartialSignature)>, BitcoinClientError<T::Error>> {
+        if let Some(index) = inputs.iter().find(|i| **i >= psbt.inputs.len()) {
+            return Err(BitcoinClientError::ClientError(format!(
+                "input {} is not in the psbt",
+                index
+            )));
+        }
+        let signatures =
+            self.sign_psbt(&select_signing_inputs(psbt, inputs), wallet, wallet_hmac)?;
+        Ok(signatures
             .into_iter()
-            .map(deserialize_pairs)
-            .collect();
-        intpr.add_known_mapping(&global_map);
-        let global_mapping_commitment = get_merkleized_map_commitment(&global_map);
-
-        let mut input_commitments: Vec<Vec<u8>> = Vec::with_capacity(psbt.inputs.len());
-        for (index, input) in psbt.inputs.iter().enumerate() {
-            let txin = psbt
-                .unsigned_tx
-                .input
-                .get(index)
-                .ok_or(BitcoinClientError::InvalidPsbt)?;
-            let input_map: Vec<(Vec<u8>, Vec<u8>)> = get_v2_input_pairs(input, txin)
-                .into_iter()
-                .map(deserialize_pairs)
-                .collect();
-            intpr.add_known_mapping(&input_map);
-            input_commitments.push(get_merkleized_map_commitment(&input_map));
+            .filter(|(index, _)| inputs.contains(index))
+            .collect())
+    }
+
+    /// Signs the input at the given index of a PSBT like `sign_psbt_inputs`, and returns its
+    /// signature alone, for a coordinator assembling the signatures of several signers.
+    /// The device refuses a PSBT without inputs to sign: an input without its keys fails like
+    /// `sign_psbt`. Fails with `BitcoinClientError::MissingSignature` if the device still did
+    /// not sign the input, and with a `ClientError` if it signed it with several keys,
+    /// returned by `sign_psbt_inputs`.
+    pub fn sign_psbt_input(
+        &self,
+        psbt: &Psbt,
+        wallet: &WalletPolicy,
+        wallet_hmac: Option<&WalletHmac>,
+        input_index: usize,
+    ) -> Result<PartialSignature, BitcoinClientError<T::Error>> {
+        let mut signatures = self.sign_psbt_inputs(psbt, wallet, wallet_hmac, &[input_index])?;
+        match signatures.len() {
+            0 => Err(BitcoinClientError::MissingSignature { input: input_index }),
+            1 => Ok(signatures.remove(0).1),
+            _ => Err(BitcoinClientError::ClientError(format!(
+                "input {} is signed with several keys",
+                input_index
+            ))),
         }
-        let input_commitments_root = intpr.add_known_list(&input_commitments);
+    }
 
-        let mut output_commitments: Vec<Vec<u8>> = Vec::with_capacity(psbt.outputs.len());
-        for (index, output) in psbt.outputs.iter().enumerate() {
-            let txout = psbt
-                .unsigned_tx
-                .output
-                .get(index)
-                .ok_or(BitcoinClientError::InvalidPsbt)?;
-            let output_map: Vec<(Vec<u8>, Vec<u8>)> = get_v2_output_pairs(output, txout)
-                .into_iter()
-                .map(deserialize_pairs)
+    /// Signs a PSBT spending from several wallets, like a single-sig and a multisig wallet,
+    /// given with their hmac if they are registered.
+    /// Each input is signed for the first wallet it is internal to, see `is_internal_input`:
+    /// the PSBT is sent once per wallet with such inputs, asking the device to sign only them
+    /// like `sign_psbt_inputs`, and the wallets without inputs are skipped. The user confirms
+    /// the transaction once per wallet signing inputs.
+    /// Returns the signatures made for all the wallets, with the index of their input.
+    #[allow(clippy::type_complexity)]
+    pub fn sign_psbt_multi(
+        &self,
+        psbt: &Psbt,
+        wallets: &[(WalletPolicy, Option<WalletHmac>)],
+    ) -> Result<Vec<(usize, PartialSignature)>, BitcoinClientError<T::Error>> {
+        let mut assigned = vec![false; psbt.inputs.len()];
+        let mut signatures = Vec::new();
+        for (wallet, wallet_hmac) in wallets {
+            let inputs: Vec<usize> = psbt
+                .inputs
+                .iter()
+                .enumerate()
+                .filter(|(index, input)| !assigned[*index] && is_internal_input(input, wallet))
+                .map(|(index, _)| index)
                 .collect();
-            intpr.add_known_mapping(&output_map);
-            output_commitments.push(get_merkleized_map_commitment(&output_map));
+            if inputs.is_empty() {
+                continue;
+            }
+            for index in &inputs {
+                assigned[*index] = true;
+            }
+            signatures.extend(self.sign_psbt_inputs(
+                psbt,
+                wallet,
+                wallet_hmac.as_ref(),
+                &inputs,
+            )?);
+        }
+        Ok(signatures)
+    }
+
+    /// Signs a PSBT like `sign_psbt`, calling `on_signature` with the input index and the
+    /// signature as soon as the device yields it, instead of collecting the signatures.
+    /// Signing stops if `on_signature` returns `ControlFlow::Break`: the command is abandoned
+    /// without answering the device and `BitcoinClientError::SigningAborted` is returned.
+    /// The notifications of the device are ignored, see `sign_psbt_with_notifications`.
+    pub fn sign_psbt_stream<F>(
+        &self,
+        psbt: &Psbt,
+        wallet: &WalletPolicy,
+        wallet_hmac: Option<&WalletHmac>,
+        mut on_signature: F,
+    ) -> Result<(), BitcoinClientError<T::Error>>
+    where
+        F: FnMut(usize, PartialSignature) -> ControlFlow<()>,
+    {
+        self.sign_psbt_with_notifications(psbt, wallet, wallet_hmac, |value| match value {
+            SignPsbtYield::Signature(input_index, signature) => {
+                on_signature(input_index, signature)
+            }
+            SignPsbtYield::Notification { .. } => ControlFlow::Continue(()),
+        })
+    }
+
+    /// Like `sign_psbt_stream`, calling `on_yield` with every value yielded by the device:
+    /// the signatures, and the notifications that are not signatures, told apart by their
+    /// tag, see `SIGN_PSBT_YIELD_TAG_MIN`.
+    pub fn sign_psbt_with_notifications<F>(
+        &self,
+        psbt: &Psbt,
+        wallet: &WalletPolicy,
+        wallet_hmac: Option<&WalletHmac>,
+        mut on_yield: F,
+    ) -> Result<(), BitcoinClientError<T::Error>>
+    where
+        F: FnMut(SignPsbtYield) -> ControlFlow<()>,
+    {
+        if wallet_hmac.is_none() && !wallet.is_standard() {
+            return Err(BitcoinClientError::HmacRequired);
+        }
+        let (inputs, outputs) = (psbt.inputs.len(), psbt.outputs.len());
+        // every version of the app signs within the oldest limits, the version is only
+        // requested for larger transactions
+        if !SigningLimits::BEFORE_2_0_4.allows(inputs, outputs) {
+            let limits = self.signing_limits()?;
+            if inputs > limits.max_inputs {
+                return Err(BitcoinClientError::TooManyInputs {
+                    inputs,
+                    max: limits.max_inputs,
+                });
+            }
+            if let Some(max) = limits.max_outputs.filter(|max| outputs > *max) {
+                return Err(BitcoinClientError::TooManyOutputs { outputs, max });
+            }
         }
-        let output_commitments_root = intpr.add_known_list(&output_commitments);
+        let mut intpr = self.wallet_interpreter(wallet);
+
+        let commitments = build_commitments(psbt, wallet)?;
+        intpr.add_known_mapping(&commitments.global_map);
+        for input_map in &commitments.input_maps {
+            intpr.add_known_mapping(input_map);
+        }
+        intpr.add_known_list(&commitments.input_commitments);
+        for output_map in &commitments.output_maps {
+            intpr.add_known_mapping(output_map);
+        }
+        intpr.add_known_list(&commitments.output_commitments);
 
         let cmd = command::sign_psbt(
-            &global_mapping_commitment,
+            &commitments.global_mapping_commitment,
             psbt.inputs.len(),
-            &input_commitments_root,
+            &commitments.input_commitments_root,
             psbt.outputs.len(),
-            &output_commitments_root,
+            &commitments.output_commitments_root,
             wallet,
-            wallet_hmac,
+            wallet_hmac.map(|hmac| &hmac.0),
         );
 
-        self.make_request(&cmd, Some(&mut intpr))?;
-
-        let results = intpr.yielded();
-        if results.iter().any(|res| res.len() <= 1) {
-            return Err(BitcoinClientError::UnexpectedResult {
+        self.make_request_with_yield(&cmd, Some(&mut intpr), |result| {
+            let unexpected_result = || BitcoinClientError::UnexpectedResult {
                 command: cmd.ins,
-                data: results.into_iter().fold(Vec::new(), |mut acc, res| {
-                    acc.extend(res);
-                    acc
-                }),
-            });
-        }
+                data: result.to_vec(),
+            };
+            let (input_index, signature) =
+                match SignPsbtYield::from_slice(result).ok_or_else(unexpected_result)? {
+                    SignPsbtYield::Signature(input_index, signature) => (input_index, signature),
+                    notification => {
+                        return match on_yield(notification) {
+                            ControlFlow::Continue(()) => Ok(()),
+                            ControlFlow::Break(()) => Err(BitcoinClientError::SigningAborted),
+                        }
+                    }
+                };
+            // an index out of the psbt would make the callers index out of their inputs
+            if input_index >= psbt.inputs.len() {
+                return Err(unexpected_result());
+            }
+            // the device must sign with the sighash type committed in the input
+            if !signature.has_sighash_type_of(&psbt.inputs[input_index])
+                || !signature.matches_leaf_of(&psbt.inputs[input_index])
+            {
+                return Err(unexpected_result());
+            }
+            #[cfg(feature = "tracing")]
+            if !signature.is_low_r() {
+                tracing::warn!(input_index, "ecdsa signature without a low R");
+            }
+            match on_yield(SignPsbtYield::Signature(input_index, signature)) {
+                ControlFlow::Continue(()) => Ok(()),
+                ControlFlow::Break(()) => Err(BitcoinClientError::SigningAborted),
+            }
+        })
+        .map_err(|e| match e {
+            // the device also answers SignatureFail to an hmac it does not accept
+            BitcoinClientError::Device {
+                status: StatusWord::SignatureFail,
+                ..
+            } if wallet_hmac.is_none()
+                && wallet.is_standard()
+                && requires_blind_signing(psbt, wallet) =>
+            {
+                BitcoinClientError::BlindSigningDisabled
+            }
+            e => e.for_wallet(wallet),
+        })?;
 
-        let mut signatures = Vec::new();
-        for result in results {
-            let (input_index, i): (VarInt, usize) =
-                deserialize_partial(&result).map_err(|_| BitcoinClientError::UnexpectedResult {
-                    command: cmd.ins,
-                    data: result.clone(),
-                })?;
+        Ok(())
+    }
 
-            signatures.push((
-                input_index.0 as usize,
-                PartialSignature::from_slice(&result[i..]).map_err(|_| {
-                    BitcoinClientError::UnexpectedResult {
-                        command: cmd.ins,
-                        data: result.clone(),
-                    }
-                })?,
-            ));
+    /// Signs a PSBT like `sign_psbt`, after checking that the signature hash of each given
+    /// input matches the one committed during a previous signing round. This allows a
+    /// coordinator to pin the transaction and detect a PSBT modified by a previous signer.
+    #[allow(clippy::type_complexity)]
+    pub fn sign_psbt_pinned(
+        &self,
+        psbt: &Psbt,
+        wallet: &WalletPolicy,
+        wallet_hmac: Option<&WalletHmac>,
+        expected_sighashes: &[(usize, [u8; 32])],
+    ) -> Result<Vec<(usize, PartialSignature)>, BitcoinClientError<T::Error>> {
+        for (input, expected_sighash) in expected_sighashes {
+            let sighash =
+                signature_hash(psbt, *input).map_err(|_| BitcoinClientError::InvalidPsbt)?;
+            if &sighash != expected_sighash {
+                return Err(BitcoinClientError::PsbtModified { input: *input });
+            }
         }
+        self.sign_psbt(psbt, wallet, wallet_hmac)
+    }
 
-        Ok(signatures)
+    /// Signs a PSBT like `sign_psbt`, stores the signatures in its inputs, then finalizes it
+    /// and returns the network serialized transaction, ready to be broadcasted.
+    /// Fails with `BitcoinClientError::IncompleteInputs` if some inputs cannot be finalized,
+    /// for example inputs of other wallets or still missing signatures of cosigners.
+    #[cfg(feature = "miniscript")]
+    pub fn sign_and_finalize(
+        &self,
+        psbt: &Psbt,
+        wallet: &WalletPolicy,
+        wallet_hmac: Option<&WalletHmac>,
+    ) -> Result<Vec<u8>, BitcoinClientError<T::Error>> {
+        let signatures = self.sign_psbt(psbt, wallet, wallet_hmac)?;
+        let mut psbt = psbt.clone();
+        for (index, signature) in signatures {
+            let input = psbt
+                .inputs
+                .get_mut(index)
+                .ok_or(BitcoinClientError::InvalidPsbt)?;
+            signature.apply(input);
+        }
+        finalize_and_extract(&psbt).map_err(|e| match e {
+            FinalizeError::IncompleteInputs(inputs) => BitcoinClientError::IncompleteInputs(inputs),
+            FinalizeError::Extract(e) => {
+                BitcoinClientError::ClientError(format!("Failed to extract transaction: {}", e))
+            }
+        })
     }
 
     /// Sign a message with the key derived with the given derivation path.
-    /// Result is the header byte (31-34: P2PKH compressed) and the ecdsa signature.
+    /// The device displays the derivation path and the hash of the message for the user to
+    /// confirm them before signing, see `message::displayed_msg_hash` to show the same hash.
+    /// Result is the BIP137 header byte of a compressed key (31-34: P2PKH, 35-38: P2SH-P2WPKH,
+    /// 39-42: P2WPKH) and the ecdsa signature, which can be checked with
+    /// `message::verify_signed_message` or `message::recover_address`.
     pub fn sign_message(
         &self,
         message: &[u8],
@@ -335,21 +1127,145 @@ impl<T: Transport> BitcoinClient<T> {
         let message_commitment_root = intpr.add_known_list(&chunks);
         let cmd = command::sign_message(message.len(), &message_commitment_root, path);
         self.make_request(&cmd, Some(&mut intpr)).and_then(|data| {
-            Ok((
-                data[0],
-                ecdsa::Signature::from_compact(&data[1..]).map_err(|_| {
-                    BitcoinClientError::UnexpectedResult {
-                        command: cmd.ins,
-                        data: data.to_vec(),
-                    }
-                })?,
-            ))
+            parse_sign_message_response(&data).ok_or(BitcoinClientError::UnexpectedResult {
+                command: cmd.ins,
+                data,
+            })
         })
     }
 }
 
+/// ReconnectingClient connects again to the device when it is disconnected, for long-lived
+/// applications where the device may be unplugged and plugged in again, possibly another
+/// device. After connecting, the master fingerprint of the device is compared to the one
+/// of the expected device, so that nothing is signed with another device after a swap.
+pub struct ReconnectingClient<T: Transport, F> {
+    client: BitcoinClient<T>,
+    connect: F,
+    fingerprint: Fingerprint,
+    // False after the connection to another device, which must not be used.
+    verified: bool,
+}
+
+impl<T: Transport, F: FnMut() -> Result<T, T::Error>> ReconnectingClient<T, F> {
+    /// Wraps the client of the device with the given master fingerprint, `connect` opening a
+    /// new transport to the device.
+    pub fn new(client: BitcoinClient<T>, fingerprint: Fingerprint, connect: F) -> Self {
+        Self {
+            client,
+            connect,
+            fingerprint,
+            verified: true,
+        }
+    }
+
+    /// Runs the operation with the client. If it fails with a transport error recognized by
+    /// `Transport::is_disconnected`, connects again and runs it once more on the expected
+    /// device, else fails with `BitcoinClientError::DeviceChanged`, and the next operation
+    /// connects again first.
+    /// An operation prompting the user, like `sign_psbt`, prompts the user again.
+    pub fn run<R, O>(&mut self, mut operation: O) -> Result<R, BitcoinClientError<T::Error>>
+    where
+        O: FnMut(&BitcoinClient<T>) -> Result<R, BitcoinClientError<T::Error>>,
+    {
+        if !self.verified {
+            self.reconnect()?;
+        }
+        match operation(&self.client) {
+            Err(BitcoinClientError::Transport(e)) if self.client.transport.is_disconnected(&e) => {
+                self.reconnect()?;
+                operation(&self.client)
+            }
+            res => res,
+        }
+    }
+
+    /// Replaces the transport of the client with a new one opened by `connect`, and checks
+    /// the master fingerprint of the device. The settings of the client are kept.
+    pub fn reconnect(&mut self) -> Result<(), BitcoinClientError<T::Error>> {
+        self.verified = false;
+        self.client.transport = (self.connect)().map_err(BitcoinClientError::Transport)?;
+        self.client.clear_master_fingerprint();
+        // the app may have been updated while the device was disconnected
+        *self
+            .client
+            .app_version
+            .get_mut()
+            .expect("app version cache not poisoned") = None;
+        let fingerprint = self.client.get_master_fingerprint()?;
+        if fingerprint != self.fingerprint {
+            return Err(BitcoinClientError::DeviceChanged {
+                expected: self.fingerprint,
+                fingerprint,
+            });
+        }
+        self.verified = true;
+        Ok(())
+    }
+
+    /// Returns the wrapped client.
+    pub fn into_inner(self) -> BitcoinClient<T> {
+        self.client
+    }
+}
+
 /// Communication layer between the bitcoin client and the Ledger device.
 pub trait Transport {
     type Error: Debug;
     fn exchange(&self, command: &APDUCommand) -> Result<(StatusWord, Vec<u8>), Self::Error>;
+
+    /// Exchanges the command like `exchange`, called instead of it by a client set
+    /// `with_timeouts` with the timeout of the exchange. Transports able to stop waiting for
+    /// the device fail once the timeout elapses, and the client returns
+    /// `BitcoinClientError::Timeout`. By default, the timeout is ignored.
+    fn exchange_with_timeout(
+        &self,
+        command: &APDUCommand,
+        _timeout: Duration,
+    ) -> Result<(StatusWord, Vec<u8>), Self::Error> {
+        self.exchange(command)
+    }
+
+    /// Called when `exchange` fails with the error, if the client is set `with_keepalive`.
+    /// Transports that time out while the device still processes the command, waiting for
+    /// the user, return the response read again without resending the command.
+    /// Returns None for other errors, which fail the command. By default, all errors do.
+    #[allow(clippy::type_complexity)]
+    fn read_after_timeout(
+        &self,
+        _error: &Self::Error,
+    ) -> Option<Result<(StatusWord, Vec<u8>), Self::Error>> {
+        None
+    }
+
+    /// Returns true if the error means that the device is disconnected, for a
+    /// `ReconnectingClient` to connect to it again. By default, no error does.
+    fn is_disconnected(&self, _error: &Self::Error) -> bool {
+        false
+    }
+}
+
+/// Communication layer exchanging raw bytes with the device, like a TCP relay or a
+/// WebUSB bridge, to be used through a `ByteTransportAdapter`.
+pub trait ByteTransport {
+    type Error: Debug;
+    /// Sends the bytes of an APDU command, and returns the bytes of the response:
+    /// its data followed by the status word.
+    fn send_recv(&self, apdu: &[u8]) -> Result<Vec<u8>, Self::Error>;
+}
+
+/// Transport encoding the commands to the bytes of a `ByteTransport` and decoding its
+/// responses.
+pub struct ByteTransportAdapter<B>(pub B);
+
+impl<B: ByteTransport> Transport for ByteTransportAdapter<B> {
+    type Error = ByteTransportError<B::Error>;
+
+    fn exchange(&self, command: &APDUCommand) -> Result<(StatusWord, Vec<u8>), Self::Error> {
+        let response = self
+            .0
+            .send_recv(&command.encode())
+            .map_err(ByteTransportError::Transport)?;
+        decode_response(&response).ok_or(ByteTransportError::InvalidResponse(response))
+    }
 }
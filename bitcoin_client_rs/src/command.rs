@@ -1,5 +1,21 @@
-/// APDU commands  for the Bitcoin application.
-///
+//! APDU commands for the Bitcoin application.
+//!
+//! The builders are public so that the client can be split across a network boundary, for
+//! example by a signing server relaying the commands to a device it does not own.
+//! `APDUCommand::encode` returns the exact bytes to send to the device.
+//!
+//! Relaying a `sign_psbt` takes the same steps as `BitcoinClient::sign_psbt`:
+//!   - fill a `ClientCommandInterpreter` with the wallet policy (`WalletPolicy::serialize`,
+//!     the list of keys and the descriptor template), then with the global, input and output
+//!     maps of the PSBT (`psbt::get_v2_*_pairs` mapped through `psbt::deserialize_pairs`);
+//!   - build the command with `sign_psbt` from the map commitments
+//!     (`interpreter::get_merkleized_map_commitment`) and the roots of the lists of input and
+//!     output commitments returned by `ClientCommandInterpreter::add_known_list`;
+//!   - while the device answers with `StatusWord::InterruptedExecution`, pass the response
+//!     data to `ClientCommandInterpreter::execute` and send its result with
+//!     `continue_interrupted`;
+//!   - collect the signatures with `ClientCommandInterpreter::take_yielded`, each one encoded
+//!     as the input index as a varint followed by the data read by `PartialSignature::from_slice`.
 use bitcoin::{
     consensus::encode::{self, VarInt},
     util::bip32::{ChildNumber, DerivationPath},
@@ -51,6 +67,23 @@ pub fn get_extended_pubkey(path: &DerivationPath, display: bool) -> APDUCommand
     }
 }
 
+/// Returns true if the command may prompt the user on the device, and wait for the
+/// confirmation: the commands signing or registering, and the displayed keys and addresses.
+pub fn prompts_user(command: &APDUCommand) -> bool {
+    let is = |code: apdu::BitcoinCommandCode| command.ins == code as u8;
+    let displayed = command.data.first() == Some(&1);
+    command.cla == apdu::Cla::Bitcoin as u8
+        && if is(apdu::BitcoinCommandCode::GetExtendedPubkey)
+            || is(apdu::BitcoinCommandCode::GetWalletAddress)
+        {
+            displayed
+        } else {
+            is(apdu::BitcoinCommandCode::RegisterWallet)
+                || is(apdu::BitcoinCommandCode::SignPSBT)
+                || is(apdu::BitcoinCommandCode::SignMessage)
+        }
+}
+
 /// Creates the APDU command required to register the given wallet policy.
 pub fn register_wallet(policy: &WalletPolicy) -> APDUCommand {
     let bytes = policy.serialize();
@@ -146,3 +179,177 @@ pub fn continue_interrupted(data: Vec<u8>) -> APDUCommand {
         ..Default::default()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wallet::{Version, WalletPubKey};
+    use bitcoin::hashes::hex::{FromHex, ToHex};
+    use core::str::FromStr;
+
+    // Golden vectors are the commands recorded with the application.
+    fn assert_apdu(cmd: APDUCommand, cla: u8, ins: u8, p1: u8, p2: u8, golden: &str) {
+        let bytes = cmd.encode();
+        assert_eq!(bytes[..4], [cla, ins, p1, p2]);
+        assert_eq!(bytes[4] as usize, bytes.len() - 5);
+        assert_eq!(bytes.to_hex(), golden);
+    }
+
+    #[test]
+    fn test_get_version() {
+        assert_apdu(get_version(), 0xB0, 0x01, 0x00, 0x00, "b001000000");
+    }
+
+    #[test]
+    fn test_get_master_fingerprint() {
+        assert_apdu(
+            get_master_fingerprint(),
+            0xE1,
+            0x05,
+            0x00,
+            0x01,
+            "e105000100",
+        );
+    }
+
+    #[test]
+    fn test_get_extended_pubkey() {
+        let path = DerivationPath::from_str("m/44'/0'/0'/0/0").unwrap();
+        assert_apdu(
+            get_extended_pubkey(&path, false),
+            0xE1,
+            0x00,
+            0x00,
+            0x01,
+            "e10000011600058000002c80000000800000000000000000000000",
+        );
+        assert_eq!(get_extended_pubkey(&path, true).data[0], 1);
+    }
+
+    #[test]
+    fn test_register_wallet() {
+        let wallet = WalletPolicy::new(
+            "Cold storage".to_string(),
+            Version::V1,
+            "wsh(sortedmulti(2,@0,@1))".to_string(),
+            vec![
+                WalletPubKey::from_str("[76223a6e/48'/1'/0'/2']tpubDE7NQymr4AFtewpAsWtnreyq9ghkzQBXpCZjWLFVRAvnbf7vya2eMTvT2fPapNqL8SuVvLQdbUbMfWLVDCZKnsEBqp6UK93QEzL8Ck23AwF/**").unwrap(),
+                WalletPubKey::from_str("[f5acc2fd/48'/1'/0'/2']tpubDFAqEGNyad35aBCKUAXbQGDjdVhNueno5ZZVEn3sQbW5ci457gLR7HyTmHBg93oourBssgUxuWz1jX5uhc1qaqFo9VsybY1J5FuedLfm4dK/**").unwrap(),
+            ],
+        );
+        assert_apdu(
+            register_wallet(&wallet),
+            0xE1,
+            0x02,
+            0x00,
+            0x01,
+            "e10200014a49010c436f6c642073746f726167651977736828736f727465646d756c746928322c40302c4031292902ab6711da5c7daaaba19169f693013ef5139eee794d4997acb8a40d1c25f87838",
+        );
+    }
+
+    #[test]
+    fn test_get_wallet_address() {
+        let wallet = WalletPolicy::new(
+            "".to_string(),
+            Version::V2,
+            "tr(@0/**)".to_string(),
+            vec![WalletPubKey::from_str("[f5acc2fd/86'/1'/0']tpubDDKYE6BREvDsSWMazgHoyQWiJwYaDDYPbCFjYxN3HFXJP5fokeiK4hwK5tTLBNEDBwrDXn8cQ4v9b2xdW62Xr5yxoQdMu1v6c7UDXYVH27U").unwrap()],
+        );
+        assert_apdu(
+            get_wallet_address(&wallet, None, false, 0, true),
+            0xE1,
+            0x03,
+            0x00,
+            0x01,
+            "e10300014601627535418bc03eeee2b62b3a0254dc0624881f8bc6fc20c4d3b2c1c4fc92989300000000000000000000000000000000000000000000000000000000000000000000000000",
+        );
+
+        let cmd = get_wallet_address(&wallet, Some(&[0xAA; 32]), true, 0x01020304, false);
+        assert_eq!(cmd.data[0], 0);
+        assert_eq!(cmd.data[33..65], [0xAA; 32]);
+        assert_eq!(cmd.data[65..], [0x01, 0x01, 0x02, 0x03, 0x04]);
+    }
+
+    #[test]
+    fn test_prompts_user() {
+        let path = DerivationPath::from_str("m/84'/1'/0'").unwrap();
+        assert!(prompts_user(&get_extended_pubkey(&path, true)));
+        assert!(!prompts_user(&get_extended_pubkey(&path, false)));
+        assert!(prompts_user(&sign_message(0, &[0; 32], &path)));
+        assert!(!prompts_user(&get_version()));
+        assert!(!prompts_user(&get_master_fingerprint()));
+        assert!(!prompts_user(&continue_interrupted(vec![1])));
+    }
+
+    #[test]
+    fn test_sign_psbt() {
+        let wallet = WalletPolicy::new(
+            "".to_string(),
+            Version::V2,
+            "sh(wpkh(@0/**))".to_string(),
+            vec![WalletPubKey::from_str("[f5acc2fd/49'/1'/0']tpubDC871vGLAiKPcwAw22EjhKVLk5L98UGXBEcGR8gpcigLQVDDfgcYW24QBEyTHTSFEjgJgbaHU8CdRi9vmG4cPm1kPLmZhJEP17FMBdNheh3").unwrap()],
+        );
+        let global_mapping_commitment = Vec::from_hex("05519b38dae74447b72151f354cb138ca3591a5ff8ac813289b18a004e3132162086d8d9498a323006ec5982eeb4ea7c41d27020d57985512ab59ff8f40d501507").unwrap();
+        let mut input_commitments_root = [0; 32];
+        input_commitments_root.copy_from_slice(
+            &Vec::from_hex("185a2fac562419c1ce8ed936d13cfe9ca4be0bec1a0d84f8ce433763fc6d41d5")
+                .unwrap(),
+        );
+        let mut output_commitments_root = [0; 32];
+        output_commitments_root.copy_from_slice(
+            &Vec::from_hex("f4c4f92e968760845d5694ce5ac4ad9ed0a33d00278d6e7fe89e807f899d2637")
+                .unwrap(),
+        );
+        assert_apdu(
+            sign_psbt(
+                &global_mapping_commitment,
+                1,
+                &input_commitments_root,
+                2,
+                &output_commitments_root,
+                &wallet,
+                None,
+            ),
+            0xE1,
+            0x04,
+            0x00,
+            0x01,
+            "e1040001c305519b38dae74447b72151f354cb138ca3591a5ff8ac813289b18a004e3132162086d8d9498a323006ec5982eeb4ea7c41d27020d57985512ab59ff8f40d50150701185a2fac562419c1ce8ed936d13cfe9ca4be0bec1a0d84f8ce433763fc6d41d502f4c4f92e968760845d5694ce5ac4ad9ed0a33d00278d6e7fe89e807f899d2637be693418eee0c522b55f74c62b6ecad9697ef1e6e8bb973ff29740432960db480000000000000000000000000000000000000000000000000000000000000000",
+        );
+    }
+
+    #[test]
+    fn test_sign_message() {
+        let path = DerivationPath::from_str("m/44'/1'/0'/0").unwrap();
+        let mut message_commitment_root = [0; 32];
+        message_commitment_root.copy_from_slice(
+            &Vec::from_hex("8a2a5c9b768827de5a9552c38a044c66959c68f6d2f21b5260af54d2f87db827")
+                .unwrap(),
+        );
+        assert_apdu(
+            sign_message(5, &message_commitment_root, &path),
+            0xE1,
+            0x10,
+            0x00,
+            0x01,
+            "e110000132048000002c800000018000000000000000058a2a5c9b768827de5a9552c38a044c66959c68f6d2f21b5260af54d2f87db827",
+        );
+    }
+
+    #[test]
+    fn test_continue_interrupted() {
+        assert_apdu(
+            continue_interrupted(
+                Vec::from_hex(
+                    "8a2a5c9b768827de5a9552c38a044c66959c68f6d2f21b5260af54d2f87db8270000",
+                )
+                .unwrap(),
+            ),
+            0xF8,
+            0x01,
+            0x00,
+            0x01,
+            "f8010001228a2a5c9b768827de5a9552c38a044c66959c68f6d2f21b5260af54d2f87db8270000",
+        );
+    }
+}
@@ -1,21 +1,528 @@
-use core::fmt::Debug;
+use core::convert::TryFrom;
+use core::fmt::{Debug, Display, Formatter};
 
-use crate::{apdu::StatusWord, interpreter::InterpreterError};
+use bitcoin::util::bip32::Fingerprint;
+
+use crate::{
+    apdu::{BitcoinCommandCode, StatusWord},
+    bip32::{AppVariant, QtumNetwork},
+    interpreter::InterpreterError,
+    psbt::{CommitmentsError, MissingUtxo, OutputMismatch},
+    wallet::{WalletError, WalletPolicy},
+};
 
 #[derive(Debug)]
 pub enum BitcoinClientError<T: Debug> {
     ClientError(String),
     InvalidPsbt,
+    PsbtModified {
+        input: usize,
+    },
+    MissingUtxo {
+        input: usize,
+        missing: MissingUtxo,
+    },
+    /// The output map of the PSBT does not agree with the output of the unsigned transaction.
+    OutputMismatch {
+        output: usize,
+        mismatch: OutputMismatch,
+    },
+    /// The sighash type of the input is not a standard sighash type for the spent output.
+    NonStandardSighashType {
+        input: usize,
+    },
     Transport(T),
+    /// The device did not answer within the timeout set `with_timeouts`: the interactive
+    /// one for a command waiting for the user, else the I/O one, the device being gone.
+    Timeout {
+        interactive: bool,
+    },
     Interpreter(InterpreterError),
-    Device { command: u8, status: StatusWord },
-    UnexpectedResult { command: u8, data: Vec<u8> },
+    /// The device sent a client command that the interpreter does not know, probably
+    /// introduced by a newer version of the app: the crate must be updated.
+    UnknownClientCommand(u8),
+    Device {
+        command: u8,
+        status: StatusWord,
+    },
+    UnexpectedResult {
+        command: u8,
+        data: Vec<u8>,
+    },
     InvalidResponse(String),
+    /// A response of the device could not be parsed, with the reason given by the parser.
+    Parse(String),
+    /// The device returned an address of a type that the wallet policy cannot produce.
+    AddressTypeMismatch {
+        expected: Option<bitcoin::AddressType>,
+        device: Option<bitcoin::AddressType>,
+    },
+    /// The device returned an address of another network than the one set `with_network`.
+    NetworkMismatch {
+        address: String,
+    },
+    /// The app running on the device does not serve the network set `with_network`, like
+    /// the test app while the client expects mainnet addresses.
+    AppNetworkMismatch {
+        app: AppVariant,
+        network: QtumNetwork,
+    },
     UnsupportedAppVersion,
+    /// The wallet policy is refused by the client before it is sent to the device, for the
+    /// given reason.
+    InvalidPolicy(&'static str),
+    /// The wallet policy uses a feature that the app does not support in its version.
+    UnsupportedFeature(&'static str),
+    /// The device rejected the command of a wallet policy using the feature as an instruction
+    /// it does not support: the app on the device must be updated.
+    UnsupportedByFirmware {
+        feature: &'static str,
+    },
+    /// An output that is not internal to the wallet, see `psbt::is_internal_output`, creates
+    /// or calls a contract with data that the device cannot decode.
+    /// Blind signing must be enabled in the settings of the app to sign it.
+    /// Only reported for standard wallets: with a registered wallet, the device rejects the
+    /// transaction with the status word of an invalid hmac, returned as the `Device` error.
+    BlindSigningDisabled,
+    /// The wallet policy is not a standard policy, it must be registered
+    /// and used with the hmac returned by the registration.
+    HmacRequired,
+    /// The signing was stopped by the caller before the device completed it.
+    SigningAborted,
+    /// The signing session was cancelled by the client between two exchanges with the device.
+    Cancelled,
+    /// The device did not sign the input requested by `sign_psbt_input`.
+    MissingSignature {
+        input: usize,
+    },
+    /// Indexes of the inputs that could not be finalized after signing.
+    IncompleteInputs(Vec<usize>),
+    /// None of the keys of the wallet belongs to the connected device,
+    /// identified by its master fingerprint.
+    WrongDeviceForWallet {
+        fingerprint: Fingerprint,
+    },
+    /// The device reconnected by a `ReconnectingClient` is not the expected one: its master
+    /// fingerprint differs.
+    DeviceChanged {
+        expected: Fingerprint,
+        fingerprint: Fingerprint,
+    },
+    /// The transaction has more inputs than the device can sign.
+    TooManyInputs {
+        inputs: usize,
+        max: usize,
+    },
+    /// The transaction has more outputs than the device can sign.
+    TooManyOutputs {
+        outputs: usize,
+        max: usize,
+    },
+    /// The signed transaction would exceed the maximum weight of a standard transaction.
+    TransactionTooLarge {
+        weight: usize,
+        max: usize,
+    },
+}
+
+impl<T: Debug> BitcoinClientError<T> {
+    /// Maps the status words with which an app rejects the instructions it does not know to
+    /// `UnsupportedByFirmware` for a taproot wallet policy, the apps older than 2.0.0 not
+    /// supporting taproot. Other errors are returned unchanged.
+    pub(crate) fn for_wallet(self, wallet: &WalletPolicy) -> Self {
+        match self {
+            BitcoinClientError::Device {
+                status:
+                    StatusWord::InsNotSupported | StatusWord::ClaNotSupported | StatusWord::NotSupported,
+                ..
+            } if wallet.descriptor_template.starts_with("tr(") => {
+                BitcoinClientError::UnsupportedByFirmware { feature: "taproot" }
+            }
+            e => e,
+        }
+    }
+
+    /// Describes the error like `Display`, and for an `UnexpectedResult` of a known command,
+    /// how its data differs from the expected response, for example where a version response
+    /// is truncated. The description is a best effort meant for bug reports, its wording may
+    /// change.
+    pub fn describe(&self) -> String {
+        match self {
+            BitcoinClientError::UnexpectedResult { command, data } => {
+                format!("{}: {}", self, describe_result(*command, data))
+            }
+            _ => self.to_string(),
+        }
+    }
+}
+
+/// Returns a description of the data of an unexpected result to the command.
+fn describe_result(command: u8, data: &[u8]) -> String {
+    use bitcoin::consensus::encode::{deserialize_partial, VarInt};
+
+    // Returns the length of the field prefixed by its length at the offset, and the offset
+    // of the next field, or None if the data ends before.
+    let field = |offset: usize| -> Option<(usize, usize)> {
+        let (len, n): (VarInt, usize) = deserialize_partial(data.get(offset..)?).ok()?;
+        let end = (offset + n).checked_add(usize::try_from(len.0).ok()?)?;
+        (end <= data.len()).then(|| (end - offset - n, end))
+    };
+
+    if command == BitcoinCommandCode::GetVersion as u8 {
+        match data.first() {
+            None => return "empty version response".to_string(),
+            Some(0x01) => {}
+            Some(format) => return format!("unknown version response format {:#04x}", format),
+        }
+        let mut offset = 1;
+        for name in ["app name", "app version", "state flags"] {
+            match field(offset) {
+                Some((_, next)) => offset = next,
+                None => {
+                    return format!(
+                        "looks like a version response truncated at byte {} while reading the {}",
+                        data.len(),
+                        name
+                    )
+                }
+            }
+        }
+        format!(
+            "version response with {} unexpected trailing bytes",
+            data.len() - offset
+        )
+    } else if command == BitcoinCommandCode::GetMasterFingerprint as u8 {
+        format!("master fingerprint of {} bytes, expected 4", data.len())
+    } else if command == BitcoinCommandCode::RegisterWallet as u8 {
+        format!(
+            "registration response of {} bytes, expected 64: the wallet id and its hmac",
+            data.len()
+        )
+    } else if command == BitcoinCommandCode::SignMessage as u8 {
+        match data.first() {
+            None => "empty message signature".to_string(),
+            Some(header) if !(31..=42).contains(header) => format!(
+                "message signature with header {}, expected a compressed key header in 31..=42",
+                header
+            ),
+            Some(_) => format!(
+                "message signature of {} bytes after its header, expected 64",
+                data.len() - 1
+            ),
+        }
+    } else if command == BitcoinCommandCode::SignPSBT as u8 {
+        let (index, n): (VarInt, usize) = match deserialize_partial(data) {
+            Ok(res) => res,
+            Err(_) => return "signature without its input index".to_string(),
+        };
+        let rest = &data[n..];
+        let kind = match rest.first() {
+            None => return format!("no signature after the input index {}", index.0),
+            Some(33) => "an ECDSA signature",
+            Some(32) => "a taproot key path signature",
+            Some(64) => "a taproot script path signature",
+            Some(len) => return format!("unknown key length {} for input {}", len, index.0),
+        };
+        format!(
+            "{} for input {} with {} bytes of key and signature",
+            kind,
+            index.0,
+            rest.len() - 1
+        )
+    } else {
+        format!("{} bytes of data", data.len())
+    }
 }
 
 impl<T: Debug> From<InterpreterError> for BitcoinClientError<T> {
     fn from(e: InterpreterError) -> BitcoinClientError<T> {
-        BitcoinClientError::Interpreter(e)
+        match e {
+            InterpreterError::UnknownCommand(code) => {
+                BitcoinClientError::UnknownClientCommand(code)
+            }
+            e => BitcoinClientError::Interpreter(e),
+        }
+    }
+}
+
+impl<T: Debug> From<WalletError> for BitcoinClientError<T> {
+    fn from(e: WalletError) -> BitcoinClientError<T> {
+        BitcoinClientError::InvalidPolicy(match e {
+            WalletError::InvalidThreshold => "invalid threshold",
+            WalletError::UnsupportedAddressType => "unsupported address type",
+            WalletError::InvalidPolicy => "invalid policy",
+            WalletError::NameTooLong => "name too long",
+        })
+    }
+}
+
+impl<T: Debug> From<CommitmentsError> for BitcoinClientError<T> {
+    fn from(e: CommitmentsError) -> BitcoinClientError<T> {
+        match e {
+            CommitmentsError::InvalidPsbt => BitcoinClientError::InvalidPsbt,
+            CommitmentsError::MissingUtxo { input, missing } => {
+                BitcoinClientError::MissingUtxo { input, missing }
+            }
+            CommitmentsError::OutputMismatch { output, mismatch } => {
+                BitcoinClientError::OutputMismatch { output, mismatch }
+            }
+            CommitmentsError::NonStandardSighashType { input } => {
+                BitcoinClientError::NonStandardSighashType { input }
+            }
+        }
+    }
+}
+
+#[cfg(feature = "miniscript")]
+impl<T: Debug> From<crate::psbt::WeightError> for BitcoinClientError<T> {
+    fn from(e: crate::psbt::WeightError) -> BitcoinClientError<T> {
+        match e {
+            crate::psbt::WeightError::UnknownWeight => BitcoinClientError::ClientError(
+                "Failed to estimate the transaction weight".to_string(),
+            ),
+            crate::psbt::WeightError::TooLarge { weight, max } => {
+                BitcoinClientError::TransactionTooLarge { weight, max }
+            }
+        }
+    }
+}
+
+impl<T: Debug> From<bitcoin::util::bip32::Error> for BitcoinClientError<T> {
+    fn from(e: bitcoin::util::bip32::Error) -> BitcoinClientError<T> {
+        BitcoinClientError::Parse(e.to_string())
+    }
+}
+
+impl<T: Debug> From<bitcoin::util::address::Error> for BitcoinClientError<T> {
+    fn from(e: bitcoin::util::address::Error) -> BitcoinClientError<T> {
+        BitcoinClientError::Parse(e.to_string())
+    }
+}
+
+impl<T: Debug> From<core::str::Utf8Error> for BitcoinClientError<T> {
+    fn from(e: core::str::Utf8Error) -> BitcoinClientError<T> {
+        BitcoinClientError::Parse(e.to_string())
+    }
+}
+
+impl<T: Debug> Display for BitcoinClientError<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            BitcoinClientError::ClientError(e) => write!(f, "client error: {}", e),
+            BitcoinClientError::InvalidPsbt => write!(f, "invalid psbt"),
+            BitcoinClientError::PsbtModified { input } => {
+                write!(
+                    f,
+                    "psbt modified: unexpected signature hash for input {}",
+                    input
+                )
+            }
+            BitcoinClientError::MissingUtxo { input, missing } => {
+                write!(f, "input {} is missing utxo data: {:?}", input, missing)
+            }
+            BitcoinClientError::OutputMismatch { output, mismatch } => write!(
+                f,
+                "output {} of the psbt does not match the transaction: {:?}",
+                output, mismatch
+            ),
+            BitcoinClientError::NonStandardSighashType { input } => {
+                write!(f, "input {} has a non-standard sighash type", input)
+            }
+            BitcoinClientError::Transport(e) => write!(f, "transport error: {:?}", e),
+            BitcoinClientError::Timeout { interactive: true } => {
+                write!(f, "the user did not answer the device in time")
+            }
+            BitcoinClientError::Timeout { interactive: false } => {
+                write!(f, "the device did not answer in time")
+            }
+            BitcoinClientError::Interpreter(e) => write!(f, "interpreter error: {:?}", e),
+            BitcoinClientError::UnknownClientCommand(code) => write!(
+                f,
+                "the device sent the unknown client command {:#04x}, please update the client",
+                code
+            ),
+            BitcoinClientError::Device { command, status } => write!(
+                f,
+                "device returned {:?} ({:#06x}) to command {:#04x}",
+                status,
+                status.to_u16(),
+                command
+            ),
+            BitcoinClientError::UnexpectedResult { command, data } => write!(
+                f,
+                "unexpected result to command {:#04x}: {} bytes",
+                command,
+                data.len()
+            ),
+            BitcoinClientError::InvalidResponse(e) => write!(f, "invalid response: {}", e),
+            BitcoinClientError::Parse(e) => write!(f, "failed to parse response: {}", e),
+            BitcoinClientError::AddressTypeMismatch { expected, device } => write!(
+                f,
+                "device returned an address of type {:?}, expected {:?}",
+                device, expected
+            ),
+            BitcoinClientError::NetworkMismatch { address } => write!(
+                f,
+                "device returned the address {} of another network",
+                address
+            ),
+            BitcoinClientError::AppNetworkMismatch { app, network } => write!(
+                f,
+                "the {:?} app running on the device does not serve the network {:?}",
+                app, network
+            ),
+            BitcoinClientError::UnsupportedAppVersion => write!(f, "unsupported app version"),
+            BitcoinClientError::InvalidPolicy(reason) => {
+                write!(f, "invalid wallet policy: {}", reason)
+            }
+            BitcoinClientError::UnsupportedFeature(feature) => {
+                write!(f, "the app does not support {}", feature)
+            }
+            BitcoinClientError::UnsupportedByFirmware { feature } => write!(
+                f,
+                "the app on the device does not support {}, please update it with Ledger Live",
+                feature
+            ),
+            BitcoinClientError::BlindSigningDisabled => {
+                write!(f, "blind signing must be enabled in the app settings")
+            }
+            BitcoinClientError::HmacRequired => {
+                write!(
+                    f,
+                    "non-standard wallet policy used without its registration hmac"
+                )
+            }
+            BitcoinClientError::SigningAborted => write!(f, "signing aborted by the caller"),
+            BitcoinClientError::Cancelled => write!(f, "signing cancelled by the client"),
+            BitcoinClientError::MissingSignature { input } => {
+                write!(f, "the device did not sign input {}", input)
+            }
+            BitcoinClientError::IncompleteInputs(inputs) => {
+                write!(f, "inputs {:?} could not be finalized", inputs)
+            }
+            BitcoinClientError::WrongDeviceForWallet { fingerprint } => write!(
+                f,
+                "the wallet has no key of the device with fingerprint {}",
+                fingerprint
+            ),
+            BitcoinClientError::DeviceChanged {
+                expected,
+                fingerprint,
+            } => write!(
+                f,
+                "the device with fingerprint {} was replaced by the device with fingerprint {}",
+                expected, fingerprint
+            ),
+            BitcoinClientError::TooManyInputs { inputs, max } => write!(
+                f,
+                "transaction has {} inputs, the device signs at most {}",
+                inputs, max
+            ),
+            BitcoinClientError::TooManyOutputs { outputs, max } => write!(
+                f,
+                "transaction has {} outputs, the device signs at most {}",
+                outputs, max
+            ),
+            BitcoinClientError::TransactionTooLarge { weight, max } => write!(
+                f,
+                "transaction weight {} exceeds the standard maximum {}",
+                weight, max
+            ),
+        }
+    }
+}
+
+impl<T: std::error::Error + 'static> std::error::Error for BitcoinClientError<T> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BitcoinClientError::Transport(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::hashes::hex::FromHex;
+    use std::error::Error;
+
+    #[test]
+    fn test_display_and_source() {
+        let e: BitcoinClientError<std::io::Error> = BitcoinClientError::Device {
+            command: 0x04,
+            status: StatusWord::Deny,
+        };
+        assert_eq!(
+            e.to_string(),
+            "device returned Deny (0x6985) to command 0x04"
+        );
+        assert!(e.source().is_none());
+
+        let e: BitcoinClientError<std::io::Error> = BitcoinClientError::Transport(
+            std::io::Error::new(std::io::ErrorKind::NotConnected, "unplugged"),
+        );
+        assert_eq!(e.source().unwrap().to_string(), "unplugged");
+    }
+
+    #[test]
+    fn test_describe() {
+        let unexpected = |command: BitcoinCommandCode, data: &str| {
+            BitcoinClientError::<std::io::Error>::UnexpectedResult {
+                command: command as u8,
+                data: Vec::from_hex(data).unwrap(),
+            }
+            .describe()
+        };
+        assert!(unexpected(
+            BitcoinCommandCode::GetVersion,
+            "01095174756d205465737405322e312e"
+        )
+        .ends_with("truncated at byte 16 while reading the app version"));
+        assert!(unexpected(
+            BitcoinCommandCode::GetVersion,
+            "01095174756d205465737405322e312e35"
+        )
+        .ends_with("truncated at byte 17 while reading the state flags"));
+        assert!(
+            unexpected(BitcoinCommandCode::GetMasterFingerprint, "f5acc2")
+                .ends_with("master fingerprint of 3 bytes, expected 4")
+        );
+        assert!(unexpected(BitcoinCommandCode::SignPSBT, "0121")
+            .ends_with("an ECDSA signature for input 1 with 0 bytes of key and signature"));
+
+        let e = BitcoinClientError::<std::io::Error>::InvalidPsbt;
+        assert_eq!(e.describe(), e.to_string());
+
+        // never panics, whatever the data
+        for command in 0..=0x10 {
+            for len in 0..80 {
+                let data: Vec<u8> = (0..len).map(|i| (i * 37 + len) as u8).collect();
+                BitcoinClientError::<std::io::Error>::UnexpectedResult { command, data }.describe();
+                let mut data = vec![0x01, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+                data.truncate(len as usize);
+                BitcoinClientError::<std::io::Error>::UnexpectedResult { command, data }.describe();
+            }
+        }
+    }
+
+    #[test]
+    fn test_from_parse_errors() {
+        use core::str::FromStr;
+
+        let data = vec![0x78, 0xff];
+        let e: BitcoinClientError<std::io::Error> = core::str::from_utf8(&data).unwrap_err().into();
+        assert!(matches!(&e, BitcoinClientError::Parse(reason) if reason.contains("utf-8")));
+
+        let e: BitcoinClientError<std::io::Error> = bitcoin::Address::from_str("not an address")
+            .unwrap_err()
+            .into();
+        assert!(matches!(e, BitcoinClientError::Parse(_)));
+
+        let e: BitcoinClientError<std::io::Error> =
+            bitcoin::util::bip32::ExtendedPubKey::from_str("xpub")
+                .unwrap_err()
+                .into();
+        assert!(e.to_string().starts_with("failed to parse response: "));
     }
 }
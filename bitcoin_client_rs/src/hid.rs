@@ -0,0 +1,162 @@
+//! HID framing of the APDUs exchanged with the Ledger devices, over hidapi, WebUSB or a
+//! bridge, independent of the USB backend.
+//!
+//! An APDU is split into packets of 64 bytes, each starting with a channel, the tag 0x05
+//! and the sequence number of the packet, all big endian. The first packet then gives the
+//! length of the APDU on two bytes. The last packet is padded with zeros.
+
+/// Size of the HID packets.
+pub const PACKET_SIZE: usize = 64;
+/// Channel of the framed commands, the device answers on the channel of the command.
+pub const CHANNEL: u16 = 0x0101;
+/// Tag of the packets carrying an APDU.
+pub const TAG_APDU: u8 = 0x05;
+
+/// Size of the header of each packet: channel, tag and sequence number.
+const HEADER_SIZE: usize = 5;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HidError {
+    /// The packets end before the length of the APDU given by the first packet.
+    Incomplete { expected: usize, received: usize },
+    /// The packet at the index has another channel than the first one, or another tag.
+    InvalidHeader { index: usize },
+    /// The packet at the index has an unexpected sequence number.
+    OutOfOrder { index: usize, sequence: u16 },
+    /// Packets remain after the end of the APDU.
+    TrailingPackets,
+}
+
+/// Splits the APDU into the packets to write to the device, on `CHANNEL`.
+pub fn frame(apdu: &[u8]) -> Vec<[u8; PACKET_SIZE]> {
+    let mut data = (apdu.len() as u16).to_be_bytes().to_vec();
+    data.extend_from_slice(apdu);
+    data.chunks(PACKET_SIZE - HEADER_SIZE)
+        .enumerate()
+        .map(|(sequence, chunk)| {
+            let mut packet = [0u8; PACKET_SIZE];
+            packet[..2].copy_from_slice(&CHANNEL.to_be_bytes());
+            packet[2] = TAG_APDU;
+            packet[3..HEADER_SIZE].copy_from_slice(&(sequence as u16).to_be_bytes());
+            packet[HEADER_SIZE..HEADER_SIZE + chunk.len()].copy_from_slice(chunk);
+            packet
+        })
+        .collect()
+}
+
+/// Reassembles the APDU, usually a response, from the packets read from the device in
+/// order. Returns `HidError::Incomplete` while more packets must be read.
+pub fn unframe(packets: &[[u8; PACKET_SIZE]]) -> Result<Vec<u8>, HidError> {
+    let first = match packets.first() {
+        Some(first) => first,
+        None => {
+            return Err(HidError::Incomplete {
+                expected: 2,
+                received: 0,
+            })
+        }
+    };
+    let expected = u16::from_be_bytes([first[HEADER_SIZE], first[HEADER_SIZE + 1]]) as usize;
+
+    let mut apdu = Vec::with_capacity(expected);
+    for (index, packet) in packets.iter().enumerate() {
+        if apdu.len() == expected && index > 0 {
+            return Err(HidError::TrailingPackets);
+        }
+        if packet[..2] != first[..2] || packet[2] != TAG_APDU {
+            return Err(HidError::InvalidHeader { index });
+        }
+        let sequence = u16::from_be_bytes([packet[3], packet[4]]);
+        if sequence as usize != index {
+            return Err(HidError::OutOfOrder { index, sequence });
+        }
+        let payload = if index == 0 {
+            &packet[HEADER_SIZE + 2..]
+        } else {
+            &packet[HEADER_SIZE..]
+        };
+        let take = payload.len().min(expected - apdu.len());
+        apdu.extend_from_slice(&payload[..take]);
+    }
+
+    if apdu.len() < expected {
+        return Err(HidError::Incomplete {
+            expected,
+            received: apdu.len(),
+        });
+    }
+    Ok(apdu)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_unframe() {
+        // a short apdu fits in a single packet
+        let packets = frame(&[0xe1, 0x05, 0x00, 0x01, 0x00]);
+        assert_eq!(packets.len(), 1);
+        assert_eq!(
+            packets[0][..12],
+            [0x01, 0x01, 0x05, 0x00, 0x00, 0x00, 0x05, 0xe1, 0x05, 0x00, 0x01, 0x00]
+        );
+        assert!(packets[0][12..].iter().all(|b| *b == 0));
+        assert_eq!(unframe(&packets).unwrap(), [0xe1, 0x05, 0x00, 0x01, 0x00]);
+
+        // the first packet holds 57 bytes of the apdu, the next ones 59 bytes
+        for (len, n_packets) in [
+            (0, 1),
+            (57, 1),
+            (58, 2),
+            (116, 2),
+            (117, 3),
+            (255, 5),
+            (300, 6),
+        ] {
+            let apdu: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let packets = frame(&apdu);
+            assert_eq!(packets.len(), n_packets);
+            for (i, packet) in packets.iter().enumerate() {
+                assert_eq!(packet[3..5], (i as u16).to_be_bytes());
+            }
+            assert_eq!(unframe(&packets).unwrap(), apdu);
+        }
+    }
+
+    #[test]
+    fn test_unframe_errors() {
+        let apdu: Vec<u8> = (0..200).map(|i| i as u8).collect();
+        let packets = frame(&apdu);
+        assert_eq!(packets.len(), 4);
+
+        assert_eq!(
+            unframe(&packets[..2]),
+            Err(HidError::Incomplete {
+                expected: 200,
+                received: 116
+            })
+        );
+        assert!(matches!(unframe(&[]), Err(HidError::Incomplete { .. })));
+
+        let swapped = [packets[0], packets[2], packets[1], packets[3]];
+        assert_eq!(
+            unframe(&swapped),
+            Err(HidError::OutOfOrder {
+                index: 1,
+                sequence: 2
+            })
+        );
+
+        let mut other_channel = packets.clone();
+        other_channel[3][1] = 0x02;
+        assert_eq!(
+            unframe(&other_channel),
+            Err(HidError::InvalidHeader { index: 3 })
+        );
+
+        let mut trailing = packets.clone();
+        trailing.push(packets[3]);
+        assert_eq!(unframe(&trailing), Err(HidError::TrailingPackets));
+    }
+}
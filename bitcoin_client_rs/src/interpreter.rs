@@ -6,18 +6,23 @@ use bitcoin::{
     hashes::{sha256, Hash, HashEngine},
 };
 
-use crate::{apdu::ClientCommandCode, merkle::MerkleTree};
+use crate::{
+    apdu::ClientCommandCode,
+    merkle::{leaf_hash, MerkleTree, LEAF_PREFIX},
+};
 
 /// Interpreter for the client-side commands.
 /// This struct keeps has methods to keep track of:
 ///   - known preimages
 ///   - known Merkle trees from lists of elements
+///
 /// Moreover, it containes the state that is relevant for the interpreted client side commands:
 ///   - a queue of bytes that contains any bytes that could not fit in a response from the
 ///     GET_PREIMAGE client command (when a preimage is too long to fit in a single message) or the
 ///     GET_MERKLE_LEAF_PROOF command (which returns a Merkle proof, which might be too long to fit
 ///     in a single message). The data in the queue is returned in one (or more) successive
 ///     GET_MORE_ELEMENTS commands from the hardware wallet.
+///
 /// Finally, it keeps track of the yielded values (that is, the values sent from the hardware
 /// wallet with a YIELD client command).
 pub struct ClientCommandInterpreter {
@@ -27,6 +32,12 @@ pub struct ClientCommandInterpreter {
     trees: Vec<MerkleTree>,
 }
 
+impl Default for ClientCommandInterpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ClientCommandInterpreter {
     pub fn new() -> Self {
         Self {
@@ -58,11 +69,9 @@ impl ClientCommandInterpreter {
     pub fn add_known_list(&mut self, elements: &[impl AsRef<[u8]>]) -> [u8; 32] {
         let mut leaves = Vec::with_capacity(elements.len());
         for element in elements {
-            let mut preimage = vec![0x00];
+            let mut preimage = vec![LEAF_PREFIX];
             preimage.extend_from_slice(element.as_ref());
-            let mut engine = sha256::Hash::engine();
-            engine.input(&preimage);
-            let hash = sha256::Hash::from_engine(engine).into_inner();
+            let hash = leaf_hash(element.as_ref());
             self.known_preimages.push((hash, preimage));
             leaves.push(hash);
         }
@@ -90,8 +99,8 @@ impl ClientCommandInterpreter {
         self.add_known_list(&values);
     }
 
-    // Interprets the client command requested by the hardware wallet, returns the appropriate
-    // response to transmit back and updates interpreter internal states.
+    /// Interprets the client command requested by the hardware wallet, returns the appropriate
+    /// response to transmit back and updates interpreter internal states.
     pub fn execute(&mut self, command: Vec<u8>) -> Result<Vec<u8>, InterpreterError> {
         if command.is_empty() {
             return Err(InterpreterError::EmptyInput);
@@ -115,9 +124,9 @@ impl ClientCommandInterpreter {
         }
     }
 
-    /// Consumes the interpreter and returns the yielded results.
-    pub fn yielded(self) -> Vec<Vec<u8>> {
-        self.yielded
+    /// Removes and returns the results yielded since the last call.
+    pub fn take_yielded(&mut self) -> Vec<Vec<u8>> {
+        core::mem::take(&mut self.yielded)
     }
 }
 
@@ -284,17 +293,8 @@ pub fn get_merkleized_map_commitment(mapping: &[(Vec<u8>, Vec<u8>)]) -> Vec<u8>
     let mut keys_hashes: Vec<[u8; 32]> = Vec::with_capacity(sorted.len());
     let mut values_hashes: Vec<[u8; 32]> = Vec::with_capacity(sorted.len());
     for (key, value) in &sorted {
-        let mut preimage = vec![0x00];
-        preimage.extend_from_slice(key);
-        let mut engine = sha256::Hash::engine();
-        engine.input(&preimage);
-        keys_hashes.push(sha256::Hash::from_engine(engine).into_inner());
-
-        let mut preimage = vec![0x00];
-        preimage.extend_from_slice(value);
-        let mut engine = sha256::Hash::engine();
-        engine.input(&preimage);
-        values_hashes.push(sha256::Hash::from_engine(engine).into_inner());
+        keys_hashes.push(leaf_hash(key));
+        values_hashes.push(leaf_hash(value));
     }
 
     let mut commitment = encode::serialize(&VarInt(sorted.len() as u64));
@@ -1,15 +1,25 @@
-mod command;
-mod interpreter;
-mod merkle;
-
 pub mod apdu;
+pub mod bip32;
 pub mod client;
+pub mod command;
 pub mod error;
+pub mod hid;
+pub mod interpreter;
+pub mod merkle;
+pub mod message;
 pub mod psbt;
+pub mod replay;
+pub mod response;
 pub mod wallet;
 
 #[cfg(feature = "async")]
 pub mod async_client;
 
+#[cfg(feature = "simulator")]
+pub mod transport;
+
+#[cfg(feature = "wasm")]
+pub mod webusb;
+
 pub use client::{BitcoinClient, Transport};
 pub use wallet::{WalletPolicy, WalletPubKey};
@@ -1,13 +1,40 @@
+//! This implementation of Merkle Trees makes usage of a
+//! simple and opinionated data structure.
+//! The Tree is only created once and does not require
+//! multiple manipulation like new leaf insertion at a choosen index.
+//! In fact the client uses merkle trees for read only commands:
+//!  - get_merkle_leaf_proof: provide the proof the hash of the leaf
+//!    with index i
+//!  - get_merkle_leaf_index: provide the index of the leaf with hash.
+//!
+//! The hashes are domain separated like in the device: a leaf is the hash of
+//! `0x00 || element` and an inner node the hash of `0x01 || left || right`,
+//! see `leaf_hash` and `node_hash`.
+
 use bitcoin::hashes::{sha256, Hash, HashEngine};
 
-///! This implementation of Merkle Trees makes usage of a
-///! simple and opinionated data structure.
-///! The Tree is only created once and does not require
-///! multiple manipulation like new leaf insertion at a choosen index.
-///! In fact the client uses merkle trees for read only commands:
-///!  - get_merkle_leaf_proof: provide the proof the hash of the leaf
-///!    with index i
-///!  - get_merkle_leaf_index: provide the index of the leaf with hash.
+/// Prefix of the preimage of a leaf hash.
+pub const LEAF_PREFIX: u8 = 0x00;
+
+/// Prefix of the preimage of an inner node hash.
+pub const NODE_PREFIX: u8 = 0x01;
+
+/// Returns the hash of the leaf committing to `element`: sha256(0x00 || element).
+pub fn leaf_hash(element: &[u8]) -> [u8; 32] {
+    let mut engine = sha256::Hash::engine();
+    engine.input(&[LEAF_PREFIX]);
+    engine.input(element);
+    sha256::Hash::from_engine(engine).into_inner()
+}
+
+/// Returns the hash of the inner node with the given children: sha256(0x01 || left || right).
+pub fn node_hash(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut engine = sha256::Hash::engine();
+    engine.input(&[NODE_PREFIX]);
+    engine.input(left);
+    engine.input(right);
+    sha256::Hash::from_engine(engine).into_inner()
+}
 
 /// MerkleTree is containing a merkle tree generated from a list of items.
 pub struct MerkleTree {
@@ -23,6 +50,16 @@ impl MerkleTree {
         }
     }
 
+    /// Builds the Merkle tree of a list of elements, hashed with `leaf_hash`.
+    pub fn from_elements(elements: &[impl AsRef<[u8]>]) -> Self {
+        Self::new(
+            elements
+                .iter()
+                .map(|element| leaf_hash(element.as_ref()))
+                .collect(),
+        )
+    }
+
     pub fn size(&self) -> usize {
         self.leaves.len()
     }
@@ -45,7 +82,8 @@ impl MerkleTree {
         self.leaves.iter().position(|v| v == val)
     }
 
-    // Get Merkle proof of a leaf with the given index.
+    /// Get Merkle proof of a leaf with the given index, ordered from the sibling of the leaf
+    /// up to the sibling of the root.
     pub fn get_leaf_proof(&self, index: usize) -> Option<Vec<Vec<u8>>> {
         if index >= self.leaves.len() {
             // Out of bound
@@ -56,6 +94,34 @@ impl MerkleTree {
     }
 }
 
+/// Computes the root of a Merkle tree of `size` leaves from the leaf at `index` and its
+/// Merkle proof, ordered from the sibling of the leaf up to the sibling of the root
+/// like the one returned by `get_leaf_proof`.
+/// Returns None if the proof does not have the length expected for the leaf position.
+pub fn root_from_proof(
+    leaf: &[u8; 32],
+    proof: &[[u8; 32]],
+    size: usize,
+    index: usize,
+) -> Option<[u8; 32]> {
+    if index >= size {
+        return None;
+    }
+    if size == 1 {
+        return if proof.is_empty() { Some(*leaf) } else { None };
+    }
+
+    let (sibling, proof) = proof.split_last()?;
+    let lchild_size = largest_power_of_2_less_than(size);
+    if index < lchild_size {
+        let lchild = root_from_proof(leaf, proof, lchild_size, index)?;
+        Some(node_hash(&lchild, sibling))
+    } else {
+        let rchild = root_from_proof(leaf, proof, size - lchild_size, index - lchild_size)?;
+        Some(node_hash(sibling, &rchild))
+    }
+}
+
 /// Tree is either a Node with children trees or a Leaf with only a given value.
 enum Tree {
     Node {
@@ -78,13 +144,7 @@ impl Tree {
         let lchild = Tree::new(leaves, start, lchild_size);
         let rchild = Tree::new(leaves, start + lchild_size, size - lchild_size);
 
-        let mut input = vec![0x01];
-        input.extend_from_slice(lchild.value(leaves));
-        input.extend_from_slice(rchild.value(leaves));
-
-        let mut engine = sha256::Hash::engine();
-        engine.input(input.as_slice());
-        let value = sha256::Hash::from_engine(engine).into_inner();
+        let value = node_hash(lchild.value(leaves), rchild.value(leaves));
         Tree::Node {
             height: lchild.height() + 1,
             left: Box::new(lchild),
@@ -220,4 +280,69 @@ mod tests {
 
         let _tree = MerkleTree::new(leaves.to_vec());
     }
+
+    #[test]
+    fn test_domain_separation() {
+        use bitcoin::hashes::hex::FromHex;
+
+        let hash = |h: &str| -> [u8; 32] { FromHex::from_hex(h).unwrap() };
+
+        assert_eq!(
+            leaf_hash(&[]),
+            hash("6e340b9cffb37a989ca544e6bb780a2c78901d3fb33738768511a30617afa01d")
+        );
+        assert_eq!(
+            leaf_hash(&[0x00]),
+            hash("96a296d224f285c67bee93c30f8a309157f0daa35dc5b87e410b78630a09cfc7")
+        );
+        // A leaf is never equal to the plain hash of its element.
+        assert_ne!(leaf_hash(&[]), sha256::Hash::hash(&[]).into_inner());
+
+        let tree = MerkleTree::from_elements(&[[0x00], [0x01]]);
+        assert_eq!(
+            tree.root_hash(),
+            &hash("a20bf9a7cc2dc8a08f5f415a71b19f6ac427bab54d24eec868b5d3103449953a")
+        );
+        assert_eq!(
+            tree.root_hash(),
+            &node_hash(&leaf_hash(&[0x00]), &leaf_hash(&[0x01]))
+        );
+
+        let tree = MerkleTree::from_elements(&[[0x00], [0x01], [0x02]]);
+        assert_eq!(
+            tree.root_hash(),
+            &hash("3b6cccd7e3e023ff393006f030315ee7ad9eb111b022b41fba7e5b7a3973f688")
+        );
+
+        let tree = MerkleTree::from_elements(&[[0x00]]);
+        assert_eq!(tree.root_hash(), &leaf_hash(&[0x00]));
+    }
+
+    #[test]
+    fn test_root_from_proof() {
+        use core::convert::TryInto;
+
+        let leaves: Vec<[u8; 32]> = (0..7_u8)
+            .map(|i| sha256::Hash::hash(&[i]).into_inner())
+            .collect();
+
+        for size in 1..leaves.len() {
+            let tree = MerkleTree::new(leaves[..size].to_vec());
+            for (index, leaf) in leaves[..size].iter().enumerate() {
+                let proof: Vec<[u8; 32]> = tree
+                    .get_leaf_proof(index)
+                    .unwrap()
+                    .into_iter()
+                    .map(|p| p.try_into().unwrap())
+                    .collect();
+                assert_eq!(
+                    root_from_proof(leaf, &proof, size, index).as_ref(),
+                    Some(tree.root_hash())
+                );
+                if !proof.is_empty() {
+                    assert_eq!(root_from_proof(leaf, &proof[1..], size, index), None);
+                }
+            }
+        }
+    }
 }
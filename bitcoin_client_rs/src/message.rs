@@ -0,0 +1,216 @@
+//! Verification of the messages signed with `sign_message`, and the hash shown by the
+//! device to confirm them.
+//!
+//! The device follows the Qtum Core `signmessage` convention: it signs the double SHA256 of
+//! the magic `"\x15Qtum Signed Message:\n"`, the length of the message as a Bitcoin-style
+//! varint and the message. Signatures made with Bitcoin's `"\x18Bitcoin Signed Message:\n"`
+//! prefix do not verify against the Qtum one.
+//!
+//! The header byte of a signature follows BIP137: it gives the recovery id of the key and
+//! the type of the address of the key, from which the address is recovered.
+
+use bitcoin::{
+    consensus::encode::{self, VarInt},
+    hashes::{sha256, sha256d, Hash, HashEngine},
+    secp256k1::{self, ecdsa, Message, Secp256k1},
+    util::misc::{MessageSignature, MessageSignatureError},
+    Address, AddressType, Network, PublicKey,
+};
+
+/// Prefix of the messages signed by the device.
+pub const QTUM_SIGNED_MSG_PREFIX: &[u8] = b"\x15Qtum Signed Message:\n";
+
+/// Returns the hash signed by the device for the message.
+pub fn signed_msg_hash(message: &[u8]) -> sha256d::Hash {
+    let mut engine = sha256d::Hash::engine();
+    engine.input(QTUM_SIGNED_MSG_PREFIX);
+    engine.input(&encode::serialize(&VarInt(message.len() as u64)));
+    engine.input(message);
+    sha256d::Hash::from_engine(engine)
+}
+
+/// Returns the hash of the message displayed by the device for the user to confirm it, the
+/// SHA256 of the message alone, shown in hex, for the wallet to display the same hash for
+/// the user to compare them. It covers the whole message, also when it is sent to the
+/// device in chunks of 64 bytes, and differs from the hash signed, see `signed_msg_hash`.
+pub fn displayed_msg_hash(message: &[u8]) -> sha256::Hash {
+    sha256::Hash::hash(message)
+}
+
+/// Returns the type of the address of the key of a signature with the BIP137 header:
+/// 27-30 and 31-34 for a P2PKH address of an uncompressed and a compressed key, 35-38 for
+/// a P2SH-P2WPKH address and 39-42 for a P2WPKH address. None for another header.
+pub fn header_address_type(header: u8) -> Option<AddressType> {
+    match header {
+        27..=34 => Some(AddressType::P2pkh),
+        35..=38 => Some(AddressType::P2sh),
+        39..=42 => Some(AddressType::P2wpkh),
+        _ => None,
+    }
+}
+
+/// Recovers the address of the key that signed the message, of the type given by the BIP137
+/// header, see `header_address_type`. The signature is valid for the returned address only.
+/// Taproot addresses have no BIP137 header, their keys cannot be recovered.
+pub fn recover_address(
+    message: &[u8],
+    header: u8,
+    signature: &ecdsa::Signature,
+    network: Network,
+) -> Result<Address, MessageSignatureError> {
+    let address_type = header_address_type(header).ok_or(
+        MessageSignatureError::InvalidEncoding(secp256k1::Error::InvalidRecoveryId),
+    )?;
+    let recovery_id = ecdsa::RecoveryId::from_i32(((header - 27) % 4) as i32)
+        .map_err(MessageSignatureError::InvalidEncoding)?;
+    let signature =
+        ecdsa::RecoverableSignature::from_compact(&signature.serialize_compact(), recovery_id)
+            .map_err(MessageSignatureError::InvalidEncoding)?;
+    let key = Secp256k1::verification_only()
+        .recover_ecdsa(
+            &Message::from_slice(&signed_msg_hash(message).into_inner()).expect("32 bytes hash"),
+            &signature,
+        )
+        .map_err(MessageSignatureError::InvalidEncoding)?;
+    let key = PublicKey {
+        compressed: header >= 31,
+        inner: key,
+    };
+    Ok(match address_type {
+        AddressType::P2pkh => Address::p2pkh(&key, network),
+        AddressType::P2sh => Address::p2shwpkh(&key, network).expect("compressed key"),
+        _ => Address::p2wpkh(&key, network).expect("compressed key"),
+    })
+}
+
+/// Verifies that the header and signature returned by `sign_message` sign the message
+/// with the key of the address, like Qtum Core `verifymessage` for a P2PKH address.
+/// With a P2PKH header, other address types are not supported and return an error; with
+/// a segwit header, the address must be the one recovered by `recover_address`.
+pub fn verify_signed_message(
+    message: &[u8],
+    header: u8,
+    signature: &ecdsa::Signature,
+    address: &Address,
+) -> Result<bool, MessageSignatureError> {
+    if let Some(AddressType::P2sh | AddressType::P2wpkh) = header_address_type(header) {
+        return Ok(recover_address(message, header, signature, address.network)? == *address);
+    }
+    let mut bytes = vec![header];
+    bytes.extend_from_slice(&signature.serialize_compact());
+    MessageSignature::from_slice(&bytes)?.is_signed_by_address(
+        &Secp256k1::verification_only(),
+        address,
+        signed_msg_hash(message),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::str::FromStr;
+
+    fn decode(sig: &str) -> (u8, ecdsa::Signature) {
+        let sig = base64::decode(sig).unwrap();
+        (sig[0], ecdsa::Signature::from_compact(&sig[1..]).unwrap())
+    }
+
+    #[test]
+    fn test_displayed_msg_hash() {
+        use bitcoin::hashes::hex::ToHex;
+        assert_eq!(
+            displayed_msg_hash(b"hello").to_hex(),
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+        );
+        // A message of two chunks.
+        let message: Vec<u8> = (0..100).collect();
+        assert_eq!(
+            displayed_msg_hash(&message).to_hex(),
+            "bce0aff19cf5aa6a7469a30d61d04e4376e4bbf6381052ee9e7f33925c954d52"
+        );
+        assert_ne!(
+            displayed_msg_hash(b"hello").into_inner(),
+            signed_msg_hash(b"hello").into_inner()
+        );
+    }
+
+    #[test]
+    fn test_verify_signed_message() {
+        let address = Address::from_str("n47smLxMkPCyu7pqdk4kiBw9ai7VSz5bKF").unwrap();
+        let key = bitcoin::PrivateKey::from_slice(&[0x01; 32], bitcoin::Network::Testnet)
+            .unwrap()
+            .public_key(&Secp256k1::signing_only());
+        let other = Address::p2pkh(&key, bitcoin::Network::Testnet);
+
+        // "hello" signed with the key m/44'/1'/0'/0 of the test seed.
+        let (header, sig) = decode(
+            "IG6iE2B791q0q8qPoZnEusFYUe3tdTHJST41W5HgnScDcPQ9RuGERoO8pQDqWcJYORUJBVEx/XIcjhryiyFslDQ=",
+        );
+        assert!(verify_signed_message(b"hello", header, &sig, &address).unwrap());
+        assert!(!verify_signed_message(b"hello!", header, &sig, &address).unwrap());
+        assert!(!verify_signed_message(b"hello", header, &sig, &other).unwrap());
+
+        // The same message and key, signed with the Bitcoin prefix.
+        let (header, sig) = decode(
+            "IL3u9GLAzgG5BdtSBqUe0Fo2Zx0UlKwSsYx2TbuVX0VULFgZYRBQCW0W7QOlsB/JgGwWNhl3eYYjXtdfyR7pM+Y=",
+        );
+        assert!(!verify_signed_message(b"hello", header, &sig, &address).unwrap());
+
+        let segwit = Address::p2wpkh(&key, bitcoin::Network::Testnet).unwrap();
+        assert!(matches!(
+            verify_signed_message(b"hello", header, &sig, &segwit),
+            Err(MessageSignatureError::UnsupportedAddressType(_))
+        ));
+    }
+
+    #[test]
+    fn test_recover_address() {
+        let secp = Secp256k1::new();
+        let secret = secp256k1::SecretKey::from_slice(&[0x01; 32]).unwrap();
+        let key = PublicKey::new(secp256k1::PublicKey::from_secret_key(&secp, &secret));
+        let (recovery_id, sig) = secp
+            .sign_ecdsa_recoverable(
+                &Message::from_slice(&signed_msg_hash(b"hello").into_inner()).unwrap(),
+                &secret,
+            )
+            .serialize_compact();
+        let sig = ecdsa::Signature::from_compact(&sig).unwrap();
+        let recovery_id = recovery_id.to_i32() as u8;
+
+        let p2pkh = Address::p2pkh(&key, Network::Testnet);
+        let p2sh = Address::p2shwpkh(&key, Network::Testnet).unwrap();
+        let p2wpkh = Address::p2wpkh(&key, Network::Testnet).unwrap();
+        for (first, address) in [(31, &p2pkh), (35, &p2sh), (39, &p2wpkh)] {
+            let header = first + recovery_id;
+            assert_eq!(
+                header_address_type(header),
+                Some(address.address_type().unwrap())
+            );
+            assert_eq!(
+                recover_address(b"hello", header, &sig, Network::Testnet).unwrap(),
+                *address
+            );
+            assert!(verify_signed_message(b"hello", header, &sig, address).unwrap());
+            assert!(!verify_signed_message(b"hello!", header, &sig, address).unwrap());
+        }
+        // The header of another address type recovers another address.
+        assert!(!verify_signed_message(b"hello", 35 + recovery_id, &sig, &p2wpkh).unwrap());
+        // A P2PKH header of an uncompressed key.
+        let uncompressed = PublicKey {
+            compressed: false,
+            inner: key.inner,
+        };
+        assert_eq!(
+            recover_address(b"hello", 27 + recovery_id, &sig, Network::Testnet).unwrap(),
+            Address::p2pkh(&uncompressed, Network::Testnet)
+        );
+
+        for header in [0, 26, 43, 255] {
+            assert_eq!(header_address_type(header), None);
+            assert!(matches!(
+                recover_address(b"hello", header, &sig, Network::Testnet),
+                Err(MessageSignatureError::InvalidEncoding(_))
+            ));
+        }
+    }
+}
@@ -4,19 +4,33 @@
 /// Note: Only psbt V2 is supported by the ledger bitcoin app.
 /// rust-bitcoin currently support V0.
 use bitcoin::{
-    blockdata::transaction::{TxIn, TxOut},
-    consensus::encode::{deserialize, serialize, Error, VarInt},
+    blockdata::{
+        script::{Instruction, Script},
+        transaction::{TxIn, TxOut},
+    },
+    consensus::encode::{deserialize, deserialize_partial, serialize, Error, VarInt},
+    hashes::Hash,
     secp256k1,
     util::{
+        bip32::{ChildNumber, KeySource},
         ecdsa::{EcdsaSig, EcdsaSigError},
         key::Error as KeyError,
         psbt::{raw, serialize::Deserialize, Input, Output, Psbt},
         schnorr::{SchnorrSig, SchnorrSigError},
+        sighash::{self, Prevouts, SighashCache},
         taproot::TapLeafHash,
     },
     PublicKey, XOnlyPublicKey,
 };
 
+use core::convert::TryFrom;
+use core::fmt::Debug;
+
+use crate::{
+    bip32::QtumNetwork, interpreter::get_merkleized_map_commitment, merkle::MerkleTree,
+    wallet::WalletPolicy,
+};
+
 #[rustfmt::skip]
 macro_rules! impl_psbt_get_pair {
     ($rv:ident.push($slf:ident.$unkeyed_name:ident, $unkeyed_typeval:ident)) => {
@@ -382,14 +396,805 @@ pub fn get_v2_output_pairs(output: &Output, txout: &TxOut) -> Vec<raw::Pair> {
     rv
 }
 
+/// Fills the fields required by the PSBT v2 commitments that a PSBT built by a
+/// software only aware of PSBT v0 may lack:
+///   - one input map per input and one output map per output of the unsigned transaction,
+///   - the witness utxo of segwit inputs, taken from their non-witness utxo.
+///
+/// `sign_psbt` expects a normalized PSBT, this function must be called first.
+pub fn normalize(psbt: &mut Psbt) {
+    if psbt.inputs.len() < psbt.unsigned_tx.input.len() {
+        psbt.inputs
+            .resize(psbt.unsigned_tx.input.len(), Input::default());
+    }
+    if psbt.outputs.len() < psbt.unsigned_tx.output.len() {
+        psbt.outputs
+            .resize(psbt.unsigned_tx.output.len(), Output::default());
+    }
+
+    for (input, txin) in psbt.inputs.iter_mut().zip(psbt.unsigned_tx.input.iter()) {
+        if input.witness_utxo.is_some() {
+            continue;
+        }
+        let txout = match &input.non_witness_utxo {
+            Some(tx) if tx.txid() == txin.previous_output.txid => {
+                tx.output.get(txin.previous_output.vout as usize)
+            }
+            _ => None,
+        };
+        if let Some(txout) = txout {
+            let is_segwit = txout.script_pubkey.is_witness_program()
+                || matches!(&input.redeem_script, Some(script) if script.is_witness_program());
+            if is_segwit {
+                input.witness_utxo = Some(txout.clone());
+            }
+        }
+    }
+}
+
+/// Qtum contract opcodes.
+const OP_CREATE: u8 = 0xc1;
+const OP_CALL: u8 = 0xc2;
+/// Address of the Qtum delegation contract, calls to it are decoded by the device.
+const DELEGATIONS_ADDRESS: [u8; 20] = [
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x86,
+];
+
+/// Returns true if the script creates or calls a contract with data that the device
+/// cannot decode, meaning that blind signing must be enabled in the app settings.
+pub fn is_contract_blind_sign(script: &Script) -> bool {
+    if script.is_v0_p2wpkh() || script.is_v0_p2wsh() || script.is_op_return() {
+        return false;
+    }
+
+    let count_op = |op: u8| {
+        script
+            .instructions()
+            .take_while(|ins| ins.is_ok())
+            .filter(|ins| matches!(ins, Ok(Instruction::Op(opcode)) if opcode.to_u8() == op))
+            .count()
+    };
+    if count_op(OP_CREATE) != 1 && count_op(OP_CALL) != 1 {
+        return false;
+    }
+
+    let bytes = script.as_bytes();
+    bytes.len() < 21 || bytes[bytes.len() - 21..bytes.len() - 1] != DELEGATIONS_ADDRESS
+}
+
+/// Returns true if signing the PSBT with the wallet requires the blind signing setting of the
+/// app: the device only checks the contract data of the outputs that are not internal, see
+/// `is_internal_output`.
+pub fn requires_blind_signing(psbt: &Psbt, wallet: &WalletPolicy) -> bool {
+    psbt.unsigned_tx
+        .output
+        .iter()
+        .enumerate()
+        .filter(|(index, _)| {
+            !matches!(psbt.outputs.get(*index), Some(output) if is_internal_output(output, wallet))
+        })
+        .any(|(_, txout)| is_contract_blind_sign(&txout.script_pubkey))
+}
+
+/// Utxo data missing in a PSBT input that the device requires to sign it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MissingUtxo {
+    /// Neither the witness utxo nor the non-witness utxo are present.
+    Any,
+    /// Segwit inputs require the witness utxo, see `normalize`.
+    WitnessUtxo,
+    /// Legacy inputs require the non-witness utxo.
+    NonWitnessUtxo,
+}
+
+/// Returns the utxo data that the input is missing according to its script type, if any.
+/// The device requires the data of the script type only for the inputs it signs, see
+/// `is_internal_input`; the other inputs only need one of the witness and non-witness utxo.
+pub fn missing_utxo(input: &Input, txin: &TxIn) -> Option<MissingUtxo> {
+    let spent = input.witness_utxo.as_ref().or_else(|| {
+        input
+            .non_witness_utxo
+            .as_ref()
+            .and_then(|tx| tx.output.get(txin.previous_output.vout as usize))
+    });
+    let script_pubkey = match spent {
+        Some(txout) => &txout.script_pubkey,
+        None => return Some(MissingUtxo::Any),
+    };
+
+    let is_segwit = script_pubkey.is_witness_program()
+        || (script_pubkey.is_p2sh()
+            && matches!(&input.redeem_script, Some(script) if script.is_witness_program()));
+
+    if is_segwit && input.witness_utxo.is_none() {
+        Some(MissingUtxo::WitnessUtxo)
+    } else if !is_segwit && input.non_witness_utxo.is_none() {
+        Some(MissingUtxo::NonWitnessUtxo)
+    } else {
+        None
+    }
+}
+
+/// Returns true if the input holds the origin of a key of the wallet in its bip32 derivations
+/// or taproot key origins, for the device to recognize it as an input of the wallet to sign.
+/// Like the device, a key origin matches a key of the wallet if it has its fingerprint and
+/// derivation path, followed by the change and address index.
+/// The inputs of other participants of a collaborative transaction hold none.
+pub fn is_internal_input(input: &Input, wallet: &WalletPolicy) -> bool {
+    input
+        .bip32_derivation
+        .values()
+        .any(|source| is_wallet_key_origin(wallet, source))
+        || input
+            .tap_key_origins
+            .values()
+            .any(|(_, source)| is_wallet_key_origin(wallet, source))
+}
+
+/// Returns true if the output holds the origin of a key of the wallet on the change branch,
+/// `/1/<address index>`, in its bip32 derivations or taproot key origins. Unlike the inputs,
+/// the device only considers the change outputs internal, and does not show them.
+pub fn is_internal_output(output: &Output, wallet: &WalletPolicy) -> bool {
+    let is_wallet_change = |source: &KeySource| {
+        is_wallet_key_origin(wallet, source)
+            && matches!(
+                source.1.as_ref(),
+                [
+                    ..,
+                    ChildNumber::Normal { index: 1 },
+                    ChildNumber::Normal { .. }
+                ]
+            )
+    };
+    output.bip32_derivation.values().any(is_wallet_change)
+        || output
+            .tap_key_origins
+            .values()
+            .any(|(_, source)| is_wallet_change(source))
+}
+
+/// Returns true if the key origin is the one of a key of the wallet derived at
+/// `/<change>/<address index>`.
+fn is_wallet_key_origin(wallet: &WalletPolicy, (fingerprint, path): &KeySource) -> bool {
+    let path: &[ChildNumber] = path.as_ref();
+    wallet.keys.iter().any(|key| match &key.source {
+        Some((key_fingerprint, key_path)) => {
+            let key_path: &[ChildNumber] = key_path.as_ref();
+            key_fingerprint == fingerprint
+                && path.len() == key_path.len() + 2
+                && path.starts_with(key_path)
+        }
+        None => false,
+    })
+}
+
+/// Field of a PSBT output map that does not agree with the output of the unsigned
+/// transaction, whose amount and script_pubkey are signed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OutputMismatch {
+    /// The PSBT has no output map for the output of the transaction, or an output map
+    /// without output in the transaction.
+    Missing,
+    /// The script_pubkey is not the P2SH of the redeem script.
+    RedeemScript,
+    /// Neither the script_pubkey nor the redeem script is the P2WSH of the witness script.
+    WitnessScript,
+    /// The script_pubkey is not the P2TR of the taproot internal key and tree.
+    TaprootKey,
+}
+
+/// Returns the field of the output map that does not agree with the script_pubkey of the
+/// output of the transaction, if any. A wallet recognizing its change output from these
+/// fields would otherwise show it as change while the transaction pays another script.
+pub fn output_mismatch(output: &Output, txout: &TxOut) -> Option<OutputMismatch> {
+    let script_pubkey = &txout.script_pubkey;
+    if let Some(redeem_script) = &output.redeem_script {
+        if *script_pubkey != Script::new_p2sh(&redeem_script.script_hash()) {
+            return Some(OutputMismatch::RedeemScript);
+        }
+    }
+    if let Some(witness_script) = &output.witness_script {
+        let p2wsh = Script::new_v0_p2wsh(&witness_script.wscript_hash());
+        if *script_pubkey != p2wsh && output.redeem_script.as_ref() != Some(&p2wsh) {
+            return Some(OutputMismatch::WitnessScript);
+        }
+    }
+    if let Some(internal_key) = output.tap_internal_key {
+        let secp = secp256k1::Secp256k1::verification_only();
+        let merkle_root = match &output.tap_tree {
+            Some(tree) => match tree.to_builder().finalize(&secp, internal_key) {
+                Ok(info) => info.merkle_root(),
+                Err(_) => return Some(OutputMismatch::TaprootKey),
+            },
+            None => None,
+        };
+        if *script_pubkey != Script::new_v1_p2tr(&secp, internal_key, merkle_root) {
+            return Some(OutputMismatch::TaprootKey);
+        }
+    }
+    None
+}
+
+/// Returns the output spent by the input at the given index,
+/// taken from its witness utxo or from its non-witness utxo.
+pub(crate) fn spent_utxo(psbt: &Psbt, input_index: usize) -> Option<TxOut> {
+    let input = psbt.inputs.get(input_index)?;
+    if let Some(txout) = &input.witness_utxo {
+        return Some(txout.clone());
+    }
+    let txin = psbt.unsigned_tx.input.get(input_index)?;
+    input
+        .non_witness_utxo
+        .as_ref()
+        .filter(|tx| tx.txid() == txin.previous_output.txid)
+        .and_then(|tx| tx.output.get(txin.previous_output.vout as usize))
+        .cloned()
+}
+
+/// Computes the signature hash of the input at the given index, as it is computed by the
+/// device to sign the input. Taproot inputs are hashed for a key path spend.
+pub fn signature_hash(psbt: &Psbt, input_index: usize) -> Result<[u8; 32], SighashError> {
+    let input = psbt
+        .inputs
+        .get(input_index)
+        .ok_or(SighashError::IndexOutOfRange)?;
+    let utxo = spent_utxo(psbt, input_index).ok_or(SighashError::MissingUtxo)?;
+    let mut cache = SighashCache::new(&psbt.unsigned_tx);
+
+    if utxo.script_pubkey.is_v1_p2tr() {
+        let prevouts = (0..psbt.inputs.len())
+            .map(|i| spent_utxo(psbt, i).ok_or(SighashError::MissingUtxo))
+            .collect::<Result<Vec<TxOut>, SighashError>>()?;
+        let sighash_type = input
+            .schnorr_hash_ty()
+            .map_err(|_| SighashError::NonStandardSighashType)?;
+        let hash = cache.taproot_key_spend_signature_hash(
+            input_index,
+            &Prevouts::All(&prevouts),
+            sighash_type,
+        )?;
+        return Ok(hash.into_inner());
+    }
+
+    let sighash_type = input
+        .ecdsa_hash_ty()
+        .map_err(|_| SighashError::NonStandardSighashType)?;
+    let script = match &input.redeem_script {
+        Some(redeem_script) if utxo.script_pubkey.is_p2sh() => redeem_script,
+        _ => &utxo.script_pubkey,
+    };
+
+    if script.is_witness_program() {
+        let script_code = if script.is_v0_p2wpkh() {
+            script
+                .p2wpkh_script_code()
+                .ok_or(SighashError::MissingScript)?
+        } else {
+            input
+                .witness_script
+                .clone()
+                .ok_or(SighashError::MissingScript)?
+        };
+        let hash =
+            cache.segwit_signature_hash(input_index, &script_code, utxo.value, sighash_type)?;
+        Ok(hash.into_inner())
+    } else {
+        let hash = cache.legacy_signature_hash(input_index, script, sighash_type.to_u32())?;
+        Ok(hash.into_inner())
+    }
+}
+
+/// Returns a copy of the PSBT where only the inputs at the given indexes can be signed:
+/// the key origins of the other inputs are removed, so that the device does not recognize
+/// its keys in them. The transaction and the spent outputs are unchanged, the signature
+/// hashes of the selected inputs still commit to the whole transaction.
+pub fn select_signing_inputs(psbt: &Psbt, inputs: &[usize]) -> Psbt {
+    let mut psbt = psbt.clone();
+    for (index, input) in psbt.inputs.iter_mut().enumerate() {
+        if !inputs.contains(&index) {
+            input.bip32_derivation.clear();
+            input.tap_key_origins.clear();
+        }
+    }
+    psbt
+}
+
+/// Splits the signing of the PSBT in parts signing at most `max_inputs` inputs each, to
+/// spread the signing of many inputs over several shorter `sign_psbt` sessions.
+/// Each part is a copy of the PSBT made with `select_signing_inputs` for consecutive inputs
+/// holding key origins; the inputs without key origins are not signed by any part.
+/// Returns no part if no input holds key origins. A `max_inputs` of zero does not split: the
+/// single part signs all the inputs holding key origins.
+///
+/// The parts keep the whole transaction and all the spent outputs: the signatures commit
+/// to the same transaction as the signatures of the whole PSBT, whatever their sighash
+/// type. The inputs are not removed from the parts, as a signature with `SIGHASH_ALL`, the
+/// default, or `SIGHASH_SINGLE` would then commit to another transaction; only
+/// `SIGHASH_ANYONECANPAY` signatures do not commit to the other inputs. The device still
+/// reads every input of each part, and the user confirms the transaction once per part.
+/// Splitting therefore does not get past the limit of `MAX_N_INPUTS_CAN_SIGN` inputs: every
+/// part of a larger transaction is rejected with `TooManyInputs` like the whole PSBT.
+/// The signatures of the parts are stored in the PSBT with `merge_signatures`.
+pub fn split_for_signing(psbt: &Psbt, max_inputs: usize) -> Vec<Psbt> {
+    let signable: Vec<usize> = psbt
+        .inputs
+        .iter()
+        .enumerate()
+        .filter(|(_, input)| {
+            !input.bip32_derivation.is_empty() || !input.tap_key_origins.is_empty()
+        })
+        .map(|(index, _)| index)
+        .collect();
+    if signable.is_empty() {
+        return Vec::new();
+    }
+    let max_inputs = if max_inputs == 0 {
+        signable.len()
+    } else {
+        max_inputs
+    };
+    signable
+        .chunks(max_inputs)
+        .map(|inputs| select_signing_inputs(psbt, inputs))
+        .collect()
+}
+
+/// Stores in a copy of the PSBT the signatures returned by the device for the parts of
+/// `split_for_signing`, their input indexes being the ones of the whole PSBT.
+pub fn merge_signatures(
+    psbt: &Psbt,
+    signatures: Vec<Vec<(usize, PartialSignature)>>,
+) -> Result<Psbt, MergeError> {
+    let mut psbt = psbt.clone();
+    for (index, signature) in signatures.into_iter().flatten() {
+        let input = psbt
+            .inputs
+            .get_mut(index)
+            .ok_or(MergeError::InputOutOfRange { input: index })?;
+        signature.apply(input);
+    }
+    Ok(psbt)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum MergeError {
+    /// The signature is for an input that is not in the PSBT.
+    InputOutOfRange { input: usize },
+}
+
+/// Returns true if the sighash type of the input at the given index, if any, is a standard
+/// sighash type for the spent output: a taproot sighash type for a taproot output,
+/// an ECDSA sighash type otherwise.
+/// Returns false if the spent output is unknown.
+pub fn has_standard_sighash_type(psbt: &Psbt, input_index: usize) -> bool {
+    let (input, utxo) = match (psbt.inputs.get(input_index), spent_utxo(psbt, input_index)) {
+        (Some(input), Some(utxo)) => (input, utxo),
+        _ => return false,
+    };
+    if utxo.script_pubkey.is_v1_p2tr() {
+        input.schnorr_hash_ty().is_ok()
+    } else {
+        input.ecdsa_hash_ty().is_ok()
+    }
+}
+
+#[derive(Debug)]
+pub enum SighashError {
+    IndexOutOfRange,
+    MissingUtxo,
+    MissingScript,
+    NonStandardSighashType,
+    Sighash(sighash::Error),
+}
+
+impl From<sighash::Error> for SighashError {
+    fn from(e: sighash::Error) -> SighashError {
+        SighashError::Sighash(e)
+    }
+}
+
+/// Amounts of a transaction and destinations of its outputs, for a wallet to show its own
+/// confirmation before the device prompts the user.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TxSummary {
+    /// Sum of the amounts spent by the inputs, in satoshis.
+    pub total_in: u64,
+    /// Sum of the amounts of the outputs, in satoshis.
+    pub total_out: u64,
+    /// Amount left to the miners, in satoshis.
+    pub fee: u64,
+    /// Address and amount of each output, the address being None for the scripts without
+    /// an address, like `OP_RETURN` or contract outputs.
+    pub outputs: Vec<(Option<String>, u64)>,
+}
+
+/// Summarizes the amounts of the transaction, the inputs being valued from their witness or
+/// non-witness utxo, already required for signing, and the addresses of the outputs being
+/// encoded for the Qtum network.
+pub fn summary(psbt: &Psbt, network: QtumNetwork) -> Result<TxSummary, SummaryError> {
+    let mut total_in: u64 = 0;
+    for input in 0..psbt.inputs.len() {
+        let utxo = spent_utxo(psbt, input).ok_or(SummaryError::MissingUtxo { input })?;
+        total_in = total_in
+            .checked_add(utxo.value)
+            .ok_or(SummaryError::InvalidAmounts)?;
+    }
+
+    let mut total_out: u64 = 0;
+    let mut outputs = Vec::with_capacity(psbt.unsigned_tx.output.len());
+    for txout in &psbt.unsigned_tx.output {
+        total_out = total_out
+            .checked_add(txout.value)
+            .ok_or(SummaryError::InvalidAmounts)?;
+        outputs.push((network.address(&txout.script_pubkey), txout.value));
+    }
+
+    Ok(TxSummary {
+        total_in,
+        total_out,
+        fee: total_in
+            .checked_sub(total_out)
+            .ok_or(SummaryError::InvalidAmounts)?,
+        outputs,
+    })
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SummaryError {
+    /// The input has neither a witness utxo nor a non-witness utxo matching its outpoint.
+    MissingUtxo { input: usize },
+    /// The outputs spend more than the inputs, or the amounts overflow.
+    InvalidAmounts,
+}
+
+/// Combines the PSBTs signed by the different cosigners of a transaction into a single PSBT
+/// holding all their partial signatures.
+/// Returns an error if the PSBTs do not spend the same transaction or if two of them hold
+/// different signatures of a same key for an input.
+pub fn combine(psbts: &[Psbt]) -> Result<Psbt, CombineError> {
+    let (first, others) = psbts.split_first().ok_or(CombineError::Empty)?;
+    let mut combined = first.clone();
+    for other in others {
+        combine_into(&mut combined, other)?;
+    }
+    Ok(combined)
+}
+
+/// Merges into the base the partial signatures, derivations and other fields of a PSBT of
+/// the same transaction signed elsewhere, for a coordinator collecting the signatures of the
+/// cosigners one at a time, like `combine`. The base is left unchanged on error.
+pub fn combine_into(base: &mut Psbt, other: &Psbt) -> Result<(), CombineError> {
+    if other.unsigned_tx != base.unsigned_tx || other.inputs.len() != base.inputs.len() {
+        return Err(CombineError::DifferentTransaction);
+    }
+    for (index, (input, other_input)) in base.inputs.iter().zip(other.inputs.iter()).enumerate() {
+        let conflicting_sig = other_input
+            .partial_sigs
+            .iter()
+            .any(|(key, sig)| matches!(input.partial_sigs.get(key), Some(s) if s != sig));
+        let conflicting_tap_script_sig = other_input
+            .tap_script_sigs
+            .iter()
+            .any(|(key, sig)| matches!(input.tap_script_sigs.get(key), Some(s) if s != sig));
+        let conflicting_tap_key_sig = matches!(
+            (&input.tap_key_sig, &other_input.tap_key_sig),
+            (Some(sig), Some(other_sig)) if sig != other_sig
+        );
+        if conflicting_sig || conflicting_tap_script_sig || conflicting_tap_key_sig {
+            return Err(CombineError::ConflictingSignature { input: index });
+        }
+    }
+    // Psbt::combine may fail after merging some of the fields.
+    let mut combined = base.clone();
+    combined
+        .combine(other.clone())
+        .map_err(CombineError::Psbt)?;
+    *base = combined;
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum CombineError {
+    /// No PSBT to combine.
+    Empty,
+    /// The PSBTs do not spend the same transaction.
+    DifferentTransaction,
+    /// Two PSBTs hold different signatures of the same key for the input.
+    ConflictingSignature {
+        input: usize,
+    },
+    Psbt(bitcoin::util::psbt::Error),
+}
+
+/// Finalizes the inputs of a signed PSBT, building their final script sig and witness
+/// once they hold enough signatures to satisfy their script.
+/// Returns the indexes of the inputs that could not be finalized, most likely because
+/// they are still missing signatures.
+#[cfg(feature = "miniscript")]
+pub fn finalize(psbt: &Psbt) -> Result<Psbt, FinalizeError> {
+    use miniscript::psbt::{Error as MsPsbtError, PsbtExt};
+
+    let secp = secp256k1::Secp256k1::verification_only();
+    let mut psbt = psbt.clone();
+    psbt.finalize_mut(&secp).map_err(|errors| {
+        let mut inputs: Vec<usize> = errors
+            .iter()
+            .filter_map(|e| match e {
+                MsPsbtError::InputError(_, index) => Some(*index),
+                _ => None,
+            })
+            .collect();
+        inputs.dedup();
+        FinalizeError::IncompleteInputs(inputs)
+    })?;
+    Ok(psbt)
+}
+
+/// Finalizes the inputs of a signed PSBT and extracts the network serialized transaction,
+/// ready to be broadcasted.
+/// Returns the indexes of the inputs that could not be finalized, most likely because
+/// they are still missing signatures.
+#[cfg(feature = "miniscript")]
+pub fn finalize_and_extract(psbt: &Psbt) -> Result<Vec<u8>, FinalizeError> {
+    use miniscript::psbt::PsbtExt;
+
+    let secp = secp256k1::Secp256k1::verification_only();
+    let tx = finalize(psbt)?
+        .extract(&secp)
+        .map_err(FinalizeError::Extract)?;
+    Ok(serialize(&tx))
+}
+
+#[cfg(feature = "miniscript")]
+#[derive(Debug)]
+pub enum FinalizeError {
+    /// Indexes of the inputs that could not be finalized.
+    IncompleteInputs(Vec<usize>),
+    Extract(miniscript::psbt::Error),
+}
+
+/// Maximum number of inputs of a transaction signed by the application since 2.0.4, the
+/// number of outputs not being limited; see `SigningLimits` for the older versions.
+pub const MAX_N_INPUTS_CAN_SIGN: usize = 512;
+
+/// Maximum weight of a standard Qtum transaction, larger transactions are not relayed.
+pub const MAX_STANDARD_TX_WEIGHT: usize = 400_000;
+
+/// Estimates the weight of the transaction once all its inputs are finalized.
+/// Finalized inputs count with their final script sig and witness, the other inputs are
+/// assumed to spend the wallet and count with the largest satisfaction of its descriptor.
+/// Returns None if the descriptor of the wallet cannot be parsed or satisfied.
+#[cfg(feature = "miniscript")]
+pub fn estimate_weight(psbt: &Psbt, wallet: &WalletPolicy) -> Option<usize> {
+    use core::str::FromStr;
+    use miniscript::{Descriptor, DescriptorPublicKey};
+
+    let descriptor =
+        Descriptor::<DescriptorPublicKey>::from_str(&wallet.get_descriptor(false).ok()?).ok()?;
+    let satisfaction_weight = descriptor.max_satisfaction_weight().ok()?;
+    let is_segwit = descriptor.desc_type().segwit_version().is_some();
+
+    let mut tx = psbt.unsigned_tx.clone();
+    let mut weight = 0;
+    let mut needs_witness = false;
+    for (txin, input) in tx.input.iter_mut().zip(&psbt.inputs) {
+        if input.final_script_sig.is_some() || input.final_script_witness.is_some() {
+            txin.script_sig = input.final_script_sig.clone().unwrap_or_default();
+            txin.witness = input.final_script_witness.clone().unwrap_or_default();
+        } else {
+            // The satisfaction weight includes the length of the script sig,
+            // already counted in the weight of the transaction.
+            weight += satisfaction_weight.saturating_sub(4);
+            needs_witness |= is_segwit;
+        }
+    }
+
+    weight += tx.weight();
+    // segwit marker and flag
+    if needs_witness && tx.input.iter().all(|txin| txin.witness.is_empty()) {
+        weight += 2;
+    }
+    Some(weight)
+}
+
+/// Checks that the transaction, once signed with the wallet, does not exceed the maximum
+/// weight of a standard Qtum transaction, to fail before asking the user to approve it.
+#[cfg(feature = "miniscript")]
+pub fn check_weight(psbt: &Psbt, wallet: &WalletPolicy) -> Result<(), WeightError> {
+    let weight = estimate_weight(psbt, wallet).ok_or(WeightError::UnknownWeight)?;
+    if weight > MAX_STANDARD_TX_WEIGHT {
+        return Err(WeightError::TooLarge {
+            weight,
+            max: MAX_STANDARD_TX_WEIGHT,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(feature = "miniscript")]
+#[derive(Debug, PartialEq, Eq)]
+pub enum WeightError {
+    /// The weight of the signed transaction cannot be estimated, see `estimate_weight`.
+    UnknownWeight,
+    /// The signed transaction would exceed the maximum weight of a standard transaction.
+    TooLarge { weight: usize, max: usize },
+}
+
 pub fn deserialize_pairs(pair: raw::Pair) -> (Vec<u8>, Vec<u8>) {
     (deserialize(&serialize(&pair.key)).unwrap(), pair.value)
 }
 
+/// Commitments to a PSBT and a wallet policy sent in the command of `sign_psbt`. The device
+/// then requests the maps and lists behind them from the client while signing.
+pub struct Commitments {
+    /// Id of the wallet policy, see `WalletPolicy::id`.
+    pub wallet_id: [u8; 32],
+    /// Merkleized map commitment of the global map, see `get_merkleized_map_commitment`.
+    pub global_mapping_commitment: Vec<u8>,
+    /// Merkleized map commitments of the input maps, in the order of the inputs.
+    pub input_commitments: Vec<Vec<u8>>,
+    /// Merkle root of the list of the input commitments.
+    pub input_commitments_root: [u8; 32],
+    /// Merkleized map commitments of the output maps, in the order of the outputs.
+    pub output_commitments: Vec<Vec<u8>>,
+    /// Merkle root of the list of the output commitments.
+    pub output_commitments_root: [u8; 32],
+    // The maps behind the commitments, served to the device while signing.
+    pub(crate) global_map: Vec<(Vec<u8>, Vec<u8>)>,
+    pub(crate) input_maps: Vec<Vec<(Vec<u8>, Vec<u8>)>>,
+    pub(crate) output_maps: Vec<Vec<(Vec<u8>, Vec<u8>)>>,
+}
+
+/// Computes the commitments to the PSBT of the PSBT v2 maps and the wallet policy, like
+/// `sign_psbt` does before sending the command, without the device. Fails like `sign_psbt`
+/// for a PSBT that the device cannot sign: an input missing its utxo or with a non-standard
+/// sighash type, or outputs that do not match the unsigned transaction.
+pub fn build_commitments(
+    psbt: &Psbt,
+    wallet: &WalletPolicy,
+) -> Result<Commitments, CommitmentsError> {
+    let global_map: Vec<(Vec<u8>, Vec<u8>)> = get_v2_global_pairs(psbt)
+        .into_iter()
+        .map(deserialize_pairs)
+        .collect();
+
+    let mut input_maps = Vec::with_capacity(psbt.inputs.len());
+    for (index, input) in psbt.inputs.iter().enumerate() {
+        let txin = psbt
+            .unsigned_tx
+            .input
+            .get(index)
+            .ok_or(CommitmentsError::InvalidPsbt)?;
+        let missing = if is_internal_input(input, wallet) {
+            missing_utxo(input, txin)
+        } else if input.witness_utxo.is_none() && input.non_witness_utxo.is_none() {
+            Some(MissingUtxo::Any)
+        } else {
+            None
+        };
+        if let Some(missing) = missing {
+            return Err(CommitmentsError::MissingUtxo {
+                input: index,
+                missing,
+            });
+        }
+        if !has_standard_sighash_type(psbt, index) {
+            return Err(CommitmentsError::NonStandardSighashType { input: index });
+        }
+        input_maps.push(
+            get_v2_input_pairs(input, txin)
+                .into_iter()
+                .map(deserialize_pairs)
+                .collect::<Vec<_>>(),
+        );
+    }
+
+    if psbt.outputs.len() != psbt.unsigned_tx.output.len() {
+        return Err(CommitmentsError::OutputMismatch {
+            output: psbt.outputs.len().min(psbt.unsigned_tx.output.len()),
+            mismatch: OutputMismatch::Missing,
+        });
+    }
+    let mut output_maps = Vec::with_capacity(psbt.outputs.len());
+    for (index, (output, txout)) in psbt
+        .outputs
+        .iter()
+        .zip(psbt.unsigned_tx.output.iter())
+        .enumerate()
+    {
+        if let Some(mismatch) = output_mismatch(output, txout) {
+            return Err(CommitmentsError::OutputMismatch {
+                output: index,
+                mismatch,
+            });
+        }
+        output_maps.push(
+            get_v2_output_pairs(output, txout)
+                .into_iter()
+                .map(deserialize_pairs)
+                .collect::<Vec<_>>(),
+        );
+    }
+
+    let input_commitments: Vec<Vec<u8>> = input_maps
+        .iter()
+        .map(|map| get_merkleized_map_commitment(map))
+        .collect();
+    let output_commitments: Vec<Vec<u8>> = output_maps
+        .iter()
+        .map(|map| get_merkleized_map_commitment(map))
+        .collect();
+    Ok(Commitments {
+        wallet_id: wallet.id(),
+        global_mapping_commitment: get_merkleized_map_commitment(&global_map),
+        input_commitments_root: *MerkleTree::from_elements(&input_commitments).root_hash(),
+        input_commitments,
+        output_commitments_root: *MerkleTree::from_elements(&output_commitments).root_hash(),
+        output_commitments,
+        global_map,
+        input_maps,
+        output_maps,
+    })
+}
+
+/// Reason why the commitments of a PSBT cannot be built, the device rejecting the PSBT.
+#[derive(Debug, PartialEq, Eq)]
+pub enum CommitmentsError {
+    /// The PSBT has more input maps than the transaction has inputs.
+    InvalidPsbt,
+    MissingUtxo {
+        input: usize,
+        missing: MissingUtxo,
+    },
+    /// The output map of the PSBT does not agree with the output of the unsigned transaction.
+    OutputMismatch {
+        output: usize,
+        mismatch: OutputMismatch,
+    },
+    /// The sighash type of the input is not a standard sighash type for the spent output.
+    NonStandardSighashType {
+        input: usize,
+    },
+}
+
+/// Smallest tag of the values yielded by the device while signing a PSBT that are not
+/// signatures. A signature starts with the index of its input as a varint, always below the
+/// tags; another value starts with its tag as a varint, followed by its data.
+pub const SIGN_PSBT_YIELD_TAG_MIN: u64 = 0xFFFF_FF00;
+
+/// A value yielded by the device while signing a PSBT.
+pub enum SignPsbtYield {
+    /// The signature of the input at the index.
+    Signature(usize, PartialSignature),
+    /// A notification of the device that is not a signature, like a warning, with its tag,
+    /// at least `SIGN_PSBT_YIELD_TAG_MIN`, and its data.
+    Notification { tag: u64, data: Vec<u8> },
+}
+
+impl SignPsbtYield {
+    /// Parses a value yielded by the device, None if it is neither a tagged notification nor
+    /// an input index followed by a signature.
+    pub fn from_slice(slice: &[u8]) -> Option<Self> {
+        let (first, i): (VarInt, usize) = deserialize_partial(slice).ok()?;
+        if first.0 >= SIGN_PSBT_YIELD_TAG_MIN {
+            return Some(Self::Notification {
+                tag: first.0,
+                data: slice[i..].to_vec(),
+            });
+        }
+        let input_index = usize::try_from(first.0).ok()?;
+        let signature = PartialSignature::from_slice(&slice[i..]).ok()?;
+        Some(Self::Signature(input_index, signature))
+    }
+}
+
+/// A signature yielded by the device, with the public key it was made with.
 pub enum PartialSignature {
     /// signature stored in pbst.partial_sigs
     Sig(PublicKey, EcdsaSig),
-    /// signature stored in pbst.tap_script_sigs
+    /// signature stored in pbst.tap_script_sigs,
+    /// or in pbst.tap_key_sig for a key path spend (no leaf hash).
     TapScriptSig(XOnlyPublicKey, Option<TapLeafHash>, SchnorrSig),
 }
 
@@ -412,7 +1217,7 @@ impl PartialSignature {
         } else if key_augment_len == 32 {
             let key = XOnlyPublicKey::from_slice(&slice[1..33])
                 .map_err(PartialSignatureError::XOnlyPubKey)?;
-            let sig = SchnorrSig::from_slice(&slice[65..])?;
+            let sig = SchnorrSig::from_slice(&slice[33..])?;
             Ok(Self::TapScriptSig(key, None, sig))
         } else {
             let key = PublicKey::from_slice(&slice[1..key_augment_len + 1])
@@ -421,6 +1226,74 @@ impl PartialSignature {
             Ok(Self::Sig(key, sig))
         }
     }
+
+    /// Returns the key and the signature of a segwit v0 or legacy input.
+    pub fn as_ecdsa(&self) -> Option<(PublicKey, EcdsaSig)> {
+        match self {
+            Self::Sig(key, sig) => Some((*key, *sig)),
+            Self::TapScriptSig(..) => None,
+        }
+    }
+
+    /// Returns the key, the signature and, for a script path spend, the leaf hash of a
+    /// taproot input.
+    pub fn as_schnorr(&self) -> Option<(XOnlyPublicKey, SchnorrSig, Option<TapLeafHash>)> {
+        match self {
+            Self::Sig(..) => None,
+            Self::TapScriptSig(key, tap_leaf_hash, sig) => Some((*key, *sig, *tap_leaf_hash)),
+        }
+    }
+
+    pub fn is_taproot(&self) -> bool {
+        matches!(self, Self::TapScriptSig(..))
+    }
+
+    /// Returns true if the signature is not an ECDSA signature whose R has its high bit set.
+    /// The device grinds its ECDSA signatures for a low R, which DER encodes in 32 bytes: the
+    /// signatures are at most 71 bytes with the sighash type. Schnorr signatures always have
+    /// the same size.
+    pub fn is_low_r(&self) -> bool {
+        match self {
+            Self::Sig(_, sig) => sig.sig.serialize_compact()[0] < 0x80,
+            Self::TapScriptSig(..) => true,
+        }
+    }
+
+    /// Returns true if the signature commits to the sighash type requested by the input,
+    /// `SIGHASH_ALL` for ECDSA and `SIGHASH_DEFAULT` for taproot if it requests none.
+    pub fn has_sighash_type_of(&self, input: &Input) -> bool {
+        match self {
+            Self::Sig(_, sig) => matches!(input.ecdsa_hash_ty(), Ok(ty) if ty == sig.hash_ty),
+            Self::TapScriptSig(_, _, sig) => {
+                matches!(input.schnorr_hash_ty(), Ok(ty) if ty == sig.hash_ty)
+            }
+        }
+    }
+
+    /// Returns true unless the signature is a taproot script path signature for a leaf that
+    /// the input does not list for its key in `tap_key_origins`.
+    pub fn matches_leaf_of(&self, input: &Input) -> bool {
+        match self {
+            Self::TapScriptSig(key, Some(leaf_hash), _) => matches!(
+                input.tap_key_origins.get(key),
+                Some((leaf_hashes, _)) if leaf_hashes.contains(leaf_hash)
+            ),
+            _ => true,
+        }
+    }
+
+    /// Stores the signature in the psbt input, keyed by the public key that made it.
+    pub fn apply(self, input: &mut Input) {
+        match self {
+            Self::Sig(key, sig) => {
+                input.partial_sigs.insert(key, sig);
+            }
+            Self::TapScriptSig(key, Some(tap_leaf_hash), sig) => {
+                input.tap_script_sigs.insert((key, tap_leaf_hash), sig);
+            }
+            Self::TapScriptSig(_, None, sig) => input.tap_key_sig = Some(sig),
+        }
+    }
 }
 
 pub enum PartialSignatureError {
@@ -443,3 +1316,626 @@ impl From<EcdsaSigError> for PartialSignatureError {
         PartialSignatureError::EcdsaSig(e)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::get_merkleized_map_commitment;
+    use bitcoin::{
+        hashes::hex::FromHex,
+        util::bip32::{DerivationPath, Fingerprint},
+        OutPoint, PackedLockTime, Script, Sequence, Transaction, Txid, Witness,
+    };
+
+    fn p2wpkh_psbt() -> Psbt {
+        let mut psbt = Psbt::from_unsigned_tx(Transaction {
+            version: 2,
+            lock_time: PackedLockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(Txid::all_zeros(), 0),
+                script_sig: Script::new(),
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                witness: Witness::default(),
+            }],
+            output: vec![TxOut {
+                value: 49_000,
+                script_pubkey: p2wpkh_script(),
+            }],
+        })
+        .unwrap();
+        psbt.inputs[0].witness_utxo = Some(TxOut {
+            value: 50_000,
+            script_pubkey: p2wpkh_script(),
+        });
+        psbt
+    }
+
+    fn p2wpkh_script() -> Script {
+        Script::from(Vec::from_hex("0014331e3bb02f5f3a6d7e2b0c3d7d2b1c2a3d4e5f60").unwrap())
+    }
+
+    #[test]
+    fn test_normalize_v0_psbt() {
+        let prev_tx = Transaction {
+            version: 2,
+            lock_time: PackedLockTime::ZERO,
+            input: vec![],
+            output: vec![
+                TxOut {
+                    value: 1000,
+                    script_pubkey: Script::new(),
+                },
+                TxOut {
+                    value: 50_000,
+                    script_pubkey: p2wpkh_script(),
+                },
+            ],
+        };
+        let unsigned_tx = Transaction {
+            version: 2,
+            lock_time: PackedLockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(prev_tx.txid(), 1),
+                script_sig: Script::new(),
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                witness: Witness::default(),
+            }],
+            output: vec![TxOut {
+                value: 49_000,
+                script_pubkey: p2wpkh_script(),
+            }],
+        };
+        let mut psbt = Psbt::from_unsigned_tx(unsigned_tx).unwrap();
+        psbt.inputs.clear();
+        psbt.outputs.clear();
+
+        normalize(&mut psbt);
+        assert_eq!(psbt.inputs.len(), 1);
+        assert_eq!(psbt.outputs.len(), 1);
+        assert!(psbt.inputs[0].witness_utxo.is_none());
+
+        psbt.inputs[0].non_witness_utxo = Some(prev_tx.clone());
+        normalize(&mut psbt);
+        assert_eq!(psbt.inputs[0].witness_utxo, Some(prev_tx.output[1].clone()));
+
+        for (input, txin) in psbt.inputs.iter().zip(psbt.unsigned_tx.input.iter()) {
+            let input_map: Vec<(Vec<u8>, Vec<u8>)> = get_v2_input_pairs(input, txin)
+                .into_iter()
+                .map(deserialize_pairs)
+                .collect();
+            assert!(input_map
+                .iter()
+                .any(|(key, _)| key == &vec![PSBT_IN_WITNESS_UTXO]));
+            assert!(!get_merkleized_map_commitment(&input_map).is_empty());
+        }
+        for (output, txout) in psbt.outputs.iter().zip(psbt.unsigned_tx.output.iter()) {
+            let output_map: Vec<(Vec<u8>, Vec<u8>)> = get_v2_output_pairs(output, txout)
+                .into_iter()
+                .map(deserialize_pairs)
+                .collect();
+            assert!(!get_merkleized_map_commitment(&output_map).is_empty());
+        }
+    }
+
+    #[test]
+    fn test_signature_hash() {
+        let mut psbt = p2wpkh_psbt();
+        let sighash = signature_hash(&psbt, 0).unwrap();
+        assert_eq!(signature_hash(&psbt, 0).unwrap(), sighash);
+        assert!(matches!(
+            signature_hash(&psbt, 1),
+            Err(SighashError::IndexOutOfRange)
+        ));
+
+        psbt.unsigned_tx.output[0].value = 40_000;
+        assert_ne!(signature_hash(&psbt, 0).unwrap(), sighash);
+
+        psbt.inputs[0].witness_utxo = None;
+        assert!(matches!(
+            signature_hash(&psbt, 0),
+            Err(SighashError::MissingUtxo)
+        ));
+    }
+
+    #[test]
+    fn test_summary() {
+        let mut psbt = p2wpkh_psbt();
+        psbt.unsigned_tx.output.push(TxOut {
+            value: 0,
+            script_pubkey: Script::new_op_return(b"hello"),
+        });
+        psbt.outputs.push(Output::default());
+
+        let tx = summary(&psbt, QtumNetwork::Testnet).unwrap();
+        assert_eq!(tx.total_in, 50_000);
+        assert_eq!(tx.total_out, 49_000);
+        assert_eq!(tx.fee, 1_000);
+        assert_eq!(
+            tx.outputs,
+            vec![
+                (
+                    Some("tq1qxv0rhvp0tuax6l3tps7h62cu9g75uhmqgn9e9w".to_string()),
+                    49_000
+                ),
+                (None, 0),
+            ]
+        );
+
+        psbt.unsigned_tx.output[0].value = 60_000;
+        assert_eq!(
+            summary(&psbt, QtumNetwork::Testnet),
+            Err(SummaryError::InvalidAmounts)
+        );
+        psbt.inputs[0].witness_utxo = None;
+        assert_eq!(
+            summary(&psbt, QtumNetwork::Testnet),
+            Err(SummaryError::MissingUtxo { input: 0 })
+        );
+    }
+
+    #[test]
+    fn test_output_mismatch() {
+        let psbt = p2wpkh_psbt();
+        let mut output = psbt.outputs[0].clone();
+        let txout = &psbt.unsigned_tx.output[0];
+        assert_eq!(output_mismatch(&output, txout), None);
+
+        // nested segwit output of the same key
+        let nested = TxOut {
+            value: txout.value,
+            script_pubkey: Script::new_p2sh(&p2wpkh_script().script_hash()),
+        };
+        output.redeem_script = Some(p2wpkh_script());
+        assert_eq!(output_mismatch(&output, &nested), None);
+        assert_eq!(
+            output_mismatch(&output, txout),
+            Some(OutputMismatch::RedeemScript)
+        );
+
+        let witness_script = Script::from(Vec::from_hex("51").unwrap());
+        let p2wsh = Script::new_v0_p2wsh(&witness_script.wscript_hash());
+        output.redeem_script = None;
+        output.witness_script = Some(witness_script);
+        assert_eq!(
+            output_mismatch(&output, txout),
+            Some(OutputMismatch::WitnessScript)
+        );
+        let p2wsh_out = TxOut {
+            value: txout.value,
+            script_pubkey: p2wsh.clone(),
+        };
+        assert_eq!(output_mismatch(&output, &p2wsh_out), None);
+        output.redeem_script = Some(p2wsh.clone());
+        let nested = TxOut {
+            value: txout.value,
+            script_pubkey: Script::new_p2sh(&p2wsh.script_hash()),
+        };
+        assert_eq!(output_mismatch(&output, &nested), None);
+
+        let secp = secp256k1::Secp256k1::new();
+        let internal_key = XOnlyPublicKey::from_keypair(
+            &secp256k1::KeyPair::from_seckey_slice(&secp, &[0x01; 32]).unwrap(),
+        )
+        .0;
+        let p2tr = TxOut {
+            value: txout.value,
+            script_pubkey: Script::new_v1_p2tr(&secp, internal_key, None),
+        };
+        let output = Output {
+            tap_internal_key: Some(internal_key),
+            ..Default::default()
+        };
+        assert_eq!(output_mismatch(&output, &p2tr), None);
+        assert_eq!(
+            output_mismatch(&output, txout),
+            Some(OutputMismatch::TaprootKey)
+        );
+    }
+
+    #[test]
+    fn test_missing_utxo() {
+        let mut psbt = p2wpkh_psbt();
+        let txin = psbt.unsigned_tx.input[0].clone();
+        assert_eq!(missing_utxo(&psbt.inputs[0], &txin), None);
+
+        let prev_tx = Transaction {
+            version: 2,
+            lock_time: PackedLockTime::ZERO,
+            input: vec![],
+            output: vec![psbt.inputs[0].witness_utxo.take().unwrap()],
+        };
+        assert_eq!(missing_utxo(&psbt.inputs[0], &txin), Some(MissingUtxo::Any));
+
+        psbt.inputs[0].non_witness_utxo = Some(prev_tx);
+        assert_eq!(
+            missing_utxo(&psbt.inputs[0], &txin),
+            Some(MissingUtxo::WitnessUtxo)
+        );
+
+        psbt.inputs[0].witness_utxo = Some(TxOut {
+            value: 50_000,
+            script_pubkey: Script::from(
+                Vec::from_hex("76a914331e3bb02f5f3a6d7e2b0c3d7d2b1c2a3d4e5f6088ac").unwrap(),
+            ),
+        });
+        psbt.inputs[0].non_witness_utxo = None;
+        assert_eq!(
+            missing_utxo(&psbt.inputs[0], &txin),
+            Some(MissingUtxo::NonWitnessUtxo)
+        );
+    }
+
+    #[cfg(feature = "miniscript")]
+    #[test]
+    fn test_finalize_and_extract() {
+        use bitcoin::{secp256k1::SecretKey, EcdsaSighashType};
+
+        let secp = secp256k1::Secp256k1::new();
+        let secret_key = SecretKey::from_slice(&[0x01; 32]).unwrap();
+        let public_key = PublicKey::new(secret_key.public_key(&secp));
+        let script_pubkey = Script::new_v0_p2wpkh(&public_key.wpubkey_hash().unwrap());
+
+        let mut psbt = p2wpkh_psbt();
+        psbt.inputs[0].witness_utxo = Some(TxOut {
+            value: 50_000,
+            script_pubkey,
+        });
+
+        assert!(matches!(
+            finalize_and_extract(&psbt),
+            Err(FinalizeError::IncompleteInputs(inputs)) if inputs == vec![0]
+        ));
+
+        let sighash = signature_hash(&psbt, 0).unwrap();
+        let sig = secp.sign_ecdsa(
+            &secp256k1::Message::from_slice(&sighash).unwrap(),
+            &secret_key,
+        );
+        psbt.inputs[0].partial_sigs.insert(
+            public_key,
+            EcdsaSig {
+                sig,
+                hash_ty: EcdsaSighashType::All,
+            },
+        );
+
+        let tx: Transaction = deserialize(&finalize_and_extract(&psbt).unwrap()).unwrap();
+        assert_eq!(tx.txid(), psbt.unsigned_tx.txid());
+        assert_eq!(tx.input[0].witness.len(), 2);
+    }
+
+    #[cfg(feature = "miniscript")]
+    #[test]
+    fn test_check_weight() {
+        use crate::wallet::{Version, WalletPubKey};
+        use core::str::FromStr;
+
+        let wallet = WalletPolicy::new(
+            "".to_string(),
+            Version::V2,
+            "wpkh(@0/**)".to_string(),
+            vec![WalletPubKey::from_str("[f5acc2fd/84'/1'/0']tpubDCtKfsNyRhULjZ9XMS4VKKtVcPdVDi8MKUbcSD9MJDyjRu1A2ND5MiipozyyspBT9bg8upEp7a8EAgFxNxXn1d7QkdbL52Ty5jiSLcxPt1P").unwrap()],
+        );
+
+        let mut psbt = p2wpkh_psbt();
+        // version, lock time, counts, outpoint, sequence, empty script sig and output
+        // count 4 times, then the marker, flag and the witness of the input.
+        let weight = estimate_weight(&psbt, &wallet).unwrap();
+        assert_eq!(weight, (4 + 4 + 1 + 1 + 36 + 4 + 1 + 31) * 4 + 2 + 108);
+        assert!(check_weight(&psbt, &wallet).is_ok());
+
+        let output = psbt.unsigned_tx.output[0].clone();
+        while psbt.unsigned_tx.output.len() < 4000 {
+            psbt.unsigned_tx.output.push(output.clone());
+            psbt.outputs.push(Output::default());
+        }
+        assert!(matches!(
+            check_weight(&psbt, &wallet),
+            Err(WeightError::TooLarge { weight, max })
+                if weight > max && max == MAX_STANDARD_TX_WEIGHT
+        ));
+    }
+
+    #[test]
+    fn test_is_contract_blind_sign() {
+        use crate::wallet::{Version, WalletPubKey};
+        use core::str::FromStr;
+
+        // OP_4 <gas limit> <gas price> <data> <contract address> OP_CALL
+        let call = |address: &str| {
+            Script::from(
+                Vec::from_hex(&format!("540390d00301280461e14c7814{}c2", address)).unwrap(),
+            )
+        };
+        assert!(is_contract_blind_sign(&call(
+            "c0a8ee4a8bc2ec1d6e4cc5fc0b4e55e0bc5ee8ba"
+        )));
+        assert!(!is_contract_blind_sign(&call(
+            "0000000000000000000000000000000000000086"
+        )));
+        assert!(!is_contract_blind_sign(&p2wpkh_script()));
+
+        let key = "[f5acc2fd/84'/1'/0']tpubDCtKfsNyRhULjZ9XMS4VKKtVcPdVDi8MKUbcSD9MJDyjRu1A2ND5MiipozyyspBT9bg8upEp7a8EAgFxNxXn1d7QkdbL52Ty5jiSLcxPt1P";
+        let wallet = WalletPolicy::new(
+            "".to_string(),
+            Version::V2,
+            "wpkh(@0/**)".to_string(),
+            vec![WalletPubKey::from_str(key).unwrap()],
+        );
+        let mut psbt = p2wpkh_psbt();
+        assert!(!requires_blind_signing(&psbt, &wallet));
+        psbt.unsigned_tx.output[0].script_pubkey = call("c0a8ee4a8bc2ec1d6e4cc5fc0b4e55e0bc5ee8ba");
+        assert!(requires_blind_signing(&psbt, &wallet));
+
+        // only the outputs on the change branch of the wallet are internal
+        let origin = |path: &str| {
+            (
+                Fingerprint::from_hex("f5acc2fd").unwrap(),
+                DerivationPath::from_str(path).unwrap(),
+            )
+        };
+        let pubkey = secp256k1::PublicKey::from_secret_key(
+            &secp256k1::Secp256k1::signing_only(),
+            &secp256k1::SecretKey::from_slice(&[0x01; 32]).unwrap(),
+        );
+        psbt.outputs[0]
+            .bip32_derivation
+            .insert(pubkey, origin("m/84'/1'/0'/0/3"));
+        assert!(!is_internal_output(&psbt.outputs[0], &wallet));
+        assert!(requires_blind_signing(&psbt, &wallet));
+        psbt.outputs[0]
+            .bip32_derivation
+            .insert(pubkey, origin("m/84'/1'/0'/1/3"));
+        assert!(is_internal_output(&psbt.outputs[0], &wallet));
+        assert!(!requires_blind_signing(&psbt, &wallet));
+    }
+
+    #[cfg(feature = "miniscript")]
+    #[test]
+    fn test_combine_and_finalize_multisig() {
+        use bitcoin::{blockdata::opcodes::all::OP_CHECKMULTISIG, secp256k1::SecretKey};
+
+        let secp = secp256k1::Secp256k1::new();
+        let secret_keys = [
+            SecretKey::from_slice(&[0x01; 32]).unwrap(),
+            SecretKey::from_slice(&[0x02; 32]).unwrap(),
+        ];
+        let public_keys: Vec<PublicKey> = secret_keys
+            .iter()
+            .map(|sk| PublicKey::new(sk.public_key(&secp)))
+            .collect();
+        let witness_script = bitcoin::blockdata::script::Builder::new()
+            .push_int(2)
+            .push_key(&public_keys[0])
+            .push_key(&public_keys[1])
+            .push_int(2)
+            .push_opcode(OP_CHECKMULTISIG)
+            .into_script();
+
+        let mut psbt = p2wpkh_psbt();
+        psbt.inputs[0].witness_utxo = Some(TxOut {
+            value: 50_000,
+            script_pubkey: Script::new_v0_p2wsh(&witness_script.wscript_hash()),
+        });
+        psbt.inputs[0].witness_script = Some(witness_script);
+
+        let sighash = secp256k1::Message::from_slice(&signature_hash(&psbt, 0).unwrap()).unwrap();
+        let signed: Vec<Psbt> = secret_keys
+            .iter()
+            .zip(public_keys.iter())
+            .map(|(sk, pk)| {
+                let mut signed = psbt.clone();
+                signed.inputs[0]
+                    .partial_sigs
+                    .insert(*pk, EcdsaSig::sighash_all(secp.sign_ecdsa(&sighash, sk)));
+                signed
+            })
+            .collect();
+
+        assert!(matches!(
+            finalize(&signed[0]),
+            Err(FinalizeError::IncompleteInputs(inputs)) if inputs == vec![0]
+        ));
+
+        let combined = combine(&signed).unwrap();
+        assert_eq!(combined.inputs[0].partial_sigs.len(), 2);
+
+        // A coordinator merging the signature of each cosigner as it comes.
+        let mut collected = psbt.clone();
+        combine_into(&mut collected, &signed[0]).unwrap();
+        assert_eq!(collected.inputs[0].partial_sigs.len(), 1);
+        combine_into(&mut collected, &signed[1]).unwrap();
+        assert_eq!(collected, combined);
+        let finalized = finalize(&combined).unwrap();
+        assert_eq!(
+            finalized.inputs[0]
+                .final_script_witness
+                .as_ref()
+                .unwrap()
+                .len(),
+            4
+        );
+
+        let mut conflicting = signed[0].clone();
+        conflicting.inputs[0].partial_sigs.insert(
+            public_keys[0],
+            EcdsaSig::sighash_all(secp.sign_ecdsa(
+                &secp256k1::Message::from_slice(&[0x03; 32]).unwrap(),
+                &secret_keys[0],
+            )),
+        );
+        assert!(matches!(
+            combine(&[signed[0].clone(), conflicting]),
+            Err(CombineError::ConflictingSignature { input: 0 })
+        ));
+
+        let mut other_tx = signed[1].clone();
+        other_tx.unsigned_tx.lock_time = PackedLockTime(1);
+        assert!(matches!(
+            combine(&[signed[0].clone(), other_tx.clone()]),
+            Err(CombineError::DifferentTransaction)
+        ));
+        assert!(matches!(
+            combine_into(&mut collected, &other_tx),
+            Err(CombineError::DifferentTransaction)
+        ));
+        assert_eq!(collected, combined);
+        assert!(matches!(combine(&[]), Err(CombineError::Empty)));
+    }
+
+    #[test]
+    fn test_partial_signature_from_slice() {
+        let key = "6b16e8c1f979fa4cc0f05b6a300affff941459b6f20de77de55b0160ef8e4cac";
+        let sig = "43493158062db6905dea9ba3ae6c14e1e155ba47aa1cfb35282052ac4dbc1c6718cda5c911a11599a869557ab34242cb0a227836e98976061530ca4de49eed9e01";
+
+        // Taproot key path signature: <32> <x-only pubkey> <signature>
+        let slice = Vec::from_hex(&format!("20{}{}", key, sig)).unwrap();
+        match PartialSignature::from_slice(&slice) {
+            Ok(PartialSignature::TapScriptSig(k, None, s)) => {
+                assert_eq!(k.serialize().to_vec(), Vec::from_hex(key).unwrap());
+                assert_eq!(s.to_vec(), Vec::from_hex(sig).unwrap());
+            }
+            _ => panic!("expected a key path signature"),
+        }
+    }
+
+    #[test]
+    fn test_partial_signature_apply() {
+        let key = "6b16e8c1f979fa4cc0f05b6a300affff941459b6f20de77de55b0160ef8e4cac";
+        let leaf_hash = "e9e11c5b6e8e8e8cd2bc1b69aa1bf1d8c7c4f2c0b7e1b4db5e1bbf7b7d4ab1c3";
+        let sig = "43493158062db6905dea9ba3ae6c14e1e155ba47aa1cfb35282052ac4dbc1c6718cda5c911a11599a869557ab34242cb0a227836e98976061530ca4de49eed9e01";
+
+        let mut input = Input::default();
+        PartialSignature::from_slice(&Vec::from_hex(&format!("20{}{}", key, sig)).unwrap())
+            .unwrap_or_else(|_| panic!("invalid key path signature"))
+            .apply(&mut input);
+        assert_eq!(
+            input.tap_key_sig.unwrap().to_vec(),
+            Vec::from_hex(sig).unwrap()
+        );
+        assert!(input.tap_script_sigs.is_empty());
+
+        PartialSignature::from_slice(
+            &Vec::from_hex(&format!("40{}{}{}", key, leaf_hash, sig)).unwrap(),
+        )
+        .unwrap_or_else(|_| panic!("invalid script path signature"))
+        .apply(&mut input);
+        let ((k, h), s) = input.tap_script_sigs.iter().next().unwrap();
+        assert_eq!(k.serialize().to_vec(), Vec::from_hex(key).unwrap());
+        assert_eq!(h.to_vec(), Vec::from_hex(leaf_hash).unwrap());
+        assert_eq!(s.to_vec(), Vec::from_hex(sig).unwrap());
+    }
+
+    #[test]
+    fn test_partial_signature_accessors() {
+        let secp = secp256k1::Secp256k1::new();
+        let secret = secp256k1::SecretKey::from_slice(&[0x01; 32]).unwrap();
+        let key = PublicKey::new(secp256k1::PublicKey::from_secret_key(&secp, &secret));
+        let sig = EcdsaSig::sighash_all(secp.sign_ecdsa(
+            &secp256k1::Message::from_slice(&[0x02; 32]).unwrap(),
+            &secret,
+        ));
+        let mut payload = vec![33];
+        payload.extend(key.to_bytes());
+        payload.extend(sig.to_vec());
+
+        let signature = PartialSignature::from_slice(&payload)
+            .unwrap_or_else(|_| panic!("invalid segwit v0 signature"));
+        assert!(!signature.is_taproot());
+        assert!(signature.as_schnorr().is_none());
+        assert_eq!(signature.as_ecdsa(), Some((key, sig)));
+
+        let key = "6b16e8c1f979fa4cc0f05b6a300affff941459b6f20de77de55b0160ef8e4cac";
+        let leaf_hash = "e9e11c5b6e8e8e8cd2bc1b69aa1bf1d8c7c4f2c0b7e1b4db5e1bbf7b7d4ab1c3";
+        let sig = "43493158062db6905dea9ba3ae6c14e1e155ba47aa1cfb35282052ac4dbc1c6718cda5c911a11599a869557ab34242cb0a227836e98976061530ca4de49eed9e01";
+
+        let signature = PartialSignature::from_slice(
+            &Vec::from_hex(&format!("40{}{}{}", key, leaf_hash, sig)).unwrap(),
+        )
+        .unwrap_or_else(|_| panic!("invalid taproot signature"));
+        assert!(signature.is_taproot());
+        assert!(signature.as_ecdsa().is_none());
+        let (k, s, h) = signature.as_schnorr().unwrap();
+        assert_eq!(k.serialize().to_vec(), Vec::from_hex(key).unwrap());
+        assert_eq!(s.to_vec(), Vec::from_hex(sig).unwrap());
+        assert_eq!(h.unwrap().to_vec(), Vec::from_hex(leaf_hash).unwrap());
+
+        let signature =
+            PartialSignature::from_slice(&Vec::from_hex(&format!("20{}{}", key, sig)).unwrap())
+                .unwrap_or_else(|_| panic!("invalid taproot signature"));
+        assert!(matches!(signature.as_schnorr(), Some((_, _, None))));
+    }
+
+    #[test]
+    fn test_partial_signature_is_low_r() {
+        let secp = secp256k1::Secp256k1::new();
+        let secret = secp256k1::SecretKey::from_slice(&[0x01; 32]).unwrap();
+        let key = PublicKey::new(secp256k1::PublicKey::from_secret_key(&secp, &secret));
+        let signature = |sig: secp256k1::ecdsa::Signature| {
+            let mut payload = vec![33];
+            payload.extend(key.to_bytes());
+            payload.extend(EcdsaSig::sighash_all(sig).to_vec());
+            (
+                payload.len() - 34,
+                PartialSignature::from_slice(&payload)
+                    .unwrap_or_else(|_| panic!("invalid segwit v0 signature")),
+            )
+        };
+
+        // Grinded like the device does: R fits in 32 bytes in DER.
+        let sig = secp.sign_ecdsa_low_r(
+            &secp256k1::Message::from_slice(&[0x02; 32]).unwrap(),
+            &secret,
+        );
+        let (len, low_r) = signature(sig);
+        assert!(low_r.is_low_r());
+        assert!(len <= 71);
+
+        // Same S, with the high bit of R set: R takes 33 bytes in DER.
+        let mut compact = sig.serialize_compact();
+        compact[0] |= 0x80;
+        let (len, high_r) = signature(secp256k1::ecdsa::Signature::from_compact(&compact).unwrap());
+        assert!(!high_r.is_low_r());
+        assert_eq!(len, 72);
+
+        let key = "6b16e8c1f979fa4cc0f05b6a300affff941459b6f20de77de55b0160ef8e4cac";
+        let sig = "43493158062db6905dea9ba3ae6c14e1e155ba47aa1cfb35282052ac4dbc1c6718cda5c911a11599a869557ab34242cb0a227836e98976061530ca4de49eed9e01";
+        let taproot =
+            PartialSignature::from_slice(&Vec::from_hex(&format!("20{}{}", key, sig)).unwrap())
+                .unwrap_or_else(|_| panic!("invalid taproot signature"));
+        assert!(taproot.is_low_r());
+    }
+
+    #[test]
+    fn test_partial_signature_matches_leaf_of() {
+        let key = "6b16e8c1f979fa4cc0f05b6a300affff941459b6f20de77de55b0160ef8e4cac";
+        let leaf_hash = "e9e11c5b6e8e8e8cd2bc1b69aa1bf1d8c7c4f2c0b7e1b4db5e1bbf7b7d4ab1c3";
+        let sig = "43493158062db6905dea9ba3ae6c14e1e155ba47aa1cfb35282052ac4dbc1c6718cda5c911a11599a869557ab34242cb0a227836e98976061530ca4de49eed9e01";
+        let script_path = PartialSignature::from_slice(
+            &Vec::from_hex(&format!("40{}{}{}", key, leaf_hash, sig)).unwrap(),
+        )
+        .unwrap_or_else(|_| panic!("invalid taproot signature"));
+        let key_path =
+            PartialSignature::from_slice(&Vec::from_hex(&format!("20{}{}", key, sig)).unwrap())
+                .unwrap_or_else(|_| panic!("invalid taproot signature"));
+        let (xonly, _, leaf_hash) = script_path.as_schnorr().unwrap();
+        let origin = (Fingerprint::default(), DerivationPath::master());
+
+        let mut input = Input::default();
+        assert!(key_path.matches_leaf_of(&input));
+        assert!(!script_path.matches_leaf_of(&input));
+
+        // The key is in another leaf.
+        input.tap_key_origins.insert(
+            xonly,
+            (vec![TapLeafHash::from_inner([0x01; 32])], origin.clone()),
+        );
+        assert!(!script_path.matches_leaf_of(&input));
+
+        input
+            .tap_key_origins
+            .insert(xonly, (vec![leaf_hash.unwrap()], origin));
+        assert!(script_path.matches_leaf_of(&input));
+        assert!(key_path.matches_leaf_of(&input));
+    }
+}
@@ -0,0 +1,194 @@
+//! Recording of the APDUs exchanged with a device and their replay, to test a wallet
+//! against the exchanges of a real device without the device.
+//!
+//! A recording is a sequence of frames, each being the length of its bytes on four bytes
+//! big endian followed by the bytes. The frames alternate between a command, as encoded by
+//! `APDUCommand::encode`, and the response of the device to it, its data followed by the
+//! status word.
+
+use core::fmt::Debug;
+use std::io::{self, Read, Write};
+use std::sync::Mutex;
+
+#[cfg(feature = "async")]
+use async_trait::async_trait;
+
+use crate::apdu::{decode_response, APDUCommand, ApduLogger, Direction, StatusWord};
+
+#[derive(Debug)]
+pub enum ReplayError {
+    Io(io::Error),
+    /// The recording ends in the middle of a frame, or after a command without response.
+    Truncated,
+    /// The command at the index differs from the command recorded.
+    UnexpectedCommand {
+        index: usize,
+        expected: Vec<u8>,
+        command: Vec<u8>,
+    },
+    /// All the recorded exchanges were replayed.
+    Exhausted,
+    /// The recorded response at the index is shorter than a status word.
+    InvalidResponse(usize),
+}
+
+impl From<io::Error> for ReplayError {
+    fn from(e: io::Error) -> Self {
+        ReplayError::Io(e)
+    }
+}
+
+/// Writes a frame of the recording format.
+pub fn write_frame<W: Write>(writer: &mut W, bytes: &[u8]) -> io::Result<()> {
+    writer.write_all(&(bytes.len() as u32).to_be_bytes())?;
+    writer.write_all(bytes)
+}
+
+/// Returns a logger writing the exchanges of the client to the writer in the recording
+/// format, to be set with `BitcoinClient::set_apdu_logger`. The logger cannot report the
+/// errors of the writer: the first one stops the recording, which is then truncated.
+pub fn apdu_recorder<W: Write + Send + 'static>(writer: W) -> ApduLogger {
+    let writer = Mutex::new(Some(writer));
+    Box::new(move |_: Direction, apdu: &[u8]| {
+        let mut writer = writer.lock().expect("recorder not poisoned");
+        if let Some(w) = writer.as_mut() {
+            if write_frame(w, apdu).and_then(|_| w.flush()).is_err() {
+                *writer = None;
+            }
+        }
+    })
+}
+
+/// Transport answering the commands with the responses of a recording, in order. The
+/// commands must be the ones recorded: the exchange fails with
+/// `ReplayError::UnexpectedCommand` on the first command that differs.
+pub struct ReplayTransport {
+    exchanges: Vec<(Vec<u8>, Vec<u8>)>,
+    next: Mutex<usize>,
+}
+
+impl ReplayTransport {
+    /// Creates a transport replaying the pairs of command and response.
+    pub fn new(exchanges: Vec<(Vec<u8>, Vec<u8>)>) -> Self {
+        Self {
+            exchanges,
+            next: Mutex::new(0),
+        }
+    }
+
+    /// Reads the recording until the end of the reader.
+    pub fn from_reader<R: Read>(mut reader: R) -> Result<Self, ReplayError> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        let mut frames = Vec::new();
+        let mut rest = bytes.as_slice();
+        while !rest.is_empty() {
+            if rest.len() < 4 {
+                return Err(ReplayError::Truncated);
+            }
+            let len = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+            if rest.len() - 4 < len {
+                return Err(ReplayError::Truncated);
+            }
+            frames.push(rest[4..4 + len].to_vec());
+            rest = &rest[4 + len..];
+        }
+        if frames.len() % 2 != 0 {
+            return Err(ReplayError::Truncated);
+        }
+        let mut frames = frames.into_iter();
+        let mut exchanges = Vec::new();
+        while let (Some(command), Some(response)) = (frames.next(), frames.next()) {
+            exchanges.push((command, response));
+        }
+        Ok(Self::new(exchanges))
+    }
+
+    /// Returns the number of recorded exchanges not replayed yet, zero once the client
+    /// sent every command of the recording.
+    pub fn remaining(&self) -> usize {
+        self.exchanges.len() - *self.next.lock().expect("replay not poisoned")
+    }
+
+    fn replay(&self, command: &APDUCommand) -> Result<(StatusWord, Vec<u8>), ReplayError> {
+        let mut next = self.next.lock().expect("replay not poisoned");
+        let index = *next;
+        let (expected, response) = self.exchanges.get(index).ok_or(ReplayError::Exhausted)?;
+        let command = command.encode();
+        if command != *expected {
+            return Err(ReplayError::UnexpectedCommand {
+                index,
+                expected: expected.clone(),
+                command,
+            });
+        }
+        *next += 1;
+        decode_response(response).ok_or(ReplayError::InvalidResponse(index))
+    }
+}
+
+impl crate::client::Transport for ReplayTransport {
+    type Error = ReplayError;
+
+    fn exchange(&self, command: &APDUCommand) -> Result<(StatusWord, Vec<u8>), Self::Error> {
+        self.replay(command)
+    }
+}
+
+#[cfg(feature = "async")]
+#[async_trait]
+impl crate::async_client::Transport for ReplayTransport {
+    type Error = ReplayError;
+
+    async fn exchange(&self, command: &APDUCommand) -> Result<(StatusWord, Vec<u8>), Self::Error> {
+        self.replay(command)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Transport;
+
+    #[test]
+    fn test_replay_transport() {
+        let command = APDUCommand {
+            cla: 0xe1,
+            ins: 0x01,
+            p1: 0x00,
+            p2: 0x00,
+            data: vec![0x01, 0x02],
+        };
+        let mut bytes = Vec::new();
+        write_frame(&mut bytes, &command.encode()).unwrap();
+        write_frame(&mut bytes, &[0xaa, 0x90, 0x00]).unwrap();
+        assert_eq!(bytes[..9], [0, 0, 0, 7, 0xe1, 0x01, 0x00, 0x00, 0x02]);
+
+        let replay = ReplayTransport::from_reader(bytes.as_slice()).unwrap();
+        assert_eq!(replay.remaining(), 1);
+        let other = APDUCommand {
+            ins: 0x02,
+            ..command.clone()
+        };
+        assert!(matches!(
+            replay.exchange(&other),
+            Err(ReplayError::UnexpectedCommand { index: 0, .. })
+        ));
+        assert_eq!(
+            replay.exchange(&command).unwrap(),
+            (StatusWord::OK, vec![0xaa])
+        );
+        assert_eq!(replay.remaining(), 0);
+        assert!(matches!(
+            replay.exchange(&command),
+            Err(ReplayError::Exhausted)
+        ));
+
+        for len in [3, bytes.len() - 1, 12] {
+            assert!(matches!(
+                ReplayTransport::from_reader(&bytes[..len]),
+                Err(ReplayError::Truncated)
+            ));
+        }
+    }
+}
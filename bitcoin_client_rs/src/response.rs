@@ -0,0 +1,130 @@
+//! Parsing of the responses of the device to the commands, separate from the exchanges with
+//! the device so that they can be tested, and fuzzed, on arbitrary bytes.
+//!
+//! The parsers never panic: they return None for a malformed response, which the clients
+//! report as `BitcoinClientError::UnexpectedResult`. The values yielded while signing a PSBT
+//! are parsed by `psbt::SignPsbtYield::from_slice`.
+
+use bitcoin::{consensus::encode::deserialize_partial, secp256k1::ecdsa, util::bip32::Fingerprint};
+
+/// Length of the extended pubkeys returned by the device, encoded in base58.
+pub const XPUB_BASE58_LENGTH: usize = 111;
+
+/// Parses the response to `get_version`: the format 0x01, then the name of the app, its
+/// version and its state flags, each prefixed by its length.
+pub fn parse_version_response(data: &[u8]) -> Option<(String, String, Vec<u8>)> {
+    if data.first() != Some(&0x01) {
+        return None;
+    }
+    // deserialize_partial consumes at most the given bytes: the offsets below stay in the
+    // data, and a truncated field is reported as None.
+    let (name, i): (String, usize) = deserialize_partial(&data[1..]).ok()?;
+    let (version, j): (String, usize) = deserialize_partial(&data[1 + i..]).ok()?;
+    let (flags, k): (Vec<u8>, usize) = deserialize_partial(&data[1 + i + j..]).ok()?;
+    if 1 + i + j + k != data.len() {
+        return None;
+    }
+    Some((name, version, flags))
+}
+
+/// Parses the response to `get_master_fingerprint`, the fingerprint on four bytes.
+pub fn parse_master_fingerprint_response(data: &[u8]) -> Option<Fingerprint> {
+    if data.len() != 4 {
+        return None;
+    }
+    Some(Fingerprint::from(data))
+}
+
+/// Parses the response to `get_extended_pubkey` into the base58 encoding of the key, left
+/// to be decoded by the caller. A longer response is an xpub followed by other bytes, and a
+/// base58 xpub is ASCII.
+pub fn parse_extended_pubkey_response(data: &[u8]) -> Option<&str> {
+    if data.len() > XPUB_BASE58_LENGTH || !data.is_ascii() {
+        return None;
+    }
+    core::str::from_utf8(data).ok()
+}
+
+/// Parses the response to `get_wallet_address` into the address, left to be decoded by the
+/// caller for its network. An address is ASCII.
+pub fn parse_address_response(data: &[u8]) -> Option<&str> {
+    if !data.is_ascii() {
+        return None;
+    }
+    core::str::from_utf8(data).ok()
+}
+
+/// Parses the response to `sign_message`: the BIP137 header of a compressed key, in
+/// 31..=42, followed by the compact signature.
+pub fn parse_sign_message_response(data: &[u8]) -> Option<(u8, ecdsa::Signature)> {
+    match data.split_first() {
+        Some((header @ 31..=42, sig)) if sig.len() == 64 => ecdsa::Signature::from_compact(sig)
+            .ok()
+            .map(|sig| (*header, sig)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::psbt::SignPsbtYield;
+    use bitcoin::hashes::hex::FromHex;
+
+    // Runs every parser on the data.
+    fn parse_all(data: &[u8]) {
+        parse_version_response(data);
+        parse_master_fingerprint_response(data);
+        parse_extended_pubkey_response(data);
+        parse_address_response(data);
+        parse_sign_message_response(data);
+        SignPsbtYield::from_slice(data);
+    }
+
+    #[test]
+    fn test_parse_version_response() {
+        let data = Vec::from_hex("010c426974636f696e205465737405322e312e300100").unwrap();
+        assert_eq!(
+            parse_version_response(&data),
+            Some(("Bitcoin Test".to_string(), "2.1.0".to_string(), vec![0x00]))
+        );
+        for len in 0..data.len() {
+            assert_eq!(parse_version_response(&data[..len]), None);
+        }
+        let mut trailing = data.clone();
+        trailing.push(0x00);
+        assert_eq!(parse_version_response(&trailing), None);
+        // a length larger than the data
+        assert_eq!(parse_version_response(&[0x01, 0xff, 0xff, 0xff]), None);
+    }
+
+    #[test]
+    fn test_parse_arbitrary_bytes() {
+        let valid = [
+            "010c426974636f696e205465737405322e312e300100",
+            "f5acc2fd",
+            // shaped like an ECDSA signature yielded by sign_psbt for input 0
+            "00210279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f817983044022011111111111111111111111111111111111111111111111111111111111111110220222222222222222222222222222222222222222222222222222222222222222201",
+            // shaped like a taproot script path signature yielded for input 1
+            "01400000000000000000000000000000000000000000000000000000000000000000333333333333333333333333333333333333333333333333333333333333333344444444444444444444444444444444444444444444444444444444444444444444444444444444444444444444444444444444444444444444444444444444",
+        ];
+        for hex in valid {
+            let data = Vec::from_hex(hex).unwrap();
+            for len in 0..=data.len() {
+                parse_all(&data[..len]);
+            }
+            for i in 0..data.len() {
+                for byte in [0x00, 0x20, 0x40, 0x7f, 0x80, 0xfd, 0xfe, 0xff] {
+                    let mut mutated = data.clone();
+                    mutated[i] = byte;
+                    parse_all(&mutated);
+                }
+            }
+        }
+        for len in 0..=200 {
+            parse_all(&vec![0xff; len]);
+            parse_all(&vec![0x40; len]);
+            parse_all(&vec![0x20; len]);
+        }
+    }
+}
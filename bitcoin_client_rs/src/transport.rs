@@ -0,0 +1,1007 @@
+//! Simulator of the Qtum application.
+//!
+//! `SimTransport` implements the device side of the protocol in Rust: it parses the APDUs
+//! sent by the client, runs the interpreter protocol from the device side to fetch the data
+//! the client committed to, and answers like the application would. Keys are derived from a
+//! known test seed and signatures are deterministic, so that the client can be tested
+//! in-process, without a device or Speculos.
+//!
+//! The simulator models the rules of the application that a client can run into: wallet
+//! policy registration and authorization, internal inputs, required utxos, blind signing of
+//! contract outputs and approval by the user. It does not check the amounts of the
+//! transactions it signs, and only identifies their change outputs for blind signing.
+use core::{convert::Infallible, str::FromStr};
+use std::sync::Mutex;
+
+#[cfg(feature = "async")]
+use async_trait::async_trait;
+
+use bitcoin::{
+    blockdata::transaction::{OutPoint, Transaction, TxIn, TxOut},
+    consensus::encode::{self, deserialize, deserialize_partial, VarInt},
+    hashes::{hmac, sha256, sha256d, sha512, Hash, HashEngine},
+    secp256k1::{All, KeyPair, Message, Secp256k1},
+    util::{
+        bip32::{ChildNumber, DerivationPath, ExtendedPrivKey, ExtendedPubKey, Fingerprint},
+        ecdsa::EcdsaSig,
+        psbt::{Output, Psbt},
+        schnorr::{SchnorrSig, TapTweak},
+        sighash::{Prevouts, SighashCache},
+    },
+    Network, PackedLockTime, Script, Sequence, Txid, Witness,
+};
+use miniscript::{Descriptor, DescriptorPublicKey};
+
+use crate::{
+    apdu::{
+        APDUCommand, BitcoinCommandCode, Cla, ClientCommandCode, FrameworkCommandCode, StatusWord,
+    },
+    bip32::QtumNetwork,
+    client::Transport,
+    merkle::root_from_proof,
+    message::QTUM_SIGNED_MSG_PREFIX,
+    psbt::{
+        is_contract_blind_sign, missing_utxo, signature_hash, spent_utxo, MAX_N_INPUTS_CAN_SIGN,
+    },
+    wallet::{Version, WalletPolicy, WalletPubKey},
+};
+
+/// Seed of the mnemonic used by the Speculos emulator in the tests of the application
+/// ("glory promote mansion idle axis finger extra february uncover one trip resource lawn
+/// turtle enact monster seven myth punch hobby comfort wild raise skin").
+/// Its master fingerprint is f5acc2fd.
+pub const TEST_SEED: [u8; 64] = [
+    0xb1, 0x19, 0x97, 0xfa, 0xff, 0x42, 0x0a, 0x33, 0x1b, 0xb4, 0xa4, 0xff, 0xdc, 0x8b, 0xdc, 0x8b,
+    0xa7, 0xc0, 0x17, 0x32, 0xa9, 0x9a, 0x30, 0xd8, 0x3d, 0xbb, 0xeb, 0xd4, 0x69, 0x66, 0x6c, 0x84,
+    0xb4, 0x7d, 0x09, 0xd3, 0xf5, 0xf4, 0x72, 0xb3, 0xb9, 0x38, 0x4a, 0xc6, 0x34, 0xbe, 0xba, 0x2a,
+    0x44, 0x0b, 0xa3, 0x6e, 0xc7, 0x66, 0x11, 0x44, 0x13, 0x2f, 0x35, 0xe2, 0x06, 0x87, 0x35, 0x64,
+];
+
+const APP_NAME: &str = "Qtum Test";
+const APP_VERSION: &str = "2.1.5";
+
+/// Maximum length of a derivation path accepted by the application.
+const MAX_BIP32_PATH_STEPS: u8 = 8;
+/// Maximum length of the name of a registered wallet policy.
+const MAX_WALLET_NAME_LENGTH: usize = 64;
+
+/// SLIP-0021 label of the key authenticating the registered wallet policies.
+const WALLET_SLIP0021_LABEL: &[u8] = b"\0LEDGER-Wallet policy";
+
+/// Transport to an in-process model of the Qtum application (Qtum Test), see the module
+/// documentation.
+pub struct SimTransport {
+    secp: Secp256k1<All>,
+    master: ExtendedPrivKey,
+    wallet_hmac_key: [u8; 32],
+    approve: bool,
+    blind_signing: bool,
+    /// The command being executed and the responses of the client to the client commands
+    /// of the device so far.
+    pending: Mutex<Option<(APDUCommand, Vec<Vec<u8>>)>>,
+}
+
+impl SimTransport {
+    /// Creates a simulator of a device initialized with the given seed.
+    pub fn new(seed: &[u8]) -> Self {
+        Self {
+            secp: Secp256k1::new(),
+            master: ExtendedPrivKey::new_master(Network::Testnet, seed)
+                .expect("seed of valid length"),
+            wallet_hmac_key: slip21_key(seed, WALLET_SLIP0021_LABEL),
+            approve: true,
+            blind_signing: false,
+            pending: Mutex::new(None),
+        }
+    }
+
+    /// Sets whether the user approves the requests displayed on the screen, true by default.
+    pub fn with_user_approval(mut self, approve: bool) -> Self {
+        self.approve = approve;
+        self
+    }
+
+    /// Sets the blind signing setting of the application, disabled by default.
+    pub fn with_blind_signing(mut self, enabled: bool) -> Self {
+        self.blind_signing = enabled;
+        self
+    }
+
+    pub fn master_fingerprint(&self) -> Fingerprint {
+        self.master.fingerprint(&self.secp)
+    }
+
+    /// Returns the extended pubkey of the device derived with the given path.
+    pub fn xpub(&self, path: &DerivationPath) -> ExtendedPubKey {
+        ExtendedPubKey::from_priv(&self.secp, &self.xpriv(path))
+    }
+
+    fn xpriv(&self, path: &DerivationPath) -> ExtendedPrivKey {
+        self.master
+            .derive_priv(&self.secp, path)
+            .expect("valid derivation")
+    }
+
+    fn execute(&self, command: &APDUCommand, session: &mut Session) -> Result<Vec<u8>, Halt> {
+        let mut data = Reader::new(&command.data);
+        if command.cla == Cla::Default as u8 {
+            return match command.ins {
+                ins if ins == BitcoinCommandCode::GetVersion as u8 => Ok(self.get_version()),
+                _ => Err(Halt::Status(StatusWord::InsNotSupported)),
+            };
+        } else if command.cla != Cla::Bitcoin as u8 {
+            return Err(Halt::Status(StatusWord::ClaNotSupported));
+        }
+
+        match command.ins {
+            ins if ins == BitcoinCommandCode::GetExtendedPubkey as u8 => {
+                self.get_extended_pubkey(&mut data)
+            }
+            ins if ins == BitcoinCommandCode::GetMasterFingerprint as u8 => {
+                Ok(self.master_fingerprint().as_bytes().to_vec())
+            }
+            ins if ins == BitcoinCommandCode::RegisterWallet as u8 => {
+                self.register_wallet(session, &mut data)
+            }
+            ins if ins == BitcoinCommandCode::GetWalletAddress as u8 => {
+                self.get_wallet_address(session, &mut data)
+            }
+            ins if ins == BitcoinCommandCode::SignPSBT as u8 => self.sign_psbt(session, &mut data),
+            ins if ins == BitcoinCommandCode::SignMessage as u8 => {
+                self.sign_message(session, &mut data)
+            }
+            _ => Err(Halt::Status(StatusWord::InsNotSupported)),
+        }
+    }
+
+    fn get_version(&self) -> Vec<u8> {
+        let mut response = vec![0x01];
+        response.extend(encode::serialize(&APP_NAME.to_string()));
+        response.extend(encode::serialize(&APP_VERSION.to_string()));
+        response.extend(encode::serialize(&vec![0x00_u8]));
+        response
+    }
+
+    fn get_extended_pubkey(&self, data: &mut Reader) -> Result<Vec<u8>, Halt> {
+        let display = data.read_bool()?;
+        let path = data.read_path()?;
+        data.finish()?;
+
+        if display && !self.approve {
+            return Err(Halt::Status(StatusWord::Deny));
+        }
+        Ok(self.xpub(&path).to_string().into_bytes())
+    }
+
+    fn register_wallet(&self, session: &mut Session, data: &mut Reader) -> Result<Vec<u8>, Halt> {
+        let len = data.read_varint()?;
+        let serialized = data.read_bytes(len as usize)?;
+        data.finish()?;
+
+        let policy = session.read_wallet_policy(serialized)?;
+        if policy.name.is_empty() || policy.name.len() > MAX_WALLET_NAME_LENGTH {
+            return Err(Halt::Status(StatusWord::IncorrectData));
+        }
+        if !["pkh(", "wpkh(", "sh(", "wsh(", "tr("]
+            .iter()
+            .any(|top| policy.descriptor_template.starts_with(top))
+            || descriptor(&policy, false).is_err()
+        {
+            return Err(Halt::Status(StatusWord::NotSupported));
+        }
+
+        if !self.approve {
+            return Err(Halt::Status(StatusWord::Deny));
+        }
+
+        let n_internal_keys = policy
+            .keys
+            .iter()
+            .filter(|key| self.is_internal_key(key))
+            .count();
+        if n_internal_keys < 1 || (policy.version == Version::V1 && n_internal_keys != 1) {
+            return Err(Halt::Status(StatusWord::IncorrectData));
+        }
+
+        let id = sha256::Hash::hash(serialized).into_inner();
+        let mut response = id.to_vec();
+        response.extend_from_slice(&self.wallet_hmac(&id));
+        Ok(response)
+    }
+
+    fn get_wallet_address(
+        &self,
+        session: &mut Session,
+        data: &mut Reader,
+    ) -> Result<Vec<u8>, Halt> {
+        let display = data.read_bool()?;
+        let id = data.read_hash()?;
+        let hmac = data.read_hash()?;
+        let change = data.read_bool()?;
+        let address_index = data.read_u32()?;
+        data.finish()?;
+
+        let policy = session.get_wallet_policy(&id)?;
+        self.authorize(&policy, &id, &hmac)?;
+        if ChildNumber::from_normal_idx(address_index).is_err() {
+            return Err(Halt::Status(StatusWord::IncorrectData));
+        }
+
+        let address = descriptor(&policy, change)?
+            .at_derivation_index(address_index)
+            .address(Network::Testnet)
+            .map_err(|_| Halt::Status(StatusWord::NotSupported))?;
+
+        if display && !self.approve {
+            return Err(Halt::Status(StatusWord::Deny));
+        }
+        Ok(address.to_string().into_bytes())
+    }
+
+    fn sign_psbt(&self, session: &mut Session, data: &mut Reader) -> Result<Vec<u8>, Halt> {
+        let global_commitment = data.read_map_commitment()?;
+        let n_inputs = data.read_varint()?;
+        let inputs_root = data.read_hash()?;
+        let n_outputs = data.read_varint()?;
+        let outputs_root = data.read_hash()?;
+        let id = data.read_hash()?;
+        let hmac = data.read_hash()?;
+        data.finish()?;
+
+        if n_inputs > MAX_N_INPUTS_CAN_SIGN as u64 {
+            return Err(Halt::Status(StatusWord::NotSupported));
+        }
+
+        let policy = session.get_wallet_policy(&id)?;
+        self.authorize(&policy, &id, &hmac)?;
+
+        let global_map = session.get_merkleized_map(&global_commitment)?;
+        let mut input_maps = Vec::new();
+        for index in 0..n_inputs {
+            let commitment =
+                session.get_merkle_leaf_element(&inputs_root, n_inputs as usize, index as usize)?;
+            input_maps.push(
+                session.get_merkleized_map(&Reader::new(&commitment).read_map_commitment()?)?,
+            );
+        }
+        let mut output_maps = Vec::new();
+        for index in 0..n_outputs {
+            let commitment = session.get_merkle_leaf_element(
+                &outputs_root,
+                n_outputs as usize,
+                index as usize,
+            )?;
+            output_maps.push(
+                session.get_merkleized_map(&Reader::new(&commitment).read_map_commitment()?)?,
+            );
+        }
+
+        let psbt = psbt_from_v2_maps(&global_map, &input_maps, &output_maps)
+            .ok_or(Halt::Status(StatusWord::IncorrectData))?;
+
+        let mut signers = Vec::new();
+        for (index, (input, txin)) in psbt
+            .inputs
+            .iter()
+            .zip(psbt.unsigned_tx.input.iter())
+            .enumerate()
+        {
+            if input.witness_utxo.is_none() && input.non_witness_utxo.is_none() {
+                return Err(Halt::Status(StatusWord::IncorrectData));
+            }
+            let input_signers = self.input_signers(&psbt, &policy, index)?;
+            // the utxo of the script type is only required for the inputs of the wallet
+            if !input_signers.is_empty() && missing_utxo(input, txin).is_some() {
+                return Err(Halt::Status(StatusWord::IncorrectData));
+            }
+            signers.push(input_signers);
+        }
+        if signers.iter().all(|signers| signers.is_empty()) {
+            return Err(Halt::Status(StatusWord::IncorrectData));
+        }
+
+        if !self.blind_signing {
+            // the contract data of the change outputs of the wallet is not checked
+            for (output, txout) in psbt.outputs.iter().zip(psbt.unsigned_tx.output.iter()) {
+                if is_contract_blind_sign(&txout.script_pubkey)
+                    && !self.is_change_output(&policy, output, &txout.script_pubkey)?
+                {
+                    return Err(Halt::Status(StatusWord::SignatureFail));
+                }
+            }
+        }
+
+        if !self.approve {
+            return Err(Halt::Status(StatusWord::Deny));
+        }
+
+        for (index, signers) in signers.into_iter().enumerate() {
+            for signer in signers {
+                let mut result = encode::serialize(&VarInt(index as u64));
+                result.extend(self.sign_input(&psbt, index, signer)?);
+                session.yield_result(result)?;
+            }
+        }
+
+        Ok(Vec::new())
+    }
+
+    fn sign_message(&self, session: &mut Session, data: &mut Reader) -> Result<Vec<u8>, Halt> {
+        let path = data.read_path()?;
+        let message_length = data.read_varint()? as usize;
+        let message_root = data.read_hash()?;
+        data.finish()?;
+
+        let mut engine = sha256d::Hash::engine();
+        engine.input(QTUM_SIGNED_MSG_PREFIX);
+        engine.input(&encode::serialize(&VarInt(message_length as u64)));
+        let n_chunks = (0..message_length).step_by(64).count();
+        for index in 0..n_chunks {
+            let chunk = session.get_merkle_leaf_element(&message_root, n_chunks, index)?;
+            if chunk.len() > 64 || (chunk.len() != 64 && index != n_chunks - 1) {
+                return Err(Halt::Status(StatusWord::BadState));
+            }
+            engine.input(&chunk);
+        }
+        let digest = sha256d::Hash::from_engine(engine);
+
+        if !self.approve {
+            return Err(Halt::Status(StatusWord::Deny));
+        }
+
+        let message = Message::from_slice(&digest.into_inner()).expect("32 bytes");
+        let (recovery_id, signature) = self
+            .secp
+            .sign_ecdsa_recoverable(&message, &self.xpriv(&path).private_key)
+            .serialize_compact();
+        let mut response = vec![27 + 4 + recovery_id.to_i32() as u8];
+        response.extend_from_slice(&signature);
+        Ok(response)
+    }
+
+    /// Checks that the wallet policy is either registered with the given hmac, or a standard
+    /// policy of the device used without hmac.
+    fn authorize(&self, policy: &WalletPolicy, id: &[u8; 32], hmac: &[u8; 32]) -> Result<(), Halt> {
+        let authorized = if hmac == &[0x00; 32] {
+            policy.is_standard()
+                && self.is_internal_key(&policy.keys[0])
+                && matches!(&policy.keys[0].source, Some((_, path))
+                    if path[1] == ChildNumber::Hardened { index: QtumNetwork::Testnet.coin_type() })
+        } else {
+            hmac == &self.wallet_hmac(id)
+        };
+        if authorized {
+            Ok(())
+        } else {
+            Err(Halt::Status(StatusWord::SignatureFail))
+        }
+    }
+
+    fn wallet_hmac(&self, id: &[u8; 32]) -> [u8; 32] {
+        let mut engine = hmac::HmacEngine::<sha256::Hash>::new(&self.wallet_hmac_key);
+        engine.input(id);
+        hmac::Hmac::<sha256::Hash>::from_engine(engine).into_inner()
+    }
+
+    fn is_internal_key(&self, key: &WalletPubKey) -> bool {
+        match &key.source {
+            Some((fingerprint, path)) => {
+                *fingerprint == self.master_fingerprint() && self.xpub(path) == key.inner
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the keys of the device that must sign the input, none if the input is not
+    /// spending a script of the wallet policy.
+    fn input_signers(
+        &self,
+        psbt: &Psbt,
+        policy: &WalletPolicy,
+        index: usize,
+    ) -> Result<Vec<Signer>, Halt> {
+        let input = &psbt.inputs[index];
+        let script_pubkey = spent_utxo(psbt, index)
+            .ok_or(Halt::Status(StatusWord::IncorrectData))?
+            .script_pubkey;
+
+        let mut signers = Vec::new();
+        for (pubkey, (fingerprint, path)) in &input.bip32_derivation {
+            if *fingerprint == self.master_fingerprint()
+                && self.is_wallet_script(policy, path, &script_pubkey)?
+                && self.xpriv(path).private_key.public_key(&self.secp) == *pubkey
+            {
+                signers.push(Signer::Ecdsa(path.clone()));
+            }
+        }
+        for (pubkey, (leaf_hashes, (fingerprint, path))) in &input.tap_key_origins {
+            if *fingerprint != self.master_fingerprint()
+                || !self.is_wallet_script(policy, path, &script_pubkey)?
+                || self
+                    .xpriv(path)
+                    .to_keypair(&self.secp)
+                    .x_only_public_key()
+                    .0
+                    != *pubkey
+            {
+                continue;
+            }
+            if leaf_hashes.is_empty() && input.tap_internal_key == Some(*pubkey) {
+                signers.push(Signer::TaprootKeyPath(path.clone()));
+            }
+            for leaf_hash in leaf_hashes {
+                signers.push(Signer::TaprootScriptPath(path.clone(), *leaf_hash));
+            }
+        }
+        Ok(signers)
+    }
+
+    /// Returns true if the path derives an internal key of the policy, and the script pubkey
+    /// is the one of the policy at the change and address index ending the path.
+    fn is_wallet_script(
+        &self,
+        policy: &WalletPolicy,
+        path: &DerivationPath,
+        script_pubkey: &Script,
+    ) -> Result<bool, Halt> {
+        let path: &[ChildNumber] = path.as_ref();
+        if path.len() < 2 {
+            return Ok(false);
+        }
+        let (origin, suffix) = path.split_at(path.len() - 2);
+        let (change, address_index) = match suffix {
+            [ChildNumber::Normal { index: change }, ChildNumber::Normal {
+                index: address_index,
+            }] if *change <= 1 => (*change == 1, *address_index),
+            _ => return Ok(false),
+        };
+
+        let is_policy_key = policy.keys.iter().any(|key| {
+            matches!(&key.source, Some((_, key_path)) if key_path.as_ref() == origin)
+                && self.is_internal_key(key)
+        });
+        Ok(is_policy_key
+            && descriptor(policy, change)?
+                .at_derivation_index(address_index)
+                .script_pubkey()
+                == *script_pubkey)
+    }
+
+    /// Returns true if the output is a change output of the wallet policy: one of its key
+    /// origins is a key of the device on the change branch of the policy, at which the
+    /// policy derives the script_pubkey of the output.
+    fn is_change_output(
+        &self,
+        policy: &WalletPolicy,
+        output: &Output,
+        script_pubkey: &Script,
+    ) -> Result<bool, Halt> {
+        let sources = output
+            .bip32_derivation
+            .values()
+            .chain(output.tap_key_origins.values().map(|(_, source)| source));
+        for (fingerprint, path) in sources {
+            let is_change = matches!(
+                path.as_ref(),
+                [
+                    ..,
+                    ChildNumber::Normal { index: 1 },
+                    ChildNumber::Normal { .. }
+                ]
+            );
+            if *fingerprint == self.master_fingerprint()
+                && is_change
+                && self.is_wallet_script(policy, path, script_pubkey)?
+            {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Signs the input, returns the key augmented with the tapleaf hash for taproot script
+    /// path signatures, prefixed by its length, followed by the signature.
+    fn sign_input(&self, psbt: &Psbt, index: usize, signer: Signer) -> Result<Vec<u8>, Halt> {
+        let input = &psbt.inputs[index];
+        let mut result = Vec::new();
+        match signer {
+            Signer::Ecdsa(path) => {
+                let sighash = signature_hash(psbt, index)
+                    .map_err(|_| Halt::Status(StatusWord::IncorrectData))?;
+                let hash_ty = input
+                    .ecdsa_hash_ty()
+                    .map_err(|_| Halt::Status(StatusWord::IncorrectData))?;
+                let private_key = self.xpriv(&path).private_key;
+                let sig = self.secp.sign_ecdsa_low_r(
+                    &Message::from_slice(&sighash).expect("32 bytes"),
+                    &private_key,
+                );
+                result.push(33);
+                result.extend_from_slice(&private_key.public_key(&self.secp).serialize());
+                result.extend(EcdsaSig { sig, hash_ty }.to_vec());
+            }
+            Signer::TaprootKeyPath(path) => {
+                let sighash = signature_hash(psbt, index)
+                    .map_err(|_| Halt::Status(StatusWord::IncorrectData))?;
+                let keypair = self
+                    .xpriv(&path)
+                    .to_keypair(&self.secp)
+                    .tap_tweak(&self.secp, input.tap_merkle_root)
+                    .to_inner();
+                result.push(32);
+                result.extend_from_slice(&keypair.x_only_public_key().0.serialize());
+                result.extend(self.sign_schnorr(psbt, index, &sighash, &keypair)?);
+            }
+            Signer::TaprootScriptPath(path, leaf_hash) => {
+                let prevouts = (0..psbt.inputs.len())
+                    .map(|i| spent_utxo(psbt, i).ok_or(Halt::Status(StatusWord::IncorrectData)))
+                    .collect::<Result<Vec<TxOut>, Halt>>()?;
+                let hash_ty = input
+                    .schnorr_hash_ty()
+                    .map_err(|_| Halt::Status(StatusWord::IncorrectData))?;
+                let sighash = SighashCache::new(&psbt.unsigned_tx)
+                    .taproot_script_spend_signature_hash(
+                        index,
+                        &Prevouts::All(&prevouts),
+                        leaf_hash,
+                        hash_ty,
+                    )
+                    .map_err(|_| Halt::Status(StatusWord::IncorrectData))?;
+                let keypair = self.xpriv(&path).to_keypair(&self.secp);
+                result.push(64);
+                result.extend_from_slice(&keypair.x_only_public_key().0.serialize());
+                result.extend_from_slice(&leaf_hash.into_inner());
+                result.extend(self.sign_schnorr(psbt, index, &sighash.into_inner(), &keypair)?);
+            }
+        }
+        Ok(result)
+    }
+
+    fn sign_schnorr(
+        &self,
+        psbt: &Psbt,
+        index: usize,
+        sighash: &[u8; 32],
+        keypair: &KeyPair,
+    ) -> Result<Vec<u8>, Halt> {
+        let hash_ty = psbt.inputs[index]
+            .schnorr_hash_ty()
+            .map_err(|_| Halt::Status(StatusWord::IncorrectData))?;
+        let sig = self
+            .secp
+            .sign_schnorr_no_aux_rand(&Message::from_slice(sighash).expect("32 bytes"), keypair);
+        Ok(SchnorrSig { sig, hash_ty }.to_vec())
+    }
+}
+
+impl Default for SimTransport {
+    fn default() -> Self {
+        Self::new(&TEST_SEED)
+    }
+}
+
+impl Transport for SimTransport {
+    type Error = Infallible;
+
+    fn exchange(&self, command: &APDUCommand) -> Result<(StatusWord, Vec<u8>), Self::Error> {
+        let mut pending = self.pending.lock().expect("simulator not poisoned");
+        if command.cla == Cla::Framework as u8
+            && command.ins == FrameworkCommandCode::ContinueInterrupted as u8
+        {
+            match pending.as_mut() {
+                Some((_, responses)) => responses.push(command.data.clone()),
+                None => return Ok((StatusWord::BadState, Vec::new())),
+            }
+        } else {
+            *pending = Some((command.clone(), Vec::new()));
+        }
+
+        // The device logic is run again from the start with the responses of the client
+        // known so far, until it completes or it sends a new client command.
+        let res = match pending.as_ref() {
+            Some((command, responses)) => {
+                self.execute(command, &mut Session { responses, next: 0 })
+            }
+            None => unreachable!("a command is pending"),
+        };
+
+        match res {
+            Ok(data) => {
+                *pending = None;
+                Ok((StatusWord::OK, data))
+            }
+            Err(Halt::Interrupt(request)) => Ok((StatusWord::InterruptedExecution, request)),
+            Err(Halt::Status(status)) => {
+                *pending = None;
+                Ok((status, Vec::new()))
+            }
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+#[async_trait]
+impl crate::async_client::Transport for SimTransport {
+    type Error = Infallible;
+
+    async fn exchange(&self, command: &APDUCommand) -> Result<(StatusWord, Vec<u8>), Self::Error> {
+        Transport::exchange(self, command)
+    }
+}
+
+/// Stops the execution of a command of the device.
+enum Halt {
+    /// The device sends a client command and waits for the response of the client.
+    Interrupt(Vec<u8>),
+    /// The command fails with the status word.
+    Status(StatusWord),
+}
+
+/// Key of the device signing an input.
+enum Signer {
+    Ecdsa(DerivationPath),
+    TaprootKeyPath(DerivationPath),
+    TaprootScriptPath(DerivationPath, bitcoin::util::taproot::TapLeafHash),
+}
+
+/// Client commands sent by the device during the execution of a command.
+struct Session<'a> {
+    responses: &'a [Vec<u8>],
+    next: usize,
+}
+
+impl<'a> Session<'a> {
+    /// Returns the response of the client to the request if it is known, otherwise interrupts
+    /// the execution to send the request.
+    fn call(&mut self, request: Vec<u8>) -> Result<Vec<u8>, Halt> {
+        match self.responses.get(self.next) {
+            Some(response) => {
+                self.next += 1;
+                Ok(response.clone())
+            }
+            None => Err(Halt::Interrupt(request)),
+        }
+    }
+
+    fn yield_result(&mut self, result: Vec<u8>) -> Result<(), Halt> {
+        let mut request = vec![ClientCommandCode::Yield as u8];
+        request.extend(result);
+        self.call(request).map(|_| ())
+    }
+
+    fn get_preimage(&mut self, hash: &[u8; 32]) -> Result<Vec<u8>, Halt> {
+        let mut request = vec![ClientCommandCode::GetPreimage as u8, 0x00];
+        request.extend_from_slice(hash);
+        let response = self.call(request)?;
+
+        let mut reader = Reader::new(&response);
+        let preimage_len = reader.read_varint()? as usize;
+        let payload_len = reader.read_u8()? as usize;
+        let mut preimage = reader.read_bytes(payload_len)?.to_vec();
+        reader.finish()?;
+        while preimage.len() < preimage_len {
+            preimage.extend(self.get_more_elements(1)?);
+        }
+
+        if preimage.len() != preimage_len || &sha256::Hash::hash(&preimage).into_inner() != hash {
+            return Err(Halt::Status(StatusWord::IncorrectData));
+        }
+        Ok(preimage)
+    }
+
+    fn get_more_elements(&mut self, element_len: usize) -> Result<Vec<u8>, Halt> {
+        let response = self.call(vec![ClientCommandCode::GetMoreElements as u8])?;
+        let mut reader = Reader::new(&response);
+        let n_elements = reader.read_u8()? as usize;
+        if n_elements == 0 || reader.read_u8()? as usize != element_len {
+            return Err(Halt::Status(StatusWord::IncorrectData));
+        }
+        let elements = reader.read_bytes(n_elements * element_len)?.to_vec();
+        reader.finish()?;
+        Ok(elements)
+    }
+
+    /// Returns the leaf at the index of the Merkle tree, after checking its Merkle proof.
+    fn get_merkle_leaf(
+        &mut self,
+        root: &[u8; 32],
+        size: usize,
+        index: usize,
+    ) -> Result<[u8; 32], Halt> {
+        let mut request = vec![ClientCommandCode::GetMerkleLeafProof as u8];
+        request.extend_from_slice(root);
+        request.extend(encode::serialize(&VarInt(size as u64)));
+        request.extend(encode::serialize(&VarInt(index as u64)));
+        let response = self.call(request)?;
+
+        let mut reader = Reader::new(&response);
+        let leaf = reader.read_hash()?;
+        let proof_len = reader.read_u8()? as usize;
+        let n_elements = reader.read_u8()? as usize;
+        let mut proof = reader.read_bytes(n_elements * 32)?.to_vec();
+        reader.finish()?;
+        while proof.len() < proof_len * 32 {
+            proof.extend(self.get_more_elements(32)?);
+        }
+
+        let proof: Vec<[u8; 32]> = proof
+            .chunks(32)
+            .map(|element| {
+                let mut hash = [0x00; 32];
+                hash.copy_from_slice(element);
+                hash
+            })
+            .collect();
+        if root_from_proof(&leaf, &proof, size, index).as_ref() != Some(root) {
+            return Err(Halt::Status(StatusWord::IncorrectData));
+        }
+        Ok(leaf)
+    }
+
+    /// Returns the element at the index of a Merkleized list.
+    fn get_merkle_leaf_element(
+        &mut self,
+        root: &[u8; 32],
+        size: usize,
+        index: usize,
+    ) -> Result<Vec<u8>, Halt> {
+        let leaf = self.get_merkle_leaf(root, size, index)?;
+        let preimage = self.get_preimage(&leaf)?;
+        match preimage.split_first() {
+            Some((0x00, element)) => Ok(element.to_vec()),
+            _ => Err(Halt::Status(StatusWord::IncorrectData)),
+        }
+    }
+
+    /// Returns the key value pairs of a Merkleized map.
+    fn get_merkleized_map(&mut self, commitment: &MapCommitment) -> Result<KeyValueMap, Halt> {
+        (0..commitment.size)
+            .map(|index| {
+                Ok((
+                    self.get_merkle_leaf_element(&commitment.keys_root, commitment.size, index)?,
+                    self.get_merkle_leaf_element(&commitment.values_root, commitment.size, index)?,
+                ))
+            })
+            .collect()
+    }
+
+    fn get_wallet_policy(&mut self, id: &[u8; 32]) -> Result<WalletPolicy, Halt> {
+        let serialized = self.get_preimage(id)?;
+        self.read_wallet_policy(&serialized)
+    }
+
+    /// Parses a serialized wallet policy, fetching its descriptor template and its keys.
+    fn read_wallet_policy(&mut self, serialized: &[u8]) -> Result<WalletPolicy, Halt> {
+        let mut reader = Reader::new(serialized);
+        let version = match reader.read_u8()? {
+            1 => Version::V1,
+            2 => Version::V2,
+            _ => return Err(Halt::Status(StatusWord::IncorrectData)),
+        };
+        let name_len = reader.read_u8()? as usize;
+        let name = String::from_utf8(reader.read_bytes(name_len)?.to_vec())
+            .map_err(|_| Halt::Status(StatusWord::IncorrectData))?;
+        let template_len = reader.read_varint()? as usize;
+        let template = if version == Version::V2 {
+            self.get_preimage(&reader.read_hash()?)?
+        } else {
+            reader.read_bytes(template_len)?.to_vec()
+        };
+        let n_keys = reader.read_varint()? as usize;
+        let keys_root = reader.read_hash()?;
+        reader.finish()?;
+
+        let template =
+            String::from_utf8(template).map_err(|_| Halt::Status(StatusWord::IncorrectData))?;
+        if template.len() != template_len {
+            return Err(Halt::Status(StatusWord::IncorrectData));
+        }
+
+        let mut keys = Vec::with_capacity(n_keys);
+        for index in 0..n_keys {
+            let key = self.get_merkle_leaf_element(&keys_root, n_keys, index)?;
+            keys.push(
+                String::from_utf8(key)
+                    .ok()
+                    .and_then(|key| WalletPubKey::from_str(&key).ok())
+                    .ok_or(Halt::Status(StatusWord::IncorrectData))?,
+            );
+        }
+
+        Ok(WalletPolicy::new(name, version, template, keys))
+    }
+}
+
+/// Key value pairs of a Merkleized map.
+type KeyValueMap = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// Commitment to a Merkleized map: its size, the roots of the Merkle trees of its keys and of
+/// its values.
+struct MapCommitment {
+    size: usize,
+    keys_root: [u8; 32],
+    values_root: [u8; 32],
+}
+
+/// Reads the data of a command, failing with a wrong data length status word.
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], Halt> {
+        if self.data.len() < len {
+            return Err(Halt::Status(StatusWord::WrongDataLength));
+        }
+        let (bytes, data) = self.data.split_at(len);
+        self.data = data;
+        Ok(bytes)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, Halt> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    fn read_bool(&mut self) -> Result<bool, Halt> {
+        match self.read_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(Halt::Status(StatusWord::IncorrectData)),
+        }
+    }
+
+    fn read_u32(&mut self) -> Result<u32, Halt> {
+        let mut bytes = [0x00; 4];
+        bytes.copy_from_slice(self.read_bytes(4)?);
+        Ok(u32::from_be_bytes(bytes))
+    }
+
+    fn read_hash(&mut self) -> Result<[u8; 32], Halt> {
+        let mut hash = [0x00; 32];
+        hash.copy_from_slice(self.read_bytes(32)?);
+        Ok(hash)
+    }
+
+    fn read_varint(&mut self) -> Result<u64, Halt> {
+        let (varint, read): (VarInt, usize) = deserialize_partial(self.data)
+            .map_err(|_| Halt::Status(StatusWord::WrongDataLength))?;
+        self.data = &self.data[read..];
+        Ok(varint.0)
+    }
+
+    fn read_path(&mut self) -> Result<DerivationPath, Halt> {
+        let len = self.read_u8()?;
+        if len > MAX_BIP32_PATH_STEPS {
+            return Err(Halt::Status(StatusWord::IncorrectData));
+        }
+        (0..len)
+            .map(|_| self.read_u32().map(ChildNumber::from))
+            .collect::<Result<Vec<ChildNumber>, Halt>>()
+            .map(DerivationPath::from)
+    }
+
+    fn read_map_commitment(&mut self) -> Result<MapCommitment, Halt> {
+        Ok(MapCommitment {
+            size: self.read_varint()? as usize,
+            keys_root: self.read_hash()?,
+            values_root: self.read_hash()?,
+        })
+    }
+
+    fn finish(&self) -> Result<(), Halt> {
+        if self.data.is_empty() {
+            Ok(())
+        } else {
+            Err(Halt::Status(StatusWord::WrongDataLength))
+        }
+    }
+}
+
+fn descriptor(
+    policy: &WalletPolicy,
+    change: bool,
+) -> Result<Descriptor<DescriptorPublicKey>, Halt> {
+    policy
+        .get_descriptor(change)
+        .ok()
+        .and_then(|desc| Descriptor::<DescriptorPublicKey>::from_str(&desc).ok())
+        .ok_or(Halt::Status(StatusWord::NotSupported))
+}
+
+/// Derives the symmetric key of the label following SLIP-0021.
+fn slip21_key(seed: &[u8], label: &[u8]) -> [u8; 32] {
+    let mut engine = hmac::HmacEngine::<sha512::Hash>::new(b"Symmetric key seed");
+    engine.input(seed);
+    let master = hmac::Hmac::<sha512::Hash>::from_engine(engine).into_inner();
+    let mut engine = hmac::HmacEngine::<sha512::Hash>::new(&master[..32]);
+    engine.input(label);
+    let node = hmac::Hmac::<sha512::Hash>::from_engine(engine).into_inner();
+    let mut key = [0x00; 32];
+    key.copy_from_slice(&node[32..]);
+    key
+}
+
+/// Global and input fields of the PSBT v2 replacing the unsigned transaction of the PSBT v0.
+const PSBT_V2_GLOBAL_FIELDS: [u8; 5] = [0x02, 0x03, 0x04, 0x05, 0xFB];
+const PSBT_V2_INPUT_FIELDS: [u8; 5] = [0x0e, 0x0f, 0x10, 0x11, 0x12];
+const PSBT_V2_OUTPUT_FIELDS: [u8; 2] = [0x03, 0x04];
+
+/// Rebuilds the PSBT from the maps of a PSBT v2, the way the client serializes it.
+fn psbt_from_v2_maps(
+    global_map: &[(Vec<u8>, Vec<u8>)],
+    input_maps: &[KeyValueMap],
+    output_maps: &[KeyValueMap],
+) -> Option<Psbt> {
+    fn value(map: &[(Vec<u8>, Vec<u8>)], key: u8) -> Option<&[u8]> {
+        map.iter()
+            .find(|(k, _)| k.as_slice() == [key])
+            .map(|(_, v)| v.as_slice())
+    }
+    fn u32_le(bytes: &[u8]) -> Option<u32> {
+        let mut le = [0x00; 4];
+        le.copy_from_slice(bytes.get(..4).filter(|_| bytes.len() == 4)?);
+        Some(u32::from_le_bytes(le))
+    }
+    fn serialize_map(bytes: &mut Vec<u8>, map: &[(Vec<u8>, Vec<u8>)], v2_fields: &[u8]) {
+        for (key, value) in map {
+            if key.len() == 1 && v2_fields.contains(&key[0]) {
+                continue;
+            }
+            bytes.extend(encode::serialize(key));
+            bytes.extend(encode::serialize(value));
+        }
+        bytes.push(0x00);
+    }
+
+    let mut input = Vec::with_capacity(input_maps.len());
+    for map in input_maps {
+        input.push(TxIn {
+            previous_output: OutPoint {
+                txid: deserialize::<Txid>(value(map, 0x0e)?).ok()?,
+                vout: u32_le(value(map, 0x0f)?)?,
+            },
+            script_sig: Script::new(),
+            sequence: match value(map, 0x10) {
+                Some(sequence) => Sequence(u32_le(sequence)?),
+                None => Sequence::MAX,
+            },
+            witness: Witness::default(),
+        });
+    }
+
+    let mut output = Vec::with_capacity(output_maps.len());
+    for map in output_maps {
+        let mut amount = [0x00; 8];
+        amount.copy_from_slice(value(map, 0x03).filter(|v| v.len() == 8)?);
+        output.push(TxOut {
+            value: u64::from_le_bytes(amount),
+            script_pubkey: Script::from(value(map, 0x04)?.to_vec()),
+        });
+    }
+
+    let unsigned_tx = Transaction {
+        version: u32_le(value(global_map, 0x02)?)? as i32,
+        lock_time: PackedLockTime(match value(global_map, 0x03) {
+            Some(lock_time) => u32_le(lock_time)?,
+            None => 0,
+        }),
+        input,
+        output,
+    };
+
+    let mut bytes = b"psbt\xff".to_vec();
+    bytes.extend(encode::serialize(&vec![0x00_u8]));
+    bytes.extend(encode::serialize(&encode::serialize(&unsigned_tx)));
+    serialize_map(&mut bytes, global_map, &PSBT_V2_GLOBAL_FIELDS);
+    for map in input_maps {
+        serialize_map(&mut bytes, map, &PSBT_V2_INPUT_FIELDS);
+    }
+    for map in output_maps {
+        serialize_map(&mut bytes, map, &PSBT_V2_OUTPUT_FIELDS);
+    }
+    deserialize(&bytes).ok()
+}
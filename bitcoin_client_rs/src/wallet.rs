@@ -1,14 +1,31 @@
-use core::convert::From;
+use core::convert::{From, TryFrom};
 use core::iter::IntoIterator;
 use core::str::FromStr;
+use std::collections::HashMap;
 
 use bitcoin::{
     consensus::encode::{self, VarInt},
-    hashes::{sha256, Hash, HashEngine},
-    util::bip32::{DerivationPath, Error, ExtendedPubKey, Fingerprint, KeySource},
+    hashes::{
+        hex::{FromHex, ToHex},
+        sha256, Hash, HashEngine,
+    },
+    util::bip32::{ChildNumber, DerivationPath, Error, ExtendedPubKey, Fingerprint, KeySource},
 };
 
-use crate::merkle::MerkleTree;
+#[cfg(feature = "paranoid_client")]
+use bitcoin::{Address, Network};
+#[cfg(feature = "paranoid_client")]
+use miniscript::{Descriptor, DescriptorPublicKey};
+
+use crate::merkle::{leaf_hash, MerkleTree};
+
+/// Derivation indexes starting from this offset are hardened
+/// and cannot be used to derive an address of the wallet.
+#[cfg(feature = "paranoid_client")]
+const BIP32_HARDENED_OFFSET: u32 = 0x80000000;
+
+/// Maximum length in bytes of the name of a wallet policy registered on the device.
+pub const MAX_WALLET_NAME_LENGTH: usize = 64;
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Version {
@@ -16,6 +33,149 @@ pub enum Version {
     V2 = 2,
 }
 
+impl Version {
+    /// Returns whether an app of the given version, as returned by `get_version`, supports
+    /// the wallet policies of this version. Policies of version 2 were introduced in 2.1.0.
+    pub fn is_supported_by(&self, app_version: &str) -> bool {
+        match self {
+            Version::V1 => true,
+            Version::V2 => Capabilities::of_app_version(app_version).wallet_policy_v2,
+        }
+    }
+
+    /// Returns the latest version of the wallet policies supported by an app of the given
+    /// version, to convert a policy to with `WalletPolicy::to_version`.
+    pub fn latest_supported_by(app_version: &str) -> Self {
+        if Version::V2.is_supported_by(app_version) {
+            Version::V2
+        } else {
+            Version::V1
+        }
+    }
+}
+
+/// Features of the wallet policies supported by an app, derived from its version.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Wallet policies of version 2, since 2.1.0.
+    pub wallet_policy_v2: bool,
+    /// Miniscript policies on segwit, since 2.1.0.
+    pub miniscript: bool,
+    /// Taproot key path policies `tr(@0/**)`.
+    pub taproot: bool,
+    /// Taproot policies with a tree of scripts, since 2.1.2.
+    pub taproot_scripts: bool,
+    /// Signing inputs with another sighash type than `SIGHASH_ALL`, since 2.1.0.
+    pub sighash_flags: bool,
+}
+
+impl Capabilities {
+    /// Returns the capabilities of an app of the given version, as returned by
+    /// `get_version`. A version of unknown format is assumed to support every feature,
+    /// the device rejecting the policies it does not support.
+    pub fn of_app_version(app_version: &str) -> Self {
+        let version = parse_app_version(app_version);
+        Capabilities {
+            wallet_policy_v2: version >= (2, 1, 0),
+            miniscript: version >= (2, 1, 0),
+            taproot: version >= (2, 0, 0),
+            taproot_scripts: version >= (2, 1, 2),
+            sighash_flags: version >= (2, 1, 0),
+        }
+    }
+
+    /// Returns the first feature used by the wallet policy that is missing from these
+    /// capabilities, or None if the app supports the policy.
+    pub fn missing_for(&self, wallet: &WalletPolicy) -> Option<&'static str> {
+        let template = wallet.descriptor_template.as_str();
+        let taproot = template.starts_with("tr(");
+        if wallet.version == Version::V2 && !self.wallet_policy_v2 {
+            Some("wallet policy v2")
+        } else if taproot && !self.taproot {
+            Some("taproot")
+        } else if taproot && template.contains(',') && !self.taproot_scripts {
+            Some("taproot scripts")
+        } else if !taproot && is_miniscript(template) && !self.miniscript {
+            Some("miniscript")
+        } else {
+            None
+        }
+    }
+}
+
+/// Limits of the transactions signed by an app, derived from its version.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SigningLimits {
+    /// Maximum number of inputs: 64 before 2.0.4, `MAX_N_INPUTS_CAN_SIGN` since.
+    pub max_inputs: usize,
+    /// Maximum number of outputs: 32 before 2.0.4, None since, the number of outputs not
+    /// being limited.
+    pub max_outputs: Option<usize>,
+}
+
+impl SigningLimits {
+    /// Limits of the apps older than 2.0.4, within which every version of the app signs.
+    pub const BEFORE_2_0_4: SigningLimits = SigningLimits {
+        max_inputs: 64,
+        max_outputs: Some(32),
+    };
+
+    /// Returns the limits of an app of the given version, as returned by `get_version`.
+    /// A version of unknown format is assumed to have the limits of the latest versions.
+    pub fn of_app_version(app_version: &str) -> Self {
+        if parse_app_version(app_version) >= (2, 0, 4) {
+            SigningLimits {
+                max_inputs: crate::psbt::MAX_N_INPUTS_CAN_SIGN,
+                max_outputs: None,
+            }
+        } else {
+            Self::BEFORE_2_0_4
+        }
+    }
+
+    /// Returns whether a transaction with the given numbers of inputs and outputs is within
+    /// these limits.
+    pub fn allows(&self, n_inputs: usize, n_outputs: usize) -> bool {
+        n_inputs <= self.max_inputs && !matches!(self.max_outputs, Some(max) if n_outputs > max)
+    }
+}
+
+/// Parses the version returned by `get_version` as (major, minor, patch), a version of
+/// unknown format being greater than any other.
+fn parse_app_version(app_version: &str) -> (u32, u32, u32) {
+    // the patch may be followed by a pre-release suffix, like 2.1.2-rc
+    let mut numbers = app_version
+        .split('.')
+        .map(|n| n.split('-').next().unwrap_or(n).parse::<u32>());
+    match (numbers.next(), numbers.next(), numbers.next()) {
+        (Some(Ok(major)), Some(Ok(minor)), Some(Ok(patch))) => (major, minor, patch),
+        (Some(Ok(major)), Some(Ok(minor)), None) => (major, minor, 0),
+        _ => (u32::MAX, 0, 0),
+    }
+}
+
+/// Returns true if the descriptor template of a segwit or legacy policy is not one of the
+/// single signature or multisig templates supported before miniscript.
+fn is_miniscript(template: &str) -> bool {
+    // the key expressions of the templates have no parenthesis
+    let single_sig =
+        |prefix: &str| matches!(template.strip_prefix(prefix), Some(keys) if !keys.contains('('));
+    let multisig = |prefix: &str| {
+        ["multi(", "sortedmulti("].iter().any(|multi| {
+            let keys = template
+                .strip_prefix(prefix)
+                .and_then(|inner| inner.strip_prefix(multi));
+            matches!(keys, Some(keys) if !keys.contains('('))
+        })
+    };
+    !(single_sig("pkh(")
+        || single_sig("wpkh(")
+        || single_sig("sh(wpkh(")
+        || multisig("sh(")
+        || multisig("wsh(")
+        || multisig("sh(wsh("))
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum AddressType {
     /// Legacy address type. P2PKH for single sig, P2SH for scripts.
@@ -28,9 +188,96 @@ pub enum AddressType {
     Taproot,
 }
 
+impl AddressType {
+    /// Returns the script type of the addresses, for single sig or for scripts.
+    pub fn script_type(&self, multisig: bool) -> ScriptType {
+        match (self, multisig) {
+            (AddressType::Legacy, false) => ScriptType::P2pkh,
+            (AddressType::Legacy, true) => ScriptType::P2sh,
+            (AddressType::NativeSegwit, false) => ScriptType::P2wpkh,
+            (AddressType::NativeSegwit, true) => ScriptType::P2wsh,
+            (AddressType::NestedSegwit, false) => ScriptType::P2shP2wpkh,
+            (AddressType::NestedSegwit, true) => ScriptType::P2shP2wsh,
+            (AddressType::Taproot, _) => ScriptType::P2tr,
+        }
+    }
+}
+
+/// Fee rate in satoshis per 1000 virtual bytes used by Qtum nodes to define dust outputs.
+pub const DUST_RELAY_FEE: u64 = 400_000;
+
+/// Script type of an output, single source of the script-specific knowledge.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ScriptType {
+    P2pkh,
+    P2sh,
+    P2shP2wpkh,
+    P2wpkh,
+    P2wsh,
+    P2shP2wsh,
+    P2tr,
+}
+
+impl ScriptType {
+    /// Returns the script type of the address, None for unknown witness versions.
+    /// Nested segwit addresses cannot be told apart from other P2SH addresses and
+    /// are all classified as `P2sh`.
+    pub fn of(address: &bitcoin::Address) -> Option<ScriptType> {
+        match address.address_type()? {
+            bitcoin::AddressType::P2pkh => Some(ScriptType::P2pkh),
+            bitcoin::AddressType::P2sh => Some(ScriptType::P2sh),
+            bitcoin::AddressType::P2wpkh => Some(ScriptType::P2wpkh),
+            bitcoin::AddressType::P2wsh => Some(ScriptType::P2wsh),
+            bitcoin::AddressType::P2tr => Some(ScriptType::P2tr),
+            _ => None,
+        }
+    }
+
+    /// Wraps the key or script expression `inner` into the descriptor of the script type.
+    pub fn descriptor_fragment(&self, inner: &str) -> String {
+        match self {
+            ScriptType::P2pkh => format!("pkh({})", inner),
+            ScriptType::P2sh => format!("sh({})", inner),
+            ScriptType::P2shP2wpkh => format!("sh(wpkh({}))", inner),
+            ScriptType::P2wpkh => format!("wpkh({})", inner),
+            ScriptType::P2wsh => format!("wsh({})", inner),
+            ScriptType::P2shP2wsh => format!("sh(wsh({}))", inner),
+            ScriptType::P2tr => format!("tr({})", inner),
+        }
+    }
+
+    /// Returns true if the inputs spending the script have a witness.
+    pub fn is_segwit(&self) -> bool {
+        !matches!(self, ScriptType::P2pkh | ScriptType::P2sh)
+    }
+
+    /// Returns true if the script is a segwit v1 Taproot output.
+    pub fn is_taproot(&self) -> bool {
+        *self == ScriptType::P2tr
+    }
+
+    /// Returns the value in satoshis under which an output of this type is dust,
+    /// following the computation of the nodes with the `DUST_RELAY_FEE` rate.
+    pub fn dust_limit(&self) -> u64 {
+        let output_size = match self {
+            ScriptType::P2pkh => 34,
+            ScriptType::P2sh | ScriptType::P2shP2wpkh | ScriptType::P2shP2wsh => 32,
+            ScriptType::P2wpkh => 31,
+            ScriptType::P2wsh | ScriptType::P2tr => 43,
+        };
+        // size of the input spending the output, the witness being discounted
+        // for native witness programs only.
+        let spend_size = match self {
+            ScriptType::P2wpkh | ScriptType::P2wsh | ScriptType::P2tr => 32 + 4 + 1 + 107 / 4 + 4,
+            _ => 32 + 4 + 1 + 107 + 4,
+        };
+        (output_size + spend_size) * DUST_RELAY_FEE / 1000
+    }
+}
+
 /// Represents a wallet stored with a wallet policy.
 pub struct WalletPolicy {
-    /// wallet name (ASCII string, max 64 bytes)
+    /// wallet name (ASCII string, max `MAX_WALLET_NAME_LENGTH` bytes)
     pub name: String,
     /// wallet version
     pub version: Version,
@@ -60,6 +307,28 @@ impl WalletPolicy {
         }
     }
 
+    /// Builds the standard single-sig policy of the address type for the key of an account,
+    /// with its origin `[fingerprint/purpose'/coin_type'/account']`: `pkh(@0/**)`,
+    /// `sh(wpkh(@0/**))`, `wpkh(@0/**)`, or `tr(@0/**)` for a BIP86 taproot account. The
+    /// device accepts these policies without registration.
+    /// Fails with `WalletError::InvalidPolicy` if the origin of the key is not an account of
+    /// the address type, see `is_standard`.
+    pub fn new_single_sig(
+        address_type: AddressType,
+        key: impl Into<WalletPubKey>,
+    ) -> Result<Self, WalletError> {
+        let wallet = Self::new(
+            String::new(),
+            Version::V2,
+            address_type.script_type(false).descriptor_fragment("@0/**"),
+            vec![key],
+        );
+        if !wallet.is_standard() {
+            return Err(WalletError::InvalidPolicy);
+        }
+        Ok(wallet)
+    }
+
     pub fn new_multisig<T: Into<WalletPubKey>>(
         name: String,
         version: Version,
@@ -82,16 +351,12 @@ impl WalletPolicy {
             .collect::<Vec<String>>()
             .join(",");
 
-        let descriptor_template = match address_type {
-            AddressType::Legacy => format!("sh({}({},{}))", multisig_op, threshold, keys_str),
-            AddressType::NativeSegwit => {
-                format!("wsh({}({},{}))", multisig_op, threshold, keys_str)
-            }
-            AddressType::NestedSegwit => {
-                format!("sh(wsh({}({},{})))", multisig_op, threshold, keys_str)
-            }
-            _ => return Err(WalletError::UnsupportedAddressType),
-        };
+        let script_type = address_type.script_type(true);
+        if script_type.is_taproot() {
+            return Err(WalletError::UnsupportedAddressType);
+        }
+        let descriptor_template = script_type
+            .descriptor_fragment(&format!("{}({},{})", multisig_op, threshold, keys_str));
 
         Ok(Self {
             name,
@@ -102,12 +367,211 @@ impl WalletPolicy {
         })
     }
 
+    /// Orders the keys of a `sortedmulti` policy by their key expression and renumbers the
+    /// placeholders of the template to match, each placeholder keeping the derivation of its
+    /// key. The same multisig built from keys given in any order is then the same policy, with
+    /// the same id, and needs a single registration.
+    /// The keys are sorted lexicographically by the script at each address, so the addresses
+    /// do not depend on this order. Other policies are left unchanged.
+    pub fn normalize(&mut self) {
+        let start = match self.descriptor_template.find("sortedmulti(") {
+            Some(start) => start + "sortedmulti(".len(),
+            None => return,
+        };
+        let end = match self.descriptor_template[start..].find(')') {
+            Some(end) => start + end,
+            None => return,
+        };
+
+        // (key index, derivation suffix) of each key argument, after the threshold
+        let mut args: Vec<(usize, &str)> = Vec::new();
+        for arg in self.descriptor_template[start..end].split(',').skip(1) {
+            let arg = match arg.strip_prefix('@') {
+                Some(arg) => arg,
+                None => return,
+            };
+            let digits = arg.find(|c: char| !c.is_ascii_digit()).unwrap_or(arg.len());
+            match arg[..digits].parse::<usize>() {
+                Ok(index) => args.push((index, &arg[digits..])),
+                Err(_) => return,
+            }
+        }
+
+        // every key must be an argument of the multisig, and only there
+        let mut indexes: Vec<usize> = args.iter().map(|(index, _)| *index).collect();
+        indexes.sort_unstable();
+        if indexes != (0..self.keys.len()).collect::<Vec<usize>>()
+            || self.descriptor_template[end..].contains('@')
+        {
+            return;
+        }
+
+        args.sort_by_cached_key(|(index, _)| self.keys[*index].to_string());
+        let threshold = self.descriptor_template[start..end]
+            .split(',')
+            .next()
+            .unwrap_or_default();
+        let mut template = format!("{}{}", &self.descriptor_template[..start], threshold);
+        for (i, (_, suffix)) in args.iter().enumerate() {
+            template.push_str(&format!(",@{}{}", i, suffix));
+        }
+        template.push_str(&self.descriptor_template[end..]);
+
+        let mut keys: Vec<Option<WalletPubKey>> = self.keys.drain(..).map(Some).collect();
+        self.keys = args
+            .iter()
+            .filter_map(|(index, _)| keys[*index].take())
+            .collect();
+        self.descriptor_template = template;
+    }
+
+    /// Builds a wallet policy from a descriptor with extended keys, like
+    /// `wsh(and_v(v:pk([f5acc2fd/48'/1'/0'/2']tpub.../<0;1>/*),older(144)))`, each key expression
+    /// being replaced by its `@i` placeholder in the descriptor template.
+    /// Keys must be ranged with `/**` or `/<M;N>/*`, and the descriptor must parse with miniscript.
+    #[cfg(feature = "paranoid_client")]
+    pub fn from_descriptor(name: String, descriptor: &str) -> Result<Self, WalletError> {
+        // the checksum is not part of the policy
+        let descriptor = descriptor.split('#').next().unwrap_or_default();
+
+        let mut descriptor_template = String::with_capacity(descriptor.len());
+        let mut keys: Vec<(String, WalletPubKey)> = Vec::new();
+        for token in descriptor.split_inclusive(['(', ')', ',']) {
+            let (expr, delimiter) = match token.char_indices().last() {
+                Some((i, '(' | ')' | ',')) => token.split_at(i),
+                _ => (token, ""),
+            };
+            let (key_str, suffix) = if let Some(key_str) = expr.strip_suffix("/**") {
+                (key_str, "/**")
+            } else if let Some(start) = expr.rfind("/<").filter(|_| expr.ends_with(">/*")) {
+                expr.split_at(start)
+            } else {
+                (expr, "")
+            };
+
+            match WalletPubKey::from_str(key_str) {
+                Ok(_) if suffix.is_empty() => return Err(WalletError::InvalidPolicy),
+                Ok(key) => {
+                    let index = match keys.iter().position(|(s, _)| s == key_str) {
+                        Some(index) => index,
+                        None => {
+                            keys.push((key_str.to_string(), key));
+                            keys.len() - 1
+                        }
+                    };
+                    descriptor_template.push_str(&format!("@{}{}", index, suffix));
+                }
+                Err(_) => descriptor_template.push_str(expr),
+            }
+            descriptor_template.push_str(delimiter);
+        }
+
+        if keys.is_empty() {
+            return Err(WalletError::InvalidPolicy);
+        }
+
+        let policy = Self::new(
+            name,
+            Version::V2,
+            descriptor_template,
+            keys.into_iter().map(|(_, key)| key),
+        );
+        policy.descriptor(false)?;
+        policy.descriptor(true)?;
+        Ok(policy)
+    }
+
+    /// Returns the same policy in the serialization of the given version. The derivation of
+    /// each key, like `/**`, follows the key in version 1, `wsh(sortedmulti(2,@0,@1))` with
+    /// the keys `[...]xpub/**`, and its placeholder in the template of version 2,
+    /// `wsh(sortedmulti(2,@0/**,@1/**))`. Both describe the same addresses, but their ids
+    /// differ and they are registered separately.
+    /// Fails with `WalletError::InvalidPolicy` if the policy has no equivalent in the other
+    /// version: a key used more than once in a template of version 2, a placeholder out of
+    /// the keys, or a derivation both on a key and on its placeholder.
+    pub fn to_version(&self, version: Version) -> Result<WalletPolicy, WalletError> {
+        let mut multipaths: Vec<Option<String>> = match (self.version, version) {
+            (Version::V1, Version::V2) | (Version::V2, Version::V1) => vec![None; self.keys.len()],
+            _ => self.keys.iter().map(|key| key.multipath.clone()).collect(),
+        };
+        let mut seen = vec![false; self.keys.len()];
+        let mut template = String::with_capacity(self.descriptor_template.len());
+        let mut rest = self.descriptor_template.as_str();
+        while let Some(at) = rest.find('@') {
+            template.push_str(&rest[..=at]);
+            rest = &rest[at + 1..];
+            let digits = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len());
+            let index: usize = rest[..digits]
+                .parse()
+                .map_err(|_| WalletError::InvalidPolicy)?;
+            let key = self.keys.get(index).ok_or(WalletError::InvalidPolicy)?;
+            template.push_str(&rest[..digits]);
+            rest = &rest[digits..];
+            let end = rest.find([',', ')']).unwrap_or(rest.len());
+            let derivation = &rest[..end];
+            match (self.version, version) {
+                (Version::V2, Version::V1) => {
+                    if seen[index] || key.multipath.is_some() {
+                        return Err(WalletError::InvalidPolicy);
+                    }
+                    multipaths[index] = Some(derivation.to_string()).filter(|d| !d.is_empty());
+                    rest = &rest[end..];
+                }
+                (Version::V1, Version::V2) => {
+                    if !derivation.is_empty() {
+                        return Err(WalletError::InvalidPolicy);
+                    }
+                    template.push_str(key.multipath.as_deref().unwrap_or(""));
+                }
+                _ => {}
+            }
+            seen[index] = true;
+        }
+        template.push_str(rest);
+
+        let keys = self
+            .keys
+            .iter()
+            .zip(multipaths)
+            .map(|(key, multipath)| WalletPubKey {
+                inner: key.inner,
+                source: key.source.clone(),
+                multipath,
+            });
+        let mut policy = WalletPolicy::new(self.name.clone(), version, template, keys);
+        policy.threshold = self.threshold;
+        Ok(policy)
+    }
+
+    /// Checks the limits of the device that the constructors do not enforce: fails with
+    /// `WalletError::NameTooLong` if the name is longer than `MAX_WALLET_NAME_LENGTH` bytes,
+    /// a policy that the device would refuse to register.
+    pub fn validate(&self) -> Result<(), WalletError> {
+        if self.name.len() > MAX_WALLET_NAME_LENGTH {
+            return Err(WalletError::NameTooLong);
+        }
+        Ok(())
+    }
+
+    /// Serializes the wallet policy as the device receives it on registration, the id of the
+    /// policy being the hash of this serialization:
+    ///   - the version, on one byte;
+    ///   - the length of the name on one byte, then the name;
+    ///   - the length of the descriptor template as a varint, then the template for version 1,
+    ///     or its SHA256 for version 2;
+    ///   - the number of keys as a varint, then the root of the merkle tree of the key
+    ///     expressions.
+    ///
+    /// The template and the keys are committed to by their hashes, the device requesting them
+    /// from the client: see `to_backup` for a serialization holding them.
     pub fn serialize(&self) -> Vec<u8> {
         let mut res: Vec<u8> = (self.version as u8).to_be_bytes().to_vec();
         res.extend_from_slice(&(self.name.len() as u8).to_be_bytes());
         res.extend_from_slice(self.name.as_bytes());
         res.extend(encode::serialize(&VarInt(
-            self.descriptor_template.as_bytes().len() as u64,
+            self.descriptor_template.len() as u64
         )));
 
         if self.version == Version::V2 {
@@ -125,13 +589,7 @@ impl WalletPolicy {
             MerkleTree::new(
                 self.keys
                     .iter()
-                    .map(|key| {
-                        let mut preimage = vec![0x00];
-                        preimage.extend_from_slice(key.to_string().as_bytes());
-                        let mut engine = sha256::Hash::engine();
-                        engine.input(&preimage);
-                        sha256::Hash::from_engine(engine).into_inner()
-                    })
+                    .map(|key| leaf_hash(key.to_string().as_bytes()))
                     .collect(),
             )
             .root_hash(),
@@ -140,6 +598,121 @@ impl WalletPolicy {
         res
     }
 
+    /// Serializes the wallet policy with everything the device needs to register it again:
+    /// the bytes of `serialize`, followed by the descriptor template and each key expression,
+    /// each prefixed with its length as a varint. With the hmac of the registration, this
+    /// forms a complete backup of the wallet. The threshold is not part of it.
+    pub fn to_backup(&self) -> Vec<u8> {
+        let mut res = self.serialize();
+        res.extend(encode::serialize(&VarInt(
+            self.descriptor_template.len() as u64
+        )));
+        res.extend_from_slice(self.descriptor_template.as_bytes());
+        for key in &self.keys {
+            let key = key.to_string();
+            res.extend(encode::serialize(&VarInt(key.len() as u64)));
+            res.extend_from_slice(key.as_bytes());
+        }
+        res
+    }
+
+    /// Reads a wallet policy serialized by `to_backup`.
+    /// Returns `WalletError::InvalidPolicy` if the bytes are malformed, or if the descriptor
+    /// template or the keys do not match their commitments in the serialization of the policy.
+    pub fn from_backup(bytes: &[u8]) -> Result<Self, WalletError> {
+        fn take<'a>(bytes: &'a [u8], pos: &mut usize, len: usize) -> Result<&'a [u8], WalletError> {
+            let end = pos
+                .checked_add(len)
+                .filter(|end| *end <= bytes.len())
+                .ok_or(WalletError::InvalidPolicy)?;
+            let res = &bytes[*pos..end];
+            *pos = end;
+            Ok(res)
+        }
+        fn take_varint(bytes: &[u8], pos: &mut usize) -> Result<usize, WalletError> {
+            let (VarInt(n), len) = encode::deserialize_partial::<VarInt>(&bytes[*pos..])
+                .map_err(|_| WalletError::InvalidPolicy)?;
+            *pos += len;
+            usize::try_from(n).map_err(|_| WalletError::InvalidPolicy)
+        }
+        fn take_string(bytes: &[u8], pos: &mut usize, len: usize) -> Result<String, WalletError> {
+            String::from_utf8(take(bytes, pos, len)?.to_vec())
+                .map_err(|_| WalletError::InvalidPolicy)
+        }
+
+        let mut pos = 0;
+        let version = match take(bytes, &mut pos, 1)?[0] {
+            1 => Version::V1,
+            2 => Version::V2,
+            _ => return Err(WalletError::InvalidPolicy),
+        };
+        let name_len = take(bytes, &mut pos, 1)?[0] as usize;
+        let name = take_string(bytes, &mut pos, name_len)?;
+        let template_len = take_varint(bytes, &mut pos)?;
+        // the template of version 1, or its hash
+        let template_commitment_len = match version {
+            Version::V1 => template_len,
+            Version::V2 => 32,
+        };
+        take(bytes, &mut pos, template_commitment_len)?;
+        let n_keys = take_varint(bytes, &mut pos)?;
+        take(bytes, &mut pos, 32)?;
+        let serialized = &bytes[..pos];
+
+        let template_len = take_varint(bytes, &mut pos)?;
+        let descriptor_template = take_string(bytes, &mut pos, template_len)?;
+        let mut keys = Vec::new();
+        for _ in 0..n_keys {
+            let key_len = take_varint(bytes, &mut pos)?;
+            let key = take_string(bytes, &mut pos, key_len)?;
+            keys.push(WalletPubKey::from_str(&key).map_err(|_| WalletError::InvalidPolicy)?);
+        }
+        if pos != bytes.len() {
+            return Err(WalletError::InvalidPolicy);
+        }
+
+        let policy = Self::new(name, version, descriptor_template, keys);
+        if policy.serialize() != serialized {
+            return Err(WalletError::InvalidPolicy);
+        }
+        Ok(policy)
+    }
+
+    /// Returns the receive and change descriptors of the wallet, with their checksum, to be
+    /// compared with the descriptors shown by other wallets or imported in a watch-only node.
+    /// On registration the device displays the `descriptor_template` and each of the `keys`,
+    /// which expand to these descriptors.
+    pub fn display_descriptors(&self) -> Result<(String, String), WalletError> {
+        let with_checksum = |desc: String| -> Result<String, WalletError> {
+            let checksum = descriptor_checksum(&desc).ok_or(WalletError::InvalidPolicy)?;
+            Ok(format!("{}#{}", desc, checksum))
+        };
+        Ok((
+            with_checksum(self.get_descriptor(false)?)?,
+            with_checksum(self.get_descriptor(true)?)?,
+        ))
+    }
+
+    /// Returns the multipath descriptor of the wallet with its checksum, covering both the
+    /// receive and change addresses, like `wpkh([f5acc2fd/84'/2301'/0']xpub.../<0;1>/*)#...`.
+    /// It can be imported in a watch-only wallet supporting the multipath descriptors of
+    /// BIP-389; `display_descriptors` gives one descriptor per branch for the others.
+    pub fn to_watch_descriptor(&self) -> Result<String, WalletError> {
+        let mut desc = self.descriptor_template.clone();
+
+        for (i, key) in self.keys.iter().enumerate().rev() {
+            desc = desc.replace(&format!("@{}", i), &key.to_string());
+        }
+
+        desc = desc.replace("/**", "/<0;1>/*");
+
+        let checksum = descriptor_checksum(&desc).ok_or(WalletError::InvalidPolicy)?;
+        Ok(format!("{}#{}", desc, checksum))
+    }
+
+    /// Returns the descriptor of the receive or change addresses of the wallet, the keys
+    /// being followed by their branch: `/**` resolves to `/0/*` or `/1/*`, and the multipath
+    /// expression `/<M;N>/*` to `/M/*` for the receive addresses or `/N/*` for the change.
     pub fn get_descriptor(&self, change: bool) -> Result<String, WalletError> {
         let mut desc = self.descriptor_template.clone();
 
@@ -150,80 +723,543 @@ impl WalletPolicy {
         desc = desc.replace("/**", &format!("/{}/{}", if change { 1 } else { 0 }, "*"));
 
         // For every "/<M;N>" expression, replace with M if not change, or with N if change
-        if let Some(start) = desc.find("/<") {
-            if let Some(end) = desc.find(">") {
-                let nums: Vec<&str> = desc[start + 2..end].split(";").collect();
-                if nums.len() == 2 {
-                    let replacement = if change { nums[1] } else { nums[0] };
-                    desc = format!("{}{}{}", &desc[..start + 1], replacement, &desc[end + 1..]);
-                } else {
-                    return Err(WalletError::InvalidPolicy);
-                }
+        while let Some(start) = desc.find("/<") {
+            let end = desc[start..]
+                .find('>')
+                .map(|end| start + end)
+                .ok_or(WalletError::InvalidPolicy)?;
+            let nums: Vec<&str> = desc[start + 2..end].split(';').collect();
+            if nums.len() == 2 {
+                let replacement = if change { nums[1] } else { nums[0] };
+                desc = format!("{}{}{}", &desc[..start + 1], replacement, &desc[end + 1..]);
+            } else {
+                return Err(WalletError::InvalidPolicy);
             }
         }
 
         Ok(desc)
     }
 
+    /// Returns the address of the wallet at the given change and index, derived offline from
+    /// the descriptor like the client checks the addresses returned by the device.
+    /// Fails with `WalletError::InvalidPolicy` for a hardened index.
+    #[cfg(feature = "paranoid_client")]
+    pub fn address_at(
+        &self,
+        change: bool,
+        index: u32,
+        network: Network,
+    ) -> Result<Address, WalletError> {
+        if index >= BIP32_HARDENED_OFFSET {
+            return Err(WalletError::InvalidPolicy);
+        }
+        self.descriptor(change)?
+            .at_derivation_index(index)
+            .address(network)
+            .map_err(|_| WalletError::InvalidPolicy)
+    }
+
+    /// Returns an iterator over the addresses of the wallet, starting at the `start` index.
+    /// Addresses are derived lazily and offline, without any interaction with the device.
+    /// The iterator stops after the first error or when it reaches the hardened indexes.
+    /// A policy whose keys have no wildcard, like `wpkh(@0/0/5)`, has a single address, the
+    /// same for any index and change, which the iterator yields once.
+    #[cfg(feature = "paranoid_client")]
+    pub fn address_iter(
+        &self,
+        change: bool,
+        start: u32,
+        network: Network,
+    ) -> impl Iterator<Item = Result<Address, WalletError>> {
+        let descriptor = self.descriptor(change);
+        let end = match &descriptor {
+            Ok(desc) if !desc.has_wildcard() => start.saturating_add(1),
+            _ => BIP32_HARDENED_OFFSET,
+        };
+        (start..end.min(BIP32_HARDENED_OFFSET)).scan(false, move |failed, index| {
+            if *failed {
+                return None;
+            }
+            let address = descriptor.as_ref().map_err(|e| e.clone()).and_then(|desc| {
+                desc.at_derivation_index(index)
+                    .address(network)
+                    .map_err(|_| WalletError::InvalidPolicy)
+            });
+            *failed = address.is_err();
+            Some(address)
+        })
+    }
+
+    #[cfg(feature = "paranoid_client")]
+    fn descriptor(&self, change: bool) -> Result<Descriptor<DescriptorPublicKey>, WalletError> {
+        let desc_str = self.get_descriptor(change)?;
+        Descriptor::<DescriptorPublicKey>::from_str(&desc_str)
+            .map_err(|_| WalletError::InvalidPolicy)
+    }
+
+    /// Returns true if the policy is a standard single signature policy that can be used
+    /// without registration, its key being derived at the account level of the BIP44, BIP49,
+    /// BIP84 or BIP86 path matching the policy.
+    /// The device additionally checks that the key is its own and that the coin type is valid.
+    pub fn is_standard(&self) -> bool {
+        let purpose = match self.descriptor_template.replace("/**", "").as_str() {
+            "pkh(@0)" => 44,
+            "sh(wpkh(@0))" => 49,
+            "wpkh(@0)" => 84,
+            "tr(@0)" => 86,
+            _ => return false,
+        };
+
+        if self.keys.len() != 1 {
+            return false;
+        }
+
+        match &self.keys[0].source {
+            Some((_, path)) => {
+                let path: &[ChildNumber] = path.as_ref();
+                path.len() == 3
+                    && path[0] == ChildNumber::Hardened { index: purpose }
+                    && path[1].is_hardened()
+                    && path[2].is_hardened()
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the index of the first key derived from the master key with the given
+    /// fingerprint, the key of the device with this master fingerprint, whose origin is
+    /// used to fill the bip32 derivations of the psbt before signing.
+    pub fn device_key_index(&self, master_fingerprint: Fingerprint) -> Option<usize> {
+        self.keys
+            .iter()
+            .position(|key| key.origin_fingerprint() == Some(master_fingerprint))
+    }
+
+    /// Returns a summary of the policy for the user to review it: its kind, like a 2 of 3
+    /// multisig, the script type of its addresses and the origins of its keys.
+    pub fn describe(&self) -> PolicyDescription {
+        let template = self.descriptor_template.as_str();
+        let (script_type, inner) = [
+            ("sh(wsh(", ScriptType::P2shP2wsh),
+            ("sh(wpkh(", ScriptType::P2shP2wpkh),
+            ("sh(", ScriptType::P2sh),
+            ("wsh(", ScriptType::P2wsh),
+            ("wpkh(", ScriptType::P2wpkh),
+            ("pkh(", ScriptType::P2pkh),
+            ("tr(", ScriptType::P2tr),
+        ]
+        .iter()
+        .find_map(|(prefix, script_type)| {
+            let inner = template.strip_prefix(prefix)?;
+            let closing = prefix.matches('(').count();
+            Some((
+                Some(*script_type),
+                &inner[..inner.len().saturating_sub(closing)],
+            ))
+        })
+        .unwrap_or((None, template));
+
+        let multisig = ["multi(", "sortedmulti("].iter().find_map(|multi| {
+            let keys = inner.strip_prefix(multi)?;
+            let (threshold, keys) = keys.split_once(',')?;
+            if keys.contains('(') {
+                return None;
+            }
+            Some(PolicyKind::Multisig {
+                threshold: threshold.parse().ok()?,
+                keys: keys.matches('@').count(),
+                sorted: *multi == "sortedmulti(",
+            })
+        });
+        let kind = match script_type {
+            Some(ScriptType::P2pkh | ScriptType::P2wpkh | ScriptType::P2shP2wpkh)
+                if !inner.contains('(') =>
+            {
+                PolicyKind::SingleSig
+            }
+            Some(ScriptType::P2tr) if !inner.contains(',') => PolicyKind::SingleSig,
+            Some(ScriptType::P2sh | ScriptType::P2wsh | ScriptType::P2shP2wsh)
+                if multisig.is_some() =>
+            {
+                multisig.expect("multisig policy")
+            }
+            _ => PolicyKind::Miniscript,
+        };
+
+        PolicyDescription {
+            name: self.name.clone(),
+            kind,
+            script_type,
+            key_origins: self.keys.iter().map(|key| key.source.clone()).collect(),
+        }
+    }
+
+    /// Returns the id of the wallet policy, the hash of its serialization, computed like the
+    /// device does. It is returned by the device on registration, and can be compared with the
+    /// id of a persisted registration to detect that the policy changed since.
     pub fn id(&self) -> [u8; 32] {
         let mut engine = sha256::Hash::engine();
         engine.input(&self.serialize());
         sha256::Hash::from_engine(engine).into_inner()
     }
+
+    /// Returns the differences between the policy and another one, like the policy of a
+    /// cosigner or a persisted one, empty if they are the same policy. The keys are compared
+    /// at each index, as the template refers to them by their index.
+    pub fn diff(&self, other: &WalletPolicy) -> Vec<PolicyDifference> {
+        let mut differences = Vec::new();
+        if self.name != other.name {
+            differences.push(PolicyDifference::Name);
+        }
+        if self.version != other.version {
+            differences.push(PolicyDifference::Version);
+        }
+        if self.descriptor_template != other.descriptor_template {
+            differences.push(PolicyDifference::Template);
+        }
+        if self.threshold != other.threshold {
+            differences.push(PolicyDifference::Threshold);
+        }
+        if self.keys.len() != other.keys.len() {
+            differences.push(PolicyDifference::KeyCount);
+        }
+        for (index, (key, other_key)) in self.keys.iter().zip(other.keys.iter()).enumerate() {
+            if key.inner != other_key.inner {
+                differences.push(PolicyDifference::KeyPubkey { index });
+            }
+            if key.source != other_key.source {
+                differences.push(PolicyDifference::KeyOrigin { index });
+            }
+            if key.multipath != other_key.multipath {
+                differences.push(PolicyDifference::KeyDerivation { index });
+            }
+        }
+        differences
+    }
 }
 
-#[derive(Debug)]
-pub enum WalletError {
-    InvalidThreshold,
-    UnsupportedAddressType,
-    InvalidPolicy,
+/// A difference between two wallet policies, see `WalletPolicy::diff`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PolicyDifference {
+    Name,
+    Version,
+    Template,
+    Threshold,
+    /// The policies have different numbers of keys, the keys at the indexes of both are
+    /// still compared.
+    KeyCount,
+    /// The extended pubkeys at the index differ.
+    KeyPubkey {
+        index: usize,
+    },
+    /// The origins of the keys at the index differ: their master fingerprint or their
+    /// derivation path from it.
+    KeyOrigin {
+        index: usize,
+    },
+    /// The derivations following the keys at the index in a policy of version 1, like
+    /// `/**`, differ.
+    KeyDerivation {
+        index: usize,
+    },
 }
 
-pub struct WalletPubKey {
-    pub inner: ExtendedPubKey,
-    pub source: Option<KeySource>,
+/// Kind of a wallet policy, see `WalletPolicy::describe`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PolicyKind {
+    /// A single key, without script.
+    SingleSig,
+    /// `threshold` of the `keys` must sign, with the keys sorted in the script if `sorted`.
+    Multisig {
+        threshold: usize,
+        keys: usize,
+        sorted: bool,
+    },
+    /// Any other policy, written in miniscript, including the taproot policies with scripts.
+    Miniscript,
+}
 
-    /// Used by Version V1
-    /// either /** or /<NUM;NUM>/*
-    pub multipath: Option<String>,
+impl core::fmt::Display for PolicyKind {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            PolicyKind::SingleSig => write!(f, "single signature"),
+            PolicyKind::Multisig {
+                threshold, keys, ..
+            } => write!(f, "{} of {} multisig", threshold, keys),
+            PolicyKind::Miniscript => write!(f, "miniscript"),
+        }
+    }
 }
 
-impl From<ExtendedPubKey> for WalletPubKey {
-    fn from(inner: ExtendedPubKey) -> Self {
-        Self {
-            inner,
-            source: None,
-            multipath: None,
+/// Summary of a wallet policy for the user to review it, returned by `WalletPolicy::describe`.
+/// `Display` formats it on a line, like `Cold storage: 2 of 3 multisig, P2WSH`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PolicyDescription {
+    pub name: String,
+    pub kind: PolicyKind,
+    /// Script type of the addresses, None for a template of another type.
+    pub script_type: Option<ScriptType>,
+    /// Origins of the keys, in the order of their placeholders, None for a key without
+    /// origin.
+    pub key_origins: Vec<Option<KeySource>>,
+}
+
+impl PolicyDescription {
+    /// Returns the index of the key of the device with the given master fingerprint,
+    /// like `WalletPolicy::device_key_index`.
+    pub fn device_key(&self, master_fingerprint: Fingerprint) -> Option<usize> {
+        self.key_origins.iter().position(
+            |origin| matches!(origin, Some((fingerprint, _)) if *fingerprint == master_fingerprint),
+        )
+    }
+}
+
+impl core::fmt::Display for PolicyDescription {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        if !self.name.is_empty() {
+            write!(f, "{}: ", self.name)?;
         }
+        write!(f, "{}", self.kind)?;
+        let script_type = match self.script_type {
+            Some(ScriptType::P2pkh) => "P2PKH",
+            Some(ScriptType::P2sh) => "P2SH",
+            Some(ScriptType::P2shP2wpkh) => "P2SH-P2WPKH",
+            Some(ScriptType::P2wpkh) => "P2WPKH",
+            Some(ScriptType::P2wsh) => "P2WSH",
+            Some(ScriptType::P2shP2wsh) => "P2SH-P2WSH",
+            Some(ScriptType::P2tr) => "P2TR",
+            None => return Ok(()),
+        };
+        write!(f, ", {}", script_type)
     }
 }
 
-impl From<(KeySource, ExtendedPubKey)> for WalletPubKey {
-    fn from(source_xpub: (KeySource, ExtendedPubKey)) -> Self {
-        Self {
-            inner: source_xpub.1,
-            source: Some(source_xpub.0),
-            multipath: None,
+/// Returns the checksum of a descriptor defined in BIP-380, or None if the descriptor
+/// contains a character outside of the descriptor character set.
+pub fn descriptor_checksum(desc: &str) -> Option<String> {
+    const INPUT_CHARSET: &str = "0123456789()[],'/*abcdefgh@:$%{}IJKLMNOPQRSTUVWXYZ&+-.;<=>?!^_|~ijklmnopqrstuvwxyzABCDEFGH`#\"\\ ";
+    const CHECKSUM_CHARSET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+    fn polymod(c: u64, val: u64) -> u64 {
+        let c0 = c >> 35;
+        let mut c = ((c & 0x7ffffffff) << 5) ^ val;
+        for (bit, generator) in [
+            0xf5dee51989,
+            0xa9fdca3312,
+            0x1bab10e32d,
+            0x3706b1677a,
+            0x644d626ffd,
+        ]
+        .iter()
+        .enumerate()
+        {
+            if c0 & (1 << bit) != 0 {
+                c ^= generator;
+            }
         }
+        c
     }
+
+    let mut c = 1;
+    let mut cls = 0;
+    let mut clscount = 0;
+    for ch in desc.chars() {
+        let pos = INPUT_CHARSET.find(ch)? as u64;
+        c = polymod(c, pos & 31);
+        cls = cls * 3 + (pos >> 5);
+        clscount += 1;
+        if clscount == 3 {
+            c = polymod(c, cls);
+            cls = 0;
+            clscount = 0;
+        }
+    }
+    if clscount > 0 {
+        c = polymod(c, cls);
+    }
+    for _ in 0..8 {
+        c = polymod(c, 0);
+    }
+    c ^= 1;
+
+    Some(
+        (0..8)
+            .map(|j| CHECKSUM_CHARSET[((c >> (5 * (7 - j))) & 31) as usize] as char)
+            .collect(),
+    )
 }
 
-impl From<(KeySource, ExtendedPubKey, String)> for WalletPubKey {
-    fn from(source_xpub: (KeySource, ExtendedPubKey, String)) -> Self {
-        Self {
-            inner: source_xpub.1,
-            source: Some(source_xpub.0),
-            multipath: Some(source_xpub.2),
+/// Addresses returned by the device without being displayed, by wallet id, change and
+/// address index.
+#[derive(Default)]
+pub(crate) struct AddressCache(std::sync::Mutex<HashMap<AddressKey, bitcoin::Address>>);
+
+/// Wallet id, change and address index of a cached address.
+type AddressKey = ([u8; 32], bool, u32);
+
+impl AddressCache {
+    pub(crate) fn get(&self, id: [u8; 32], change: bool, index: u32) -> Option<bitcoin::Address> {
+        let cache = self.0.lock().ok()?;
+        cache.get(&(id, change, index)).cloned()
+    }
+
+    pub(crate) fn insert(&self, id: [u8; 32], change: bool, index: u32, address: bitcoin::Address) {
+        if let Ok(mut cache) = self.0.lock() {
+            cache.insert((id, change, index), address);
+        }
+    }
+
+    /// Removes the addresses of the wallet with the given id.
+    pub(crate) fn invalidate(&self, id: [u8; 32]) {
+        if let Ok(mut cache) = self.0.lock() {
+            cache.retain(|(wallet_id, _, _), _| *wallet_id != id);
         }
     }
 }
 
-impl FromStr for WalletPubKey {
-    type Err = Error;
+/// Hmac returned by the device on the registration of a wallet policy, to be stored with
+/// the policy and given back to the device with it.
+/// The comparison of two hmacs takes the same time whatever bytes differ.
+/// A stored `Option<WalletHmac>` is passed to `sign_psbt` and `get_wallet_address` with
+/// `hmac.as_ref()`. It dereferences to its raw bytes.
+/// It is formatted and parsed as hex, and serialized as a hex string with the
+/// `serde` feature.
+#[derive(Copy, Clone, Debug)]
+pub struct WalletHmac(pub [u8; 32]);
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        if let Ok(key) = ExtendedPubKey::from_str(s) {
-            Ok(WalletPubKey {
+impl WalletHmac {
+    /// Compares the hmacs without returning early on the first differing byte.
+    pub fn ct_eq(&self, other: &Self) -> bool {
+        self.0
+            .iter()
+            .zip(other.0.iter())
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+            == 0
+    }
+}
+
+impl PartialEq for WalletHmac {
+    fn eq(&self, other: &Self) -> bool {
+        self.ct_eq(other)
+    }
+}
+
+impl Eq for WalletHmac {}
+
+impl core::ops::Deref for WalletHmac {
+    type Target = [u8; 32];
+
+    fn deref(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl From<[u8; 32]> for WalletHmac {
+    fn from(hmac: [u8; 32]) -> Self {
+        WalletHmac(hmac)
+    }
+}
+
+impl From<WalletHmac> for [u8; 32] {
+    fn from(hmac: WalletHmac) -> Self {
+        hmac.0
+    }
+}
+
+impl core::fmt::Display for WalletHmac {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "{}", self.0.to_hex())
+    }
+}
+
+impl FromStr for WalletHmac {
+    type Err = bitcoin::hashes::hex::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        <[u8; 32]>::from_hex(s).map(WalletHmac)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for WalletHmac {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for WalletHmac {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = <std::borrow::Cow<str>>::deserialize(deserializer)?;
+        WalletHmac::from_str(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WalletError {
+    InvalidThreshold,
+    UnsupportedAddressType,
+    InvalidPolicy,
+    /// The name is longer than `MAX_WALLET_NAME_LENGTH` bytes.
+    NameTooLong,
+}
+
+/// Key of a wallet policy, parsed from and formatted to the descriptor key expression
+/// `[fingerprint/path]xpub/**` with `FromStr` and `Display`.
+pub struct WalletPubKey {
+    pub inner: ExtendedPubKey,
+    /// Origin of the key: fingerprint of the master key and derivation path from it.
+    pub source: Option<KeySource>,
+
+    /// Used by Version V1
+    /// either /** or /<NUM;NUM>/*
+    pub multipath: Option<String>,
+}
+
+impl WalletPubKey {
+    /// Returns the fingerprint of the master key the key is derived from, to be compared
+    /// with the master fingerprint of a device to find its key.
+    pub fn origin_fingerprint(&self) -> Option<Fingerprint> {
+        self.source.as_ref().map(|(fingerprint, _)| *fingerprint)
+    }
+}
+
+impl From<ExtendedPubKey> for WalletPubKey {
+    fn from(inner: ExtendedPubKey) -> Self {
+        Self {
+            inner,
+            source: None,
+            multipath: None,
+        }
+    }
+}
+
+impl From<(KeySource, ExtendedPubKey)> for WalletPubKey {
+    fn from(source_xpub: (KeySource, ExtendedPubKey)) -> Self {
+        Self {
+            inner: source_xpub.1,
+            source: Some(source_xpub.0),
+            multipath: None,
+        }
+    }
+}
+
+impl From<(KeySource, ExtendedPubKey, String)> for WalletPubKey {
+    fn from(source_xpub: (KeySource, ExtendedPubKey, String)) -> Self {
+        Self {
+            inner: source_xpub.1,
+            source: Some(source_xpub.0),
+            multipath: Some(source_xpub.2),
+        }
+    }
+}
+
+impl FromStr for WalletPubKey {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(key) = ExtendedPubKey::from_str(s) {
+            Ok(WalletPubKey {
                 inner: key,
                 source: None,
                 multipath: None,
@@ -257,23 +1293,16 @@ impl FromStr for WalletPubKey {
 
 impl core::fmt::Display for WalletPubKey {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        if self.source.is_none() {
-            write!(f, "{}", self.inner)
-        } else {
-            write!(
+        match &self.source {
+            None => write!(f, "{}", self.inner),
+            Some((fingerprint, path)) => write!(
                 f,
                 "[{}{}]{}{}",
-                self.source.as_ref().unwrap().0,
-                self.source
-                    .as_ref()
-                    .unwrap()
-                    .1
-                    .to_string()
-                    .strip_prefix('m')
-                    .unwrap_or(""),
+                fingerprint,
+                path.to_string().strip_prefix('m').unwrap_or(""),
                 self.inner,
                 self.multipath.as_ref().unwrap_or(&"".to_string())
-            )
+            ),
         }
     }
 }
@@ -312,6 +1341,200 @@ mod tests {
         );
         assert_eq!(key.inner, ExtendedPubKey::from_str("tpubDEGquuorgFNb8bjh5kNZQMPtABJzoWwNm78FUmeoPkfRtoPF7JLrtoZeT3J3ybq1HmC3Rn1Q8wFQ8J5usanzups5rj7PJoQLNyvq8QbJruW").unwrap());
         assert_eq!(key.multipath, Some("/**".to_string()));
+        assert_eq!(
+            key.origin_fingerprint(),
+            Some(Fingerprint::from_str("5c9e228d").unwrap())
+        );
+
+        let key = WalletPubKey::from(key.inner);
+        assert_eq!(key.origin_fingerprint(), None);
+    }
+
+    #[test]
+    fn test_version_is_supported_by() {
+        assert!(Version::V1.is_supported_by("2.0.6"));
+        assert!(Version::V2.is_supported_by("2.1.0"));
+        assert!(Version::V2.is_supported_by("2.1.5-rc"));
+        assert!(Version::V2.is_supported_by("3.0.0"));
+        assert!(!Version::V2.is_supported_by("2.0.6"));
+        assert!(!Version::V2.is_supported_by("1.6.0"));
+    }
+
+    #[test]
+    fn test_describe() {
+        let key_a = "[76223a6e/48'/1'/0'/2']tpubDE7NQymr4AFtewpAsWtnreyq9ghkzQBXpCZjWLFVRAvnbf7vya2eMTvT2fPapNqL8SuVvLQdbUbMfWLVDCZKnsEBqp6UK93QEzL8Ck23AwF";
+        let key_b = "[f5acc2fd/48'/1'/0'/2']tpubDFAqEGNyad35aBCKUAXbQGDjdVhNueno5ZZVEn3sQbW5ci457gLR7HyTmHBg93oourBssgUxuWz1jX5uhc1qaqFo9VsybY1J5FuedLfm4dK";
+        let key_c = "tpubDFAqEGNyad35aBCKUAXbQGDjdVhNueno5ZZVEn3sQbW5ci457gLR7HyTmHBg93oourBssgUxuWz1jX5uhc1qaqFo9VsybY1J5FuedLfm4dK";
+        let policy = |name: &str, template: &str, keys: &[&str]| {
+            WalletPolicy::new(
+                name.to_string(),
+                Version::V2,
+                template.to_string(),
+                keys.iter()
+                    .map(|k| WalletPubKey::from_str(k).unwrap())
+                    .collect::<Vec<_>>(),
+            )
+        };
+
+        let description = policy(
+            "Cold storage",
+            "wsh(sortedmulti(2,@0/**,@1/**,@2/**))",
+            &[key_a, key_b, key_c],
+        )
+        .describe();
+        assert_eq!(
+            description.kind,
+            PolicyKind::Multisig {
+                threshold: 2,
+                keys: 3,
+                sorted: true
+            }
+        );
+        assert_eq!(description.script_type, Some(ScriptType::P2wsh));
+        assert_eq!(
+            description.key_origins[1],
+            Some((
+                Fingerprint::from_str("f5acc2fd").unwrap(),
+                DerivationPath::from_str("m/48'/1'/0'/2'").unwrap()
+            ))
+        );
+        assert_eq!(description.key_origins[2], None);
+        assert_eq!(
+            description.device_key(Fingerprint::from_str("f5acc2fd").unwrap()),
+            Some(1)
+        );
+        assert_eq!(
+            description.device_key(Fingerprint::from_str("00000000").unwrap()),
+            None
+        );
+        assert_eq!(
+            description.to_string(),
+            "Cold storage: 2 of 3 multisig, P2WSH"
+        );
+
+        let description = policy("", "sh(multi(1,@0/**,@1/**))", &[key_a, key_b]).describe();
+        assert_eq!(
+            description.kind,
+            PolicyKind::Multisig {
+                threshold: 1,
+                keys: 2,
+                sorted: false
+            }
+        );
+        assert_eq!(description.to_string(), "1 of 2 multisig, P2SH");
+
+        for (template, script_type) in [
+            ("pkh(@0/**)", ScriptType::P2pkh),
+            ("sh(wpkh(@0/**))", ScriptType::P2shP2wpkh),
+            ("wpkh(@0/<0;1>/*)", ScriptType::P2wpkh),
+            ("tr(@0/**)", ScriptType::P2tr),
+        ] {
+            let description = policy("", template, &[key_a]).describe();
+            assert_eq!(description.kind, PolicyKind::SingleSig, "{}", template);
+            assert_eq!(description.script_type, Some(script_type));
+        }
+
+        for (template, script_type) in [
+            (
+                "wsh(or_d(pk(@0/**),and_v(v:pkh(@1/**),older(10))))",
+                Some(ScriptType::P2wsh),
+            ),
+            ("tr(@0/**,pk(@1/**))", Some(ScriptType::P2tr)),
+            (
+                "wsh(thresh(2,pk(@0/**),s:pk(@1/**)))",
+                Some(ScriptType::P2wsh),
+            ),
+            (
+                "sh(wsh(multi(2,@0/**,pk(@1/**))))",
+                Some(ScriptType::P2shP2wsh),
+            ),
+            ("rawtr(@0/**)", None),
+        ] {
+            let description = policy("Vault", template, &[key_a, key_b]).describe();
+            assert_eq!(description.kind, PolicyKind::Miniscript, "{}", template);
+            assert_eq!(description.script_type, script_type);
+        }
+        assert_eq!(
+            policy("Vault", "wsh(or_d(pk(@0/**),pk(@1/**)))", &[key_a, key_b])
+                .describe()
+                .to_string(),
+            "Vault: miniscript, P2WSH"
+        );
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let capabilities = Capabilities::of_app_version("2.0.6");
+        assert!(!capabilities.wallet_policy_v2 && !capabilities.miniscript);
+        assert!(capabilities.taproot && !capabilities.taproot_scripts);
+        assert!(!Capabilities::of_app_version("2.1.1").taproot_scripts);
+        assert!(Capabilities::of_app_version("2.1.2-rc").taproot_scripts);
+        assert!(Capabilities::of_app_version("3.0").taproot_scripts);
+        assert!(Capabilities::of_app_version("unknown").taproot_scripts);
+
+        let key = "[f5acc2fd/48'/1'/0'/2']tpubDFAqEGNyad35aBCKUAXbQGDjdVhNueno5ZZVEn3sQbW5ci457gLR7HyTmHBg93oourBssgUxuWz1jX5uhc1qaqFo9VsybY1J5FuedLfm4dK";
+        let policy = |version, template: &str| {
+            WalletPolicy::new(
+                "Wallet".to_string(),
+                version,
+                template.to_string(),
+                vec![
+                    WalletPubKey::from_str(key).unwrap(),
+                    WalletPubKey::from_str(key).unwrap(),
+                ],
+            )
+        };
+        let v2_0 = Capabilities::of_app_version("2.0.6");
+        let v2_1 = Capabilities::of_app_version("2.1.1");
+        for template in [
+            "pkh(@0)",
+            "sh(wpkh(@0))",
+            "wsh(sortedmulti(2,@0,@1))",
+            "sh(wsh(multi(2,@0,@1)))",
+            "tr(@0)",
+        ] {
+            assert_eq!(v2_0.missing_for(&policy(Version::V1, template)), None);
+        }
+        assert_eq!(
+            v2_0.missing_for(&policy(Version::V1, "wsh(pkh(@0))")),
+            Some("miniscript")
+        );
+        assert_eq!(
+            v2_0.missing_for(&policy(Version::V2, "wpkh(@0/**)")),
+            Some("wallet policy v2")
+        );
+        assert_eq!(
+            v2_1.missing_for(&policy(
+                Version::V2,
+                "wsh(or_d(pk(@0/**),and_v(v:pkh(@1/**),older(100))))"
+            )),
+            None
+        );
+        assert_eq!(
+            v2_1.missing_for(&policy(Version::V2, "tr(@0/**,pk(@1/**))")),
+            Some("taproot scripts")
+        );
+        assert_eq!(
+            Capabilities::of_app_version("2.1.2")
+                .missing_for(&policy(Version::V2, "tr(@0/**,pk(@1/**))")),
+            None
+        );
+    }
+
+    #[test]
+    fn test_signing_limits() {
+        let old = SigningLimits::of_app_version("2.0.3");
+        assert_eq!(old, SigningLimits::BEFORE_2_0_4);
+        assert!(old.allows(64, 32));
+        assert!(!old.allows(65, 1));
+        assert!(!old.allows(1, 33));
+
+        for version in ["2.0.4", "2.1.2-rc", "unknown"] {
+            let limits = SigningLimits::of_app_version(version);
+            assert_eq!(limits.max_inputs, crate::psbt::MAX_N_INPUTS_CAN_SIGN);
+            assert!(limits.allows(512, 1000));
+            assert!(!limits.allows(513, 1));
+        }
     }
 
     #[test]
@@ -334,6 +1557,78 @@ mod tests {
         assert_eq!(wallet.serialize().as_slice().to_hex(), "020c436f6c642073746f726167651fb56c3d5542fa09b3956834a9ff6a1df5c36a38e5b02c63c54b41a9a04403b82602516d2c50a89476ecffeec658057f0110674bbfafc18797dc480c7ed53802f3fb");
     }
 
+    #[test]
+    fn test_backup() {
+        let keys = || {
+            vec![
+                WalletPubKey::from_str("[76223a6e/48'/1'/0'/2']tpubDE7NQymr4AFtewpAsWtnreyq9ghkzQBXpCZjWLFVRAvnbf7vya2eMTvT2fPapNqL8SuVvLQdbUbMfWLVDCZKnsEBqp6UK93QEzL8Ck23AwF").unwrap(),
+                WalletPubKey::from_str("[f5acc2fd/48'/1'/0'/2']tpubDFAqEGNyad35aBCKUAXbQGDjdVhNueno5ZZVEn3sQbW5ci457gLR7HyTmHBg93oourBssgUxuWz1jX5uhc1qaqFo9VsybY1J5FuedLfm4dK").unwrap(),
+            ]
+        };
+        for wallet in [
+            WalletPolicy::new(
+                "Cold storage".to_string(),
+                Version::V2,
+                "wsh(sortedmulti(2,@0/**,@1/**))".to_string(),
+                keys(),
+            ),
+            WalletPolicy::new(
+                "Cold storage".to_string(),
+                Version::V1,
+                "wsh(sortedmulti(2,@0,@1))".to_string(),
+                keys(),
+            ),
+        ] {
+            let backup = wallet.to_backup();
+            assert!(backup.starts_with(&wallet.serialize()));
+            let restored = WalletPolicy::from_backup(&backup).unwrap();
+            assert_eq!(restored.name, wallet.name);
+            assert_eq!(restored.version, wallet.version);
+            assert_eq!(restored.descriptor_template, wallet.descriptor_template);
+            assert_eq!(
+                restored
+                    .keys
+                    .iter()
+                    .map(|k| k.to_string())
+                    .collect::<Vec<_>>(),
+                wallet
+                    .keys
+                    .iter()
+                    .map(|k| k.to_string())
+                    .collect::<Vec<_>>()
+            );
+            assert_eq!(restored.id(), wallet.id());
+
+            assert!(matches!(
+                WalletPolicy::from_backup(&backup[..backup.len() - 1]),
+                Err(WalletError::InvalidPolicy)
+            ));
+            let mut trailing = backup.clone();
+            trailing.push(0);
+            assert!(matches!(
+                WalletPolicy::from_backup(&trailing),
+                Err(WalletError::InvalidPolicy)
+            ));
+        }
+
+        // the template of another policy than the serialized one
+        let wallet = |template: &str| {
+            WalletPolicy::new(
+                "Cold storage".to_string(),
+                Version::V2,
+                template.to_string(),
+                keys(),
+            )
+        };
+        let other = wallet("wsh(sortedmulti(1,@0/**,@1/**))");
+        let mut tampered = wallet("wsh(sortedmulti(2,@0/**,@1/**))").serialize();
+        tampered.extend_from_slice(&other.to_backup()[other.serialize().len()..]);
+        assert!(matches!(
+            WalletPolicy::from_backup(&tampered),
+            Err(WalletError::InvalidPolicy)
+        ));
+    }
+
     #[test]
     fn test_get_descriptor() {
         let wallet = WalletPolicy::new(
@@ -348,5 +1643,560 @@ mod tests {
 
         assert_eq!(wallet.get_descriptor(false).unwrap(), "wsh(sortedmulti(2,[76223a6e/48'/1'/0'/2']tpubDE7NQymr4AFtewpAsWtnreyq9ghkzQBXpCZjWLFVRAvnbf7vya2eMTvT2fPapNqL8SuVvLQdbUbMfWLVDCZKnsEBqp6UK93QEzL8Ck23AwF/0/*,[f5acc2fd/48'/1'/0'/2']tpubDFAqEGNyad35aBCKUAXbQGDjdVhNueno5ZZVEn3sQbW5ci457gLR7HyTmHBg93oourBssgUxuWz1jX5uhc1qaqFo9VsybY1J5FuedLfm4dK/12/*))");
         assert_eq!(wallet.get_descriptor(true).unwrap(), "wsh(sortedmulti(2,[76223a6e/48'/1'/0'/2']tpubDE7NQymr4AFtewpAsWtnreyq9ghkzQBXpCZjWLFVRAvnbf7vya2eMTvT2fPapNqL8SuVvLQdbUbMfWLVDCZKnsEBqp6UK93QEzL8Ck23AwF/1/*,[f5acc2fd/48'/1'/0'/2']tpubDFAqEGNyad35aBCKUAXbQGDjdVhNueno5ZZVEn3sQbW5ci457gLR7HyTmHBg93oourBssgUxuWz1jX5uhc1qaqFo9VsybY1J5FuedLfm4dK/3/*))");
+
+        let wallet = WalletPolicy::new(
+            "Cold storage".to_string(),
+            Version::V2,
+            "wsh(sortedmulti(2,@0/<0;1>/*,@1/<12;3>/*))".to_string(),
+            vec![
+               WalletPubKey::from_str("[76223a6e/48'/1'/0'/2']tpubDE7NQymr4AFtewpAsWtnreyq9ghkzQBXpCZjWLFVRAvnbf7vya2eMTvT2fPapNqL8SuVvLQdbUbMfWLVDCZKnsEBqp6UK93QEzL8Ck23AwF").unwrap(),
+               WalletPubKey::from_str("[f5acc2fd/48'/1'/0'/2']tpubDFAqEGNyad35aBCKUAXbQGDjdVhNueno5ZZVEn3sQbW5ci457gLR7HyTmHBg93oourBssgUxuWz1jX5uhc1qaqFo9VsybY1J5FuedLfm4dK").unwrap(),
+            ],
+        );
+
+        assert_eq!(wallet.get_descriptor(true).unwrap(), "wsh(sortedmulti(2,[76223a6e/48'/1'/0'/2']tpubDE7NQymr4AFtewpAsWtnreyq9ghkzQBXpCZjWLFVRAvnbf7vya2eMTvT2fPapNqL8SuVvLQdbUbMfWLVDCZKnsEBqp6UK93QEzL8Ck23AwF/1/*,[f5acc2fd/48'/1'/0'/2']tpubDFAqEGNyad35aBCKUAXbQGDjdVhNueno5ZZVEn3sQbW5ci457gLR7HyTmHBg93oourBssgUxuWz1jX5uhc1qaqFo9VsybY1J5FuedLfm4dK/3/*))");
+    }
+
+    #[test]
+    fn test_descriptor_checksum() {
+        // test vectors of BIP-380
+        assert_eq!(
+            descriptor_checksum("raw(deadbeef)").as_deref(),
+            Some("89f8spxm")
+        );
+        assert_eq!(descriptor_checksum("raw(Ü)"), None);
+
+        let wallet = WalletPolicy::new(
+            "Cold storage".to_string(),
+            Version::V2,
+            "wsh(sortedmulti(2,@0/**,@1/**))".to_string(),
+            vec![
+               WalletPubKey::from_str("[76223a6e/48'/1'/0'/2']tpubDE7NQymr4AFtewpAsWtnreyq9ghkzQBXpCZjWLFVRAvnbf7vya2eMTvT2fPapNqL8SuVvLQdbUbMfWLVDCZKnsEBqp6UK93QEzL8Ck23AwF").unwrap(),
+               WalletPubKey::from_str("[f5acc2fd/48'/1'/0'/2']tpubDFAqEGNyad35aBCKUAXbQGDjdVhNueno5ZZVEn3sQbW5ci457gLR7HyTmHBg93oourBssgUxuWz1jX5uhc1qaqFo9VsybY1J5FuedLfm4dK").unwrap(),
+            ],
+        );
+        let (receive, change) = wallet.display_descriptors().unwrap();
+        assert!(receive.starts_with(&format!("{}#", wallet.get_descriptor(false).unwrap())));
+        assert!(change.starts_with(&format!("{}#", wallet.get_descriptor(true).unwrap())));
+
+        #[cfg(feature = "paranoid_client")]
+        {
+            assert_eq!(receive, wallet.descriptor(false).unwrap().to_string());
+            assert_eq!(change, wallet.descriptor(true).unwrap().to_string());
+        }
+    }
+
+    #[test]
+    fn test_to_watch_descriptor() {
+        let wallet = WalletPolicy::new(
+            "Segwit account".to_string(),
+            Version::V2,
+            "wpkh(@0/**)".to_string(),
+            vec![WalletPubKey::from_str("[f5acc2fd/84'/1'/0']tpubDCtKfsNyRhULjZ9XMS4VKKtVcPdVDi8MKUbcSD9MJDyjRu1A2ND5MiipozyyspBT9bg8upEp7a8EAgFxNxXn1d7QkdbL52Ty5jiSLcxPt1P").unwrap()],
+        );
+        assert_eq!(wallet.to_watch_descriptor().unwrap(), "wpkh([f5acc2fd/84'/1'/0']tpubDCtKfsNyRhULjZ9XMS4VKKtVcPdVDi8MKUbcSD9MJDyjRu1A2ND5MiipozyyspBT9bg8upEp7a8EAgFxNxXn1d7QkdbL52Ty5jiSLcxPt1P/<0;1>/*)#02nd8n34");
+
+        let wallet = WalletPolicy::new(
+            "Cold storage".to_string(),
+            Version::V2,
+            "wsh(sortedmulti(2,@0/**,@1/<12;3>/*))".to_string(),
+            vec![
+               WalletPubKey::from_str("[76223a6e/48'/1'/0'/2']tpubDE7NQymr4AFtewpAsWtnreyq9ghkzQBXpCZjWLFVRAvnbf7vya2eMTvT2fPapNqL8SuVvLQdbUbMfWLVDCZKnsEBqp6UK93QEzL8Ck23AwF").unwrap(),
+               WalletPubKey::from_str("[f5acc2fd/48'/1'/0'/2']tpubDFAqEGNyad35aBCKUAXbQGDjdVhNueno5ZZVEn3sQbW5ci457gLR7HyTmHBg93oourBssgUxuWz1jX5uhc1qaqFo9VsybY1J5FuedLfm4dK").unwrap(),
+            ],
+        );
+        let desc = wallet.to_watch_descriptor().unwrap();
+        assert!(desc.starts_with("wsh(sortedmulti(2,[76223a6e/48'/1'/0'/2']tpubDE7NQymr4AFtewpAsWtnreyq9ghkzQBXpCZjWLFVRAvnbf7vya2eMTvT2fPapNqL8SuVvLQdbUbMfWLVDCZKnsEBqp6UK93QEzL8Ck23AwF/<0;1>/*,[f5acc2fd/48'/1'/0'/2']tpubDFAqEGNyad35aBCKUAXbQGDjdVhNueno5ZZVEn3sQbW5ci457gLR7HyTmHBg93oourBssgUxuWz1jX5uhc1qaqFo9VsybY1J5FuedLfm4dK/<12;3>/*))#"));
+    }
+
+    #[test]
+    fn test_wallet_hmac() {
+        let hmac = WalletHmac::from_str(
+            "d6434852fb3caa7edbd1165084968f1691444b3cfc10cf1e431acbbc7f48451f",
+        )
+        .unwrap();
+        assert_eq!(
+            hmac.to_string(),
+            "d6434852fb3caa7edbd1165084968f1691444b3cfc10cf1e431acbbc7f48451f"
+        );
+        assert_eq!(hmac, WalletHmac(hmac.0));
+        let mut other = hmac.0;
+        other[31] ^= 0x01;
+        assert_ne!(hmac, WalletHmac::from(other));
+        assert!(WalletHmac::from_str("d6434852").is_err());
+        assert_eq!(Some(hmac).as_deref(), Some(&hmac.0));
+
+        #[cfg(feature = "serde")]
+        {
+            let json = serde_json::to_string(&hmac).unwrap();
+            assert_eq!(
+                json,
+                "\"d6434852fb3caa7edbd1165084968f1691444b3cfc10cf1e431acbbc7f48451f\""
+            );
+            assert_eq!(serde_json::from_str::<WalletHmac>(&json).unwrap(), hmac);
+        }
+    }
+
+    #[cfg(feature = "paranoid_client")]
+    #[test]
+    fn test_address_iter() {
+        let wallet = WalletPolicy::new(
+            "Cold storage".to_string(),
+            Version::V2,
+            "wsh(sortedmulti(2,@0/**,@1/**))".to_string(),
+            vec![
+               WalletPubKey::from_str("[76223a6e/48'/1'/0'/2']tpubDE7NQymr4AFtewpAsWtnreyq9ghkzQBXpCZjWLFVRAvnbf7vya2eMTvT2fPapNqL8SuVvLQdbUbMfWLVDCZKnsEBqp6UK93QEzL8Ck23AwF").unwrap(),
+               WalletPubKey::from_str("[f5acc2fd/48'/1'/0'/2']tpubDFAqEGNyad35aBCKUAXbQGDjdVhNueno5ZZVEn3sQbW5ci457gLR7HyTmHBg93oourBssgUxuWz1jX5uhc1qaqFo9VsybY1J5FuedLfm4dK").unwrap(),
+            ],
+        );
+
+        let addresses: Vec<Address> = wallet
+            .address_iter(false, 0, Network::Testnet)
+            .take(3)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            addresses[0].to_string(),
+            "tb1qmyauyzn08cduzdqweexgna2spwd0rndj55fsrkefry2cpuyt4cpsn2pg28"
+        );
+        assert_ne!(addresses[0], addresses[1]);
+
+        let second = wallet
+            .address_iter(false, 1, Network::Testnet)
+            .next()
+            .unwrap()
+            .unwrap();
+        assert_eq!(second, addresses[1]);
+
+        assert_eq!(
+            wallet
+                .address_iter(false, BIP32_HARDENED_OFFSET - 1, Network::Testnet)
+                .count(),
+            1
+        );
+
+        for (index, address) in addresses.iter().enumerate() {
+            assert_eq!(
+                wallet
+                    .address_at(false, index as u32, Network::Testnet)
+                    .unwrap(),
+                *address
+            );
+        }
+        assert_ne!(
+            wallet.address_at(true, 0, Network::Testnet).unwrap(),
+            addresses[0]
+        );
+        assert!(matches!(
+            wallet.address_at(false, BIP32_HARDENED_OFFSET, Network::Testnet),
+            Err(WalletError::InvalidPolicy)
+        ));
+    }
+
+    #[test]
+    fn test_normalize() {
+        let key_a = "[76223a6e/48'/1'/0'/2']tpubDE7NQymr4AFtewpAsWtnreyq9ghkzQBXpCZjWLFVRAvnbf7vya2eMTvT2fPapNqL8SuVvLQdbUbMfWLVDCZKnsEBqp6UK93QEzL8Ck23AwF";
+        let key_b = "[f5acc2fd/48'/1'/0'/2']tpubDFAqEGNyad35aBCKUAXbQGDjdVhNueno5ZZVEn3sQbW5ci457gLR7HyTmHBg93oourBssgUxuWz1jX5uhc1qaqFo9VsybY1J5FuedLfm4dK";
+        let policy = |template: &str, keys: [&str; 2]| {
+            WalletPolicy::new(
+                "Cold storage".to_string(),
+                Version::V2,
+                template.to_string(),
+                keys.iter()
+                    .map(|k| WalletPubKey::from_str(k).unwrap())
+                    .collect::<Vec<_>>(),
+            )
+        };
+
+        let mut ordered = policy("wsh(sortedmulti(2,@0/**,@1/<12;3>/*))", [key_a, key_b]);
+        let mut reversed = policy("wsh(sortedmulti(2,@0/<12;3>/*,@1/**))", [key_b, key_a]);
+        assert_ne!(ordered.id(), reversed.id());
+        assert_eq!(
+            ordered.get_descriptor(true).unwrap(),
+            "wsh(sortedmulti(2,".to_string() + key_a + "/1/*," + key_b + "/3/*))"
+        );
+
+        ordered.normalize();
+        reversed.normalize();
+        assert_eq!(
+            reversed.descriptor_template,
+            "wsh(sortedmulti(2,@0/**,@1/<12;3>/*))"
+        );
+        assert_eq!(reversed.keys[0].to_string(), key_a);
+        assert_eq!(reversed.keys[1].to_string(), key_b);
+        assert_eq!(ordered.id(), reversed.id());
+        assert_eq!(
+            reversed.get_descriptor(true).unwrap(),
+            ordered.get_descriptor(true).unwrap()
+        );
+
+        // the order of the keys of a multi matters
+        let mut multi = policy("wsh(multi(2,@0/**,@1/**))", [key_b, key_a]);
+        multi.normalize();
+        assert_eq!(multi.keys[0].to_string(), key_b);
+        // only the keys of a sortedmulti are reordered
+        let mut nested = policy("tr(@0/**,sortedmulti_a(1,@1/**))", [key_b, key_a]);
+        nested.normalize();
+        assert_eq!(
+            nested.descriptor_template,
+            "tr(@0/**,sortedmulti_a(1,@1/**))"
+        );
+        assert_eq!(nested.keys[0].to_string(), key_b);
+    }
+
+    #[cfg(feature = "paranoid_client")]
+    #[test]
+    fn test_from_descriptor() {
+        let key_a = "[76223a6e/48'/1'/0'/2']tpubDE7NQymr4AFtewpAsWtnreyq9ghkzQBXpCZjWLFVRAvnbf7vya2eMTvT2fPapNqL8SuVvLQdbUbMfWLVDCZKnsEBqp6UK93QEzL8Ck23AwF";
+        let key_b = "[f5acc2fd/48'/1'/0'/2']tpubDFAqEGNyad35aBCKUAXbQGDjdVhNueno5ZZVEn3sQbW5ci457gLR7HyTmHBg93oourBssgUxuWz1jX5uhc1qaqFo9VsybY1J5FuedLfm4dK";
+
+        let wallet = WalletPolicy::from_descriptor(
+            "Timelocked".to_string(),
+            &format!(
+                "wsh(or_d(pk({}/<0;1>/*),and_v(v:pkh({}/**),older(144))))#abcdefgh",
+                key_b, key_a
+            ),
+        )
+        .unwrap();
+        assert_eq!(wallet.version, Version::V2);
+        assert_eq!(
+            wallet.descriptor_template,
+            "wsh(or_d(pk(@0/<0;1>/*),and_v(v:pkh(@1/**),older(144))))"
+        );
+        assert_eq!(wallet.keys[0].to_string(), key_b);
+        assert_eq!(wallet.keys[1].to_string(), key_a);
+        assert!(wallet
+            .address_iter(false, 0, Network::Testnet)
+            .next()
+            .unwrap()
+            .is_ok());
+
+        let wallet = WalletPolicy::from_descriptor(
+            "Cold storage".to_string(),
+            &format!("wsh(sortedmulti(2,{}/**,{}/**))", key_a, key_b),
+        )
+        .unwrap();
+        assert_eq!(
+            wallet
+                .address_iter(false, 0, Network::Testnet)
+                .next()
+                .unwrap()
+                .unwrap()
+                .to_string(),
+            "tb1qmyauyzn08cduzdqweexgna2spwd0rndj55fsrkefry2cpuyt4cpsn2pg28"
+        );
+
+        // keys must be ranged
+        assert!(matches!(
+            WalletPolicy::from_descriptor("".to_string(), &format!("wpkh({})", key_b)),
+            Err(WalletError::InvalidPolicy)
+        ));
+        // not a valid miniscript
+        assert!(matches!(
+            WalletPolicy::from_descriptor("".to_string(), &format!("wsh(older({}/**))", key_b)),
+            Err(WalletError::InvalidPolicy)
+        ));
+    }
+
+    #[test]
+    fn test_script_type() {
+        assert_eq!(
+            AddressType::NestedSegwit.script_type(true),
+            ScriptType::P2shP2wsh
+        );
+        assert_eq!(AddressType::Legacy.script_type(false), ScriptType::P2pkh);
+        assert_eq!(
+            ScriptType::P2shP2wpkh.descriptor_fragment("@0/**"),
+            "sh(wpkh(@0/**))"
+        );
+        assert!(ScriptType::P2shP2wsh.is_segwit());
+        assert!(!ScriptType::P2sh.is_segwit());
+        assert!(ScriptType::P2tr.is_taproot());
+        assert_eq!(ScriptType::P2pkh.dust_limit(), 72_800);
+        assert_eq!(ScriptType::P2shP2wpkh.dust_limit(), 72_000);
+        assert_eq!(ScriptType::P2wpkh.dust_limit(), 39_200);
+        assert_eq!(ScriptType::P2tr.dust_limit(), 44_000);
+
+        let address = |s: &str| bitcoin::Address::from_str(s).unwrap();
+        assert_eq!(
+            ScriptType::of(&address(
+                "tb1qmyauyzn08cduzdqweexgna2spwd0rndj55fsrkefry2cpuyt4cpsn2pg28"
+            )),
+            Some(ScriptType::P2wsh)
+        );
+        assert_eq!(
+            ScriptType::of(&address(
+                "tb1pws8wvnj99ca6acf8kq7pjk7vyxknah0d9mexckh5s0vu2ccy68js9am6u7"
+            )),
+            Some(ScriptType::P2tr)
+        );
+        assert_eq!(
+            ScriptType::of(&address("mkHS9ne12qx9pS9VojpwU5xtRd4T7X7ZUt")),
+            Some(ScriptType::P2pkh)
+        );
+
+        let keys = || {
+            vec![
+                WalletPubKey::from_str(MASTER_KEY_EXAMPLE).unwrap(),
+                WalletPubKey::from_str(KEY_EXAMPLE).unwrap(),
+            ]
+        };
+        let wallet = WalletPolicy::new_multisig(
+            "Cold storage".to_string(),
+            Version::V2,
+            AddressType::NestedSegwit,
+            2,
+            keys(),
+            true,
+        )
+        .unwrap();
+        assert_eq!(
+            wallet.descriptor_template,
+            "sh(wsh(sortedmulti(2,@0/**,@1/**)))"
+        );
+        assert!(matches!(
+            WalletPolicy::new_multisig(
+                "Cold storage".to_string(),
+                Version::V2,
+                AddressType::Taproot,
+                2,
+                keys(),
+                true,
+            ),
+            Err(WalletError::UnsupportedAddressType)
+        ));
+    }
+
+    #[test]
+    fn test_is_standard() {
+        let wallet = WalletPolicy::new(
+            "".to_string(),
+            Version::V2,
+            "tr(@0/**)".to_string(),
+            vec![WalletPubKey::from_str("[f5acc2fd/86'/1'/0']tpubDDKYE6BREvDsSWMazgHoyQWiJwYaDDYPbCFjYxN3HFXJP5fokeiK4hwK5tTLBNEDBwrDXn8cQ4v9b2xdW62Xr5yxoQdMu1v6c7UDXYVH27U").unwrap()],
+        );
+        assert!(wallet.is_standard());
+
+        let wallet = WalletPolicy::new(
+            "".to_string(),
+            Version::V2,
+            "wpkh(@0/**)".to_string(),
+            vec![WalletPubKey::from_str("[f5acc2fd/86'/1'/0']tpubDDKYE6BREvDsSWMazgHoyQWiJwYaDDYPbCFjYxN3HFXJP5fokeiK4hwK5tTLBNEDBwrDXn8cQ4v9b2xdW62Xr5yxoQdMu1v6c7UDXYVH27U").unwrap()],
+        );
+        assert!(!wallet.is_standard());
+
+        let wallet = WalletPolicy::new(
+            "Cold storage".to_string(),
+            Version::V2,
+            "wsh(sortedmulti(2,@0/**,@1/**))".to_string(),
+            vec![
+               WalletPubKey::from_str("[76223a6e/48'/1'/0'/2']tpubDE7NQymr4AFtewpAsWtnreyq9ghkzQBXpCZjWLFVRAvnbf7vya2eMTvT2fPapNqL8SuVvLQdbUbMfWLVDCZKnsEBqp6UK93QEzL8Ck23AwF").unwrap(),
+               WalletPubKey::from_str("[f5acc2fd/48'/1'/0'/2']tpubDFAqEGNyad35aBCKUAXbQGDjdVhNueno5ZZVEn3sQbW5ci457gLR7HyTmHBg93oourBssgUxuWz1jX5uhc1qaqFo9VsybY1J5FuedLfm4dK").unwrap(),
+            ],
+        );
+        assert!(!wallet.is_standard());
+    }
+
+    #[test]
+    fn test_to_version() {
+        let keys = [
+            "[76223a6e/48'/1'/0'/2']tpubDE7NQymr4AFtewpAsWtnreyq9ghkzQBXpCZjWLFVRAvnbf7vya2eMTvT2fPapNqL8SuVvLQdbUbMfWLVDCZKnsEBqp6UK93QEzL8Ck23AwF",
+            "[f5acc2fd/48'/1'/0'/2']tpubDFAqEGNyad35aBCKUAXbQGDjdVhNueno5ZZVEn3sQbW5ci457gLR7HyTmHBg93oourBssgUxuWz1jX5uhc1qaqFo9VsybY1J5FuedLfm4dK",
+        ];
+        let v2 = WalletPolicy::new(
+            "Cold storage".to_string(),
+            Version::V2,
+            "wsh(sortedmulti(2,@0/**,@1/**))".to_string(),
+            keys.iter().map(|key| WalletPubKey::from_str(key).unwrap()),
+        );
+        let v2_hex = "020c436f6c642073746f726167651fb56c3d5542fa09b3956834a9ff6a1df5c36a38e5b02c63c54b41a9a04403b82602516d2c50a89476ecffeec658057f0110674bbfafc18797dc480c7ed53802f3fb";
+        // the serialization registered by the app before 2.1.0, see command::test_register_wallet
+        let v1_hex = "010c436f6c642073746f726167651977736828736f727465646d756c746928322c40302c4031292902ab6711da5c7daaaba19169f693013ef5139eee794d4997acb8a40d1c25f87838";
+        assert_eq!(v2.serialize().to_hex(), v2_hex);
+
+        let v1 = v2.to_version(Version::V1).unwrap();
+        assert_eq!(v1.descriptor_template, "wsh(sortedmulti(2,@0,@1))");
+        assert_eq!(v1.keys[1].to_string(), format!("{}/**", keys[1]));
+        assert_eq!(v1.serialize().to_hex(), v1_hex);
+        assert_eq!(v1.get_descriptor(true), v2.get_descriptor(true));
+
+        let back = v1.to_version(Version::V2).unwrap();
+        assert_eq!(back.descriptor_template, v2.descriptor_template);
+        assert_eq!(back.serialize().to_hex(), v2_hex);
+        assert_eq!(
+            v1.to_version(Version::V1).unwrap().serialize().to_hex(),
+            v1_hex
+        );
+
+        // a key used twice has a derivation per use
+        let reused = WalletPolicy::new(
+            "".to_string(),
+            Version::V2,
+            "wsh(or_d(pk(@0/<0;1>/*),pk(@0/<2;3>/*)))".to_string(),
+            vec![WalletPubKey::from_str(keys[0]).unwrap()],
+        );
+        assert_eq!(
+            reused.to_version(Version::V1).err(),
+            Some(WalletError::InvalidPolicy)
+        );
+        let out_of_keys = WalletPolicy::new(
+            "".to_string(),
+            Version::V2,
+            "wpkh(@1/**)".to_string(),
+            vec![WalletPubKey::from_str(keys[0]).unwrap()],
+        );
+        assert_eq!(
+            out_of_keys.to_version(Version::V1).err(),
+            Some(WalletError::InvalidPolicy)
+        );
+
+        assert_eq!(Version::latest_supported_by("2.0.6"), Version::V1);
+        assert_eq!(Version::latest_supported_by("2.1.0"), Version::V2);
+    }
+
+    #[test]
+    fn test_diff() {
+        let policy = |keys: &[&str]| {
+            WalletPolicy::new(
+                "Cold storage".to_string(),
+                Version::V2,
+                "wsh(sortedmulti(2,@0/**,@1/**))".to_string(),
+                keys.iter().map(|key| WalletPubKey::from_str(key).unwrap()),
+            )
+        };
+        let keys = [
+            "[76223a6e/48'/1'/0'/2']tpubDE7NQymr4AFtewpAsWtnreyq9ghkzQBXpCZjWLFVRAvnbf7vya2eMTvT2fPapNqL8SuVvLQdbUbMfWLVDCZKnsEBqp6UK93QEzL8Ck23AwF",
+            "[f5acc2fd/48'/1'/0'/2']tpubDFAqEGNyad35aBCKUAXbQGDjdVhNueno5ZZVEn3sQbW5ci457gLR7HyTmHBg93oourBssgUxuWz1jX5uhc1qaqFo9VsybY1J5FuedLfm4dK",
+        ];
+        let wallet = policy(&keys);
+        assert_eq!(wallet.diff(&policy(&keys)), []);
+
+        // the same keys, the second one with another derivation path
+        let tampered = policy(&[
+            keys[0],
+            "[f5acc2fd/48'/1'/1'/2']tpubDFAqEGNyad35aBCKUAXbQGDjdVhNueno5ZZVEn3sQbW5ci457gLR7HyTmHBg93oourBssgUxuWz1jX5uhc1qaqFo9VsybY1J5FuedLfm4dK",
+        ]);
+        assert_eq!(
+            wallet.diff(&tampered),
+            [PolicyDifference::KeyOrigin { index: 1 }]
+        );
+
+        let mut other = policy(&[keys[1]]);
+        other.name = "Hot storage".to_string();
+        other.threshold = Some(1);
+        assert_eq!(
+            wallet.diff(&other),
+            [
+                PolicyDifference::Name,
+                PolicyDifference::Threshold,
+                PolicyDifference::KeyCount,
+                PolicyDifference::KeyPubkey { index: 0 },
+                PolicyDifference::KeyOrigin { index: 0 },
+            ]
+        );
+        assert_eq!(
+            wallet.diff(&wallet.to_version(Version::V1).unwrap()),
+            [
+                PolicyDifference::Version,
+                PolicyDifference::Template,
+                PolicyDifference::KeyDerivation { index: 0 },
+                PolicyDifference::KeyDerivation { index: 1 },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let key = "[f5acc2fd/84'/1'/0']tpubDCtKfsNyRhULjZ9XMS4VKKtVcPdVDi8MKUbcSD9MJDyjRu1A2ND5MiipozyyspBT9bg8upEp7a8EAgFxNxXn1d7QkdbL52Ty5jiSLcxPt1P";
+        let mut wallet = WalletPolicy::new(
+            "a".repeat(MAX_WALLET_NAME_LENGTH),
+            Version::V2,
+            "wpkh(@0/**)".to_string(),
+            vec![WalletPubKey::from_str(key).unwrap()],
+        );
+        assert_eq!(wallet.validate(), Ok(()));
+        wallet.name.push('a');
+        assert_eq!(wallet.validate(), Err(WalletError::NameTooLong));
+    }
+
+    #[test]
+    fn test_new_single_sig() {
+        // The account 0 of the BIP86 test vectors, of the mnemonic "abandon ... about".
+        let key = "[73c5da0a/86'/0'/0']xpub6BgBgsespWvERF3LHQu6CnqdvfEvtMcQjYrcRzx53QJjSxarj2afYWcLteoGVky7D3UKDP9QyrLprQ3VCECoY49yfdDEHGCtMMj92pReUsQ";
+        let wallet = WalletPolicy::new_single_sig(
+            AddressType::Taproot,
+            WalletPubKey::from_str(key).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(wallet.descriptor_template, "tr(@0/**)");
+        assert_eq!(wallet.name, "");
+        assert_eq!(wallet.version, Version::V2);
+        assert_eq!(wallet.keys[0].to_string(), key);
+        assert!(wallet.is_standard());
+
+        #[cfg(feature = "paranoid_client")]
+        for (change, index, address, qtum_address) in [
+            (
+                false,
+                0,
+                "bc1p5cyxnuxmeuwuvkwfem96lqzszd02n6xdcjrs20cac6yqjjwudpxqkedrcr",
+                "qc1p5cyxnuxmeuwuvkwfem96lqzszd02n6xdcjrs20cac6yqjjwudpxq9v4s20",
+            ),
+            (
+                false,
+                1,
+                "bc1p4qhjn9zdvkux4e44uhx8tc55attvtyu358kutcqkudyccelu0was9fqzwh",
+                "qc1p4qhjn9zdvkux4e44uhx8tc55attvtyu358kutcqkudyccelu0waskuc3um",
+            ),
+        ] {
+            let derived = wallet.address_at(change, index, Network::Bitcoin).unwrap();
+            assert_eq!(derived.to_string(), address);
+            assert_eq!(
+                crate::bip32::QtumNetwork::Mainnet
+                    .address(&derived.script_pubkey())
+                    .unwrap(),
+                qtum_address
+            );
+        }
+
+        let key = "[f5acc2fd/84'/1'/0']tpubDCtKfsNyRhULjZ9XMS4VKKtVcPdVDi8MKUbcSD9MJDyjRu1A2ND5MiipozyyspBT9bg8upEp7a8EAgFxNxXn1d7QkdbL52Ty5jiSLcxPt1P";
+        let wallet = WalletPolicy::new_single_sig(
+            AddressType::NativeSegwit,
+            WalletPubKey::from_str(key).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(wallet.descriptor_template, "wpkh(@0/**)");
+        assert!(matches!(
+            WalletPolicy::new_single_sig(
+                AddressType::Taproot,
+                WalletPubKey::from_str(key).unwrap()
+            ),
+            Err(WalletError::InvalidPolicy)
+        ));
+    }
+
+    #[test]
+    fn test_device_key_index() {
+        let wallet = WalletPolicy::new(
+            "Cold storage".to_string(),
+            Version::V2,
+            "wsh(sortedmulti(2,@0/**,@1/**))".to_string(),
+            vec![
+               WalletPubKey::from_str("[76223a6e/48'/1'/0'/2']tpubDE7NQymr4AFtewpAsWtnreyq9ghkzQBXpCZjWLFVRAvnbf7vya2eMTvT2fPapNqL8SuVvLQdbUbMfWLVDCZKnsEBqp6UK93QEzL8Ck23AwF").unwrap(),
+               WalletPubKey::from_str("[f5acc2fd/48'/1'/0'/2']tpubDFAqEGNyad35aBCKUAXbQGDjdVhNueno5ZZVEn3sQbW5ci457gLR7HyTmHBg93oourBssgUxuWz1jX5uhc1qaqFo9VsybY1J5FuedLfm4dK").unwrap(),
+            ],
+        );
+        assert_eq!(
+            wallet.device_key_index(Fingerprint::from_str("f5acc2fd").unwrap()),
+            Some(1)
+        );
+        assert_eq!(
+            wallet.device_key_index(Fingerprint::from_str("76223a6e").unwrap()),
+            Some(0)
+        );
+        assert_eq!(
+            wallet.device_key_index(Fingerprint::from_str("5c9e228d").unwrap()),
+            None
+        );
     }
 }
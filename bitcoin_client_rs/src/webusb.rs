@@ -0,0 +1,170 @@
+//! Transport to a Ledger device connected with WebUSB, for wallets running in a browser
+//! or a web extension compiled to WebAssembly.
+//!
+//! The WebUSB API of `web-sys` is unstable: the crate must be built with
+//! `RUSTFLAGS=--cfg=web_sys_unstable_apis`.
+//!
+//! The device is obtained by the page with `navigator.usb.requestDevice`, which requires a
+//! user gesture, filtering on the Ledger vendor id `0x2c97`.
+
+use core::convert::TryFrom;
+use core::fmt::Debug;
+
+use async_lock::Mutex;
+use async_trait::async_trait;
+use js_sys::Uint8Array;
+use send_wrapper::SendWrapper;
+use wasm_bindgen::{JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{UsbAlternateInterface, UsbDevice, UsbInTransferResult, UsbInterface};
+
+use crate::{
+    apdu::{decode_response, APDUCommand, StatusWord},
+    async_client::Transport,
+    hid::{frame, unframe, HidError, PACKET_SIZE},
+};
+
+/// Vendor id of the Ledger devices.
+pub const LEDGER_VENDOR_ID: u16 = 0x2c97;
+
+/// Class of the USB interface exchanging the APDUs.
+const VENDOR_INTERFACE_CLASS: u8 = 0xff;
+/// Endpoint of the USB interface exchanging the APDUs, both in and out.
+const ENDPOINT: u8 = 3;
+
+#[derive(Debug)]
+pub enum WebUsbError {
+    /// A call to the WebUSB API failed, with the description of the JavaScript error.
+    Js(String),
+    /// The device has no interface exchanging APDUs.
+    NoInterface,
+    /// The device answered with a packet of another size than a HID packet.
+    InvalidPacket(Vec<u8>),
+    /// The packets of the device do not frame a response.
+    Hid(HidError),
+    /// The response is shorter than a status word.
+    InvalidResponse(Vec<u8>),
+}
+
+impl From<JsValue> for WebUsbError {
+    fn from(e: JsValue) -> Self {
+        WebUsbError::Js(format!("{:?}", e))
+    }
+}
+
+/// Transport exchanging the APDUs with the device over WebUSB, wrapped in the HID framing
+/// of the Ledger devices.
+/// JavaScript values cannot be shared between threads: the transport must be used from the
+/// thread that opened it, which is always the case in a browser.
+pub struct WebUsbTransport {
+    device: SendWrapper<UsbDevice>,
+    interface: u8,
+    // An exchange spans several transfers that must not interleave with another one.
+    exchange: Mutex<()>,
+}
+
+impl WebUsbTransport {
+    /// Opens the device and claims its interface exchanging the APDUs.
+    pub async fn open(device: UsbDevice) -> Result<Self, WebUsbError> {
+        wait(device.open()).await?;
+        if device.configuration().is_none() {
+            wait(device.select_configuration(1)).await?;
+        }
+        let interface = device
+            .configuration()
+            .ok_or(WebUsbError::NoInterface)?
+            .interfaces()
+            .iter()
+            .map(|interface| interface.unchecked_into::<UsbInterface>())
+            .find(|interface| {
+                interface.alternates().iter().any(|alternate| {
+                    alternate
+                        .unchecked_into::<UsbAlternateInterface>()
+                        .interface_class()
+                        == VENDOR_INTERFACE_CLASS
+                })
+            })
+            .ok_or(WebUsbError::NoInterface)?
+            .interface_number();
+        wait(device.claim_interface(interface)).await?;
+        Ok(Self {
+            device: SendWrapper::new(device),
+            interface,
+            exchange: Mutex::new(()),
+        })
+    }
+
+    /// Releases the interface and closes the device.
+    pub async fn close(self) -> Result<(), WebUsbError> {
+        wait(self.device.release_interface(self.interface)).await?;
+        wait(self.device.close()).await?;
+        Ok(())
+    }
+
+    async fn send(&self, apdu: &[u8]) -> Result<(), WebUsbError> {
+        for packet in frame(apdu) {
+            let packet = Uint8Array::from(&packet[..]);
+            wait(
+                self.device
+                    .transfer_out_with_buffer_source(ENDPOINT, &packet)?,
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    async fn receive(&self) -> Result<Vec<u8>, WebUsbError> {
+        let mut packets = Vec::new();
+        loop {
+            let packet = self.read_packet().await?;
+            packets.push(
+                <[u8; PACKET_SIZE]>::try_from(packet.as_slice())
+                    .map_err(|_| WebUsbError::InvalidPacket(packet))?,
+            );
+            match unframe(&packets) {
+                Err(HidError::Incomplete { .. }) => continue,
+                res => return res.map_err(WebUsbError::Hid),
+            }
+        }
+    }
+
+    async fn read_packet(&self) -> Result<Vec<u8>, WebUsbError> {
+        let result: UsbInTransferResult =
+            wait(self.device.transfer_in(ENDPOINT, PACKET_SIZE as u32))
+                .await?
+                .unchecked_into();
+        let data = result
+            .data()
+            .ok_or(WebUsbError::InvalidPacket(Vec::new()))?;
+        Ok(Uint8Array::new_with_byte_offset_and_length(
+            &data.buffer(),
+            data.byte_offset() as u32,
+            data.byte_length() as u32,
+        )
+        .to_vec())
+    }
+}
+
+#[async_trait]
+impl Transport for WebUsbTransport {
+    type Error = WebUsbError;
+
+    async fn exchange(&self, command: &APDUCommand) -> Result<(StatusWord, Vec<u8>), Self::Error> {
+        let _exchange = self.exchange.lock().await;
+        self.send(&command.encode()).await?;
+        let response = self.receive().await?;
+        decode_response(&response).ok_or(WebUsbError::InvalidResponse(response))
+    }
+
+    /// The WebUSB API rejects the transfers to an unplugged device with a `NotFoundError`.
+    fn is_disconnected(&self, error: &Self::Error) -> bool {
+        matches!(error, WebUsbError::Js(e) if e.contains("NotFoundError"))
+    }
+}
+
+/// Waits for the promise, in a future that can be held by the `Send` futures of the
+/// async client. It must be polled from the thread that created the promise.
+async fn wait(promise: impl JsCast) -> Result<JsValue, WebUsbError> {
+    let promise = promise.unchecked_into::<js_sys::Promise>();
+    Ok(SendWrapper::new(JsFuture::from(promise)).await?)
+}
@@ -3,10 +3,24 @@ use std::str::FromStr;
 
 use bitcoin::{
     consensus::encode::deserialize,
-    hashes::hex::{FromHex, ToHex},
+    hashes::{
+        hex::{FromHex, ToHex},
+        Hash,
+    },
     util::{bip32::DerivationPath, psbt::Psbt},
 };
-use ledger_bitcoin_client::{async_client, client, psbt::PartialSignature, wallet};
+use ledger_bitcoin_client::{
+    apdu::{Stats, StatusWord},
+    async_client,
+    bip32::QtumNetwork,
+    client,
+    error::BitcoinClientError,
+    interpreter::InterpreterError,
+    message, psbt,
+    psbt::PartialSignature,
+    transport::SimTransport,
+    wallet,
+};
 
 fn test_cases(path: &str) -> Vec<serde_json::Value> {
     let data = std::fs::read_to_string(path).expect("Unable to read file");
@@ -41,6 +55,75 @@ async fn test_get_version() {
     assert_eq!(flags, vec![0x00]);
 }
 
+#[tokio::test]
+async fn test_get_version_truncated() {
+    for response in [
+        // the version is one byte shorter than its length
+        "01095174756d205465737405322e312e9000",
+        // nothing after the name
+        "01095174756d20546573749000",
+        // the flags are missing
+        "01095174756d205465737405322e312e359000",
+    ] {
+        let exchanges: Vec<String> = vec!["=> b001000000".into(), format!("<= {}", response)];
+        let store = utils::RecordStore::new(&exchanges);
+        let res =
+            client::BitcoinClient::new(utils::TransportReplayer::new(store.clone())).get_version();
+        assert!(matches!(
+            res,
+            Err(BitcoinClientError::UnexpectedResult { .. })
+        ));
+        let res = async_client::BitcoinClient::new(utils::TransportReplayer::new(store))
+            .get_version()
+            .await;
+        assert!(matches!(
+            res,
+            Err(BitcoinClientError::UnexpectedResult { .. })
+        ));
+    }
+}
+
+#[tokio::test]
+async fn test_get_master_fingerprint_wrong_length() {
+    let exchanges: Vec<String> = vec!["=> e105000100".into(), "<= f5acc29000".into()];
+
+    let store = utils::RecordStore::new(&exchanges);
+    let res = client::BitcoinClient::new(utils::TransportReplayer::new(store.clone()))
+        .get_master_fingerprint();
+    assert!(matches!(
+        res,
+        Err(BitcoinClientError::UnexpectedResult { command: 0x05, data }) if data.len() == 3
+    ));
+
+    let res = async_client::BitcoinClient::new(utils::TransportReplayer::new(store.clone()))
+        .get_master_fingerprint()
+        .await;
+    assert!(matches!(
+        res,
+        Err(BitcoinClientError::UnexpectedResult { command: 0x05, .. })
+    ));
+}
+
+#[tokio::test]
+async fn test_with_cla() {
+    let exchanges: Vec<String> = vec!["=> e005000100".into(), "<= f5acc2fd9000".into()];
+
+    let store = utils::RecordStore::new(&exchanges);
+    let fingerprint = client::BitcoinClient::new(utils::TransportReplayer::new(store.clone()))
+        .with_cla(0xE0)
+        .get_master_fingerprint()
+        .unwrap();
+    assert_eq!(fingerprint.to_hex(), "f5acc2fd");
+
+    let fingerprint =
+        async_client::BitcoinClient::new(utils::TransportReplayer::new(store.clone()))
+            .with_cla(0xE0)
+            .get_master_fingerprint()
+            .await
+            .unwrap();
+    assert_eq!(fingerprint.to_hex(), "f5acc2fd");
+}
+
 #[tokio::test]
 async fn test_sign_message() {
     let exchanges: Vec<String> = vec![
@@ -93,7 +176,7 @@ async fn test_get_extended_pubkey() {
         let derivation_path: DerivationPath = case
             .get("derivation_path")
             .map(|v| v.as_str().unwrap())
-            .map(|s| DerivationPath::from_str(&s).unwrap())
+            .map(|s| DerivationPath::from_str(s).unwrap())
             .unwrap();
 
         let display: bool = case
@@ -122,6 +205,33 @@ async fn test_get_extended_pubkey() {
     }
 }
 
+#[tokio::test]
+async fn test_get_extended_pubkey_invalid() {
+    let path = DerivationPath::from_str("m/44'/0'/0'/0/0").unwrap();
+    // An invalid xpub fails to parse, bytes that are not ASCII are returned unparsed.
+    for (response, parse) in [("78707562", true), ("ff", false)] {
+        let exchanges: Vec<String> = vec![
+            "=> e10000011600058000002c80000000800000000000000000000000".into(),
+            format!("<= {}9000", response),
+        ];
+        let store = utils::RecordStore::new(&exchanges);
+        let res = client::BitcoinClient::new(utils::TransportReplayer::new(store.clone()))
+            .get_extended_pubkey(&path, false);
+        assert_eq!(matches!(res, Err(BitcoinClientError::Parse(_))), parse);
+
+        let res = async_client::BitcoinClient::new(utils::TransportReplayer::new(store))
+            .get_extended_pubkey(&path, false)
+            .await;
+        assert_eq!(matches!(res, Err(BitcoinClientError::Parse(_))), parse);
+        if !parse {
+            assert!(matches!(
+                res,
+                Err(BitcoinClientError::UnexpectedResult { data, .. }) if data == [0xff]
+            ));
+        }
+    }
+}
+
 #[tokio::test]
 async fn test_register_wallet() {
     for case in test_cases("./tests/data/register_wallet.json") {
@@ -185,6 +295,31 @@ async fn test_register_wallet() {
     }
 }
 
+#[tokio::test]
+async fn test_register_wallet_name_too_long() {
+    let key = "[f5acc2fd/84'/1'/0']tpubDCtKfsNyRhULjZ9XMS4VKKtVcPdVDi8MKUbcSD9MJDyjRu1A2ND5MiipozyyspBT9bg8upEp7a8EAgFxNxXn1d7QkdbL52Ty5jiSLcxPt1P";
+    let wallet = wallet::WalletPolicy::new(
+        "a".repeat(wallet::MAX_WALLET_NAME_LENGTH + 1),
+        wallet::Version::V2,
+        "wpkh(@0/**)".to_string(),
+        vec![wallet::WalletPubKey::from_str(key).unwrap()],
+    );
+
+    // No exchange is recorded: the policy must be refused before being sent.
+    let store = utils::RecordStore::new(&[]);
+    assert!(matches!(
+        client::BitcoinClient::new(utils::TransportReplayer::new(store.clone()))
+            .register_wallet(&wallet),
+        Err(BitcoinClientError::InvalidPolicy("name too long"))
+    ));
+    assert!(matches!(
+        async_client::BitcoinClient::new(utils::TransportReplayer::new(store))
+            .register_wallet(&wallet)
+            .await,
+        Err(BitcoinClientError::InvalidPolicy("name too long"))
+    ));
+}
+
 #[tokio::test]
 async fn test_get_wallet_address() {
     for case in test_cases("./tests/data/get_wallet_address.json") {
@@ -217,11 +352,7 @@ async fn test_get_wallet_address() {
             .get("hmac")
             .map(|v| serde_json::from_value(v.clone()).unwrap())
             .unwrap();
-        let hmac = hmac.map(|s| {
-            let mut h = [b'\0'; 32];
-            h.copy_from_slice(&Vec::from_hex(&s).unwrap());
-            h
-        });
+        let hmac = hmac.map(|s| wallet::WalletHmac::from_str(&s).unwrap());
 
         let change: bool = case
             .get("change")
@@ -263,6 +394,7 @@ async fn test_get_wallet_address() {
 }
 
 #[tokio::test]
+#[allow(clippy::single_match)]
 async fn test_sign_psbt() {
     for case in test_cases("./tests/data/sign_psbt.json") {
         let exchanges: Vec<String> = case
@@ -294,11 +426,7 @@ async fn test_sign_psbt() {
             .get("hmac")
             .map(|v| serde_json::from_value(v.clone()).unwrap())
             .unwrap();
-        let hmac = hmac.map(|s| {
-            let mut h = [b'\0'; 32];
-            h.copy_from_slice(&Vec::from_hex(&s).unwrap());
-            h
-        });
+        let hmac = hmac.map(|s| wallet::WalletHmac::from_str(&s).unwrap());
 
         let sigs: Vec<serde_json::Value> = case
             .get("sigs")
@@ -357,3 +485,3273 @@ async fn test_sign_psbt() {
         }
     }
 }
+
+#[test]
+fn test_build_commitments() {
+    for case in test_cases("./tests/data/sign_psbt.json") {
+        let wallet = wallet_from_case(&case);
+        let psbt: Psbt =
+            deserialize(&base64::decode(case["psbt"].as_str().unwrap()).unwrap()).unwrap();
+        // The command recorded with the device, after its header.
+        let command = Vec::from_hex(&case["exchanges"][0].as_str().unwrap()[3..]).unwrap();
+
+        let commitments = psbt::build_commitments(&psbt, &wallet).unwrap();
+        assert_eq!(commitments.input_commitments.len(), psbt.inputs.len());
+        assert_eq!(commitments.output_commitments.len(), psbt.outputs.len());
+        let mut data = commitments.global_mapping_commitment.clone();
+        data.extend(bitcoin::consensus::encode::serialize(&bitcoin::VarInt(
+            psbt.inputs.len() as u64,
+        )));
+        data.extend(commitments.input_commitments_root);
+        data.extend(bitcoin::consensus::encode::serialize(&bitcoin::VarInt(
+            psbt.outputs.len() as u64,
+        )));
+        data.extend(commitments.output_commitments_root);
+        data.extend(commitments.wallet_id);
+        assert_eq!(command[5..5 + data.len()], data);
+    }
+
+    let case = test_cases("./tests/data/sign_psbt.json").remove(0);
+    let mut psbt: Psbt =
+        deserialize(&base64::decode(case["psbt"].as_str().unwrap()).unwrap()).unwrap();
+    psbt.outputs.pop();
+    assert!(matches!(
+        psbt::build_commitments(&psbt, &wallet_from_case(&case)),
+        Err(psbt::CommitmentsError::OutputMismatch { .. })
+    ));
+
+    // An input of another participant, spending a legacy output with only its witness utxo.
+    let wallet = wallet_from_case(&case);
+    let mut psbt: Psbt =
+        deserialize(&base64::decode(case["psbt"].as_str().unwrap()).unwrap()).unwrap();
+    psbt.unsigned_tx.input.push(bitcoin::TxIn {
+        previous_output: bitcoin::OutPoint::new(bitcoin::Txid::all_zeros(), 1),
+        ..Default::default()
+    });
+    psbt.inputs.push(bitcoin::util::psbt::Input {
+        witness_utxo: Some(bitcoin::TxOut {
+            value: 10_000,
+            script_pubkey: bitcoin::Script::from(
+                Vec::from_hex("76a914331e3bb02f5f3a6d7e2b0c3d7d2b1c2a3d4e5f6088ac").unwrap(),
+            ),
+        }),
+        ..Default::default()
+    });
+    let external = psbt.inputs.len() - 1;
+    assert!(!psbt::is_internal_input(&psbt.inputs[external], &wallet));
+    assert!(psbt::build_commitments(&psbt, &wallet).is_ok());
+
+    // The same input with the key origin of a key of the wallet requires the non-witness utxo.
+    let key = &wallet.keys[0];
+    let (fingerprint, path) = key.source.clone().unwrap();
+    let path = path
+        .child(bitcoin::util::bip32::ChildNumber::Normal { index: 0 })
+        .child(bitcoin::util::bip32::ChildNumber::Normal { index: 0 });
+    psbt.inputs[external]
+        .bip32_derivation
+        .insert(key.inner.public_key, (fingerprint, path));
+    assert!(psbt::is_internal_input(&psbt.inputs[external], &wallet));
+    assert!(matches!(
+        psbt::build_commitments(&psbt, &wallet),
+        Err(psbt::CommitmentsError::MissingUtxo {
+            input,
+            missing: psbt::MissingUtxo::NonWitnessUtxo,
+        }) if input == external
+    ));
+
+    psbt.inputs[external].witness_utxo = None;
+    psbt.inputs[external].bip32_derivation.clear();
+    assert!(matches!(
+        psbt::build_commitments(&psbt, &wallet),
+        Err(psbt::CommitmentsError::MissingUtxo {
+            missing: psbt::MissingUtxo::Any,
+            ..
+        })
+    ));
+}
+
+#[tokio::test]
+async fn test_sign_psbt_pinned() {
+    let case = test_cases("./tests/data/sign_psbt.json").remove(0);
+    let exchanges: Vec<String> = case
+        .get("exchanges")
+        .map(|v| serde_json::from_value(v.clone()).unwrap())
+        .unwrap();
+
+    let name: String = case
+        .get("name")
+        .map(|v| serde_json::from_value(v.clone()).unwrap())
+        .unwrap();
+
+    let policy: String = case
+        .get("policy")
+        .map(|v| serde_json::from_value(v.clone()).unwrap())
+        .unwrap();
+
+    let keys: Vec<wallet::WalletPubKey> = case
+        .get("keys")
+        .map(|v| serde_json::from_value::<Vec<String>>(v.clone()).unwrap())
+        .unwrap()
+        .iter()
+        .map(|s| wallet::WalletPubKey::from_str(s).unwrap())
+        .collect();
+
+    let psbt_str: String = case
+        .get("psbt")
+        .map(|v| serde_json::from_value(v.clone()).unwrap())
+        .unwrap();
+
+    let psbt: Psbt = deserialize(&base64::decode(&psbt_str).unwrap()).unwrap();
+    let wallet = wallet::WalletPolicy::new(name, wallet::Version::V2, policy, keys);
+
+    let sighashes: Vec<(usize, [u8; 32])> = (0..psbt.inputs.len())
+        .map(|i| (i, psbt::signature_hash(&psbt, i).unwrap()))
+        .collect();
+
+    let store = utils::RecordStore::new(&exchanges);
+    let res = client::BitcoinClient::new(utils::TransportReplayer::new(store.clone()))
+        .sign_psbt_pinned(&psbt, &wallet, None, &sighashes)
+        .unwrap();
+    assert!(!res.is_empty());
+
+    let res = async_client::BitcoinClient::new(utils::TransportReplayer::new(store.clone()))
+        .sign_psbt_pinned(&psbt, &wallet, None, &sighashes)
+        .await
+        .unwrap();
+    assert!(!res.is_empty());
+
+    let mut tampered = sighashes.clone();
+    tampered[0].1[0] ^= 0x01;
+
+    let store = utils::RecordStore::new(&[]);
+    let res = client::BitcoinClient::new(utils::TransportReplayer::new(store.clone()))
+        .sign_psbt_pinned(&psbt, &wallet, None, &tampered);
+    assert!(matches!(
+        res,
+        Err(BitcoinClientError::PsbtModified { input: 0 })
+    ));
+
+    let res = async_client::BitcoinClient::new(utils::TransportReplayer::new(store.clone()))
+        .sign_psbt_pinned(&psbt, &wallet, None, &tampered)
+        .await;
+    assert!(matches!(
+        res,
+        Err(BitcoinClientError::PsbtModified { input: 0 })
+    ));
+}
+
+#[tokio::test]
+async fn test_sign_psbt_blind_signing_disabled() {
+    let case = test_cases("./tests/data/sign_psbt.json").remove(0);
+    let policy: String = case
+        .get("policy")
+        .map(|v| serde_json::from_value(v.clone()).unwrap())
+        .unwrap();
+
+    let keys: Vec<wallet::WalletPubKey> = case
+        .get("keys")
+        .map(|v| serde_json::from_value::<Vec<String>>(v.clone()).unwrap())
+        .unwrap()
+        .iter()
+        .map(|s| wallet::WalletPubKey::from_str(s).unwrap())
+        .collect();
+
+    let psbt_str: String = case
+        .get("psbt")
+        .map(|v| serde_json::from_value(v.clone()).unwrap())
+        .unwrap();
+
+    let mut psbt: Psbt = deserialize(&base64::decode(&psbt_str).unwrap()).unwrap();
+    // OP_4 <gas limit> <gas price> <data> <contract address> OP_CALL
+    psbt.unsigned_tx.output[0].script_pubkey = bitcoin::Script::from(
+        Vec::from_hex("540390d00301280461e14c7814c0a8ee4a8bc2ec1d6e4cc5fc0b4e55e0bc5ee8bac2")
+            .unwrap(),
+    );
+    let wallet = wallet::WalletPolicy::new("".to_string(), wallet::Version::V2, policy, keys);
+
+    let exchanges: Vec<String> = vec![
+        "=> e1040001c305519b38dae74447b72151f354cb138ca3591a5ff8ac813289b18a004e3132162086d8d9498a323006ec5982eeb4ea7c41d27020d57985512ab59ff8f40d50150701185a2fac562419c1ce8ed936d13cfe9ca4be0bec1a0d84f8ce433763fc6d41d502a8a1eccfe6f817670fb93883e690ed32097ebc3b6b63fc50f7d939f027eaeb92be693418eee0c522b55f74c62b6ecad9697ef1e6e8bb973ff29740432960db480000000000000000000000000000000000000000000000000000000000000000".into(),
+        "<= b008".into(),
+    ];
+
+    let store = utils::RecordStore::new(&exchanges);
+    let res = client::BitcoinClient::new(utils::TransportReplayer::new(store.clone()))
+        .sign_psbt(&psbt, &wallet, None);
+    assert!(matches!(res, Err(BitcoinClientError::BlindSigningDisabled)));
+
+    let res = async_client::BitcoinClient::new(utils::TransportReplayer::new(store.clone()))
+        .sign_psbt(&psbt, &wallet, None)
+        .await;
+    assert!(matches!(res, Err(BitcoinClientError::BlindSigningDisabled)));
+}
+
+#[tokio::test]
+async fn test_get_wallet_address_hmac_required() {
+    let wallet = wallet::WalletPolicy::new(
+        "Cold storage".to_string(),
+        wallet::Version::V2,
+        "wsh(sortedmulti(2,@0/**,@1/**))".to_string(),
+        vec![
+            wallet::WalletPubKey::from_str("[76223a6e/48'/1'/0'/2']tpubDE7NQymr4AFtewpAsWtnreyq9ghkzQBXpCZjWLFVRAvnbf7vya2eMTvT2fPapNqL8SuVvLQdbUbMfWLVDCZKnsEBqp6UK93QEzL8Ck23AwF").unwrap(),
+            wallet::WalletPubKey::from_str("[f5acc2fd/48'/1'/0'/2']tpubDFAqEGNyad35aBCKUAXbQGDjdVhNueno5ZZVEn3sQbW5ci457gLR7HyTmHBg93oourBssgUxuWz1jX5uhc1qaqFo9VsybY1J5FuedLfm4dK").unwrap(),
+        ],
+    );
+
+    let store = utils::RecordStore::new(&[]);
+    let res = client::BitcoinClient::new(utils::TransportReplayer::new(store.clone()))
+        .get_wallet_address(&wallet, None, false, 0, false);
+    assert!(matches!(res, Err(BitcoinClientError::HmacRequired)));
+
+    let res = async_client::BitcoinClient::new(utils::TransportReplayer::new(store.clone()))
+        .get_wallet_address(&wallet, None, false, 0, false)
+        .await;
+    assert!(matches!(res, Err(BitcoinClientError::HmacRequired)));
+}
+
+#[tokio::test]
+async fn test_get_wallet_address_type_mismatch() {
+    let cases = test_cases("./tests/data/get_wallet_address.json");
+    let case = &cases[0];
+    let wallet = wallet_from_case(case);
+    let mut exchanges: Vec<String> = serde_json::from_value(case["exchanges"].clone()).unwrap();
+    // tr(@0/**) wallet displayed on the device, which answers with the P2WSH address of another test case.
+    *exchanges.last_mut().unwrap() = cases[1]["exchanges"]
+        .as_array()
+        .unwrap()
+        .last()
+        .unwrap()
+        .as_str()
+        .unwrap()
+        .to_string();
+
+    let store = utils::RecordStore::new(&exchanges);
+    let res = client::BitcoinClient::new(utils::TransportReplayer::new(store.clone()))
+        .get_wallet_address(&wallet, None, false, 0, true);
+    assert!(matches!(
+        res,
+        Err(BitcoinClientError::AddressTypeMismatch {
+            expected: Some(bitcoin::AddressType::P2tr),
+            device: Some(bitcoin::AddressType::P2wsh),
+        })
+    ));
+
+    let res = async_client::BitcoinClient::new(utils::TransportReplayer::new(store.clone()))
+        .get_wallet_address(&wallet, None, false, 0, true)
+        .await;
+    assert!(matches!(
+        res,
+        Err(BitcoinClientError::AddressTypeMismatch { .. })
+    ));
+}
+
+#[tokio::test]
+async fn test_extra_preimages() {
+    let case = &test_cases("./tests/data/get_wallet_address.json")[0];
+    let wallet = wallet_from_case(case);
+    let preimage = b"hashlock secret".to_vec();
+    let hash = bitcoin::hashes::sha256::Hash::hash(&preimage);
+    let first: Vec<String> = serde_json::from_value(case["exchanges"].clone()).unwrap();
+    // The device requests the preimage, then the user rejects the address.
+    let exchanges = vec![
+        first[0].clone(),
+        format!("<= 4000{}e000", hash.to_hex()),
+        format!("=> f8010001110f0f{}", preimage.to_hex()),
+        "<= 6985".to_string(),
+    ];
+
+    let store = utils::RecordStore::new(&exchanges);
+    let res = client::BitcoinClient::new(utils::TransportReplayer::new(store.clone()))
+        .with_extra_preimages(vec![preimage.clone()])
+        .get_wallet_address(&wallet, None, false, 0, true);
+    assert!(matches!(
+        res,
+        Err(BitcoinClientError::Device {
+            status: StatusWord::Deny,
+            ..
+        })
+    ));
+
+    let res = async_client::BitcoinClient::new(utils::TransportReplayer::new(store.clone()))
+        .with_extra_preimages(vec![preimage])
+        .get_wallet_address(&wallet, None, false, 0, true)
+        .await;
+    assert!(matches!(
+        res,
+        Err(BitcoinClientError::Device {
+            status: StatusWord::Deny,
+            ..
+        })
+    ));
+
+    let res = client::BitcoinClient::new(utils::TransportReplayer::new(store.clone()))
+        .get_wallet_address(&wallet, None, false, 0, true);
+    assert!(matches!(
+        res,
+        Err(BitcoinClientError::Interpreter(
+            InterpreterError::UnknownHash
+        ))
+    ));
+}
+
+#[tokio::test]
+async fn test_unknown_client_command() {
+    let case = &test_cases("./tests/data/get_wallet_address.json")[0];
+    let wallet = wallet_from_case(case);
+    let exchanges: Vec<String> = serde_json::from_value(case["exchanges"].clone()).unwrap();
+    // The device interrupts the command with a client command code it does not know yet.
+    let exchanges = vec![exchanges[0].clone(), "<= 7fe000".to_string()];
+
+    let store = utils::RecordStore::new(&exchanges);
+    let res = client::BitcoinClient::new(utils::TransportReplayer::new(store.clone()))
+        .get_wallet_address(&wallet, None, false, 0, true);
+    assert!(matches!(
+        res,
+        Err(BitcoinClientError::UnknownClientCommand(0x7f))
+    ));
+    assert!(res
+        .unwrap_err()
+        .to_string()
+        .contains("unknown client command 0x7f"));
+
+    let res = async_client::BitcoinClient::new(utils::TransportReplayer::new(store.clone()))
+        .get_wallet_address(&wallet, None, false, 0, true)
+        .await;
+    assert!(matches!(
+        res,
+        Err(BitcoinClientError::UnknownClientCommand(0x7f))
+    ));
+}
+
+#[tokio::test]
+async fn test_get_wallet_address_regtest() {
+    let case = &test_cases("./tests/data/get_wallet_address.json")[1];
+    let wallet = wallet_from_case(case);
+    let hmac = wallet::WalletHmac::from_str(case["hmac"].as_str().unwrap()).unwrap();
+    let expected = bitcoin::Address::from_str(case["address"].as_str().unwrap()).unwrap();
+    let exchanges = |network: QtumNetwork| -> Vec<String> {
+        let mut exchanges: Vec<String> = serde_json::from_value(case["exchanges"].clone()).unwrap();
+        // The app answers with the Qtum address of the network.
+        let address = network.address(&expected.script_pubkey()).unwrap();
+        *exchanges.last_mut().unwrap() = format!("<= {}9000", address.as_bytes().to_hex());
+        exchanges
+    };
+
+    let regtest = QtumNetwork::regtest();
+    let store = utils::RecordStore::new(&exchanges(regtest));
+    let address = client::BitcoinClient::new(utils::TransportReplayer::new(store.clone()))
+        .with_network(regtest)
+        .get_wallet_address(&wallet, Some(&hmac), false, 0, true)
+        .unwrap();
+    assert_eq!(address.script_pubkey(), expected.script_pubkey());
+    assert_eq!(address.network, bitcoin::Network::Regtest);
+
+    let address = async_client::BitcoinClient::new(utils::TransportReplayer::new(store.clone()))
+        .with_network(regtest)
+        .get_wallet_address(&wallet, Some(&hmac), false, 0, true)
+        .await
+        .unwrap();
+    assert_eq!(address.script_pubkey(), expected.script_pubkey());
+
+    // An app of the mainnet plugged in the regtest setup.
+    let store = utils::RecordStore::new(&exchanges(QtumNetwork::Mainnet));
+    let res = client::BitcoinClient::new(utils::TransportReplayer::new(store.clone()))
+        .with_network(regtest)
+        .get_wallet_address(&wallet, Some(&hmac), false, 0, true);
+    assert!(matches!(
+        res,
+        Err(BitcoinClientError::NetworkMismatch { address }) if address.starts_with("qc1")
+    ));
+
+    let res = async_client::BitcoinClient::new(utils::TransportReplayer::new(store.clone()))
+        .with_network(regtest)
+        .get_wallet_address(&wallet, Some(&hmac), false, 0, true)
+        .await;
+    assert!(matches!(
+        res,
+        Err(BitcoinClientError::NetworkMismatch { .. })
+    ));
+}
+
+#[tokio::test]
+async fn test_unsupported_by_firmware() {
+    let cases = test_cases("./tests/data/get_wallet_address.json");
+    for (case, taproot) in [(&cases[0], true), (&cases[1], false)] {
+        let wallet = wallet_from_case(case);
+        let exchanges: Vec<String> = serde_json::from_value(case["exchanges"].clone()).unwrap();
+        // An older firmware rejects the first command of the wallet.
+        let exchanges = vec![exchanges[0].clone(), "<= 6d00".to_string()];
+        let hmac = hmac_from_case(case);
+
+        let store = utils::RecordStore::new(&exchanges);
+        let res = client::BitcoinClient::new(utils::TransportReplayer::new(store.clone()))
+            .get_wallet_address(&wallet, hmac.as_ref(), false, 0, true);
+        if taproot {
+            assert!(matches!(
+                res,
+                Err(BitcoinClientError::UnsupportedByFirmware { feature: "taproot" })
+            ));
+        } else {
+            assert!(matches!(
+                res,
+                Err(BitcoinClientError::Device {
+                    status: StatusWord::InsNotSupported,
+                    ..
+                })
+            ));
+        }
+
+        let res = async_client::BitcoinClient::new(utils::TransportReplayer::new(store.clone()))
+            .get_wallet_address(&wallet, hmac.as_ref(), false, 0, true)
+            .await;
+        assert_eq!(
+            matches!(
+                res,
+                Err(BitcoinClientError::UnsupportedByFirmware { feature: "taproot" })
+            ),
+            taproot
+        );
+    }
+}
+
+fn wallet_from_case(case: &serde_json::Value) -> wallet::WalletPolicy {
+    let name: String = serde_json::from_value(case["name"].clone()).unwrap();
+    let policy: String = serde_json::from_value(case["policy"].clone()).unwrap();
+    let keys: Vec<wallet::WalletPubKey> =
+        serde_json::from_value::<Vec<String>>(case["keys"].clone())
+            .unwrap()
+            .iter()
+            .map(|s| wallet::WalletPubKey::from_str(s).unwrap())
+            .collect();
+    let version = if case.get("version").and_then(|v| v.as_u64()) == Some(1) {
+        wallet::Version::V1
+    } else {
+        wallet::Version::V2
+    };
+    wallet::WalletPolicy::new(name, version, policy, keys)
+}
+
+fn hmac_from_case(case: &serde_json::Value) -> Option<wallet::WalletHmac> {
+    case["hmac"]
+        .as_str()
+        .map(|s| wallet::WalletHmac::from_str(s).unwrap())
+}
+
+#[tokio::test]
+async fn test_sim_get_extended_pubkey() {
+    let sim = SimTransport::default();
+    assert_eq!(
+        client::BitcoinClient::new(SimTransport::default())
+            .get_master_fingerprint()
+            .unwrap(),
+        sim.master_fingerprint()
+    );
+    assert_eq!(sim.master_fingerprint().to_hex(), "f5acc2fd");
+
+    for case in test_cases("./tests/data/get_extended_pubkey.json") {
+        let derivation_path =
+            DerivationPath::from_str(case["derivation_path"].as_str().unwrap()).unwrap();
+        let xpk_str = case["result"].as_str().unwrap();
+        // The simulator only serializes testnet extended pubkeys.
+        if !xpk_str.starts_with("tpub") {
+            continue;
+        }
+
+        let key = client::BitcoinClient::new(SimTransport::default())
+            .get_extended_pubkey(&derivation_path, false)
+            .unwrap();
+        assert_eq!(key.to_string(), xpk_str);
+
+        let key = async_client::BitcoinClient::new(SimTransport::default())
+            .get_extended_pubkey(&derivation_path, false)
+            .await
+            .unwrap();
+        assert_eq!(key.to_string(), xpk_str);
+    }
+}
+
+#[tokio::test]
+async fn test_sim_get_keyexpression() {
+    use ledger_bitcoin_client::apdu::{BitcoinCommandCode, Direction};
+    use std::sync::{Arc, Mutex};
+
+    let commands = Arc::new(Mutex::new(Vec::new()));
+    let mut client = client::BitcoinClient::new(SimTransport::default());
+    let log = commands.clone();
+    client.set_apdu_logger(Box::new(move |direction, apdu| {
+        if direction == Direction::Command {
+            log.lock().unwrap().push(apdu[1]);
+        }
+    }));
+
+    let path = DerivationPath::from_str("m/84'/1'/0'").unwrap();
+    let key = client.get_keyexpression(&path, false).unwrap();
+    assert_eq!(key, "[f5acc2fd/84'/1'/0']tpubDCtKfsNyRhULjZ9XMS4VKKtVcPdVDi8MKUbcSD9MJDyjRu1A2ND5MiipozyyspBT9bg8upEp7a8EAgFxNxXn1d7QkdbL52Ty5jiSLcxPt1P");
+    assert_eq!(
+        wallet::WalletPubKey::from_str(&key).unwrap().to_string(),
+        key
+    );
+
+    let path = DerivationPath::from_str("m/86'/1'/0'").unwrap();
+    let key = client.get_keyexpression(&path, false).unwrap();
+    assert!(key.starts_with("[f5acc2fd/86'/1'/0']tpub"));
+    // the master fingerprint is only requested once
+    let fingerprint_requests = commands
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|ins| **ins == BitcoinCommandCode::GetMasterFingerprint as u8)
+        .count();
+    assert_eq!(fingerprint_requests, 1);
+
+    let key_async = async_client::BitcoinClient::new(SimTransport::default())
+        .get_keyexpression(&path, false)
+        .await
+        .unwrap();
+    assert_eq!(key_async, key);
+}
+
+#[tokio::test]
+async fn test_sim_with_master_fingerprint() {
+    use bitcoin::util::bip32::Fingerprint;
+    use ledger_bitcoin_client::apdu::{BitcoinCommandCode, Direction};
+    use std::sync::{Arc, Mutex};
+
+    let sim = SimTransport::default();
+    let requests = Arc::new(Mutex::new(0));
+    let log = requests.clone();
+    let mut client = client::BitcoinClient::new(SimTransport::default())
+        .with_master_fingerprint(sim.master_fingerprint());
+    client.set_apdu_logger(Box::new(move |direction, apdu| {
+        if direction == Direction::Command
+            && apdu[1] == BitcoinCommandCode::GetMasterFingerprint as u8
+        {
+            *log.lock().unwrap() += 1;
+        }
+    }));
+    let fingerprint_requests = || std::mem::take(&mut *requests.lock().unwrap());
+
+    let path = DerivationPath::from_str("m/84'/1'/0'").unwrap();
+    let key = client.get_keyexpression(&path, false).unwrap();
+    assert!(key.starts_with("[f5acc2fd/84'/1'/0']tpub"));
+    assert_eq!(fingerprint_requests(), 0);
+
+    // The fingerprint is requested again once forgotten, and then kept.
+    client.clear_master_fingerprint();
+    assert_eq!(client.get_keyexpression(&path, false).unwrap(), key);
+    assert_eq!(client.get_keyexpression(&path, false).unwrap(), key);
+    assert_eq!(fingerprint_requests(), 1);
+
+    // The helpers trust the fingerprint given to the client.
+    let other = Fingerprint::from(&[1, 2, 3, 4][..]);
+    let key_async = async_client::BitcoinClient::new(SimTransport::default())
+        .with_master_fingerprint(other)
+        .get_keyexpression(&path, false)
+        .await
+        .unwrap();
+    assert!(key_async.starts_with("[01020304/84'/1'/0']tpub"));
+}
+
+#[tokio::test]
+async fn test_sim_confirm_device_identity() {
+    let sim = SimTransport::default();
+    let path = DerivationPath::from_str("m/44'").unwrap();
+    let (fingerprint, xpub) = client::BitcoinClient::new(SimTransport::default())
+        .confirm_device_identity(&path)
+        .unwrap();
+    assert_eq!(fingerprint, sim.master_fingerprint());
+    assert_eq!(xpub, sim.xpub(&path));
+
+    let res = async_client::BitcoinClient::new(SimTransport::default().with_user_approval(false))
+        .confirm_device_identity(&path)
+        .await;
+    assert!(matches!(
+        res,
+        Err(BitcoinClientError::Device {
+            status: StatusWord::Deny,
+            ..
+        })
+    ));
+}
+
+#[tokio::test]
+async fn test_sim_register_wallet() {
+    for case in test_cases("./tests/data/register_wallet.json") {
+        let wallet = wallet_from_case(&case);
+        let hmac_result = case["hmac"].as_str().unwrap();
+
+        let (id, hmac) = client::BitcoinClient::new(SimTransport::default())
+            .register_wallet(&wallet)
+            .unwrap();
+        assert_eq!(id, wallet.id());
+        assert_eq!(hmac.to_hex(), hmac_result);
+
+        let (id, hmac) = async_client::BitcoinClient::new(SimTransport::default())
+            .register_wallet(&wallet)
+            .await
+            .unwrap();
+        assert_eq!(id, wallet.id());
+        assert_eq!(hmac.to_hex(), hmac_result);
+
+        let res = client::BitcoinClient::new(SimTransport::default().with_user_approval(false))
+            .register_wallet(&wallet);
+        assert!(matches!(
+            res,
+            Err(BitcoinClientError::Device {
+                status: StatusWord::Deny,
+                ..
+            })
+        ));
+
+        let res = client::BitcoinClient::new(utils::TamperingTransport::default())
+            .register_wallet(&wallet);
+        assert!(matches!(res, Err(BitcoinClientError::InvalidResponse(_))));
+    }
+}
+
+#[tokio::test]
+async fn test_sim_ensure_registered() {
+    use ledger_bitcoin_client::apdu::{BitcoinCommandCode, Direction};
+    use std::sync::{Arc, Mutex};
+
+    let case = test_cases("./tests/data/register_wallet.json").remove(1);
+    let wallet = wallet_from_case(&case);
+    let expected = Vec::from_hex(case["hmac"].as_str().unwrap()).unwrap();
+
+    let commands = Arc::new(Mutex::new(Vec::new()));
+    let mut client = client::BitcoinClient::new(SimTransport::default());
+    let log = commands.clone();
+    client.set_apdu_logger(Box::new(move |direction, apdu| {
+        if direction == Direction::Command {
+            log.lock().unwrap().push(apdu[1]);
+        }
+    }));
+    let registered = || {
+        std::mem::take(&mut *commands.lock().unwrap())
+            .contains(&(BitcoinCommandCode::RegisterWallet as u8))
+    };
+
+    let hmac = client.ensure_registered(&wallet, None).unwrap();
+    assert_eq!(hmac.to_vec(), expected);
+    assert!(registered());
+
+    assert_eq!(client.ensure_registered(&wallet, Some(hmac)).unwrap(), hmac);
+    assert!(!registered());
+
+    assert!(client.is_hmac_valid(&wallet, &hmac).unwrap());
+    assert!(!client
+        .is_hmac_valid(&wallet, &wallet::WalletHmac([0x01; 32]))
+        .unwrap());
+    assert!(!registered());
+    let silent =
+        async_client::BitcoinClient::new(SimTransport::default().with_user_approval(false));
+    assert!(silent.is_hmac_valid(&wallet, &hmac).await.unwrap());
+    assert!(!silent
+        .is_hmac_valid(&wallet, &wallet::WalletHmac([0x01; 32]))
+        .await
+        .unwrap());
+
+    // An address of the wallet in the cache does not validate another hmac.
+    let cached = async_client::BitcoinClient::new(SimTransport::default()).with_address_cache();
+    cached
+        .get_wallet_address(&wallet, Some(&hmac), false, 0, false)
+        .await
+        .unwrap();
+    assert!(!cached
+        .is_hmac_valid(&wallet, &wallet::WalletHmac([0x01; 32]))
+        .await
+        .unwrap());
+    assert!(cached.is_hmac_valid(&wallet, &hmac).await.unwrap());
+
+    assert_eq!(
+        client
+            .ensure_registered(&wallet, Some(wallet::WalletHmac([0x01; 32])))
+            .unwrap(),
+        hmac
+    );
+    assert!(registered());
+
+    // A stale hmac is replaced even when an address of the wallet is cached.
+    let cached = client::BitcoinClient::new(SimTransport::default()).with_address_cache();
+    cached
+        .get_wallet_address(&wallet, Some(&hmac), false, 0, false)
+        .unwrap();
+    assert_eq!(
+        cached
+            .ensure_registered(&wallet, Some(wallet::WalletHmac([0x01; 32])))
+            .unwrap(),
+        hmac
+    );
+
+    let res = async_client::BitcoinClient::new(SimTransport::default().with_user_approval(false))
+        .ensure_registered(&wallet, Some(wallet::WalletHmac([0x01; 32])))
+        .await;
+    assert!(matches!(
+        res,
+        Err(BitcoinClientError::Device {
+            status: StatusWord::Deny,
+            ..
+        })
+    ));
+}
+
+#[tokio::test]
+async fn test_register_wallet_app_version() {
+    use ledger_bitcoin_client::apdu::{BitcoinCommandCode, Direction};
+    use std::sync::{Arc, Mutex};
+
+    let cases = test_cases("./tests/data/register_wallet.json");
+    let v1 = wallet_from_case(&cases[0]);
+    let v2 = wallet_from_case(&cases[1]);
+    assert_eq!(v1.version, wallet::Version::V1);
+    assert_eq!(v2.version, wallet::Version::V2);
+
+    // An app older than 2.1.0 only receives the get_version command.
+    let exchanges: Vec<String> = vec![
+        "=> b001000000".into(),
+        "<= 01095174756d205465737405322e302e3601009000".into(),
+    ];
+    let store = utils::RecordStore::new(&exchanges);
+    let res = client::BitcoinClient::new(utils::TransportReplayer::new(store.clone()))
+        .register_wallet(&v2);
+    assert!(matches!(
+        res,
+        Err(BitcoinClientError::UnsupportedAppVersion)
+    ));
+    let res = async_client::BitcoinClient::new(utils::TransportReplayer::new(store))
+        .register_wallet(&v2)
+        .await;
+    assert!(matches!(
+        res,
+        Err(BitcoinClientError::UnsupportedAppVersion)
+    ));
+
+    // An app older than 2.1.2 does not support taproot scripts.
+    let tr = wallet::WalletPolicy::new(
+        "Taproot".to_string(),
+        wallet::Version::V2,
+        "tr(@0/**,pk(@1/**))".to_string(),
+        v2.keys
+            .iter()
+            .map(|k| wallet::WalletPubKey::from_str(&k.to_string()).unwrap())
+            .collect::<Vec<_>>(),
+    );
+    let exchanges: Vec<String> = vec![
+        "=> b001000000".into(),
+        "<= 01095174756d205465737405322e312e3101009000".into(),
+    ];
+    let store = utils::RecordStore::new(&exchanges);
+    let res = client::BitcoinClient::new(utils::TransportReplayer::new(store.clone()))
+        .register_wallet(&tr);
+    assert!(matches!(
+        res,
+        Err(BitcoinClientError::UnsupportedFeature("taproot scripts"))
+    ));
+    let capabilities = async_client::BitcoinClient::new(utils::TransportReplayer::new(store))
+        .capabilities()
+        .await
+        .unwrap();
+    assert!(capabilities.miniscript && !capabilities.taproot_scripts);
+
+    // Version 1 policies are registered without querying the version of the app.
+    let commands = Arc::new(Mutex::new(Vec::new()));
+    let mut client = client::BitcoinClient::new(SimTransport::default());
+    let log = commands.clone();
+    client.set_apdu_logger(Box::new(move |direction, apdu| {
+        if direction == Direction::Command {
+            log.lock().unwrap().push(apdu[1]);
+        }
+    }));
+    client.register_wallet(&v1).unwrap();
+    assert_eq!(
+        commands.lock().unwrap()[0],
+        BitcoinCommandCode::RegisterWallet as u8
+    );
+
+    commands.lock().unwrap().clear();
+    client.register_wallet(&v2).unwrap();
+    assert_eq!(
+        commands.lock().unwrap()[0],
+        BitcoinCommandCode::GetVersion as u8
+    );
+
+    // The version of the app is only requested once.
+    commands.lock().unwrap().clear();
+    client.register_wallet(&v2).unwrap();
+    assert_eq!(
+        commands.lock().unwrap()[0],
+        BitcoinCommandCode::RegisterWallet as u8
+    );
+}
+
+#[tokio::test]
+async fn test_sim_miniscript_wallet_from_descriptor() {
+    let sim = SimTransport::default();
+    let path = DerivationPath::from_str("m/48'/1'/0'/2'").unwrap();
+    let own_key = format!(
+        "[{}/48'/1'/0'/2']{}",
+        sim.master_fingerprint(),
+        sim.xpub(&path)
+    );
+    let descriptor = format!(
+        "wsh(or_d(pk({}/**),and_v(v:pkh([76223a6e/48'/1'/0'/2']tpubDE7NQymr4AFtewpAsWtnreyq9ghkzQBXpCZjWLFVRAvnbf7vya2eMTvT2fPapNqL8SuVvLQdbUbMfWLVDCZKnsEBqp6UK93QEzL8Ck23AwF/**),older(144))))",
+        own_key
+    );
+    let wallet =
+        wallet::WalletPolicy::from_descriptor("Timelocked".to_string(), &descriptor).unwrap();
+
+    let client = async_client::BitcoinClient::new(SimTransport::default());
+    let (_, hmac) = client.register_wallet(&wallet).await.unwrap();
+    let address = client
+        .get_wallet_address(&wallet, Some(&hmac), false, 3, false)
+        .await
+        .unwrap();
+    assert_eq!(
+        address,
+        wallet
+            .address_iter(false, 3, bitcoin::Network::Testnet)
+            .next()
+            .unwrap()
+            .unwrap()
+    );
+}
+
+#[tokio::test]
+async fn test_sim_sortedmulti_key_order() {
+    let sim = SimTransport::default();
+    let path = DerivationPath::from_str("m/48'/1'/0'/2'").unwrap();
+    let own_key = format!(
+        "[{}/48'/1'/0'/2']{}",
+        sim.master_fingerprint(),
+        sim.xpub(&path)
+    );
+    let other_key = "[76223a6e/48'/1'/0'/2']tpubDE7NQymr4AFtewpAsWtnreyq9ghkzQBXpCZjWLFVRAvnbf7vya2eMTvT2fPapNqL8SuVvLQdbUbMfWLVDCZKnsEBqp6UK93QEzL8Ck23AwF";
+    let wallet = |keys: [&str; 2]| {
+        wallet::WalletPolicy::new(
+            "Cold storage".to_string(),
+            wallet::Version::V2,
+            "wsh(sortedmulti(2,@0/**,@1/**))".to_string(),
+            keys.iter()
+                .map(|k| wallet::WalletPubKey::from_str(k).unwrap())
+                .collect::<Vec<_>>(),
+        )
+    };
+
+    let client = client::BitcoinClient::new(SimTransport::default());
+    let mut addresses = Vec::new();
+    for keys in [[own_key.as_str(), other_key], [other_key, own_key.as_str()]] {
+        let wallet = wallet(keys);
+        let (_, hmac) = client.register_wallet(&wallet).unwrap();
+        addresses.push(
+            client
+                .get_wallet_address(&wallet, Some(&hmac), false, 7, false)
+                .unwrap(),
+        );
+    }
+    assert_eq!(addresses[0], addresses[1]);
+
+    // Both orders normalize to the same policy, registered once.
+    let mut ordered = wallet([own_key.as_str(), other_key]);
+    let mut normalized = wallet([other_key, own_key.as_str()]);
+    ordered.normalize();
+    normalized.normalize();
+    assert_eq!(ordered.id(), normalized.id());
+    let (_, hmac) = async_client::BitcoinClient::new(SimTransport::default())
+        .register_wallet(&normalized)
+        .await
+        .unwrap();
+    let address = async_client::BitcoinClient::new(SimTransport::default())
+        .get_wallet_address(&normalized, Some(&hmac), false, 7, true)
+        .await
+        .unwrap();
+    assert_eq!(address, addresses[0]);
+}
+
+#[tokio::test]
+async fn test_sim_register_and_preview() {
+    let sim = SimTransport::default();
+    let path = DerivationPath::from_str("m/48'/1'/0'/2'").unwrap();
+    let wallet = wallet::WalletPolicy::new(
+        "Cold storage".to_string(),
+        wallet::Version::V2,
+        "wsh(sortedmulti(2,@0/**,@1/**))".to_string(),
+        vec![
+            wallet::WalletPubKey::from_str("[76223a6e/48'/1'/0'/2']tpubDE7NQymr4AFtewpAsWtnreyq9ghkzQBXpCZjWLFVRAvnbf7vya2eMTvT2fPapNqL8SuVvLQdbUbMfWLVDCZKnsEBqp6UK93QEzL8Ck23AwF").unwrap(),
+            wallet::WalletPubKey::from_str(&format!(
+                "[{}/48'/1'/0'/2']{}",
+                sim.master_fingerprint(),
+                sim.xpub(&path)
+            ))
+            .unwrap(),
+        ],
+    );
+    let expected = |change| -> Vec<bitcoin::Address> {
+        wallet
+            .address_iter(change, 0, bitcoin::Network::Testnet)
+            .take(3)
+            .collect::<Result<_, _>>()
+            .unwrap()
+    };
+
+    let (hmac, receive, change) = client::BitcoinClient::new(SimTransport::default())
+        .register_and_preview(&wallet, 3, 2)
+        .unwrap();
+    assert_eq!(receive, expected(false));
+    assert_eq!(change, expected(true)[..2]);
+
+    let client = async_client::BitcoinClient::new(SimTransport::default());
+    let (hmac_async, receive, change) = client.register_and_preview(&wallet, 1, 0).await.unwrap();
+    assert_eq!(hmac_async, hmac);
+    assert_eq!(receive, expected(false)[..1]);
+    assert!(change.is_empty());
+}
+
+#[tokio::test]
+async fn test_sim_address_at() {
+    let sim = SimTransport::default();
+    let key = |path: &str| {
+        let path = DerivationPath::from_str(path).unwrap();
+        format!(
+            "[{}{}]{}",
+            sim.master_fingerprint(),
+            &path.to_string()[1..],
+            sim.xpub(&path)
+        )
+    };
+    let cosigner = "[76223a6e/48'/1'/0'/2']tpubDE7NQymr4AFtewpAsWtnreyq9ghkzQBXpCZjWLFVRAvnbf7vya2eMTvT2fPapNqL8SuVvLQdbUbMfWLVDCZKnsEBqp6UK93QEzL8Ck23AwF";
+    let wallets = [
+        ("pkh(@0/**)", vec![key("m/44'/1'/0'")]),
+        ("sh(wpkh(@0/**))", vec![key("m/49'/1'/0'")]),
+        ("wpkh(@0/**)", vec![key("m/84'/1'/0'")]),
+        ("tr(@0/**)", vec![key("m/86'/1'/0'")]),
+        (
+            "wsh(sortedmulti(2,@0/**,@1/**))",
+            vec![cosigner.to_string(), key("m/48'/1'/0'/2'")],
+        ),
+    ];
+
+    let client = client::BitcoinClient::new(SimTransport::default());
+    let async_client = async_client::BitcoinClient::new(SimTransport::default());
+    for (template, keys) in wallets {
+        let name = if keys.len() > 1 { "Cold storage" } else { "" };
+        let wallet = wallet::WalletPolicy::new(
+            name.to_string(),
+            wallet::Version::V2,
+            template.to_string(),
+            keys.iter()
+                .map(|k| wallet::WalletPubKey::from_str(k).unwrap())
+                .collect::<Vec<_>>(),
+        );
+        let hmac = if wallet.is_standard() {
+            None
+        } else {
+            Some(client.register_wallet(&wallet).unwrap().1)
+        };
+        for (change, index) in [(false, 0), (false, 5), (true, 2)] {
+            let address = wallet
+                .address_at(change, index, bitcoin::Network::Testnet)
+                .unwrap();
+            assert_eq!(
+                client
+                    .get_wallet_address(&wallet, hmac.as_ref(), change, index, false)
+                    .unwrap(),
+                address,
+                "{}",
+                template
+            );
+            assert_eq!(
+                async_client
+                    .get_wallet_address(&wallet, hmac.as_ref(), change, index, false)
+                    .await
+                    .unwrap(),
+                address
+            );
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_sim_display_addresses() {
+    let sim = SimTransport::default();
+    let account = DerivationPath::from_str("m/84'/1'/0'").unwrap();
+    let wallet = wallet::WalletPolicy::new(
+        "".to_string(),
+        wallet::Version::V2,
+        "wpkh(@0/**)".to_string(),
+        vec![wallet::WalletPubKey::from_str(&format!(
+            "[{}/84'/1'/0']{}",
+            sim.master_fingerprint(),
+            sim.xpub(&account)
+        ))
+        .unwrap()],
+    );
+    let expected: Vec<bitcoin::Address> = wallet
+        .address_iter(true, 0, bitcoin::Network::Testnet)
+        .take(5)
+        .collect::<Result<_, _>>()
+        .unwrap();
+
+    let addresses = client::BitcoinClient::new(SimTransport::default())
+        .display_addresses(&wallet, None, true, &[0, 2, 4])
+        .unwrap();
+    assert_eq!(
+        addresses,
+        vec![
+            (0, Some(expected[0].clone())),
+            (2, Some(expected[2].clone())),
+            (4, Some(expected[4].clone())),
+        ]
+    );
+
+    // Every address is rejected, the walk goes on.
+    let addresses =
+        async_client::BitcoinClient::new(SimTransport::default().with_user_approval(false))
+            .display_addresses(&wallet, None, true, &[1, 3])
+            .await
+            .unwrap();
+    assert_eq!(addresses, vec![(1, None), (3, None)]);
+}
+
+#[tokio::test]
+async fn test_sim_fixed_index_wallet() {
+    let sim = SimTransport::default();
+    let account = DerivationPath::from_str("m/84'/1'/0'").unwrap();
+    let wallet = wallet::WalletPolicy::new(
+        "Donations".to_string(),
+        wallet::Version::V2,
+        "wpkh(@0/0/5)".to_string(),
+        vec![wallet::WalletPubKey::from_str(&format!(
+            "[{}/84'/1'/0']{}",
+            sim.master_fingerprint(),
+            sim.xpub(&account)
+        ))
+        .unwrap()],
+    );
+    let key = sim.xpub(&DerivationPath::from_str("m/84'/1'/0'/0/5").unwrap());
+    let expected = bitcoin::Address::p2wpkh(&key.to_pub(), bitcoin::Network::Testnet).unwrap();
+
+    // The address index and change are ignored, the policy has a single address.
+    assert_eq!(
+        wallet
+            .address_iter(false, 0, bitcoin::Network::Testnet)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap(),
+        vec![expected.clone()]
+    );
+
+    let client = client::BitcoinClient::new(SimTransport::default());
+    let (_, hmac) = client.register_wallet(&wallet).unwrap();
+    for (change, index) in [(false, 0), (false, 7), (true, 3)] {
+        let address = client
+            .get_wallet_address(&wallet, Some(&hmac), change, index, true)
+            .unwrap();
+        assert_eq!(address, expected);
+    }
+    let address = async_client::BitcoinClient::new(SimTransport::default())
+        .get_wallet_address(&wallet, Some(&hmac), false, 1, false)
+        .await
+        .unwrap();
+    assert_eq!(address, expected);
+}
+
+#[tokio::test]
+async fn test_sim_shared_client() {
+    let case = &test_cases("./tests/data/get_wallet_address.json")[0];
+    let wallet = wallet_from_case(case);
+    let hmac = hmac_from_case(case);
+    let change = case["change"].as_bool().unwrap();
+    let address_index = case["address_index"].as_u64().unwrap() as u32;
+    let address_result = case["address"].as_str().unwrap();
+
+    let shared = async_client::SharedClient::new(async_client::BitcoinClient::new(
+        utils::YieldingTransport::default(),
+    ));
+    let get_address = |shared: async_client::SharedClient<utils::YieldingTransport>| {
+        let wallet = &wallet;
+        async move {
+            shared
+                .lock()
+                .await
+                .get_wallet_address(wallet, hmac.as_ref(), change, address_index, false)
+                .await
+        }
+    };
+    let (first, second) = tokio::join!(get_address(shared.clone()), get_address(shared.clone()));
+    assert_eq!(first.unwrap().to_string(), address_result);
+    assert_eq!(second.unwrap().to_string(), address_result);
+}
+
+#[tokio::test]
+async fn test_sim_get_wallet_address() {
+    for case in test_cases("./tests/data/get_wallet_address.json") {
+        let wallet = wallet_from_case(&case);
+        let hmac = hmac_from_case(&case);
+        let change = case["change"].as_bool().unwrap();
+        let address_index = case["address_index"].as_u64().unwrap() as u32;
+        let address_result = case["address"].as_str().unwrap();
+
+        let address = client::BitcoinClient::new(SimTransport::default())
+            .get_wallet_address(&wallet, hmac.as_ref(), change, address_index, true)
+            .unwrap();
+        assert_eq!(address.to_string(), address_result);
+
+        let address = async_client::BitcoinClient::new(SimTransport::default())
+            .get_wallet_address(&wallet, hmac.as_ref(), change, address_index, true)
+            .await
+            .unwrap();
+        assert_eq!(address.to_string(), address_result);
+
+        let res = client::BitcoinClient::new(SimTransport::default()).get_wallet_address(
+            &wallet,
+            Some(&wallet::WalletHmac([0x01; 32])),
+            change,
+            address_index,
+            false,
+        );
+        assert!(matches!(
+            res,
+            Err(BitcoinClientError::Device {
+                status: StatusWord::SignatureFail,
+                ..
+            })
+        ));
+    }
+}
+
+#[tokio::test]
+async fn test_sim_sign_psbt() {
+    let secp = bitcoin::secp256k1::Secp256k1::verification_only();
+    for case in test_cases("./tests/data/sign_psbt.json") {
+        let wallet = wallet_from_case(&case);
+        let hmac = hmac_from_case(&case);
+        let psbt: Psbt =
+            deserialize(&base64::decode(case["psbt"].as_str().unwrap()).unwrap()).unwrap();
+        let sigs = case["sigs"].as_array().unwrap();
+
+        let res = client::BitcoinClient::new(SimTransport::default())
+            .sign_psbt(&psbt, &wallet, hmac.as_ref())
+            .unwrap();
+        assert!(!res.is_empty());
+
+        let res_async = async_client::BitcoinClient::new(SimTransport::default())
+            .sign_psbt(&psbt, &wallet, hmac.as_ref())
+            .await
+            .unwrap();
+        assert_eq!(res.len(), res_async.len());
+
+        let mut signed = psbt.clone();
+        for (i, psbt_sig) in res {
+            assert!(psbt_sig.is_low_r());
+            match psbt_sig {
+                PartialSignature::Sig(key, sig) => {
+                    let sighash = psbt::signature_hash(&psbt, i).unwrap();
+                    secp.verify_ecdsa(
+                        &bitcoin::secp256k1::Message::from_slice(&sighash).unwrap(),
+                        &sig.sig,
+                        &key.inner,
+                    )
+                    .unwrap();
+                    signed.inputs[i].partial_sigs.insert(key, sig);
+                }
+                PartialSignature::TapScriptSig(key, tapleaf_hash, _) => {
+                    assert_eq!(sigs[i]["key"].as_str().unwrap(), key.to_hex());
+                    assert_eq!(
+                        sigs[i]["tapleaf_hash"].as_str(),
+                        tapleaf_hash.map(|h| h.to_hex()).as_deref()
+                    );
+                }
+            }
+        }
+
+        if sigs.is_empty() {
+            psbt::finalize_and_extract(&signed).unwrap();
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_sim_sign_psbt_taproot_script_path() {
+    use bitcoin::{
+        util::{
+            sighash::{Prevouts, SighashCache},
+            taproot::{LeafVersion, TapLeafHash},
+        },
+        OutPoint, PackedLockTime, Script, Sequence, Transaction, TxIn, TxOut, Txid, Witness,
+    };
+    use miniscript::{psbt::PsbtExt, Descriptor, DescriptorPublicKey};
+
+    let sim = SimTransport::default();
+    let path = DerivationPath::from_str("m/48'/1'/0'/2'").unwrap();
+    let wallet = wallet::WalletPolicy::new(
+        "Taproot tree".to_string(),
+        wallet::Version::V2,
+        "tr(@0/**,{pk(@1/**),pk(@2/**)})".to_string(),
+        [
+            "[76223a6e/48'/1'/0'/2']tpubDE7NQymr4AFtewpAsWtnreyq9ghkzQBXpCZjWLFVRAvnbf7vya2eMTvT2fPapNqL8SuVvLQdbUbMfWLVDCZKnsEBqp6UK93QEzL8Ck23AwF".to_string(),
+            format!("[{}/48'/1'/0'/2']{}", sim.master_fingerprint(), sim.xpub(&path)),
+            "[5c9e228d/48'/1'/0'/0']tpubDEGquuorgFNb8bjh5kNZQMPtABJzoWwNm78FUmeoPkfRtoPF7JLrtoZeT3J3ybq1HmC3Rn1Q8wFQ8J5usanzups5rj7PJoQLNyvq8QbJruW".to_string(),
+        ]
+        .iter()
+        .map(|k| wallet::WalletPubKey::from_str(k).unwrap())
+        .collect::<Vec<_>>(),
+    );
+    let client = client::BitcoinClient::new(SimTransport::default());
+    let (_, hmac) = client.register_wallet(&wallet).unwrap();
+
+    // The input spends the first receive address of the wallet, the output pays its first
+    // change address.
+    let descriptor = |change: bool| {
+        Descriptor::<DescriptorPublicKey>::from_str(&wallet.get_descriptor(change).unwrap())
+            .unwrap()
+            .at_derivation_index(0)
+    };
+    let spent = descriptor(false);
+    let mut psbt = Psbt::from_unsigned_tx(Transaction {
+        version: 2,
+        lock_time: PackedLockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint::new(Txid::from_inner([0x42; 32]), 1),
+            script_sig: Script::new(),
+            sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+            witness: Witness::new(),
+        }],
+        output: vec![TxOut {
+            value: 90_000,
+            script_pubkey: descriptor(true).script_pubkey(),
+        }],
+    })
+    .unwrap();
+    psbt.inputs[0].witness_utxo = Some(TxOut {
+        value: 100_000,
+        script_pubkey: spent.script_pubkey(),
+    });
+    psbt.update_input_with_descriptor(0, &spent).unwrap();
+    psbt::normalize(&mut psbt);
+    assert!(psbt.inputs[0].tap_merkle_root.is_some());
+    assert_eq!(psbt.inputs[0].tap_scripts.len(), 2);
+
+    // The only key of the device is in the leaf pk(@1).
+    let key = sim
+        .xpub(&path.child(0.into()).child(0.into()))
+        .to_x_only_pub();
+    let mut leaf_script = vec![0x20];
+    leaf_script.extend_from_slice(&key.serialize());
+    leaf_script.push(0xac);
+    let leaf_hash = TapLeafHash::from_script(&Script::from(leaf_script), LeafVersion::TapScript);
+
+    let res = client.sign_psbt(&psbt, &wallet, Some(&hmac)).unwrap();
+    let res_async = async_client::BitcoinClient::new(SimTransport::default())
+        .sign_psbt(&psbt, &wallet, Some(&hmac))
+        .await
+        .unwrap();
+    assert_eq!(res.len(), 1);
+    assert_eq!(res_async.len(), 1);
+
+    let secp = bitcoin::secp256k1::Secp256k1::verification_only();
+    for (index, signature) in res.into_iter().chain(res_async) {
+        assert_eq!(index, 0);
+        let (sig_key, sig, sig_leaf_hash) = signature.as_schnorr().unwrap();
+        assert_eq!(sig_key, key);
+        assert_eq!(sig_leaf_hash, Some(leaf_hash));
+        let sighash = SighashCache::new(&psbt.unsigned_tx)
+            .taproot_script_spend_signature_hash(
+                0,
+                &Prevouts::All(&[psbt.inputs[0].witness_utxo.clone().unwrap()]),
+                leaf_hash,
+                sig.hash_ty,
+            )
+            .unwrap();
+        secp.verify_schnorr(
+            &sig.sig,
+            &bitcoin::secp256k1::Message::from_slice(&sighash.into_inner()).unwrap(),
+            &key,
+        )
+        .unwrap();
+
+        let mut signed = psbt.clone();
+        signature.apply(&mut signed.inputs[0]);
+        assert!(signed.inputs[0]
+            .tap_script_sigs
+            .contains_key(&(key, leaf_hash)));
+        assert!(signed.inputs[0].tap_key_sig.is_none());
+    }
+}
+
+#[tokio::test]
+async fn test_sim_reconnecting_client() {
+    use ledger_bitcoin_client::apdu::APDUCommand;
+    use std::collections::VecDeque;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    };
+
+    #[derive(Debug)]
+    struct Unplugged;
+
+    // Fails the next `unplug` exchanges, whatever the transport, as if the device was unplugged.
+    struct Unpluggable {
+        sim: SimTransport,
+        unplug: Arc<AtomicUsize>,
+    }
+    impl client::Transport for Unpluggable {
+        type Error = Unplugged;
+        fn exchange(&self, command: &APDUCommand) -> Result<(StatusWord, Vec<u8>), Self::Error> {
+            if self
+                .unplug
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .is_ok()
+            {
+                return Err(Unplugged);
+            }
+            Ok(client::Transport::exchange(&self.sim, command).unwrap())
+        }
+        fn is_disconnected(&self, _error: &Self::Error) -> bool {
+            true
+        }
+    }
+    #[async_trait::async_trait]
+    impl async_client::Transport for Unpluggable {
+        type Error = Unplugged;
+        async fn exchange(
+            &self,
+            command: &APDUCommand,
+        ) -> Result<(StatusWord, Vec<u8>), Self::Error> {
+            client::Transport::exchange(self, command)
+        }
+        fn is_disconnected(&self, _error: &Self::Error) -> bool {
+            true
+        }
+    }
+
+    let fingerprint = SimTransport::default().master_fingerprint();
+    let other = SimTransport::new(&[0x07; 32]);
+    let other_fingerprint = other.master_fingerprint();
+    let unplug = Arc::new(AtomicUsize::new(0));
+    // The devices found by the next connections, the default one unless set.
+    let devices: Arc<Mutex<VecDeque<SimTransport>>> = Arc::default();
+    let connect = {
+        let (unplug, devices) = (unplug.clone(), devices.clone());
+        move || Unpluggable {
+            sim: devices.lock().unwrap().pop_front().unwrap_or_default(),
+            unplug: unplug.clone(),
+        }
+    };
+
+    let mut client =
+        client::ReconnectingClient::new(client::BitcoinClient::new(connect()), fingerprint, {
+            let connect = connect.clone();
+            move || Ok(connect())
+        });
+    assert!(client.run(|c| c.get_version()).is_ok());
+
+    // The operation is run again on the same device.
+    unplug.store(1, Ordering::SeqCst);
+    assert_eq!(
+        client.run(|c| c.get_master_fingerprint()).unwrap(),
+        fingerprint
+    );
+
+    // Another device is plugged in: it is not used until the expected one is back.
+    unplug.store(1, Ordering::SeqCst);
+    devices.lock().unwrap().push_back(other);
+    assert!(matches!(
+        client.run(|c| c.get_version()),
+        Err(BitcoinClientError::DeviceChanged { expected, fingerprint: found })
+            if expected == fingerprint && found == other_fingerprint
+    ));
+    assert!(client.run(|c| c.get_version()).is_ok());
+
+    // The device still unplugged on reconnection.
+    unplug.store(2, Ordering::SeqCst);
+    assert!(matches!(
+        client.run(|c| c.get_version()),
+        Err(BitcoinClientError::Transport(Unplugged))
+    ));
+
+    let mut client = async_client::ReconnectingClient::new(
+        async_client::BitcoinClient::new(connect()),
+        fingerprint,
+        move || {
+            let transport = connect();
+            async move { Ok(transport) }
+        },
+    );
+    unplug.store(1, Ordering::SeqCst);
+    assert_eq!(
+        client
+            .run(|c| Box::pin(c.get_master_fingerprint()))
+            .await
+            .unwrap(),
+        fingerprint
+    );
+    unplug.store(1, Ordering::SeqCst);
+    devices
+        .lock()
+        .unwrap()
+        .push_back(SimTransport::new(&[0x07; 32]));
+    assert!(matches!(
+        client.run(|c| Box::pin(c.get_version())).await,
+        Err(BitcoinClientError::DeviceChanged { .. })
+    ));
+    assert!(client.run(|c| Box::pin(c.get_version())).await.is_ok());
+}
+
+#[tokio::test]
+async fn test_check_app_network() {
+    use ledger_bitcoin_client::bip32::AppVariant;
+
+    // The simulated device runs the test app.
+    for network in [
+        None,
+        Some(QtumNetwork::Testnet),
+        Some(QtumNetwork::regtest()),
+    ] {
+        let mut client = client::BitcoinClient::new(SimTransport::default());
+        let mut async_client = async_client::BitcoinClient::new(SimTransport::default());
+        if let Some(network) = network {
+            client = client.with_network(network);
+            async_client = async_client.with_network(network);
+        }
+        assert_eq!(client.check_app_network().unwrap(), AppVariant::Test);
+        assert_eq!(
+            async_client.check_app_network().await.unwrap(),
+            AppVariant::Test
+        );
+    }
+    let res = client::BitcoinClient::new(SimTransport::default())
+        .with_network(QtumNetwork::Mainnet)
+        .check_app_network();
+    assert!(matches!(
+        res,
+        Err(BitcoinClientError::AppNetworkMismatch {
+            app: AppVariant::Test,
+            network: QtumNetwork::Mainnet
+        })
+    ));
+
+    // The mainnet app.
+    let exchanges: Vec<String> = vec![
+        "=> b001000000".into(),
+        "<= 01045174756d05322e312e3001009000".into(),
+    ];
+    let store = utils::RecordStore::new(&exchanges);
+    let res = async_client::BitcoinClient::new(utils::TransportReplayer::new(store.clone()))
+        .with_network(QtumNetwork::Testnet)
+        .check_app_network()
+        .await;
+    assert!(matches!(
+        res,
+        Err(BitcoinClientError::AppNetworkMismatch {
+            app: AppVariant::Mainnet,
+            network: QtumNetwork::Testnet
+        })
+    ));
+    let client = client::BitcoinClient::new(utils::TransportReplayer::new(store))
+        .with_network(QtumNetwork::Mainnet);
+    assert_eq!(client.check_app_network().unwrap(), AppVariant::Mainnet);
+}
+
+#[tokio::test]
+async fn test_stats() {
+    let exchanges: Vec<String> = vec![
+        "=> b001000000".into(),
+        "<= 010c426974636f696e205465737405322e312e3001009000".into(),
+    ];
+    let store = utils::RecordStore::new(&exchanges);
+    let client = client::BitcoinClient::new(utils::TransportReplayer::new(store.clone()));
+    assert_eq!(client.stats(), Stats::default());
+    client.get_version().unwrap();
+    assert_eq!(
+        client.stats(),
+        Stats {
+            exchanges: 1,
+            client_commands: 0,
+            bytes_sent: 5,
+            bytes_received: 24,
+        }
+    );
+
+    // Every client command answered while signing costs an exchange.
+    let case = test_cases("./tests/data/sign_psbt.json").remove(0);
+    let wallet = wallet_from_case(&case);
+    let psbt: Psbt = deserialize(&base64::decode(case["psbt"].as_str().unwrap()).unwrap()).unwrap();
+    let client = async_client::BitcoinClient::new(SimTransport::default());
+    client.get_master_fingerprint().await.unwrap();
+    client.reset_stats();
+    client.sign_psbt(&psbt, &wallet, None).await.unwrap();
+    let stats = client.stats();
+    assert!(stats.client_commands > 10);
+    assert_eq!(stats.exchanges, stats.client_commands + 1);
+    assert!(stats.bytes_sent > stats.exchanges * 5);
+    assert!(stats.bytes_received >= stats.exchanges * 2);
+}
+
+#[tokio::test]
+async fn test_sim_sign_psbt_rules() {
+    let case = test_cases("./tests/data/sign_psbt.json").remove(0);
+    let wallet = wallet_from_case(&case);
+    let mut psbt: Psbt =
+        deserialize(&base64::decode(case["psbt"].as_str().unwrap()).unwrap()).unwrap();
+
+    let res = client::BitcoinClient::new(SimTransport::default().with_user_approval(false))
+        .sign_psbt(&psbt, &wallet, None);
+    assert!(matches!(
+        res,
+        Err(BitcoinClientError::Device {
+            status: StatusWord::Deny,
+            ..
+        })
+    ));
+
+    // OP_4 <gas limit> <gas price> <data> <contract address> OP_CALL
+    psbt.unsigned_tx.output[0].script_pubkey = bitcoin::Script::from(
+        Vec::from_hex("540390d00301280461e14c7814c0a8ee4a8bc2ec1d6e4cc5fc0b4e55e0bc5ee8bac2")
+            .unwrap(),
+    );
+    let res = async_client::BitcoinClient::new(SimTransport::default())
+        .sign_psbt(&psbt, &wallet, None)
+        .await;
+    assert!(matches!(res, Err(BitcoinClientError::BlindSigningDisabled)));
+
+    let res = async_client::BitcoinClient::new(SimTransport::default().with_blind_signing(true))
+        .sign_psbt(&psbt, &wallet, None)
+        .await
+        .unwrap();
+    assert_eq!(res.len(), 1);
+
+    // A contract output holding a key origin on the change branch of the wallet is internal
+    // for the client: the rejection is not reported as BlindSigningDisabled.
+    let mut internal = psbt.clone();
+    let (fingerprint, path) = wallet.keys[0].source.clone().unwrap();
+    let path = path
+        .child(bitcoin::util::bip32::ChildNumber::Normal { index: 1 })
+        .child(bitcoin::util::bip32::ChildNumber::Normal { index: 0 });
+    internal.outputs[0]
+        .bip32_derivation
+        .insert(wallet.keys[0].inner.public_key, (fingerprint, path));
+    assert!(psbt::is_internal_output(&internal.outputs[0], &wallet));
+    let res =
+        client::BitcoinClient::new(SimTransport::default()).sign_psbt(&internal, &wallet, None);
+    assert!(matches!(
+        res,
+        Err(BitcoinClientError::Device {
+            status: StatusWord::SignatureFail,
+            ..
+        })
+    ));
+
+    // A registered wallet used with an hmac that the device does not accept.
+    let mut registered = wallet_from_case(&case);
+    registered.name = "Savings".to_string();
+    let res = async_client::BitcoinClient::new(SimTransport::default())
+        .sign_psbt(&psbt, &registered, Some(&wallet::WalletHmac([0x01; 32])))
+        .await;
+    assert!(matches!(
+        res,
+        Err(BitcoinClientError::Device {
+            status: StatusWord::SignatureFail,
+            ..
+        })
+    ));
+
+    // rejected after the get_version command, without sending the transaction
+    let get_version = |version: &str| -> Vec<String> {
+        vec![
+            "=> b001000000".into(),
+            format!(
+                "<= 01095174756d20546573740{}{}01009000",
+                version.len(),
+                version.as_bytes().to_hex()
+            ),
+        ]
+    };
+    let mut outputs = psbt.clone();
+    while outputs.outputs.len() <= 32 {
+        outputs
+            .unsigned_tx
+            .output
+            .push(outputs.unsigned_tx.output[0].clone());
+        outputs.outputs.push(outputs.outputs[0].clone());
+    }
+    // an app older than 2.0.4 signs at most 32 outputs
+    let store = utils::RecordStore::new(&get_version("2.0.3"));
+    let res = client::BitcoinClient::new(utils::TransportReplayer::new(store))
+        .sign_psbt(&outputs, &wallet, None);
+    assert!(matches!(
+        res,
+        Err(BitcoinClientError::TooManyOutputs {
+            outputs: 33,
+            max: 32
+        })
+    ));
+    let store = utils::RecordStore::new(&get_version("2.0.4"));
+    let res = async_client::BitcoinClient::new(utils::TransportReplayer::new(store))
+        .signing_limits()
+        .await
+        .unwrap();
+    assert!(res.allows(1, 33));
+
+    while psbt.inputs.len() <= 64 {
+        psbt.unsigned_tx
+            .input
+            .push(psbt.unsigned_tx.input[0].clone());
+        psbt.inputs.push(psbt.inputs[0].clone());
+    }
+    // an app older than 2.0.4 signs at most 64 inputs
+    let store = utils::RecordStore::new(&get_version("2.0.3"));
+    let res = async_client::BitcoinClient::new(utils::TransportReplayer::new(store))
+        .sign_psbt(&psbt, &wallet, None)
+        .await;
+    assert!(matches!(
+        res,
+        Err(BitcoinClientError::TooManyInputs {
+            inputs: 65,
+            max: 64
+        })
+    ));
+
+    while psbt.inputs.len() <= psbt::MAX_N_INPUTS_CAN_SIGN {
+        psbt.unsigned_tx
+            .input
+            .push(psbt.unsigned_tx.input[0].clone());
+        psbt.inputs.push(psbt.inputs[0].clone());
+    }
+    let store = utils::RecordStore::new(&get_version("2.1.0"));
+    let res = client::BitcoinClient::new(utils::TransportReplayer::new(store))
+        .sign_psbt(&psbt, &wallet, None);
+    assert!(matches!(
+        res,
+        Err(BitcoinClientError::TooManyInputs {
+            inputs: 513,
+            max: 512
+        })
+    ));
+}
+
+#[tokio::test]
+async fn test_sim_sign_psbt_stream() {
+    use core::ops::ControlFlow;
+
+    for case in test_cases("./tests/data/sign_psbt.json") {
+        let wallet = wallet_from_case(&case);
+        let hmac = hmac_from_case(&case);
+        let psbt: Psbt =
+            deserialize(&base64::decode(case["psbt"].as_str().unwrap()).unwrap()).unwrap();
+
+        let expected = client::BitcoinClient::new(SimTransport::default())
+            .sign_psbt(&psbt, &wallet, hmac.as_ref())
+            .unwrap();
+
+        let mut streamed = Vec::new();
+        client::BitcoinClient::new(SimTransport::default())
+            .sign_psbt_stream(&psbt, &wallet, hmac.as_ref(), |i, _| {
+                streamed.push(i);
+                ControlFlow::Continue(())
+            })
+            .unwrap();
+        assert_eq!(
+            streamed,
+            expected.iter().map(|(i, _)| *i).collect::<Vec<_>>()
+        );
+
+        let mut calls = 0;
+        let res = async_client::BitcoinClient::new(SimTransport::default())
+            .sign_psbt_stream(&psbt, &wallet, hmac.as_ref(), |_, _| {
+                calls += 1;
+                ControlFlow::Break(())
+            })
+            .await;
+        assert!(matches!(res, Err(BitcoinClientError::SigningAborted)));
+        assert_eq!(calls, 1);
+    }
+}
+
+#[tokio::test]
+async fn test_sim_sign_psbt_cancellable() {
+    use core::ops::ControlFlow;
+
+    for case in test_cases("./tests/data/sign_psbt.json") {
+        let wallet = wallet_from_case(&case);
+        let hmac = hmac_from_case(&case);
+        let psbt: Psbt =
+            deserialize(&base64::decode(case["psbt"].as_str().unwrap()).unwrap()).unwrap();
+        let client = async_client::BitcoinClient::new(SimTransport::default());
+
+        let mut calls = 0;
+        let cancel = async_client::CancellationToken::new();
+        let res = client
+            .sign_psbt_cancellable(&psbt, &wallet, hmac.as_ref(), &cancel.clone(), |_, _| {
+                calls += 1;
+                cancel.cancel();
+                ControlFlow::Continue(())
+            })
+            .await;
+        assert!(matches!(res, Err(BitcoinClientError::Cancelled)));
+        assert_eq!(calls, 1);
+
+        let res = client
+            .sign_psbt_cancellable(&psbt, &wallet, hmac.as_ref(), &cancel, |_, _| {
+                panic!("cancelled before the first signature")
+            })
+            .await;
+        assert!(matches!(res, Err(BitcoinClientError::Cancelled)));
+
+        // A new session is accepted by the device after a cancelled one.
+        let mut calls = 0;
+        client
+            .sign_psbt_cancellable(
+                &psbt,
+                &wallet,
+                hmac.as_ref(),
+                &async_client::CancellationToken::new(),
+                |_, _| {
+                    calls += 1;
+                    ControlFlow::Continue(())
+                },
+            )
+            .await
+            .unwrap();
+        assert!(calls > 0);
+    }
+}
+
+#[tokio::test]
+async fn test_sim_sign_psbt_with_notifications() {
+    use core::ops::ControlFlow;
+    use ledger_bitcoin_client::{
+        apdu::{APDUCommand, ClientCommandCode},
+        psbt::SignPsbtYield,
+    };
+    use std::sync::Mutex;
+
+    // Yields a notification tagged 0xffffff01 before each signature of the simulated device.
+    struct Notifying {
+        sim: SimTransport,
+        signature: Mutex<Option<(StatusWord, Vec<u8>)>>,
+    }
+    impl client::Transport for Notifying {
+        type Error = core::convert::Infallible;
+        fn exchange(&self, command: &APDUCommand) -> Result<(StatusWord, Vec<u8>), Self::Error> {
+            // The answer to the notification is not forwarded to the device.
+            if let Some(signature) = self.signature.lock().unwrap().take() {
+                return Ok(signature);
+            }
+            let (sw, data) = client::Transport::exchange(&self.sim, command)?;
+            if sw == StatusWord::InterruptedExecution
+                && data.first() == Some(&(ClientCommandCode::Yield as u8))
+            {
+                *self.signature.lock().unwrap() = Some((sw, data));
+                let mut notification = vec![ClientCommandCode::Yield as u8, 0xfe];
+                notification.extend_from_slice(&0xffff_ff01u32.to_le_bytes());
+                notification.extend_from_slice(b"low fee");
+                return Ok((sw, notification));
+            }
+            Ok((sw, data))
+        }
+    }
+    #[async_trait::async_trait]
+    impl async_client::Transport for Notifying {
+        type Error = core::convert::Infallible;
+        async fn exchange(
+            &self,
+            command: &APDUCommand,
+        ) -> Result<(StatusWord, Vec<u8>), Self::Error> {
+            client::Transport::exchange(self, command)
+        }
+    }
+    let notifying = || Notifying {
+        sim: SimTransport::default(),
+        signature: Mutex::new(None),
+    };
+
+    let case = test_cases("./tests/data/sign_psbt.json").remove(0);
+    let wallet = wallet_from_case(&case);
+    let psbt: Psbt = deserialize(&base64::decode(case["psbt"].as_str().unwrap()).unwrap()).unwrap();
+    let expected: Vec<usize> = client::BitcoinClient::new(SimTransport::default())
+        .sign_psbt(&psbt, &wallet, None)
+        .unwrap()
+        .into_iter()
+        .map(|(i, _)| i)
+        .collect();
+    assert!(!expected.is_empty());
+
+    let mut yields = Vec::new();
+    client::BitcoinClient::new(notifying())
+        .sign_psbt_with_notifications(&psbt, &wallet, None, |value| {
+            yields.push(match value {
+                SignPsbtYield::Signature(i, _) => Ok(i),
+                SignPsbtYield::Notification { tag, data } => Err((tag, data)),
+            });
+            ControlFlow::Continue(())
+        })
+        .unwrap();
+    assert_eq!(yields.len(), 2 * expected.len());
+    for (pair, i) in yields.chunks(2).zip(&expected) {
+        assert_eq!(pair[0], Err((0xffff_ff01, b"low fee".to_vec())));
+        assert_eq!(pair[1], Ok(*i));
+    }
+
+    // The notifications are not taken for signatures by the other signing methods.
+    let signatures = async_client::BitcoinClient::new(notifying())
+        .sign_psbt(&psbt, &wallet, None)
+        .await
+        .unwrap();
+    assert_eq!(
+        signatures.into_iter().map(|(i, _)| i).collect::<Vec<_>>(),
+        expected
+    );
+
+    let mut notifications = 0;
+    let res = async_client::BitcoinClient::new(notifying())
+        .sign_psbt_with_notifications(
+            &psbt,
+            &wallet,
+            None,
+            &async_client::CancellationToken::new(),
+            |value| {
+                assert!(matches!(value, SignPsbtYield::Notification { .. }));
+                notifications += 1;
+                ControlFlow::Break(())
+            },
+        )
+        .await;
+    assert!(matches!(res, Err(BitcoinClientError::SigningAborted)));
+    assert_eq!(notifications, 1);
+}
+
+#[test]
+fn test_sim_sign_psbt_index_out_of_range() {
+    use ledger_bitcoin_client::apdu::{APDUCommand, ClientCommandCode};
+
+    // Yields the signatures of the simulated device for an input out of the psbt.
+    struct WrongIndex(SimTransport);
+    impl client::Transport for WrongIndex {
+        type Error = core::convert::Infallible;
+        fn exchange(&self, command: &APDUCommand) -> Result<(StatusWord, Vec<u8>), Self::Error> {
+            let (sw, mut data) = client::Transport::exchange(&self.0, command)?;
+            if sw == StatusWord::InterruptedExecution
+                && data.first() == Some(&(ClientCommandCode::Yield as u8))
+            {
+                data[1] = 0xfc;
+            }
+            Ok((sw, data))
+        }
+    }
+
+    let case = test_cases("./tests/data/sign_psbt.json").remove(0);
+    let wallet = wallet_from_case(&case);
+    let psbt: Psbt = deserialize(&base64::decode(case["psbt"].as_str().unwrap()).unwrap()).unwrap();
+
+    let res = client::BitcoinClient::new(WrongIndex(SimTransport::default())).sign_psbt_stream(
+        &psbt,
+        &wallet,
+        None,
+        |_, _| panic!("signature of an input out of the psbt"),
+    );
+    assert!(matches!(
+        res,
+        Err(BitcoinClientError::UnexpectedResult { .. })
+    ));
+}
+
+#[tokio::test]
+async fn test_sim_sign_psbt_sighash_type() {
+    use bitcoin::{util::psbt::PsbtSighashType, EcdsaSighashType, SchnorrSighashType};
+
+    let cases = test_cases("./tests/data/sign_psbt.json");
+    let psbt_of = |case: &serde_json::Value| -> Psbt {
+        deserialize(&base64::decode(case["psbt"].as_str().unwrap()).unwrap()).unwrap()
+    };
+
+    let wallet = wallet_from_case(&cases[0]);
+    let mut psbt = psbt_of(&cases[0]);
+    psbt.inputs[0].sighash_type = Some(EcdsaSighashType::AllPlusAnyoneCanPay.into());
+    let signatures = client::BitcoinClient::new(SimTransport::default())
+        .sign_psbt(&psbt, &wallet, None)
+        .unwrap();
+    let (_, sig) = signatures
+        .iter()
+        .find(|(i, _)| *i == 0)
+        .and_then(|(_, sig)| sig.as_ecdsa())
+        .unwrap();
+    assert_eq!(sig.hash_ty, EcdsaSighashType::AllPlusAnyoneCanPay);
+    assert_eq!(sig.to_vec().last(), Some(&0x81));
+
+    // a taproot sighash type is not a sighash type of a segwit v0 input
+    psbt.inputs[0].sighash_type = Some(SchnorrSighashType::Default.into());
+    let res = async_client::BitcoinClient::new(SimTransport::default())
+        .sign_psbt(&psbt, &wallet, None)
+        .await;
+    assert!(matches!(
+        res,
+        Err(BitcoinClientError::NonStandardSighashType { input: 0 })
+    ));
+
+    let wallet = wallet_from_case(&cases[2]);
+    let hmac = hmac_from_case(&cases[2]);
+    let mut psbt = psbt_of(&cases[2]);
+    psbt.inputs[0].sighash_type = Some(SchnorrSighashType::AllPlusAnyoneCanPay.into());
+    let signatures = async_client::BitcoinClient::new(SimTransport::default())
+        .sign_psbt(&psbt, &wallet, hmac.as_ref())
+        .await
+        .unwrap();
+    let (_, sig, _) = signatures
+        .iter()
+        .find(|(i, _)| *i == 0)
+        .and_then(|(_, sig)| sig.as_schnorr())
+        .unwrap();
+    assert_eq!(sig.hash_ty, SchnorrSighashType::AllPlusAnyoneCanPay);
+    assert_eq!(sig.to_vec().len(), 65);
+    assert_eq!(sig.to_vec().last(), Some(&0x81));
+
+    psbt.inputs[0].sighash_type = Some(PsbtSighashType::from_u32(0x04));
+    let res = client::BitcoinClient::new(SimTransport::default()).sign_psbt(
+        &psbt,
+        &wallet,
+        hmac.as_ref(),
+    );
+    assert!(matches!(
+        res,
+        Err(BitcoinClientError::NonStandardSighashType { input: 0 })
+    ));
+}
+
+#[tokio::test]
+async fn test_sign_psbt_output_mismatch() {
+    use ledger_bitcoin_client::psbt::OutputMismatch;
+
+    let case = test_cases("./tests/data/sign_psbt.json").remove(0);
+    let wallet = wallet_from_case(&case);
+    let psbt: Psbt = deserialize(&base64::decode(case["psbt"].as_str().unwrap()).unwrap()).unwrap();
+    let store = utils::RecordStore::new(&[]);
+
+    let mut wrong_script = psbt.clone();
+    let last = wrong_script.outputs.len() - 1;
+    wrong_script.outputs[last].witness_script = Some(bitcoin::Script::from(vec![0x51]));
+    let res = client::BitcoinClient::new(utils::TransportReplayer::new(store.clone())).sign_psbt(
+        &wrong_script,
+        &wallet,
+        None,
+    );
+    assert!(matches!(
+        res,
+        Err(BitcoinClientError::OutputMismatch {
+            output,
+            mismatch: OutputMismatch::WitnessScript,
+        }) if output == last
+    ));
+
+    let mut missing = psbt.clone();
+    missing.outputs.pop();
+    let res = async_client::BitcoinClient::new(utils::TransportReplayer::new(store))
+        .sign_psbt(&missing, &wallet, None)
+        .await;
+    assert!(matches!(
+        res,
+        Err(BitcoinClientError::OutputMismatch {
+            output,
+            mismatch: OutputMismatch::Missing,
+        }) if output == last
+    ));
+}
+
+#[tokio::test]
+async fn test_sim_keepalive() {
+    use ledger_bitcoin_client::apdu::APDUCommand;
+    use std::sync::Mutex;
+
+    #[derive(Debug, PartialEq)]
+    enum TimeoutError {
+        Timeout,
+        Disconnected,
+    }
+
+    // Times out the reads of the responses of the simulated device `timeouts` times in a
+    // row, as a transport would while the user takes time to confirm a prompt.
+    struct SlowUser {
+        sim: SimTransport,
+        timeouts: usize,
+        remaining: Mutex<usize>,
+        response: Mutex<Option<(StatusWord, Vec<u8>)>>,
+    }
+    impl SlowUser {
+        fn read(&self) -> Result<(StatusWord, Vec<u8>), TimeoutError> {
+            let mut remaining = self.remaining.lock().unwrap();
+            if *remaining > 0 {
+                *remaining -= 1;
+                return Err(TimeoutError::Timeout);
+            }
+            self.response
+                .lock()
+                .unwrap()
+                .take()
+                .ok_or(TimeoutError::Disconnected)
+        }
+    }
+    impl client::Transport for SlowUser {
+        type Error = TimeoutError;
+        fn exchange(&self, command: &APDUCommand) -> Result<(StatusWord, Vec<u8>), Self::Error> {
+            let res = client::Transport::exchange(&self.sim, command).unwrap();
+            *self.remaining.lock().unwrap() = self.timeouts;
+            *self.response.lock().unwrap() = Some(res);
+            self.read()
+        }
+        fn read_after_timeout(
+            &self,
+            error: &Self::Error,
+        ) -> Option<Result<(StatusWord, Vec<u8>), Self::Error>> {
+            match error {
+                TimeoutError::Timeout => Some(self.read()),
+                TimeoutError::Disconnected => None,
+            }
+        }
+    }
+    #[async_trait::async_trait]
+    impl async_client::Transport for SlowUser {
+        type Error = TimeoutError;
+        async fn exchange(
+            &self,
+            command: &APDUCommand,
+        ) -> Result<(StatusWord, Vec<u8>), Self::Error> {
+            client::Transport::exchange(self, command)
+        }
+        async fn read_after_timeout(
+            &self,
+            error: &Self::Error,
+        ) -> Option<Result<(StatusWord, Vec<u8>), Self::Error>> {
+            client::Transport::read_after_timeout(self, error)
+        }
+    }
+    let slow_user = |timeouts| SlowUser {
+        sim: SimTransport::default(),
+        timeouts,
+        remaining: Mutex::new(0),
+        response: Mutex::new(None),
+    };
+
+    let case = test_cases("./tests/data/sign_psbt.json").remove(0);
+    let wallet = wallet_from_case(&case);
+    let psbt: Psbt = deserialize(&base64::decode(case["psbt"].as_str().unwrap()).unwrap()).unwrap();
+
+    let res = client::BitcoinClient::new(slow_user(1)).sign_psbt(&psbt, &wallet, None);
+    assert!(matches!(
+        res,
+        Err(BitcoinClientError::Transport(TimeoutError::Timeout))
+    ));
+
+    let res = client::BitcoinClient::new(slow_user(2))
+        .with_keepalive(1)
+        .sign_psbt(&psbt, &wallet, None);
+    assert!(matches!(
+        res,
+        Err(BitcoinClientError::Transport(TimeoutError::Timeout))
+    ));
+
+    let res = client::BitcoinClient::new(slow_user(2))
+        .with_keepalive(2)
+        .sign_psbt(&psbt, &wallet, None)
+        .unwrap();
+    assert!(!res.is_empty());
+
+    let res_async = async_client::BitcoinClient::new(slow_user(3))
+        .with_keepalive(5)
+        .sign_psbt(&psbt, &wallet, None)
+        .await
+        .unwrap();
+    assert_eq!(res.len(), res_async.len());
+}
+
+#[tokio::test]
+async fn test_sim_timeouts() {
+    use ledger_bitcoin_client::apdu::APDUCommand;
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+
+    #[derive(Debug)]
+    struct Timeout;
+
+    // Answers with the response of the simulated device once `delay` elapsed after the
+    // command, timing out the reads every millisecond until then.
+    struct Delayed {
+        sim: SimTransport,
+        delay: Duration,
+        ready: Mutex<Instant>,
+        response: Mutex<Option<(StatusWord, Vec<u8>)>>,
+    }
+    impl Delayed {
+        fn read(&self) -> Result<(StatusWord, Vec<u8>), Timeout> {
+            std::thread::sleep(Duration::from_millis(1));
+            if *self.ready.lock().unwrap() > Instant::now() {
+                return Err(Timeout);
+            }
+            self.response.lock().unwrap().take().ok_or(Timeout)
+        }
+    }
+    impl client::Transport for Delayed {
+        type Error = Timeout;
+        fn exchange(&self, command: &APDUCommand) -> Result<(StatusWord, Vec<u8>), Self::Error> {
+            let res = client::Transport::exchange(&self.sim, command).unwrap();
+            *self.ready.lock().unwrap() = Instant::now() + self.delay;
+            *self.response.lock().unwrap() = Some(res);
+            self.read()
+        }
+        fn read_after_timeout(
+            &self,
+            _error: &Self::Error,
+        ) -> Option<Result<(StatusWord, Vec<u8>), Self::Error>> {
+            Some(self.read())
+        }
+    }
+    #[async_trait::async_trait]
+    impl async_client::Transport for Delayed {
+        type Error = Timeout;
+        async fn exchange(
+            &self,
+            command: &APDUCommand,
+        ) -> Result<(StatusWord, Vec<u8>), Self::Error> {
+            client::Transport::exchange(self, command)
+        }
+        async fn read_after_timeout(
+            &self,
+            error: &Self::Error,
+        ) -> Option<Result<(StatusWord, Vec<u8>), Self::Error>> {
+            client::Transport::read_after_timeout(self, error)
+        }
+    }
+    let delayed = |delay| Delayed {
+        sim: SimTransport::default(),
+        delay: Duration::from_millis(delay),
+        ready: Mutex::new(Instant::now()),
+        response: Mutex::new(None),
+    };
+    let path = DerivationPath::from_str("m/84'/1'/0'").unwrap();
+
+    // The device answers within both timeouts.
+    let client = client::BitcoinClient::new(delayed(5))
+        .with_timeouts(Duration::from_millis(100), Duration::from_secs(10));
+    client.get_extended_pubkey(&path, false).unwrap();
+
+    // The device is slower than the I/O timeout, but not than the interactive one.
+    let client = client::BitcoinClient::new(delayed(200))
+        .with_timeouts(Duration::from_millis(20), Duration::from_secs(10));
+    assert!(matches!(
+        client.get_master_fingerprint(),
+        Err(BitcoinClientError::Timeout { interactive: false })
+    ));
+    client.get_extended_pubkey(&path, true).unwrap();
+
+    let client = async_client::BitcoinClient::new(delayed(200))
+        .with_timeouts(Duration::from_millis(20), Duration::from_millis(50));
+    assert!(matches!(
+        client.get_extended_pubkey(&path, false).await,
+        Err(BitcoinClientError::Timeout { interactive: false })
+    ));
+    assert!(matches!(
+        client.get_extended_pubkey(&path, true).await,
+        Err(BitcoinClientError::Timeout { interactive: true })
+    ));
+}
+
+#[tokio::test]
+async fn test_sim_exchange_with_timeout() {
+    use ledger_bitcoin_client::apdu::APDUCommand;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[derive(Debug)]
+    struct Timeout;
+
+    // Stops waiting for the simulated device, answering after `delay`, once the timeout of
+    // the exchange elapsed, and records the timeouts.
+    struct Timed {
+        sim: SimTransport,
+        delay: Duration,
+        timeouts: Arc<Mutex<Vec<Option<Duration>>>>,
+    }
+    impl Timed {
+        fn answer(
+            &self,
+            command: &APDUCommand,
+            timeout: Option<Duration>,
+        ) -> Result<(StatusWord, Vec<u8>), Timeout> {
+            self.timeouts.lock().unwrap().push(timeout);
+            match timeout {
+                Some(timeout) if timeout < self.delay => {
+                    std::thread::sleep(timeout);
+                    Err(Timeout)
+                }
+                _ => Ok(client::Transport::exchange(&self.sim, command).unwrap()),
+            }
+        }
+    }
+    impl client::Transport for Timed {
+        type Error = Timeout;
+        fn exchange(&self, command: &APDUCommand) -> Result<(StatusWord, Vec<u8>), Self::Error> {
+            self.answer(command, None)
+        }
+        fn exchange_with_timeout(
+            &self,
+            command: &APDUCommand,
+            timeout: Duration,
+        ) -> Result<(StatusWord, Vec<u8>), Self::Error> {
+            self.answer(command, Some(timeout))
+        }
+    }
+    #[async_trait::async_trait]
+    impl async_client::Transport for Timed {
+        type Error = Timeout;
+        async fn exchange(
+            &self,
+            command: &APDUCommand,
+        ) -> Result<(StatusWord, Vec<u8>), Self::Error> {
+            self.answer(command, None)
+        }
+        async fn exchange_with_timeout(
+            &self,
+            command: &APDUCommand,
+            timeout: Duration,
+        ) -> Result<(StatusWord, Vec<u8>), Self::Error> {
+            self.answer(command, Some(timeout))
+        }
+    }
+    let timeouts = Arc::new(Mutex::new(Vec::new()));
+    let timed = |delay| Timed {
+        sim: SimTransport::default(),
+        delay: Duration::from_millis(delay),
+        timeouts: timeouts.clone(),
+    };
+    let (io, interactive) = (Duration::from_millis(20), Duration::from_secs(10));
+    let path = DerivationPath::from_str("m/84'/1'/0'").unwrap();
+
+    // Without timeouts, the transport exchanges the commands with `exchange`.
+    let client = client::BitcoinClient::new(timed(5));
+    client.get_master_fingerprint().unwrap();
+    assert_eq!(*timeouts.lock().unwrap(), [None]);
+    timeouts.lock().unwrap().clear();
+
+    let client = client::BitcoinClient::new(timed(5)).with_timeouts(io, interactive);
+    client.get_master_fingerprint().unwrap();
+    client.get_extended_pubkey(&path, true).unwrap();
+    assert_eq!(*timeouts.lock().unwrap(), [Some(io), Some(interactive)]);
+
+    // The transport gives up after the I/O timeout, without retries.
+    let client = async_client::BitcoinClient::new(timed(200)).with_timeouts(io, interactive);
+    assert!(matches!(
+        client.get_master_fingerprint().await,
+        Err(BitcoinClientError::Timeout { interactive: false })
+    ));
+    client.get_extended_pubkey(&path, true).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_non_ascii_response() {
+    use ledger_bitcoin_client::apdu::{APDUCommand, BitcoinCommandCode};
+
+    // Answers every command with the same bytes.
+    struct Raw(Vec<u8>);
+    impl client::Transport for Raw {
+        type Error = core::convert::Infallible;
+        fn exchange(&self, _command: &APDUCommand) -> Result<(StatusWord, Vec<u8>), Self::Error> {
+            Ok((StatusWord::OK, self.0.clone()))
+        }
+    }
+    #[async_trait::async_trait]
+    impl async_client::Transport for Raw {
+        type Error = core::convert::Infallible;
+        async fn exchange(
+            &self,
+            command: &APDUCommand,
+        ) -> Result<(StatusWord, Vec<u8>), Self::Error> {
+            client::Transport::exchange(self, command)
+        }
+    }
+
+    let case = test_cases("./tests/data/get_wallet_address.json").remove(0);
+    let wallet = wallet_from_case(&case);
+    let path = DerivationPath::from_str("m/84'/1'/0'").unwrap();
+    // Invalid UTF-8, and valid UTF-8 that is not ASCII.
+    for data in [b"tb1q\xff\xfe".to_vec(), "tb1q\u{e9}".as_bytes().to_vec()] {
+        let unexpected = |command: BitcoinCommandCode| {
+            let data = data.clone();
+            move |e: BitcoinClientError<core::convert::Infallible>| {
+                matches!(e, BitcoinClientError::UnexpectedResult { command: c, data: d }
+                    if c == command as u8 && d == data)
+            }
+        };
+
+        let client = client::BitcoinClient::new(Raw(data.clone()));
+        assert!(unexpected(BitcoinCommandCode::GetWalletAddress)(
+            client
+                .get_wallet_address(&wallet, None, false, 0, false)
+                .unwrap_err()
+        ));
+        assert!(unexpected(BitcoinCommandCode::GetExtendedPubkey)(
+            client.get_extended_pubkey(&path, false).unwrap_err()
+        ));
+
+        let client = async_client::BitcoinClient::new(Raw(data.clone()));
+        assert!(unexpected(BitcoinCommandCode::GetWalletAddress)(
+            client
+                .get_wallet_address(&wallet, None, false, 0, false)
+                .await
+                .unwrap_err()
+        ));
+        assert!(unexpected(BitcoinCommandCode::GetExtendedPubkey)(
+            client.get_extended_pubkey(&path, false).await.unwrap_err()
+        ));
+    }
+}
+
+#[tokio::test]
+async fn test_sim_trailing_bytes() {
+    use ledger_bitcoin_client::apdu::{APDUCommand, BitcoinCommandCode, Cla};
+
+    // Appends bytes to the final response of the simulated device to a command, which
+    // follows the responses to the client commands.
+    struct Trailing {
+        sim: SimTransport,
+        ins: u8,
+        current: std::sync::Mutex<u8>,
+    }
+    impl client::Transport for Trailing {
+        type Error = ();
+        fn exchange(&self, command: &APDUCommand) -> Result<(StatusWord, Vec<u8>), Self::Error> {
+            let mut current = self.current.lock().unwrap();
+            if command.cla != Cla::Framework as u8 {
+                *current = command.ins;
+            }
+            let (sw, mut data) = client::Transport::exchange(&self.sim, command).unwrap();
+            if *current == self.ins && sw == StatusWord::OK {
+                data.extend_from_slice(&[0x00, 0x01]);
+            }
+            Ok((sw, data))
+        }
+    }
+    #[async_trait::async_trait]
+    impl async_client::Transport for Trailing {
+        type Error = ();
+        async fn exchange(
+            &self,
+            command: &APDUCommand,
+        ) -> Result<(StatusWord, Vec<u8>), Self::Error> {
+            client::Transport::exchange(self, command)
+        }
+    }
+    let trailing = |code: BitcoinCommandCode| Trailing {
+        sim: SimTransport::default(),
+        ins: code as u8,
+        current: std::sync::Mutex::new(0),
+    };
+    let unexpected = |code: BitcoinCommandCode| move |res: BitcoinClientError<()>| matches!(res, BitcoinClientError::UnexpectedResult { command, .. } if command == code as u8);
+    let path = DerivationPath::from_str("m/84'/1'/0'").unwrap();
+    let wallet = wallet_from_case(&test_cases("./tests/data/register_wallet.json")[0]);
+
+    let client = client::BitcoinClient::new(trailing(BitcoinCommandCode::GetVersion));
+    assert!(client
+        .get_version()
+        .map_err(unexpected(BitcoinCommandCode::GetVersion))
+        .unwrap_err());
+    let client = client::BitcoinClient::new(trailing(BitcoinCommandCode::GetMasterFingerprint));
+    assert!(client
+        .get_master_fingerprint()
+        .map_err(unexpected(BitcoinCommandCode::GetMasterFingerprint))
+        .unwrap_err());
+    let client = client::BitcoinClient::new(trailing(BitcoinCommandCode::GetExtendedPubkey));
+    assert!(client
+        .get_extended_pubkey(&path, false)
+        .map_err(unexpected(BitcoinCommandCode::GetExtendedPubkey))
+        .unwrap_err());
+    let client = client::BitcoinClient::new(trailing(BitcoinCommandCode::RegisterWallet));
+    assert!(client
+        .register_wallet(&wallet)
+        .map_err(unexpected(BitcoinCommandCode::RegisterWallet))
+        .unwrap_err());
+    let client = client::BitcoinClient::new(trailing(BitcoinCommandCode::SignMessage));
+    assert!(client
+        .sign_message(b"hello", &path)
+        .map_err(unexpected(BitcoinCommandCode::SignMessage))
+        .unwrap_err());
+
+    let client = async_client::BitcoinClient::new(trailing(BitcoinCommandCode::GetVersion));
+    assert!(client
+        .get_version()
+        .await
+        .map_err(unexpected(BitcoinCommandCode::GetVersion))
+        .unwrap_err());
+    let client = async_client::BitcoinClient::new(trailing(BitcoinCommandCode::GetExtendedPubkey));
+    assert!(client
+        .get_extended_pubkey(&path, true)
+        .await
+        .map_err(unexpected(BitcoinCommandCode::GetExtendedPubkey))
+        .unwrap_err());
+    let client = async_client::BitcoinClient::new(trailing(BitcoinCommandCode::RegisterWallet));
+    assert!(client
+        .register_wallet(&wallet)
+        .await
+        .map_err(unexpected(BitcoinCommandCode::RegisterWallet))
+        .unwrap_err());
+
+    // Without the trailing bytes, the responses are accepted.
+    let client = client::BitcoinClient::new(trailing(BitcoinCommandCode::SignPSBT));
+    client.get_version().unwrap();
+    client.get_extended_pubkey(&path, false).unwrap();
+    client.register_wallet(&wallet).unwrap();
+}
+
+#[tokio::test]
+async fn test_sim_sign_psbt_inputs() {
+    let case = test_cases("./tests/data/sign_psbt.json").remove(0);
+    let wallet = wallet_from_case(&case);
+    let mut psbt: Psbt =
+        deserialize(&base64::decode(case["psbt"].as_str().unwrap()).unwrap()).unwrap();
+    // two inputs of the wallet, the simulated device does not check that they differ
+    psbt.unsigned_tx
+        .input
+        .push(psbt.unsigned_tx.input[0].clone());
+    psbt.inputs.push(psbt.inputs[0].clone());
+
+    let all = client::BitcoinClient::new(SimTransport::default())
+        .sign_psbt(&psbt, &wallet, None)
+        .unwrap();
+    assert_eq!(all.len(), psbt.inputs.len());
+
+    let selected = client::BitcoinClient::new(SimTransport::default())
+        .sign_psbt_inputs(&psbt, &wallet, None, &[1])
+        .unwrap();
+    assert_eq!(selected.len(), 1);
+    assert_eq!(selected[0].0, 1);
+    // the signature hash still commits to the whole transaction
+    assert_eq!(
+        selected[0].1.as_ecdsa().unwrap(),
+        all.iter()
+            .find(|(i, _)| *i == 1)
+            .unwrap()
+            .1
+            .as_ecdsa()
+            .unwrap()
+    );
+
+    let selected = async_client::BitcoinClient::new(SimTransport::default())
+        .sign_psbt_inputs(&psbt, &wallet, None, &[0])
+        .await
+        .unwrap();
+    assert_eq!(
+        selected.iter().map(|(i, _)| *i).collect::<Vec<_>>(),
+        vec![0]
+    );
+
+    let res = client::BitcoinClient::new(SimTransport::default()).sign_psbt_inputs(
+        &psbt,
+        &wallet,
+        None,
+        &[psbt.inputs.len()],
+    );
+    assert!(matches!(res, Err(BitcoinClientError::ClientError(_))));
+}
+
+#[tokio::test]
+async fn test_sim_sign_psbt_input() {
+    let case = test_cases("./tests/data/sign_psbt.json").remove(0);
+    let wallet = wallet_from_case(&case);
+    let mut psbt: Psbt =
+        deserialize(&base64::decode(case["psbt"].as_str().unwrap()).unwrap()).unwrap();
+    // two inputs of the wallet, and one without key origins
+    for _ in 0..2 {
+        psbt.unsigned_tx
+            .input
+            .push(psbt.unsigned_tx.input[0].clone());
+        psbt.inputs.push(psbt.inputs[0].clone());
+    }
+    psbt.inputs[2].bip32_derivation.clear();
+    psbt.inputs[2].tap_key_origins.clear();
+
+    let all = client::BitcoinClient::new(SimTransport::default())
+        .sign_psbt(&psbt, &wallet, None)
+        .unwrap();
+    let expected = all.iter().find(|(i, _)| *i == 1).unwrap().1.as_ecdsa();
+
+    let signature = client::BitcoinClient::new(SimTransport::default())
+        .sign_psbt_input(&psbt, &wallet, None, 1)
+        .unwrap();
+    assert_eq!(signature.as_ecdsa(), expected);
+    let signature = async_client::BitcoinClient::new(SimTransport::default())
+        .sign_psbt_input(&psbt, &wallet, None, 1)
+        .await
+        .unwrap();
+    assert_eq!(signature.as_ecdsa(), expected);
+
+    assert!(matches!(
+        client::BitcoinClient::new(SimTransport::default())
+            .sign_psbt_input(&psbt, &wallet, None, 2),
+        Err(BitcoinClientError::Device {
+            status: StatusWord::IncorrectData,
+            ..
+        })
+    ));
+    // an input that is not in the psbt
+    assert!(matches!(
+        client::BitcoinClient::new(SimTransport::default())
+            .sign_psbt_input(&psbt, &wallet, None, 3),
+        Err(BitcoinClientError::ClientError(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_sim_sign_psbt_multi() {
+    use ledger_bitcoin_client::apdu::{BitcoinCommandCode, Cla, Direction};
+    use std::sync::{Arc, Mutex};
+
+    let cases = test_cases("./tests/data/sign_psbt.json");
+    let psbt_of = |case: &serde_json::Value| -> Psbt {
+        deserialize(&base64::decode(case["psbt"].as_str().unwrap()).unwrap()).unwrap()
+    };
+
+    // a transaction spending from a single-sig wallet and from a taproot wallet
+    let mut psbt = psbt_of(&cases[0]);
+    let n_inputs = psbt.inputs.len();
+    let other = psbt_of(&cases[2]);
+    psbt.unsigned_tx.input.extend(other.unsigned_tx.input);
+    psbt.unsigned_tx.output.extend(other.unsigned_tx.output);
+    psbt.inputs.extend(other.inputs);
+    psbt.outputs.extend(other.outputs);
+
+    // the first wallet has no input in the transaction
+    let wallets = vec![
+        (wallet_from_case(&cases[1]), hmac_from_case(&cases[1])),
+        (wallet_from_case(&cases[0]), hmac_from_case(&cases[0])),
+        (wallet_from_case(&cases[2]), hmac_from_case(&cases[2])),
+    ];
+
+    let sign_psbt_commands = Arc::new(Mutex::new(0));
+    let mut client = client::BitcoinClient::new(SimTransport::default());
+    let count = sign_psbt_commands.clone();
+    client.set_apdu_logger(Box::new(move |direction, apdu| {
+        if direction == Direction::Command
+            && apdu[0] == Cla::Bitcoin as u8
+            && apdu[1] == BitcoinCommandCode::SignPSBT as u8
+        {
+            *count.lock().unwrap() += 1;
+        }
+    }));
+    let signatures = client.sign_psbt_multi(&psbt, &wallets).unwrap();
+    // one confirmation per wallet with inputs, and every input signed once
+    assert_eq!(*sign_psbt_commands.lock().unwrap(), 2);
+    let mut indexes: Vec<usize> = signatures.iter().map(|(i, _)| *i).collect();
+    indexes.sort_unstable();
+    assert_eq!(indexes, (0..psbt.inputs.len()).collect::<Vec<_>>());
+    for (index, signature) in &signatures {
+        assert_eq!(signature.is_taproot(), *index >= n_inputs);
+    }
+
+    let signatures_async = async_client::BitcoinClient::new(SimTransport::default())
+        .sign_psbt_multi(&psbt, &wallets)
+        .await
+        .unwrap();
+    assert_eq!(signatures_async.len(), signatures.len());
+
+    // the signing stops at the first wallet rejected by the device
+    let res = client::BitcoinClient::new(SimTransport::default()).sign_psbt_multi(
+        &psbt,
+        &[(
+            wallet_from_case(&cases[2]),
+            Some(wallet::WalletHmac([0x01; 32])),
+        )],
+    );
+    assert!(res.is_err());
+}
+
+// Relays a sign_psbt to the device as raw bytes, using only the public building blocks of
+// the client, like a signing server forwarding the commands over its own RPC would.
+#[test]
+fn test_sim_relay_sign_psbt() {
+    use bitcoin::consensus::encode::{deserialize_partial, VarInt};
+    use core::convert::TryFrom;
+    use ledger_bitcoin_client::{
+        apdu::APDUCommand, client::Transport, command, interpreter,
+        interpreter::ClientCommandInterpreter,
+    };
+
+    let device = SimTransport::default();
+    let remote = |bytes: Vec<u8>| -> Vec<u8> {
+        let (status, mut data) = device
+            .exchange(&APDUCommand {
+                cla: bytes[0],
+                ins: bytes[1],
+                p1: bytes[2],
+                p2: bytes[3],
+                data: bytes[5..].to_vec(),
+            })
+            .unwrap();
+        data.extend(status.to_u16().to_be_bytes());
+        data
+    };
+
+    let case = test_cases("./tests/data/sign_psbt.json").remove(0);
+    let wallet = wallet_from_case(&case);
+    let psbt: Psbt = deserialize(&base64::decode(case["psbt"].as_str().unwrap()).unwrap()).unwrap();
+
+    let mut intpr = ClientCommandInterpreter::new();
+    intpr.add_known_preimage(wallet.serialize());
+    let keys: Vec<String> = wallet.keys.iter().map(|k| k.to_string()).collect();
+    intpr.add_known_list(&keys);
+    intpr.add_known_preimage(wallet.descriptor_template.as_bytes().to_vec());
+
+    let global_map: Vec<(Vec<u8>, Vec<u8>)> = psbt::get_v2_global_pairs(&psbt)
+        .into_iter()
+        .map(psbt::deserialize_pairs)
+        .collect();
+    intpr.add_known_mapping(&global_map);
+    let mut input_commitments = Vec::new();
+    for (input, txin) in psbt.inputs.iter().zip(&psbt.unsigned_tx.input) {
+        let map: Vec<(Vec<u8>, Vec<u8>)> = psbt::get_v2_input_pairs(input, txin)
+            .into_iter()
+            .map(psbt::deserialize_pairs)
+            .collect();
+        intpr.add_known_mapping(&map);
+        input_commitments.push(interpreter::get_merkleized_map_commitment(&map));
+    }
+    let mut output_commitments = Vec::new();
+    for (output, txout) in psbt.outputs.iter().zip(&psbt.unsigned_tx.output) {
+        let map: Vec<(Vec<u8>, Vec<u8>)> = psbt::get_v2_output_pairs(output, txout)
+            .into_iter()
+            .map(psbt::deserialize_pairs)
+            .collect();
+        intpr.add_known_mapping(&map);
+        output_commitments.push(interpreter::get_merkleized_map_commitment(&map));
+    }
+    let cmd = command::sign_psbt(
+        &interpreter::get_merkleized_map_commitment(&global_map),
+        psbt.inputs.len(),
+        &intpr.add_known_list(&input_commitments),
+        psbt.outputs.len(),
+        &intpr.add_known_list(&output_commitments),
+        &wallet,
+        None,
+    );
+
+    let mut response = remote(cmd.encode());
+    loop {
+        let (data, sw) = response.split_at(response.len() - 2);
+        match StatusWord::try_from(u16::from_be_bytes([sw[0], sw[1]])).unwrap() {
+            StatusWord::InterruptedExecution => {
+                let reply = intpr.execute(data.to_vec()).unwrap();
+                response = remote(command::continue_interrupted(reply).encode());
+            }
+            status => {
+                assert_eq!(status, StatusWord::OK);
+                break;
+            }
+        }
+    }
+
+    let expected = client::BitcoinClient::new(SimTransport::default())
+        .sign_psbt(&psbt, &wallet, None)
+        .unwrap();
+    let yielded = intpr.take_yielded();
+    assert_eq!(yielded.len(), expected.len());
+    for (result, (index, _)) in yielded.iter().zip(expected) {
+        let (input_index, i): (VarInt, usize) = deserialize_partial(result).unwrap();
+        assert_eq!(input_index.0 as usize, index);
+        assert!(PartialSignature::from_slice(&result[i..]).is_ok());
+    }
+}
+
+#[tokio::test]
+async fn test_sim_address_cache() {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    let case = test_cases("./tests/data/register_wallet.json").remove(0);
+    let wallet = wallet_from_case(&case);
+
+    let exchanges = Arc::new(AtomicUsize::new(0));
+    let mut client = client::BitcoinClient::new(SimTransport::default()).with_address_cache();
+    let counter = exchanges.clone();
+    client.set_apdu_logger(Box::new(move |_, _| {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }));
+    let (_, hmac) = client.register_wallet(&wallet).unwrap();
+    let count = || exchanges.swap(0, Ordering::Relaxed);
+    count();
+
+    let address = client
+        .get_wallet_address(&wallet, Some(&hmac), false, 3, false)
+        .unwrap();
+    assert!(count() > 0);
+    let cached = client
+        .get_wallet_address(&wallet, Some(&hmac), false, 3, false)
+        .unwrap();
+    assert_eq!(cached, address);
+    assert_eq!(count(), 0);
+
+    // other indexes and displayed addresses are requested from the device
+    client
+        .get_wallet_address(&wallet, Some(&hmac), true, 3, false)
+        .unwrap();
+    assert!(count() > 0);
+    let displayed = client
+        .get_wallet_address(&wallet, Some(&hmac), false, 3, true)
+        .unwrap();
+    assert_eq!(displayed, address);
+    assert!(count() > 0);
+
+    // registering the policy again drops its addresses
+    client.register_wallet(&wallet).unwrap();
+    count();
+    client
+        .get_wallet_address(&wallet, Some(&hmac), true, 3, false)
+        .unwrap();
+    assert!(count() > 0);
+
+    let client = async_client::BitcoinClient::new(SimTransport::default()).with_address_cache();
+    let address_async = client
+        .get_wallet_address(&wallet, Some(&hmac), false, 3, false)
+        .await
+        .unwrap();
+    assert_eq!(address_async, address);
+}
+
+#[tokio::test]
+async fn test_sim_byte_transport() {
+    use ledger_bitcoin_client::apdu::{decode_response, ByteTransportError};
+
+    let path = DerivationPath::from_str("m/44'/1'/0'/0").unwrap();
+    let expected = client::BitcoinClient::new(SimTransport::default())
+        .get_extended_pubkey(&path, false)
+        .unwrap();
+
+    let xpub = client::BitcoinClient::new(client::ByteTransportAdapter(
+        utils::RawSimTransport::default(),
+    ))
+    .get_extended_pubkey(&path, false)
+    .unwrap();
+    assert_eq!(xpub, expected);
+
+    let xpub = async_client::BitcoinClient::new(async_client::ByteTransportAdapter(
+        utils::RawSimTransport::default(),
+    ))
+    .get_extended_pubkey(&path, false)
+    .await
+    .unwrap();
+    assert_eq!(xpub, expected);
+
+    assert_eq!(
+        decode_response(&[0x01, 0x02, 0x90, 0x00]),
+        Some((StatusWord::OK, vec![0x01, 0x02]))
+    );
+    assert_eq!(
+        decode_response(&[0x6f, 0x00]),
+        Some((StatusWord::Unknown(0x6f00), Vec::new()))
+    );
+    assert_eq!(decode_response(&[0x90]), None);
+
+    struct Truncated;
+    impl client::ByteTransport for Truncated {
+        type Error = ();
+        fn send_recv(&self, _: &[u8]) -> Result<Vec<u8>, Self::Error> {
+            Ok(vec![0x90])
+        }
+    }
+    let res = client::BitcoinClient::new(client::ByteTransportAdapter(Truncated)).get_version();
+    assert!(matches!(
+        res,
+        Err(BitcoinClientError::Transport(
+            ByteTransportError::InvalidResponse(_)
+        ))
+    ));
+}
+
+#[tokio::test]
+async fn test_apdu_logger() {
+    use ledger_bitcoin_client::{apdu::Direction, command};
+    use std::sync::{Arc, Mutex};
+
+    let case = test_cases("./tests/data/get_wallet_address.json").remove(0);
+    let wallet = wallet_from_case(&case);
+    let expected_command = command::get_wallet_address(&wallet, None, false, 0, false).encode();
+
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let mut client = client::BitcoinClient::new(SimTransport::default());
+    let sync_log = log.clone();
+    client.set_apdu_logger(Box::new(move |direction, apdu| {
+        sync_log.lock().unwrap().push((direction, apdu.to_vec()))
+    }));
+    let address = client
+        .get_wallet_address(&wallet, None, false, 0, false)
+        .unwrap();
+
+    let exchanges = std::mem::take(&mut *log.lock().unwrap());
+    // the device interrupts the command to request the wallet policy
+    assert!(exchanges.len() > 2);
+    assert_eq!(exchanges[0], (Direction::Command, expected_command.clone()));
+    for (i, (direction, _)) in exchanges.iter().enumerate() {
+        let expected = if i % 2 == 0 {
+            Direction::Command
+        } else {
+            Direction::Response
+        };
+        assert_eq!(*direction, expected);
+    }
+    let (_, response) = exchanges.last().unwrap();
+    assert_eq!(response[response.len() - 2..], [0x90, 0x00]);
+    assert_eq!(
+        &response[..response.len() - 2],
+        address.to_string().as_bytes()
+    );
+
+    let mut client = async_client::BitcoinClient::new(SimTransport::default());
+    let async_log = log.clone();
+    client.set_apdu_logger(Box::new(move |direction, apdu| {
+        async_log.lock().unwrap().push((direction, apdu.to_vec()))
+    }));
+    client
+        .get_wallet_address(&wallet, None, false, 0, false)
+        .await
+        .unwrap();
+    assert_eq!(*log.lock().unwrap(), exchanges);
+}
+
+#[tokio::test]
+async fn test_sim_check_wallet_device() {
+    let case = test_cases("./tests/data/sign_psbt.json").remove(0);
+    let wallet = wallet_from_case(&case);
+    client::BitcoinClient::new(SimTransport::default())
+        .check_wallet_device(&wallet)
+        .unwrap();
+
+    let other_device = SimTransport::new(&[0x42; 64]);
+    let res = async_client::BitcoinClient::new(other_device)
+        .check_wallet_device(&wallet)
+        .await;
+    assert!(matches!(
+        res,
+        Err(BitcoinClientError::WrongDeviceForWallet { fingerprint })
+            if fingerprint == SimTransport::new(&[0x42; 64]).master_fingerprint()
+    ));
+}
+
+#[tokio::test]
+async fn test_sim_sign_and_finalize() {
+    use bitcoin::{OutPoint, Transaction, TxIn, TxOut};
+
+    let case = test_cases("./tests/data/sign_psbt.json").remove(0);
+    let wallet = wallet_from_case(&case);
+    let mut psbt: Psbt =
+        deserialize(&base64::decode(case["psbt"].as_str().unwrap()).unwrap()).unwrap();
+
+    let tx: Transaction = deserialize(
+        &client::BitcoinClient::new(SimTransport::default())
+            .sign_and_finalize(&psbt, &wallet, None)
+            .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(tx.output, psbt.unsigned_tx.output);
+    for (txin, unsigned) in tx.input.iter().zip(&psbt.unsigned_tx.input) {
+        assert_eq!(txin.previous_output, unsigned.previous_output);
+        // P2SH-P2WPKH: redeem script and signature with the public key
+        assert!(!txin.script_sig.is_empty());
+        assert_eq!(txin.witness.len(), 2);
+    }
+
+    // An input of another wallet is left unsigned by the device.
+    let foreign = psbt.unsigned_tx.input.len();
+    psbt.unsigned_tx.input.push(TxIn {
+        previous_output: OutPoint::new(tx.txid(), 0),
+        ..psbt.unsigned_tx.input[0].clone()
+    });
+    let mut input = psbt.inputs[0].clone();
+    input.bip32_derivation.clear();
+    input.witness_utxo = Some(TxOut {
+        value: 10_000,
+        script_pubkey: tx.output[0].script_pubkey.clone(),
+    });
+    psbt.inputs.push(input);
+
+    let res = async_client::BitcoinClient::new(SimTransport::default())
+        .sign_and_finalize(&psbt, &wallet, None)
+        .await;
+    assert!(matches!(
+        res,
+        Err(BitcoinClientError::IncompleteInputs(inputs)) if inputs == vec![foreign]
+    ));
+}
+
+#[tokio::test]
+async fn test_sim_sign_message() {
+    use bitcoin::{
+        hashes::{sha256d, Hash},
+        secp256k1::{ecdsa::RecoverableSignature, ecdsa::RecoveryId, Message, Secp256k1},
+    };
+
+    let path = DerivationPath::from_str("m/44'/1'/0'/0").unwrap();
+    let message = "hello ".repeat(50);
+    let mut preimage = b"\x15Qtum Signed Message:\n".to_vec();
+    preimage.extend(bitcoin::consensus::encode::serialize(&bitcoin::VarInt(
+        message.len() as u64,
+    )));
+    preimage.extend(message.as_bytes());
+    let digest = Message::from_slice(&sha256d::Hash::hash(&preimage)).unwrap();
+    let expected_key = SimTransport::default().xpub(&path).public_key;
+
+    let (header, sig) = client::BitcoinClient::new(SimTransport::default())
+        .sign_message(message.as_bytes(), &path)
+        .unwrap();
+    // P2PKH with a compressed key
+    assert!((31..=34).contains(&header));
+    let sig = RecoverableSignature::from_compact(
+        &sig.serialize_compact(),
+        RecoveryId::from_i32(header as i32 - 31).unwrap(),
+    )
+    .unwrap();
+    assert_eq!(
+        Secp256k1::verification_only()
+            .recover_ecdsa(&digest, &sig)
+            .unwrap(),
+        expected_key
+    );
+
+    let (header_async, sig_async) = async_client::BitcoinClient::new(SimTransport::default())
+        .sign_message(message.as_bytes(), &path)
+        .await
+        .unwrap();
+    assert_eq!(header, header_async);
+
+    let address = bitcoin::Address::p2pkh(
+        &bitcoin::PublicKey::new(expected_key),
+        bitcoin::Network::Testnet,
+    );
+    assert!(
+        message::verify_signed_message(message.as_bytes(), header, &sig_async, &address).unwrap()
+    );
+    assert_eq!(
+        message::recover_address(
+            message.as_bytes(),
+            header,
+            &sig_async,
+            bitcoin::Network::Testnet
+        )
+        .unwrap(),
+        address
+    );
+
+    // the signature is bound to the key of the requested path
+    let sibling = DerivationPath::from_str("m/44'/1'/0'/1").unwrap();
+    let address = bitcoin::Address::p2pkh(
+        &bitcoin::PublicKey::new(SimTransport::default().xpub(&sibling).public_key),
+        bitcoin::Network::Testnet,
+    );
+    assert!(
+        !message::verify_signed_message(message.as_bytes(), header, &sig_async, &address).unwrap()
+    );
+}
+
+#[tokio::test]
+async fn test_replay_recorded_session() {
+    use ledger_bitcoin_client::replay::{apdu_recorder, ReplayError, ReplayTransport};
+
+    let case = test_cases("./tests/data/sign_psbt.json").remove(0);
+    let wallet = wallet_from_case(&case);
+    let psbt: Psbt = deserialize(&base64::decode(case["psbt"].as_str().unwrap()).unwrap()).unwrap();
+
+    let sigs = |sigs: Vec<(usize, PartialSignature)>| {
+        sigs.into_iter()
+            .map(|(i, sig)| (i, sig.as_ecdsa(), sig.as_schnorr()))
+            .collect::<Vec<_>>()
+    };
+
+    let path = std::env::temp_dir().join(format!("apdu-replay-{}.bin", std::process::id()));
+    let mut client = client::BitcoinClient::new(SimTransport::default());
+    client.set_apdu_logger(apdu_recorder(std::fs::File::create(&path).unwrap()));
+    let fingerprint = client.get_master_fingerprint().unwrap();
+    let signatures = sigs(client.sign_psbt(&psbt, &wallet, None).unwrap());
+    drop(client);
+    let recording = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let client =
+        client::BitcoinClient::new(ReplayTransport::from_reader(recording.as_slice()).unwrap());
+    assert_eq!(client.get_master_fingerprint().unwrap(), fingerprint);
+    assert_eq!(
+        sigs(client.sign_psbt(&psbt, &wallet, None).unwrap()),
+        signatures
+    );
+
+    let client = async_client::BitcoinClient::new(
+        ReplayTransport::from_reader(recording.as_slice()).unwrap(),
+    );
+    assert_eq!(client.get_master_fingerprint().await.unwrap(), fingerprint);
+    assert_eq!(
+        sigs(client.sign_psbt(&psbt, &wallet, None).await.unwrap()),
+        signatures
+    );
+
+    // The commands of the client must be the recorded ones.
+    let client =
+        client::BitcoinClient::new(ReplayTransport::from_reader(recording.as_slice()).unwrap());
+    assert!(matches!(
+        client.get_version(),
+        Err(BitcoinClientError::Transport(
+            ReplayError::UnexpectedCommand { index: 0, .. }
+        ))
+    ));
+}
+
+#[tokio::test]
+async fn test_sim_sign_psbt_split() {
+    let case = test_cases("./tests/data/sign_psbt.json").remove(0);
+    let wallet = wallet_from_case(&case);
+    let mut psbt: Psbt =
+        deserialize(&base64::decode(case["psbt"].as_str().unwrap()).unwrap()).unwrap();
+    // three inputs of the wallet and one without key origins
+    for _ in 0..3 {
+        psbt.unsigned_tx
+            .input
+            .push(psbt.unsigned_tx.input[0].clone());
+        psbt.inputs.push(psbt.inputs[0].clone());
+    }
+    psbt.inputs[2].bip32_derivation.clear();
+    psbt.inputs[2].tap_key_origins.clear();
+
+    let parts = psbt::split_for_signing(&psbt, 2);
+    assert_eq!(parts.len(), 2);
+    assert!(parts
+        .iter()
+        .all(|part| part.unsigned_tx == psbt.unsigned_tx));
+
+    // no split
+    let whole = psbt::split_for_signing(&psbt, 0);
+    assert_eq!(whole, psbt::split_for_signing(&psbt, 4));
+    assert_eq!(whole.len(), 1);
+    assert_eq!(whole[0].inputs, psbt.inputs);
+
+    let client = client::BitcoinClient::new(SimTransport::default());
+    let mut signatures = Vec::new();
+    for part in &parts {
+        signatures.push(client.sign_psbt(part, &wallet, None).unwrap());
+    }
+    assert_eq!(
+        signatures
+            .iter()
+            .map(|sigs| sigs.iter().map(|(i, _)| *i).collect::<Vec<_>>())
+            .collect::<Vec<_>>(),
+        vec![vec![0, 1], vec![3]]
+    );
+    let merged = psbt::merge_signatures(&psbt, signatures).unwrap();
+
+    // the same signatures as for the whole psbt
+    let mut expected = psbt.clone();
+    for (index, signature) in async_client::BitcoinClient::new(SimTransport::default())
+        .sign_psbt(&psbt, &wallet, None)
+        .await
+        .unwrap()
+    {
+        signature.apply(&mut expected.inputs[index]);
+    }
+    assert_eq!(merged, expected);
+
+    let signature = client
+        .sign_psbt(&parts[1], &wallet, None)
+        .unwrap()
+        .remove(0)
+        .1;
+    assert_eq!(
+        psbt::merge_signatures(&psbt, vec![vec![(4, signature)]]),
+        Err(psbt::MergeError::InputOutOfRange { input: 4 })
+    );
+}
+
+#[test]
+fn test_sim_sign_psbt_split_too_many_inputs() {
+    let case = test_cases("./tests/data/sign_psbt.json").remove(0);
+    let wallet = wallet_from_case(&case);
+    let mut psbt: Psbt =
+        deserialize(&base64::decode(case["psbt"].as_str().unwrap()).unwrap()).unwrap();
+    while psbt.inputs.len() <= psbt::MAX_N_INPUTS_CAN_SIGN {
+        psbt.unsigned_tx
+            .input
+            .push(psbt.unsigned_tx.input[0].clone());
+        psbt.inputs.push(psbt.inputs[0].clone());
+    }
+
+    // the parts sign fewer inputs but keep all the inputs of the transaction
+    let parts = psbt::split_for_signing(&psbt, 256);
+    assert_eq!(parts.len(), 3);
+    assert!(parts.iter().all(|part| part.inputs.len() == 513));
+
+    let client = client::BitcoinClient::new(SimTransport::default());
+    for part in &parts {
+        assert!(matches!(
+            client.sign_psbt(part, &wallet, None),
+            Err(BitcoinClientError::TooManyInputs {
+                inputs: 513,
+                max: 512
+            })
+        ));
+    }
+}
+
+#[test]
+fn test_poll_state() {
+    use ledger_bitcoin_client::{
+        apdu::{APDUCommand, DeviceState},
+        command,
+    };
+    use std::sync::{mpsc, Arc, Mutex};
+
+    // Holds the commands prompting the user until released.
+    struct Gated {
+        sim: SimTransport,
+        entered: Mutex<mpsc::Sender<()>>,
+        release: Mutex<mpsc::Receiver<()>>,
+    }
+    impl client::Transport for Gated {
+        type Error = core::convert::Infallible;
+        fn exchange(&self, command: &APDUCommand) -> Result<(StatusWord, Vec<u8>), Self::Error> {
+            if command::prompts_user(command) {
+                self.entered.lock().unwrap().send(()).unwrap();
+                self.release.lock().unwrap().recv().unwrap();
+            }
+            client::Transport::exchange(&self.sim, command)
+        }
+    }
+
+    let (entered_tx, entered) = mpsc::channel();
+    let (release, release_rx) = mpsc::channel();
+    let client = Arc::new(client::BitcoinClient::new(Gated {
+        sim: SimTransport::default(),
+        entered: Mutex::new(entered_tx),
+        release: Mutex::new(release_rx),
+    }));
+    client.get_master_fingerprint().unwrap();
+    assert_eq!(client.poll_state(), DeviceState::Idle);
+
+    let signing = {
+        let client = client.clone();
+        std::thread::spawn(move || {
+            client.sign_message(
+                b"hello",
+                &DerivationPath::from_str("m/44'/1'/0'/0/0").unwrap(),
+            )
+        })
+    };
+    entered.recv().unwrap();
+    assert_eq!(client.poll_state(), DeviceState::AwaitingUserConfirmation);
+    release.send(()).unwrap();
+    signing.join().unwrap().unwrap();
+    assert_eq!(client.poll_state(), DeviceState::Idle);
+}
+
+#[tokio::test]
+async fn test_sim_get_wallet_address_multipath() {
+    use bitcoin::{secp256k1::Secp256k1, util::bip32::ChildNumber, Script};
+
+    let sim = SimTransport::default();
+    let account = DerivationPath::from_str("m/84'/1'/0'").unwrap();
+    let xpub = sim.xpub(&account);
+    let key = wallet::WalletPubKey::from_str(&format!("[f5acc2fd/84'/1'/0']{}", xpub)).unwrap();
+    let wallet = wallet::WalletPolicy::new(
+        "Multipath".to_string(),
+        wallet::Version::V2,
+        "wpkh(@0/<0;1>/*)".to_string(),
+        vec![key],
+    );
+    assert!(wallet.get_descriptor(false).unwrap().ends_with("/0/*)"));
+    assert!(wallet.get_descriptor(true).unwrap().ends_with("/1/*)"));
+
+    let client = client::BitcoinClient::new(sim);
+    let (_, hmac) = client.register_wallet(&wallet).unwrap();
+    let async_client = async_client::BitcoinClient::new(SimTransport::default());
+
+    let secp = Secp256k1::verification_only();
+    let mut scripts = Vec::new();
+    for change in [false, true] {
+        let expected = xpub
+            .derive_pub(
+                &secp,
+                &[
+                    ChildNumber::from_normal_idx(change as u32).unwrap(),
+                    ChildNumber::from_normal_idx(3).unwrap(),
+                ],
+            )
+            .unwrap()
+            .to_pub();
+        let expected = Script::new_v0_p2wpkh(&expected.wpubkey_hash().unwrap());
+
+        let address = client
+            .get_wallet_address(&wallet, Some(&hmac), change, 3, false)
+            .unwrap();
+        assert_eq!(address.script_pubkey(), expected);
+        let address = async_client
+            .get_wallet_address(&wallet, Some(&hmac), change, 3, false)
+            .await
+            .unwrap();
+        assert_eq!(address.script_pubkey(), expected);
+        scripts.push(expected);
+    }
+    assert_ne!(scripts[0], scripts[1]);
+}
+
+#[tokio::test]
+async fn test_sim_silent_and_confirm() {
+    let case = test_cases("./tests/data/get_wallet_address.json").remove(0);
+    let wallet = wallet_from_case(&case);
+    let hmac = hmac_from_case(&case);
+    let path = DerivationPath::from_str("m/84'/1'/0'").unwrap();
+    let denied = |res: Result<(), BitcoinClientError<_>>| {
+        matches!(
+            res,
+            Err(BitcoinClientError::Device {
+                status: StatusWord::Deny,
+                ..
+            })
+        )
+    };
+
+    // The user rejects everything displayed on the device.
+    let client = client::BitcoinClient::new(SimTransport::default().with_user_approval(false));
+    let address = client
+        .get_wallet_address(&wallet, hmac.as_ref(), false, 0, false)
+        .unwrap();
+    assert_eq!(
+        client
+            .get_wallet_address_silent(&wallet, hmac.as_ref(), false, 0)
+            .unwrap(),
+        address
+    );
+    assert!(denied(
+        client
+            .confirm_wallet_address(&wallet, hmac.as_ref(), false, 0)
+            .map(drop)
+    ));
+    let xpub = client.get_extended_pubkey(&path, false).unwrap();
+    assert_eq!(client.get_extended_pubkey_silent(&path).unwrap(), xpub);
+    assert!(denied(client.confirm_extended_pubkey(&path).map(drop)));
+
+    let client = async_client::BitcoinClient::new(SimTransport::default());
+    assert_eq!(
+        client
+            .confirm_wallet_address(&wallet, hmac.as_ref(), false, 0)
+            .await
+            .unwrap(),
+        address
+    );
+    assert_eq!(client.confirm_extended_pubkey(&path).await.unwrap(), xpub);
+    let client =
+        async_client::BitcoinClient::new(SimTransport::default().with_user_approval(false));
+    assert_eq!(
+        client
+            .get_wallet_address_silent(&wallet, hmac.as_ref(), false, 0)
+            .await
+            .unwrap(),
+        address
+    );
+    assert_eq!(
+        client.get_extended_pubkey_silent(&path).await.unwrap(),
+        xpub
+    );
+    assert!(denied(
+        client.confirm_extended_pubkey(&path).await.map(drop)
+    ));
+}
@@ -1,5 +1,5 @@
 use core::convert::TryFrom;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 use async_trait::async_trait;
 use bitcoin::hashes::hex::{FromHex, ToHex};
@@ -7,6 +7,7 @@ use bitcoin::hashes::hex::{FromHex, ToHex};
 use ledger_bitcoin_client::{
     apdu::{APDUCommand, StatusWord},
     async_client, client,
+    transport::SimTransport,
 };
 
 #[derive(Default, Clone)]
@@ -15,16 +16,18 @@ pub struct RecordStore {
 }
 
 impl RecordStore {
-    pub fn new(exchanges: &Vec<String>) -> RecordStore {
+    pub fn new(exchanges: &[String]) -> RecordStore {
         let mut store = RecordStore::default();
         let mut command: Vec<u8> = Vec::new();
         for (i, exchange) in exchanges.iter().enumerate() {
             let exchange = exchange.replace(" ", "");
             if let Some(cmd) = exchange.strip_prefix("=>") {
-                command = Vec::from_hex(cmd).expect(&format!("Wrong tests data {}: {}", i, cmd));
+                command = Vec::from_hex(cmd)
+                    .unwrap_or_else(|_| panic!("Wrong tests data {}: {}", i, cmd));
             }
             if let Some(resp) = exchange.strip_prefix("<=") {
-                let resp = Vec::from_hex(resp).expect(&format!("Wrong tests data {}: {}", i, resp));
+                let resp = Vec::from_hex(resp)
+                    .unwrap_or_else(|_| panic!("Wrong tests data {}: {}", i, resp));
                 store.queue.push((command.clone(), resp));
             }
         }
@@ -83,6 +86,74 @@ impl async_client::Transport for TransportReplayer {
     }
 }
 
+/// Simulated device giving the hand back to the executor before each exchange, so that
+/// the commands of concurrent tasks get the chance to interleave.
+#[derive(Default)]
+pub struct YieldingTransport {
+    sim: SimTransport,
+}
+
+#[async_trait]
+impl async_client::Transport for YieldingTransport {
+    type Error = core::convert::Infallible;
+    async fn exchange(&self, command: &APDUCommand) -> Result<(StatusWord, Vec<u8>), Self::Error> {
+        tokio::task::yield_now().await;
+        async_client::Transport::exchange(&self.sim, command).await
+    }
+}
+
+/// Simulated device altering the first byte of the first successful response of 64 bytes,
+/// the id and hmac returned on registration of a wallet.
+#[derive(Default)]
+pub struct TamperingTransport {
+    sim: SimTransport,
+    tampered: AtomicBool,
+}
+
+impl client::Transport for TamperingTransport {
+    type Error = core::convert::Infallible;
+    fn exchange(&self, command: &APDUCommand) -> Result<(StatusWord, Vec<u8>), Self::Error> {
+        let (sw, mut data) = client::Transport::exchange(&self.sim, command)?;
+        if sw == StatusWord::OK && data.len() == 64 && !self.tampered.swap(true, Ordering::Relaxed)
+        {
+            data[0] ^= 0x01;
+        }
+        Ok((sw, data))
+    }
+}
+
+/// Simulated device behind a channel of raw bytes, like a TCP relay.
+#[derive(Default)]
+pub struct RawSimTransport {
+    sim: SimTransport,
+}
+
+impl RawSimTransport {
+    fn send_recv(&self, apdu: &[u8]) -> Result<Vec<u8>, MockError> {
+        let command = APDUCommand::decode(apdu)
+            .ok_or_else(|| MockError::ExchangeNotFound(0, apdu.to_hex()))?;
+        let (sw, mut response) = client::Transport::exchange(&self.sim, &command).unwrap();
+        response.extend_from_slice(&sw.to_u16().to_be_bytes());
+        Ok(response)
+    }
+}
+
+impl client::ByteTransport for RawSimTransport {
+    type Error = MockError;
+    fn send_recv(&self, apdu: &[u8]) -> Result<Vec<u8>, Self::Error> {
+        RawSimTransport::send_recv(self, apdu)
+    }
+}
+
+#[async_trait]
+impl async_client::ByteTransport for RawSimTransport {
+    type Error = MockError;
+    async fn send_recv(&self, apdu: &[u8]) -> Result<Vec<u8>, Self::Error> {
+        RawSimTransport::send_recv(self, apdu)
+    }
+}
+
+#[allow(dead_code)]
 #[derive(Debug)]
 pub enum MockError {
     ExchangeNotFound(usize, String),